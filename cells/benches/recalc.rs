@@ -0,0 +1,101 @@
+use cells::Sheet;
+use cells::formula::cell_name;
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+
+// Column A holds a chain where every cell adds one to the cell above it
+fn chain(len: usize) -> Sheet {
+    let mut sheet = Sheet::new(len, 1);
+    sheet.set_formula((0, 0), "0");
+    for row in 1..len {
+        sheet.set_formula((row, 0), &format!("=A{}+1", row - 1));
+    }
+    sheet
+}
+
+// A0 is read directly by every other cell of the sheet
+fn fan_out(rows: usize, cols: usize) -> Sheet {
+    let mut sheet = Sheet::new(rows, cols);
+    sheet.set_formula((0, 0), "1");
+    for row in 0..rows {
+        for col in 0..cols {
+            if (row, col) != (0, 0) {
+                sheet.set_formula((row, col), &format!("=A0*{}", row + col));
+            }
+        }
+    }
+    sheet
+}
+
+// 26 columns wide: column A holds numbers and every other cell adds one to
+// its left neighbour, so an edit in column A dirties a single row
+fn large_sheet(rows: usize) -> Sheet {
+    let cols = 26;
+    let mut sheet = Sheet::new(rows, cols);
+    for row in 0..rows {
+        sheet.set_formula((row, 0), &row.to_string());
+        for col in 1..cols {
+            sheet.set_formula((row, col), &format!("={}+1", cell_name((row, col - 1))));
+        }
+    }
+    sheet
+}
+
+fn bench_long_chain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("long_chain");
+    for len in [100, 1_000, 10_000] {
+        let mut sheet = chain(len);
+        let mut n = 0;
+        group.bench_with_input(BenchmarkId::new("edit_head", len), &len, |b, _| {
+            b.iter(|| {
+                n += 1;
+                black_box(sheet.set_formula((0, 0), &n.to_string()));
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_wide_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wide_fan_out");
+    for rows in [40, 400, 4_000] {
+        let mut sheet = fan_out(rows, 26);
+        let mut n = 0;
+        group.bench_with_input(BenchmarkId::new("edit_source", rows * 26), &rows, |b, _| {
+            b.iter(|| {
+                n += 1;
+                black_box(sheet.set_formula((0, 0), &n.to_string()));
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_large_sheet(c: &mut Criterion) {
+    // 3847 rows x 26 columns is just over 100k cells
+    let rows = 3_847;
+    let mut group = c.benchmark_group("large_sheet_100k");
+    group.sample_size(10);
+
+    group.bench_function("build", |b| b.iter(|| black_box(large_sheet(rows))));
+
+    let mut sheet = large_sheet(rows);
+    let mut n = 0;
+    group.bench_function("edit_one_row", |b| {
+        b.iter(|| {
+            n += 1;
+            black_box(sheet.set_formula((rows / 2, 0), &n.to_string()));
+        })
+    });
+    group.bench_function("recalculate_all", |b| {
+        b.iter(|| black_box(sheet.recalculate_all()))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_long_chain,
+    bench_wide_fan_out,
+    bench_large_sheet
+);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+// Parses any text as a formula, then enters it into a small sheet whose
+// cells it may refer to, checking that neither panics
+#![no_main]
+
+use cells::Sheet;
+use cells::formula::parse_expression;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &str| {
+    let _ = parse_expression(input);
+    let mut sheet = Sheet::new(4, 3);
+    sheet.set_formula((0, 0), "2");
+    sheet.set_formula((1, 1), "=A0*3");
+    sheet.set_formula((2, 1), &format!("={}", input));
+    sheet.set_formula((3, 2), input);
+});
@@ -0,0 +1,13 @@
+// Reads any bytes as an .ods workbook, going through the ZIP reader, the
+// DEFLATE inflater and the XML parser, and writes back what was read,
+// checking that none of them panics
+#![no_main]
+
+use cells::ods;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((workbook, _)) = ods::read(data) {
+        let _ = ods::write(&workbook);
+    }
+});
@@ -0,0 +1,13 @@
+// Reads any bytes as an .xlsx workbook, going through the ZIP reader, the
+// DEFLATE inflater and the XML parser, and writes back what was read,
+// checking that none of them panics
+#![no_main]
+
+use cells::xlsx;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((workbook, _)) = xlsx::read(data) {
+        let _ = xlsx::write(&workbook);
+    }
+});
@@ -0,0 +1,199 @@
+use crate::Sheet;
+use crate::formula::{Range, col_to_letter};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChartKind {
+    Line,
+    Bar,
+    Scatter,
+    Pie,
+}
+
+impl ChartKind {
+    pub const ALL: [ChartKind; 4] = [
+        ChartKind::Line,
+        ChartKind::Bar,
+        ChartKind::Scatter,
+        ChartKind::Pie,
+    ];
+}
+
+impl fmt::Display for ChartKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChartKind::Line => write!(f, "Line"),
+            ChartKind::Bar => write!(f, "Bar"),
+            ChartKind::Scatter => write!(f, "Scatter"),
+            ChartKind::Pie => write!(f, "Pie"),
+        }
+    }
+}
+
+// A chart bound to a range of the sheet. Every column of the range is a
+// series, except the first one when it holds labels (line, bar and pie
+// charts) or x values (scatter charts).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chart {
+    pub kind: ChartKind,
+    pub range: Range,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub title: String,
+}
+
+// What a chart shows, read from the current values of its range
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ChartData {
+    // Category of every row of the range, for line, bar and pie charts
+    pub labels: Vec<String>,
+    pub series: Vec<Series>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    // Letter of the column the series comes from
+    pub name: String,
+    // (x, y) points. For charts with labels, x is the index of the label.
+    // Cells that do not hold a number are left out.
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Chart {
+    pub fn data(&self, sheet: &Sheet) -> ChartData {
+        let Some(range) = sheet.clip(self.range) else {
+            return ChartData::default();
+        };
+        let rows = range.start.0..=range.end.0;
+        let number = |row: usize, col: usize| sheet.value((row, col))?.as_number();
+        let multiple_columns = range.end.1 > range.start.1;
+
+        if self.kind == ChartKind::Scatter {
+            // The first column holds x, unless it is the only one
+            let (x_col, y_cols) = if multiple_columns {
+                (Some(range.start.1), range.start.1 + 1..=range.end.1)
+            } else {
+                (None, range.start.1..=range.end.1)
+            };
+            let series = y_cols
+                .map(|col| Series {
+                    name: col_to_letter(col),
+                    points: rows
+                        .clone()
+                        .enumerate()
+                        .filter_map(|(i, row)| {
+                            let x = match x_col {
+                                Some(x_col) => number(row, x_col)?,
+                                None => i as f64,
+                            };
+                            Some((x, number(row, col)?))
+                        })
+                        .collect(),
+                })
+                .collect();
+            return ChartData {
+                labels: Vec::new(),
+                series,
+            };
+        }
+
+        // A first column without numbers labels the rows
+        let has_labels =
+            multiple_columns && rows.clone().all(|row| number(row, range.start.1).is_none());
+        let labels = rows
+            .clone()
+            .map(|row| match sheet.value((row, range.start.1)) {
+                Some(value) if has_labels => value.to_string(),
+                _ if has_labels => String::new(),
+                _ => row.to_string(),
+            })
+            .collect();
+        let first = range.start.1 + has_labels as usize;
+        let series = (first..=range.end.1)
+            .map(|col| Series {
+                name: col_to_letter(col),
+                points: rows
+                    .clone()
+                    .enumerate()
+                    .filter_map(|(i, row)| Some((i as f64, number(row, col)?)))
+                    .collect(),
+            })
+            .collect();
+        ChartData { labels, series }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::parse_range;
+
+    fn chart(kind: ChartKind, range: &str) -> Chart {
+        Chart {
+            kind,
+            range: parse_range(range).unwrap(),
+            title: String::new(),
+        }
+    }
+
+    // A0:A2 holds labels, B0:B2 and C0:C2 numbers, with a gap in C1
+    fn sheet() -> Sheet {
+        let mut sheet = Sheet::new(10, 4);
+        for (row, (label, b, c)) in [("jan", "1", "10"), ("feb", "2", "x"), ("mar", "4", "=B2*5")]
+            .into_iter()
+            .enumerate()
+        {
+            sheet.set_formula((row, 0), label);
+            sheet.set_formula((row, 1), b);
+            sheet.set_formula((row, 2), c);
+        }
+        sheet
+    }
+
+    #[test]
+    fn test_labels_and_series() {
+        let data = chart(ChartKind::Bar, "A0:C2").data(&sheet());
+        assert_eq!(data.labels, vec!["jan", "feb", "mar"]);
+        assert_eq!(data.series.len(), 2);
+        assert_eq!(data.series[0].name, "B");
+        assert_eq!(
+            data.series[0].points,
+            vec![(0.0, 1.0), (1.0, 2.0), (2.0, 4.0)]
+        );
+        assert_eq!(data.series[1].points, vec![(0.0, 10.0), (2.0, 20.0)]);
+    }
+
+    #[test]
+    fn test_without_labels() {
+        // Row numbers label the rows
+        let data = chart(ChartKind::Line, "B1:B2").data(&sheet());
+        assert_eq!(data.labels, vec!["1", "2"]);
+        assert_eq!(data.series[0].points, vec![(0.0, 2.0), (1.0, 4.0)]);
+    }
+
+    #[test]
+    fn test_scatter() {
+        let data = chart(ChartKind::Scatter, "B0:C2").data(&sheet());
+        assert!(data.labels.is_empty());
+        assert_eq!(data.series.len(), 1);
+        assert_eq!(data.series[0].points, vec![(1.0, 10.0), (4.0, 20.0)]);
+    }
+
+    #[test]
+    fn test_follows_values() {
+        let mut sheet = sheet();
+        let pie = chart(ChartKind::Pie, "A0:B9");
+        sheet.set_formula((0, 1), "7");
+        let data = pie.data(&sheet);
+        assert_eq!(data.labels.len(), 10);
+        assert_eq!(data.series[0].points[0], (0.0, 7.0));
+
+        // Ranges are clipped to the sheet
+        assert_eq!(chart(ChartKind::Bar, "D8:F20").data(&sheet).series.len(), 1);
+        assert_eq!(
+            chart(ChartKind::Bar, "A10:B12").data(&sheet),
+            ChartData::default()
+        );
+    }
+}
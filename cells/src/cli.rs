@@ -0,0 +1,485 @@
+use crate::formula::{Cell, cell_name, parse_cell_reference, parse_range};
+use crate::workbook::Workbook;
+use crate::{CellValue, Sheet, collab, file, print};
+use std::io::Write;
+use std::net::TcpListener;
+
+const USAGE: &str = "usage: cells eval <sheet.json> [--cell <CELL>]... [--dump csv]";
+const CONVERT_USAGE: &str = "usage: cells convert <input> <output> [--sheet <NAME>]";
+const EXPORT_USAGE: &str =
+    "usage: cells export <sheet.json> <output.html|output.pdf> [--range <RANGE>]";
+const SERVE_USAGE: &str = "usage: cells serve [sheet.json] [--port <PORT>]";
+
+// What to print after the sheet is evaluated
+enum Output {
+    Cell(Cell),
+    Csv,
+}
+
+// Runs `cells eval`: loads a sheet file, recalculates it and prints the
+// requested cells, one per line, or the whole grid. Returns the process exit
+// code: 0 on success, 1 when any cell of the sheet holds an error (listed on
+// `err`), 2 when the arguments or the file are invalid.
+pub fn eval(args: &[String], out: &mut impl Write, err: &mut impl Write) -> i32 {
+    match run(args, out) {
+        Ok(errors) if errors.is_empty() => 0,
+        Ok(errors) => {
+            for (cell, value) in errors {
+                let _ = writeln!(err, "{}: {}", cell_name(cell), value);
+            }
+            1
+        }
+        Err(message) => {
+            let _ = writeln!(err, "cells eval: {}\n{}", message, USAGE);
+            2
+        }
+    }
+}
+
+// Prints the outputs and returns the cells in an error state
+fn run(args: &[String], out: &mut impl Write) -> Result<Vec<(Cell, CellValue)>, String> {
+    let (path, outputs) = parse_args(args)?;
+    let sheet = file::load(path)?;
+    for output in &outputs {
+        if let Output::Cell(cell) = output
+            && (cell.0 >= sheet.rows() || cell.1 >= sheet.cols())
+        {
+            return Err(format!(
+                "cell {} is outside the {}x{} sheet",
+                cell_name(*cell),
+                sheet.rows(),
+                sheet.cols()
+            ));
+        }
+    }
+
+    for output in outputs {
+        let text = match output {
+            Output::Cell(cell) => format!(
+                "{}\n",
+                sheet
+                    .value(cell)
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
+            ),
+            Output::Csv => csv(&sheet),
+        };
+        out.write_all(text.as_bytes())
+            .map_err(|e| format!("cannot write output: {}", e))?;
+    }
+
+    let mut errors: Vec<(Cell, CellValue)> = sheet
+        .values
+        .iter()
+        .filter(|(_, value)| matches!(value, CellValue::Error(_)))
+        .map(|(cell, value)| (*cell, value.clone()))
+        .collect();
+    errors.sort_by_key(|(cell, _)| *cell);
+    Ok(errors)
+}
+
+fn parse_args(args: &[String]) -> Result<(&str, Vec<Output>), String> {
+    let mut path = None;
+    let mut outputs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cell" => {
+                let name = args.next().ok_or("--cell needs a cell name")?;
+                let cell =
+                    parse_cell_reference(name).ok_or_else(|| format!("invalid cell {:?}", name))?;
+                outputs.push(Output::Cell(cell));
+            }
+            "--dump" => match args.next().map(String::as_str) {
+                Some("csv") => outputs.push(Output::Csv),
+                Some(format) => return Err(format!("unsupported dump format {:?}", format)),
+                None => return Err("--dump needs a format".to_string()),
+            },
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    Ok((path.ok_or("missing sheet file")?, outputs))
+}
+
+// Runs `cells convert`: reads a sheet file, .xlsx or .ods workbook and
+// writes it in the format of the output's extension. Writing a sheet file
+// takes the sheet named by `--sheet`, or the only one. What could not be
+// converted is listed on `err`. Returns 0 on success and 2 when the
+// arguments or files are invalid.
+pub fn convert(args: &[String], err: &mut impl Write) -> i32 {
+    match run_convert(args) {
+        Ok(warnings) => {
+            for warning in warnings {
+                let _ = writeln!(err, "warning: {}", warning);
+            }
+            0
+        }
+        Err(message) => {
+            let _ = writeln!(err, "cells convert: {}\n{}", message, CONVERT_USAGE);
+            2
+        }
+    }
+}
+
+fn run_convert(args: &[String]) -> Result<Vec<String>, String> {
+    let mut paths = Vec::new();
+    let mut sheet = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sheet" => sheet = Some(args.next().ok_or("--sheet needs a sheet name")?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if paths.len() < 2 => paths.push(arg.as_str()),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    let [input, output] = paths[..] else {
+        return Err("expected an input and an output file".to_string());
+    };
+
+    let (mut workbook, warnings) = Workbook::load(input)?;
+    if let Some(name) = sheet {
+        let index = workbook
+            .sheets
+            .iter()
+            .position(|(n, _)| n.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("{}: no sheet named {:?}", input, name))?;
+        workbook.sheets = vec![workbook.sheets.swap_remove(index)];
+    }
+    workbook.save(output)?;
+    Ok(warnings)
+}
+
+// Runs `cells export`: prints a sheet file to an HTML or PDF file, told
+// apart by the output's extension. Prints `--range`, or else the sheet's
+// print area or the used part of the sheet. Returns 0 on success and 2 when
+// the arguments or files are invalid.
+pub fn export(args: &[String], out: &mut impl Write, err: &mut impl Write) -> i32 {
+    match run_export(args) {
+        Ok((pages, output)) => {
+            let plural = if pages == 1 { "" } else { "s" };
+            let _ = writeln!(out, "wrote {} page{} to {}", pages, plural, output);
+            0
+        }
+        Err(message) => {
+            let _ = writeln!(err, "cells export: {}\n{}", message, EXPORT_USAGE);
+            2
+        }
+    }
+}
+
+fn run_export(args: &[String]) -> Result<(usize, &str), String> {
+    let mut paths = Vec::new();
+    let mut range = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--range" => {
+                let value = args.next().ok_or("--range needs a range")?;
+                range =
+                    Some(parse_range(value).ok_or_else(|| format!("invalid range {:?}", value))?);
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if paths.len() < 2 => paths.push(arg.as_str()),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    let [input, output] = paths[..] else {
+        return Err("expected a sheet file and an output file".to_string());
+    };
+    let sheet = file::load(input)?;
+    let pages = print::export(&sheet, range, output)?;
+    Ok((pages, output))
+}
+
+// Runs `cells serve`: serves collaborative editing on localhost, starting
+// from the formulas of the sheet file when one is given, until the process
+// is stopped. Returns 2 when the arguments or the file are invalid and 1
+// when serving fails.
+pub fn serve(args: &[String], out: &mut impl Write, err: &mut impl Write) -> i32 {
+    let (listener, sheet) = match start_serving(args) {
+        Ok(started) => started,
+        Err(message) => {
+            let _ = writeln!(err, "cells serve: {}\n{}", message, SERVE_USAGE);
+            return 2;
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        let _ = writeln!(out, "serving on ws://{}", address);
+        let _ = out.flush();
+    }
+    match collab::serve(listener, &sheet) {
+        Ok(()) => 0,
+        Err(e) => {
+            let _ = writeln!(err, "cells serve: {}", e);
+            1
+        }
+    }
+}
+
+fn start_serving(args: &[String]) -> Result<(TcpListener, Sheet), String> {
+    let mut path = None;
+    let mut port = collab::DEFAULT_PORT;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                let value = args.next().ok_or("--port needs a port number")?;
+                port = value
+                    .parse()
+                    .map_err(|_| format!("invalid port {:?}", value))?;
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    let sheet = match path {
+        Some(path) => file::load(path)?,
+        None => Sheet::new(100, 26),
+    };
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| format!("cannot listen on port {}: {}", port, e))?;
+    Ok((listener, sheet))
+}
+
+// Evaluated values of the used part of the grid, from A0 to the last
+// non-empty row and column
+fn csv(sheet: &Sheet) -> String {
+    let rows = sheet
+        .values
+        .keys()
+        .map(|(row, _)| row + 1)
+        .max()
+        .unwrap_or(0);
+    let cols = sheet
+        .values
+        .keys()
+        .map(|(_, col)| col + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut csv = String::new();
+    for row in 0..rows {
+        let fields: Vec<String> = (0..cols)
+            .map(|col| {
+                let value = sheet.value((row, col)).map(ToString::to_string);
+                csv_field(&value.unwrap_or_default())
+            })
+            .collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+// Quotes a field containing separators, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the sheet to a temporary file and runs `cells eval` on it
+    fn run_eval(json: &str, args: &[&str]) -> (i32, String, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cli.json");
+        std::fs::write(&path, json).unwrap();
+
+        let mut all = vec![path.to_string_lossy().to_string()];
+        all.extend(args.iter().map(|a| a.to_string()));
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = eval(&all, &mut out, &mut err);
+
+        (
+            code,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(err).unwrap(),
+        )
+    }
+
+    const SHEET: &str = r#"{ "cells": {
+        "A0": "5", "A1": "3", "A2": "=A0+A1",
+        "B0": "Total, net", "B2": "=A2/2"
+    } }"#;
+
+    #[test]
+    fn test_print_cells() {
+        let (code, out, err) = run_eval(SHEET, &["--cell", "A2", "--cell", "b2", "--cell", "C9"]);
+        assert_eq!(code, 0, "{}", err);
+        assert_eq!(out, "8\n4\n\n");
+    }
+
+    #[test]
+    fn test_dump_csv() {
+        let (code, out, _) = run_eval(SHEET, &["--dump", "csv"]);
+        assert_eq!(code, 0);
+        assert_eq!(out, "5,\"Total, net\"\n3,\n8,4\n");
+    }
+
+    #[test]
+    fn test_error_cells_fail() {
+        let json = r#"{ "cells": { "A0": "0", "A1": "=1/A0", "B4": "=A1+1" } }"#;
+        let (code, out, err) = run_eval(json, &["--cell", "A0"]);
+        assert_eq!(code, 1);
+        assert_eq!(out, "0\n");
+        assert_eq!(err, "A1: #DIV0\nB4: #DIV0\n");
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        for args in [
+            &["--cell"][..],
+            &["--cell", "11"],
+            &["--cell", "A500"],
+            &["--dump", "xml"],
+            &["--verbose"],
+            &["extra.json"],
+        ] {
+            let (code, _, err) = run_eval(SHEET, args);
+            assert_eq!(code, 2, "{:?}", args);
+            assert!(err.contains(USAGE));
+        }
+
+        let mut err = Vec::new();
+        let code = eval(
+            &["/nonexistent/sheet.json".to_string()],
+            &mut Vec::new(),
+            &mut err,
+        );
+        assert_eq!(code, 2);
+        assert!(String::from_utf8(err).unwrap().contains("cannot read"));
+    }
+
+    #[test]
+    fn test_convert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        std::fs::write(path("in.json"), SHEET).unwrap();
+
+        let convert = |args: &[String]| {
+            let mut err = Vec::new();
+            let code = convert(args, &mut err);
+            (code, String::from_utf8(err).unwrap())
+        };
+        for args in [
+            vec![path("in.json"), path("book.xlsx")],
+            vec![path("book.xlsx"), path("book.ods")],
+            vec![
+                path("book.ods"),
+                path("out.json"),
+                "--sheet".into(),
+                "sheet1".into(),
+            ],
+        ] {
+            let (code, err) = convert(&args);
+            assert_eq!(code, 0, "{:?}: {}", args, err);
+        }
+        let sheet = file::load(path("out.json")).unwrap();
+        assert_eq!(
+            sheet.formulas,
+            file::load(path("in.json")).unwrap().formulas
+        );
+
+        for args in [
+            vec![path("in.json")],
+            vec![path("in.json"), path("out.csv")],
+            vec![
+                path("book.ods"),
+                path("x.json"),
+                "--sheet".into(),
+                "Other".into(),
+            ],
+            vec![path("missing.xlsx"), path("x.json")],
+        ] {
+            let (code, err) = convert(&args);
+            assert_eq!(code, 2, "{:?}", args);
+            assert!(err.contains(CONVERT_USAGE));
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        std::fs::write(path("in.json"), SHEET).unwrap();
+
+        let export = |args: &[String]| {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let code = export(args, &mut out, &mut err);
+            (
+                code,
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        };
+        let (code, out, err) = export(&[path("in.json"), path("out.pdf")]);
+        assert_eq!(code, 0, "{}", err);
+        assert_eq!(out, format!("wrote 1 page to {}\n", path("out.pdf")));
+        assert!(std::fs::read(path("out.pdf")).unwrap().starts_with(b"%PDF"));
+        let args = [
+            path("in.json"),
+            path("out.html"),
+            "--range".into(),
+            "A0:B1".into(),
+        ];
+        assert_eq!(export(&args).0, 0);
+        assert!(
+            std::fs::read_to_string(path("out.html"))
+                .unwrap()
+                .contains("<table")
+        );
+
+        for args in [
+            vec![path("in.json")],
+            vec![path("in.json"), path("out.txt")],
+            vec![
+                path("in.json"),
+                path("out.pdf"),
+                "--range".into(),
+                "A0:".into(),
+            ],
+            vec![path("missing.json"), path("out.pdf")],
+        ] {
+            let (code, _, err) = export(&args);
+            assert_eq!(code, 2, "{:?}", args);
+            assert!(err.contains(EXPORT_USAGE));
+        }
+    }
+
+    #[test]
+    fn test_serve_arguments() {
+        for args in [
+            vec!["--port"],
+            vec!["--port", "http"],
+            vec!["--port", "70000"],
+            vec!["--bind", "0.0.0.0"],
+            vec!["missing.json"],
+            vec!["a.json", "b.json"],
+        ] {
+            let args: Vec<String> = args.into_iter().map(String::from).collect();
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            assert_eq!(serve(&args, &mut out, &mut err), 2, "{:?}", args);
+            assert!(String::from_utf8(err).unwrap().contains(SERVE_USAGE));
+        }
+
+        // The sheet file's formulas are served
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.json").to_string_lossy().to_string();
+        std::fs::write(&path, SHEET).unwrap();
+        let (listener, sheet) = start_serving(&[path, "--port".into(), "0".into()]).unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+        assert_eq!(
+            sheet.formulas,
+            file::load(dir.path().join("in.json")).unwrap().formulas
+        );
+    }
+}
@@ -0,0 +1,618 @@
+// Collaborative editing through a small WebSocket server. Clients send the
+// formulas typed into cells as edits stamped with a Lamport clock; every
+// replica, the server's included, keeps for each cell the edit with the
+// greatest stamp, so all of them end up with the same formulas whatever
+// order the edits arrive in. The server relays the edits that win, and the
+// cells selected by each user, to everyone else. Clients recalculate their
+// own sheet as edits arrive.
+//
+// Updates travel as JSON text messages, e.g.
+//
+//     { "type": "edit", "cell": "B3", "formula": "=A0*2",
+//       "stamp": { "counter": 7, "client": 2 } }
+
+use crate::Sheet;
+use crate::formula::{Cell, cell_name, parse_cell_reference};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tungstenite::{WebSocket, protocol::Message};
+
+// Port the server listens on by default
+pub const DEFAULT_PORT: u16 = 9001;
+
+// How long a connection waits for the other side before looking for
+// updates of its own to send
+const POLL: Duration = Duration::from_millis(20);
+
+// Longest wait to reach a server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Furthest the server lets an edit's counter run ahead of its clock. Honest
+// clients only count the edits they have seen, so a larger jump is forged
+// and would leave no room for later edits to win.
+const MAX_CLOCK_JUMP: u64 = 1 << 32;
+
+// Lamport timestamp of an edit. Stamps are ordered by counter and then by
+// client, so every replica orders concurrent edits the same way.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Stamp {
+    pub counter: u64,
+    pub client: u64,
+}
+
+// A formula typed into a cell, by cell name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Edit {
+    pub cell: String,
+    pub formula: String,
+    pub stamp: Stamp,
+}
+
+// Another user connected to the server and the cell they have selected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Peer {
+    pub client: u64,
+    pub user: String,
+    pub cell: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    // First message of a client, naming its user
+    Hello {
+        user: String,
+    },
+    // The server's answer: the client's id, the latest edit of every cell
+    // and the other users
+    Welcome {
+        client: u64,
+        edits: Vec<Edit>,
+        peers: Vec<Peer>,
+    },
+    Edit(Edit),
+    // A user joined or selected another cell
+    Select(Peer),
+    Leave {
+        client: u64,
+    },
+}
+
+// Last-writer-wins state of one replica: the stamp of the latest edit of
+// every cell and the clock stamping edits made here
+#[derive(Debug, Clone, Default)]
+pub struct Replica {
+    client: u64,
+    clock: u64,
+    stamps: HashMap<Cell, Stamp>,
+}
+
+impl Replica {
+    pub fn new(client: u64) -> Self {
+        Self {
+            client,
+            ..Self::default()
+        }
+    }
+
+    // Replica of a client that was just welcomed with the given edits
+    pub fn welcomed(client: u64, edits: &[Edit]) -> Self {
+        let mut replica = Self::new(client);
+        for edit in edits {
+            if let Some(cell) = parse_cell_reference(&edit.cell) {
+                replica.merge(cell, edit.stamp);
+            }
+        }
+        replica
+    }
+
+    pub fn client(&self) -> u64 {
+        self.client
+    }
+
+    // Stamps an edit made here, which wins over every edit seen so far
+    pub fn stamp(&mut self, cell: Cell) -> Stamp {
+        self.clock = self.clock.saturating_add(1);
+        let stamp = Stamp {
+            counter: self.clock,
+            client: self.client,
+        };
+        self.stamps.insert(cell, stamp);
+        stamp
+    }
+
+    // Records an edit received from elsewhere and tells whether it wins
+    // over the one the cell holds. Either way later edits made here are
+    // stamped after it.
+    pub fn merge(&mut self, cell: Cell, stamp: Stamp) -> bool {
+        self.clock = self.clock.max(stamp.counter);
+        if self
+            .stamps
+            .get(&cell)
+            .is_some_and(|current| *current >= stamp)
+        {
+            return false;
+        }
+        self.stamps.insert(cell, stamp);
+        true
+    }
+}
+
+// Shared state of a server: the size of the sheet, its replica, the winning
+// edit of every cell and the clients connected, each with the queue of
+// updates to send it
+struct Server {
+    size: (usize, usize),
+    replica: Replica,
+    edits: BTreeMap<Cell, Edit>,
+    clients: BTreeMap<u64, (Peer, Sender<Update>)>,
+    next_client: u64,
+}
+
+impl Server {
+    // Starts from the formulas of a sheet, stamped before any edit
+    fn new(sheet: &Sheet) -> Self {
+        let edits = sheet
+            .formulas
+            .iter()
+            .map(|(cell, formula)| {
+                let edit = Edit {
+                    cell: cell_name(*cell),
+                    formula: formula.clone(),
+                    stamp: Stamp::default(),
+                };
+                (*cell, edit)
+            })
+            .collect();
+        Self {
+            size: (sheet.rows(), sheet.cols()),
+            replica: Replica::new(0),
+            edits,
+            clients: BTreeMap::new(),
+            next_client: 1,
+        }
+    }
+
+    // Welcomes a new client and tells the others about it
+    fn join(&mut self, user: String, outbox: Sender<Update>) -> u64 {
+        let client = self.next_client;
+        self.next_client += 1;
+        let peer = Peer {
+            client,
+            user,
+            cell: None,
+        };
+        let _ = outbox.send(Update::Welcome {
+            client,
+            edits: self.edits.values().cloned().collect(),
+            peers: self
+                .clients
+                .values()
+                .map(|(peer, _)| peer.clone())
+                .collect(),
+        });
+        self.broadcast(client, Update::Select(peer.clone()));
+        self.clients.insert(client, (peer, outbox));
+        client
+    }
+
+    fn receive(&mut self, client: u64, update: Update) {
+        match update {
+            Update::Edit(mut edit) => {
+                // Edits of cells outside the session's sheet are dropped,
+                // as no client could show them
+                let Some(cell) = parse_cell_reference(&edit.cell)
+                    .filter(|cell| cell.0 < self.size.0 && cell.1 < self.size.1)
+                else {
+                    eprintln!(
+                        "cells serve: dropped an edit of {:?} from client {}, not a cell of the sheet",
+                        edit.cell, client
+                    );
+                    return;
+                };
+                // Edits are stamped by the connection they came in on, and
+                // counters far ahead of every edit seen are refused
+                edit.stamp.client = client;
+                if edit.stamp.counter > self.replica.clock.saturating_add(MAX_CLOCK_JUMP) {
+                    return;
+                }
+                // Edits that lost to a later one are dropped; their author
+                // gets the winner, or already has it
+                if self.replica.merge(cell, edit.stamp) {
+                    self.edits.insert(cell, edit.clone());
+                    self.broadcast(client, Update::Edit(edit));
+                }
+            }
+            Update::Select(Peer { cell, .. }) => {
+                if let Some((peer, _)) = self.clients.get_mut(&client) {
+                    peer.cell = cell;
+                    let peer = peer.clone();
+                    self.broadcast(client, Update::Select(peer));
+                }
+            }
+            Update::Hello { .. } | Update::Welcome { .. } | Update::Leave { .. } => {}
+        }
+    }
+
+    fn leave(&mut self, client: u64) {
+        self.clients.remove(&client);
+        self.broadcast(client, Update::Leave { client });
+    }
+
+    // Sends an update to every client but its sender
+    fn broadcast(&self, sender: u64, update: Update) {
+        for (client, (_, outbox)) in &self.clients {
+            if *client != sender {
+                let _ = outbox.send(update.clone());
+            }
+        }
+    }
+}
+
+// Serves clients from the listener, starting from the formulas of the
+// sheet, until accepting a connection fails. Every client is served on a
+// thread of its own.
+pub fn serve(listener: TcpListener, sheet: &Sheet) -> std::io::Result<()> {
+    let server = Arc::new(Mutex::new(Server::new(sheet)));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            let _ = serve_client(stream, &server);
+        });
+    }
+    Ok(())
+}
+
+fn serve_client(stream: TcpStream, server: &Mutex<Server>) -> Result<(), String> {
+    let mut socket = tungstenite::accept(stream).map_err(|e| e.to_string())?;
+    let user = match receive(&mut socket)? {
+        Update::Hello { user } => user,
+        other => return Err(format!("expected hello, got {:?}", other)),
+    };
+    let (outbox, inbox) = mpsc::channel();
+    let client = server.lock().unwrap().join(user, outbox);
+
+    socket
+        .get_ref()
+        .set_read_timeout(Some(POLL))
+        .map_err(|e| e.to_string())?;
+    let result = pump(&mut socket, &inbox, |update| {
+        server.lock().unwrap().receive(client, update)
+    });
+    server.lock().unwrap().leave(client);
+    result
+}
+
+// Connects to the server at a ws:// URL as `user`. Updates from the server
+// are handed to `received` on the connection's own thread, starting with
+// the welcome. Updates sent on the returned queue go to the server; dropping
+// it closes the connection, and so does the server going away, after which
+// `received` is dropped.
+pub fn connect(
+    url: &str,
+    user: &str,
+    received: impl FnMut(Update) + Send + 'static,
+) -> Result<Sender<Update>, String> {
+    let authority = url
+        .strip_prefix("ws://")
+        .ok_or_else(|| format!("{:?} is not a ws:// URL", url))?
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let address = authority
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", authority, e))?
+        .next()
+        .ok_or_else(|| format!("{}: no address", authority))?;
+    let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+        .map_err(|e| format!("{}: {}", authority, e))?;
+    let (mut socket, _) = tungstenite::client(url, stream).map_err(|e| e.to_string())?;
+    send(
+        &mut socket,
+        &Update::Hello {
+            user: user.to_string(),
+        },
+    )?;
+    socket
+        .get_ref()
+        .set_read_timeout(Some(POLL))
+        .map_err(|e| e.to_string())?;
+
+    let (outbox, inbox) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = pump(&mut socket, &inbox, received);
+    });
+    Ok(outbox)
+}
+
+// Exchanges updates until either side closes: sends what arrives on the
+// outbox and hands what the socket receives to `received`. The socket must
+// time out on reads, so that the outbox is looked at regularly.
+fn pump(
+    socket: &mut WebSocket<TcpStream>,
+    outbox: &Receiver<Update>,
+    mut received: impl FnMut(Update),
+) -> Result<(), String> {
+    loop {
+        loop {
+            match outbox.try_recv() {
+                Ok(update) => send(socket, &update)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return Ok(());
+                }
+            }
+        }
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                // Updates this version does not know are skipped
+                if let Ok(update) = serde_json::from_str(&text) {
+                    received(update);
+                }
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                return Ok(());
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+fn send(socket: &mut WebSocket<TcpStream>, update: &Update) -> Result<(), String> {
+    let json = serde_json::to_string(update).expect("updates always serialize");
+    socket.send(Message::text(json)).map_err(|e| e.to_string())
+}
+
+// Waits for the next update, skipping other messages
+fn receive(socket: &mut WebSocket<TcpStream>) -> Result<Update, String> {
+    loop {
+        if let Message::Text(text) = socket.read().map_err(|e| e.to_string())? {
+            return serde_json::from_str(&text).map_err(|e| e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(counter: u64, client: u64) -> Stamp {
+        Stamp { counter, client }
+    }
+
+    #[test]
+    fn test_last_writer_wins() {
+        let mut a = Replica::new(1);
+        let mut b = Replica::new(2);
+        let first = a.stamp((0, 0));
+        let second = b.stamp((0, 0));
+        // Concurrent edits with the same counter: the higher client wins on
+        // both replicas
+        assert!(!b.merge((0, 0), first));
+        assert!(a.merge((0, 0), second));
+        // Edits made after seeing another are stamped after it
+        let third = a.stamp((0, 0));
+        assert!(third > second);
+        assert!(b.merge((0, 0), third));
+        // Old edits arriving late lose
+        assert!(!b.merge((0, 0), first));
+        assert!(b.merge((1, 0), stamp(1, 1)));
+        // The clock stops at its largest value instead of overflowing
+        assert!(b.merge((2, 0), stamp(u64::MAX, 1)));
+        assert_eq!(b.stamp((2, 0)), stamp(u64::MAX, 2));
+    }
+
+    #[test]
+    fn test_server_refuses_forged_stamps() {
+        let mut server = Server::new(&Sheet::new(10, 3));
+        let (ann, _) = mpsc::channel();
+        let (bob, bob_updates) = mpsc::channel();
+        let ann = server.join("ann".to_string(), ann);
+        server.join("bob".to_string(), bob);
+        assert!(matches!(bob_updates.try_recv(), Ok(Update::Welcome { .. })));
+        let edit = |formula: &str, stamp: Stamp| Edit {
+            cell: "A0".to_string(),
+            formula: formula.to_string(),
+            stamp,
+        };
+
+        // A counter far ahead of the server's clock is dropped
+        server.receive(ann, Update::Edit(edit("1", stamp(u64::MAX, ann))));
+        assert!(bob_updates.try_recv().is_err());
+        server.receive(ann, Update::Edit(edit("1", stamp(MAX_CLOCK_JUMP + 1, ann))));
+        assert!(bob_updates.try_recv().is_err());
+        // An edit claiming another client's id carries its sender's
+        server.receive(ann, Update::Edit(edit("2", stamp(1, 99))));
+        assert_eq!(
+            bob_updates.try_recv(),
+            Ok(Update::Edit(edit("2", stamp(1, ann))))
+        );
+        server.receive(ann, Update::Edit(edit("3", stamp(MAX_CLOCK_JUMP, ann))));
+        assert_eq!(
+            bob_updates.try_recv(),
+            Ok(Update::Edit(edit("3", stamp(MAX_CLOCK_JUMP, ann))))
+        );
+
+        // Cells outside the sheet are dropped
+        for cell in ["D0", "A10", "Z99999"] {
+            let outside = Edit {
+                cell: cell.to_string(),
+                ..edit("4", stamp(MAX_CLOCK_JUMP + 1, ann))
+            };
+            server.receive(ann, Update::Edit(outside));
+            assert!(bob_updates.try_recv().is_err(), "{cell}");
+        }
+        assert_eq!(server.edits.len(), 1);
+    }
+
+    #[test]
+    fn test_edits_converge_in_any_order() {
+        let edits = [
+            ((0, 0), stamp(1, 1)),
+            ((0, 0), stamp(2, 2)),
+            ((1, 0), stamp(1, 2)),
+            ((0, 0), stamp(2, 1)),
+            ((1, 0), stamp(3, 1)),
+        ];
+        let winners = |order: &[usize]| {
+            let mut replica = Replica::new(9);
+            for i in order {
+                let (cell, stamp) = edits[*i];
+                replica.merge(cell, stamp);
+            }
+            replica.stamps
+        };
+        let expected = winners(&[0, 1, 2, 3, 4]);
+        assert_eq!(expected[&(0, 0)], stamp(2, 2));
+        assert_eq!(expected[&(1, 0)], stamp(3, 1));
+        assert_eq!(winners(&[4, 3, 2, 1, 0]), expected);
+        assert_eq!(winners(&[2, 0, 4, 1, 3]), expected);
+    }
+
+    #[test]
+    fn test_update_json() {
+        let update = Update::Edit(Edit {
+            cell: "B3".to_string(),
+            formula: "=A0*2".to_string(),
+            stamp: stamp(7, 2),
+        });
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"edit","cell":"B3","formula":"=A0*2","stamp":{"counter":7,"client":2}}"#
+        );
+        assert_eq!(serde_json::from_str::<Update>(&json).unwrap(), update);
+        assert_eq!(
+            serde_json::from_str::<Update>(r#"{"type":"leave","client":3}"#).unwrap(),
+            Update::Leave { client: 3 }
+        );
+    }
+
+    // Connects a client whose updates arrive on the returned receiver
+    fn join(url: &str, user: &str) -> (Sender<Update>, Receiver<Update>) {
+        let (sender, receiver) = mpsc::channel();
+        let outbox = connect(url, user, move |update| {
+            let _ = sender.send(update);
+        })
+        .unwrap();
+        (outbox, receiver)
+    }
+
+    fn next(receiver: &Receiver<Update>) -> Update {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn test_server_relays_edits_and_selections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "5");
+        std::thread::spawn(move || serve(listener, &sheet));
+
+        let (ann, ann_updates) = join(&url, "ann");
+        let Update::Welcome {
+            client,
+            edits,
+            peers,
+        } = next(&ann_updates)
+        else {
+            panic!("expected a welcome");
+        };
+        assert_eq!(client, 1);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            (edits[0].cell.as_str(), edits[0].formula.as_str()),
+            ("A0", "5")
+        );
+        assert!(peers.is_empty());
+
+        let (bob, bob_updates) = join(&url, "bob");
+        assert!(
+            matches!(next(&bob_updates), Update::Welcome { client: 2, peers, .. } if peers.len() == 1)
+        );
+        assert_eq!(
+            next(&ann_updates),
+            Update::Select(Peer {
+                client: 2,
+                user: "bob".to_string(),
+                cell: None
+            })
+        );
+
+        // Ann's edit reaches Bob
+        let mut replica = Replica::welcomed(1, &edits);
+        let edit = Edit {
+            cell: "A0".to_string(),
+            formula: "7".to_string(),
+            stamp: replica.stamp((0, 0)),
+        };
+        ann.send(Update::Edit(edit.clone())).unwrap();
+        assert_eq!(next(&bob_updates), Update::Edit(edit.clone()));
+
+        // An edit that lost is not relayed
+        bob.send(Update::Edit(Edit {
+            formula: "old".to_string(),
+            stamp: Stamp::default(),
+            ..edit.clone()
+        }))
+        .unwrap();
+        bob.send(Update::Select(Peer {
+            client: 0,
+            user: String::new(),
+            cell: Some("B1".to_string()),
+        }))
+        .unwrap();
+        assert_eq!(
+            next(&ann_updates),
+            Update::Select(Peer {
+                client: 2,
+                user: "bob".to_string(),
+                cell: Some("B1".to_string())
+            })
+        );
+
+        // Latecomers get the latest formulas
+        let (_carol, carol_updates) = join(&url, "carol");
+        let Update::Welcome { edits, peers, .. } = next(&carol_updates) else {
+            panic!("expected a welcome");
+        };
+        assert_eq!(edits, [edit]);
+        assert_eq!(peers.len(), 2);
+
+        drop(bob);
+        assert_eq!(
+            next(&ann_updates),
+            Update::Select(Peer {
+                client: 3,
+                user: "carol".to_string(),
+                cell: None
+            })
+        );
+        assert_eq!(next(&ann_updates), Update::Leave { client: 2 });
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        assert!(connect("http://localhost", "ann", |_| {}).is_err());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        assert!(connect(&format!("ws://{}", address), "ann", |_| {}).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A note attached to a cell, with who wrote it and when it was last edited
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comment {
+    pub text: String,
+    #[serde(default)]
+    pub author: String,
+    // UTC time in RFC 3339 form, e.g. "2024-05-01T09:30:00Z"
+    #[serde(default)]
+    pub time: String,
+}
+
+impl Comment {
+    // A comment by the current user, written now
+    pub fn new(text: impl Into<String>) -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Self {
+            text: text.into(),
+            author: current_author(),
+            time: timestamp(seconds),
+        }
+    }
+}
+
+// Login name of the user running the program
+pub fn current_author() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// RFC 3339 form of a Unix time, in UTC
+pub fn timestamp(seconds: u64) -> String {
+    let (days, rest) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, counting in 400 year eras of
+    // March-based years so leap days fall at the end of a year
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(timestamp(1_714_555_800), "2024-05-01T09:30:00Z");
+        assert_eq!(timestamp(1_735_689_599), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_new_comment() {
+        let comment = Comment::new("Checked against the invoice");
+        assert_eq!(comment.text, "Checked against the invoice");
+        assert!(!comment.author.is_empty());
+        assert_eq!(comment.time.len(), "1970-01-01T00:00:00Z".len());
+    }
+}
@@ -0,0 +1,709 @@
+// Exact decimal arithmetic for sheets in decimal mode. There, numbers typed
+// into cells and written in formulas are read as the decimals they are
+// written as, and formulas compute on decimals and store decimal results,
+// like IEEE 754 decimal128 with 34 significant digits: 0.1 + 0.2 is 0.3,
+// where binary floating point gives 0.30000000000000004, and integers of up
+// to 34 digits stay exact. Functions without a decimal form, such as SQRT,
+// compute in floating point, and their results are read back as the
+// shortest decimal that round-trips them.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+// How formulas do arithmetic, chosen per sheet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Arithmetic {
+    // Binary floating point
+    #[default]
+    Float,
+    // Decimals of 34 significant digits
+    Decimal,
+}
+
+// Significant digits of decimal results, as in IEEE 754 decimal128
+const PRECISION: usize = 34;
+
+// Exponents of the leading digit of results kept as decimals. Results
+// outside them, near or beyond what f64 can hold, continue in floating
+// point and overflow to infinity or underflow to zero there.
+const EXPONENTS: std::ops::RangeInclusive<i64> = -300..=307;
+
+// Number a formula computes with: a float, or a decimal in decimal mode
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Float(f64),
+    Exact(Decimal),
+}
+
+impl Number {
+    // The nearest f64
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Float(x) => *x,
+            Number::Exact(x) => x.to_f64(),
+        }
+    }
+
+    pub fn negated(&self) -> Number {
+        match self {
+            Number::Float(x) => Number::Float(-x),
+            Number::Exact(x) => Number::Exact(x.negated()),
+        }
+    }
+
+    fn to_decimal(&self) -> Option<Decimal> {
+        match self {
+            Number::Float(x) => Decimal::from_f64(*x),
+            Number::Exact(x) => Some(x.clone()),
+        }
+    }
+
+    // Order of two numbers, exact between decimals
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Exact(a), Number::Exact(b)) => Some(a.compare(b)),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl Arithmetic {
+    pub fn is_float(&self) -> bool {
+        *self == Arithmetic::Float
+    }
+
+    pub fn add(self, a: &Number, b: &Number) -> Number {
+        self.apply(a, b, |a, b| a + b, |a, b| Some(a.add(b)))
+    }
+
+    pub fn sub(self, a: &Number, b: &Number) -> Number {
+        self.apply(a, b, |a, b| a - b, |a, b| Some(a.sub(b)))
+    }
+
+    pub fn mul(self, a: &Number, b: &Number) -> Number {
+        self.apply(a, b, |a, b| a * b, |a, b| Some(a.mul(b)))
+    }
+
+    // Callers check for division by zero
+    pub fn div(self, a: &Number, b: &Number) -> Number {
+        self.apply(a, b, |a, b| a / b, Decimal::div)
+    }
+
+    // Largest integer not above a / b
+    pub fn div_floor(self, a: &Number, b: &Number) -> Number {
+        self.apply(a, b, |a, b| (a / b).floor(), Decimal::div_floor)
+    }
+
+    // Total of the numbers, exact in decimal mode however many there are
+    pub fn sum(self, numbers: &[Number]) -> Number {
+        let zero = match self {
+            Arithmetic::Float => Number::Float(0.0),
+            Arithmetic::Decimal => Number::Exact(Decimal::zero()),
+        };
+        numbers.iter().fold(zero, |total, n| self.add(&total, n))
+    }
+
+    // Rounds half away from zero to a number of decimal places; negative
+    // digits round to the left of the decimal point
+    pub fn round(self, x: &Number, digits: i32) -> Number {
+        match (self, x.to_decimal()) {
+            (Arithmetic::Decimal, Some(decimal)) => {
+                exact(decimal.round(digits), || Arithmetic::Float.round(x, digits))
+            }
+            _ => {
+                let factor = 10f64.powi(digits);
+                Number::Float((x.to_f64() * factor).round() / factor)
+            }
+        }
+    }
+
+    // Applies the float or the decimal form of an operation, depending on
+    // the mode
+    fn apply(
+        self,
+        a: &Number,
+        b: &Number,
+        float: impl Fn(f64, f64) -> f64,
+        decimal: impl Fn(&Decimal, &Decimal) -> Option<Decimal>,
+    ) -> Number {
+        let fallback = || Number::Float(float(a.to_f64(), b.to_f64()));
+        if self.is_float() {
+            return fallback();
+        }
+        let result = a
+            .to_decimal()
+            .zip(b.to_decimal())
+            .and_then(|(a, b)| decimal(&a, &b));
+        match result {
+            Some(result) => exact(result, fallback),
+            None => fallback(),
+        }
+    }
+}
+
+// A decimal result rounded to the precision, or the float result when it is
+// out of range
+fn exact(result: Decimal, fallback: impl FnOnce() -> Number) -> Number {
+    let result = result.with_precision(PRECISION);
+    if result.in_range() {
+        Number::Exact(result)
+    } else {
+        fallback()
+    }
+}
+
+// Decimal of any size: the magnitude divided by 10^scale, with a sign
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decimal {
+    negative: bool,
+    magnitude: Natural,
+    scale: u32,
+}
+
+impl Decimal {
+    pub fn zero() -> Self {
+        Decimal {
+            negative: false,
+            magnitude: Natural::zero(),
+            scale: 0,
+        }
+    }
+
+    // Plain decimal notation such as "-12.5"
+    pub fn parse(text: &str) -> Option<Self> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+        Some(
+            Decimal {
+                negative,
+                magnitude: Natural::parse(&format!("{}{}", whole, fraction)),
+                scale: fraction.len() as u32,
+            }
+            .normalized(),
+        )
+    }
+
+    // The shortest decimal that converts back to `x`, None for infinities
+    // and NaN
+    pub fn from_f64(x: f64) -> Option<Self> {
+        if x.is_finite() {
+            Self::parse(&x.to_string())
+        } else {
+            None
+        }
+    }
+
+    // The nearest f64
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    pub fn add(&self, other: &Decimal) -> Decimal {
+        let (a, b, scale) = aligned(self, other);
+        let (negative, magnitude) = if self.negative == other.negative {
+            (self.negative, a.add(&b))
+        } else {
+            match a.cmp(&b) {
+                Ordering::Less => (other.negative, b.sub(&a)),
+                _ => (self.negative, a.sub(&b)),
+            }
+        };
+        Decimal {
+            negative,
+            magnitude,
+            scale,
+        }
+        .normalized()
+    }
+
+    pub fn sub(&self, other: &Decimal) -> Decimal {
+        self.add(&other.negated())
+    }
+
+    pub fn mul(&self, other: &Decimal) -> Decimal {
+        Decimal {
+            negative: self.negative != other.negative,
+            magnitude: self.magnitude.mul(&other.magnitude),
+            scale: self.scale + other.scale,
+        }
+        .normalized()
+    }
+
+    // Quotient rounded half away from zero to 34 significant digits, None
+    // when dividing by zero
+    pub fn div(&self, other: &Decimal) -> Option<Decimal> {
+        if other.magnitude.is_zero() {
+            return None;
+        }
+        // Enough decimal places for the significant digits wanted
+        let places = (PRECISION as i64 + other.magnitude.digits() as i64 + self.scale as i64
+            - self.magnitude.digits() as i64
+            - other.scale as i64)
+            .max(0) as u32;
+        let numerator = self.magnitude.shifted((other.scale + places) as usize);
+        let denominator = other.magnitude.shifted(self.scale as usize);
+        let (mut quotient, remainder) = numerator.div_rem(&denominator);
+        if remainder.add(&remainder).cmp(&denominator) != Ordering::Less {
+            quotient = quotient.add(&Natural::from(1));
+        }
+        Some(
+            Decimal {
+                negative: self.negative != other.negative,
+                magnitude: quotient,
+                scale: places,
+            }
+            .normalized(),
+        )
+    }
+
+    // Largest integer not above the quotient, None when dividing by zero
+    pub fn div_floor(&self, other: &Decimal) -> Option<Decimal> {
+        if other.magnitude.is_zero() {
+            return None;
+        }
+        let numerator = self.magnitude.shifted(other.scale as usize);
+        let denominator = other.magnitude.shifted(self.scale as usize);
+        let (mut quotient, remainder) = numerator.div_rem(&denominator);
+        let negative = self.negative != other.negative;
+        if negative && !remainder.is_zero() {
+            quotient = quotient.add(&Natural::from(1));
+        }
+        Some(
+            Decimal {
+                negative,
+                magnitude: quotient,
+                scale: 0,
+            }
+            .normalized(),
+        )
+    }
+
+    // Rounded half away from zero to a number of decimal places; negative
+    // digits round to the left of the decimal point
+    pub fn round(&self, digits: i32) -> Decimal {
+        if digits >= 0 && digits as u32 >= self.scale {
+            return self.clone();
+        }
+        let dropped = (self.scale as i64 - digits as i64) as usize;
+        // Less than half a unit of the last place kept
+        if dropped > self.magnitude.digits() {
+            return Decimal::zero();
+        }
+        let unit = Natural::from(1).shifted(dropped);
+        let (mut kept, remainder) = self.magnitude.div_rem(&unit);
+        if remainder.add(&remainder).cmp(&unit) != Ordering::Less {
+            kept = kept.add(&Natural::from(1));
+        }
+        let (magnitude, scale) = if digits >= 0 {
+            (kept, digits as u32)
+        } else {
+            (kept.shifted(digits.unsigned_abs() as usize), 0)
+        };
+        Decimal {
+            negative: self.negative,
+            magnitude,
+            scale,
+        }
+        .normalized()
+    }
+
+    // Rounded half away from zero to a number of significant digits
+    fn with_precision(self, digits: usize) -> Decimal {
+        let excess = self.magnitude.digits().saturating_sub(digits);
+        if excess == 0 {
+            return self;
+        }
+        self.round((self.scale as i64 - excess as i64) as i32)
+    }
+
+    // Whether the exponent of the leading digit is in `EXPONENTS`
+    fn in_range(&self) -> bool {
+        let exponent = self.magnitude.digits() as i64 - self.scale as i64 - 1;
+        self.magnitude.is_zero() || EXPONENTS.contains(&exponent)
+    }
+
+    pub fn compare(&self, other: &Decimal) -> Ordering {
+        let difference = self.sub(other);
+        if difference.magnitude.is_zero() {
+            Ordering::Equal
+        } else if difference.negative {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }
+
+    pub fn negated(&self) -> Decimal {
+        Decimal {
+            negative: !self.negative,
+            ..self.clone()
+        }
+        .normalized()
+    }
+
+    // Without trailing zeros after the decimal point, and zero positive
+    fn normalized(mut self) -> Decimal {
+        while self.scale > 0 {
+            let (quotient, remainder) = self.magnitude.div_small(10);
+            if remainder != 0 {
+                break;
+            }
+            self.magnitude = quotient;
+            self.scale -= 1;
+        }
+        if self.magnitude.is_zero() {
+            self.negative = false;
+            self.scale = 0;
+        }
+        self
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.magnitude.to_string();
+        let scale = self.scale as usize;
+        let digits = if digits.len() <= scale {
+            format!("{}{}", "0".repeat(scale - digits.len() + 1), digits)
+        } else {
+            digits
+        };
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        if self.negative {
+            write!(f, "-")?;
+        }
+        if fraction.is_empty() {
+            write!(f, "{}", whole)
+        } else {
+            write!(f, "{}.{}", whole, fraction)
+        }
+    }
+}
+
+// Magnitudes of two decimals at the same scale
+fn aligned(a: &Decimal, b: &Decimal) -> (Natural, Natural, u32) {
+    let scale = a.scale.max(b.scale);
+    (
+        a.magnitude.shifted((scale - a.scale) as usize),
+        b.magnitude.shifted((scale - b.scale) as usize),
+        scale,
+    )
+}
+
+const BASE: u64 = 1_000_000_000;
+
+// Unsigned integer of any size, in base 10^9 limbs, least significant
+// first, without leading zero limbs
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Natural(Vec<u32>);
+
+impl From<u64> for Natural {
+    fn from(mut n: u64) -> Self {
+        let mut limbs = Vec::new();
+        while n > 0 {
+            limbs.push((n % BASE) as u32);
+            n /= BASE;
+        }
+        Natural(limbs)
+    }
+}
+
+impl Natural {
+    fn zero() -> Self {
+        Natural(Vec::new())
+    }
+
+    // From decimal digits, most significant first
+    fn parse(digits: &str) -> Self {
+        let digits = digits.as_bytes();
+        let mut limbs = Vec::new();
+        let mut end = digits.len();
+        while end > 0 {
+            let start = end.saturating_sub(9);
+            let limb = digits[start..end]
+                .iter()
+                .fold(0, |limb, digit| limb * 10 + u32::from(digit - b'0'));
+            limbs.push(limb);
+            end = start;
+        }
+        Natural(limbs).trimmed()
+    }
+
+    fn trimmed(mut self) -> Self {
+        while self.0.last() == Some(&0) {
+            self.0.pop();
+        }
+        self
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Number of decimal digits, none for zero
+    fn digits(&self) -> usize {
+        match self.0.last() {
+            Some(top) => (self.0.len() - 1) * 9 + top.to_string().len(),
+            None => 0,
+        }
+    }
+
+    fn cmp(&self, other: &Natural) -> Ordering {
+        self.0
+            .len()
+            .cmp(&other.0.len())
+            .then_with(|| self.0.iter().rev().cmp(other.0.iter().rev()))
+    }
+
+    fn add(&self, other: &Natural) -> Natural {
+        let mut limbs = Vec::with_capacity(self.0.len().max(other.0.len()) + 1);
+        let mut carry = 0;
+        for i in 0..self.0.len().max(other.0.len()) {
+            let sum = u64::from(*self.0.get(i).unwrap_or(&0))
+                + u64::from(*other.0.get(i).unwrap_or(&0))
+                + carry;
+            limbs.push((sum % BASE) as u32);
+            carry = sum / BASE;
+        }
+        if carry > 0 {
+            limbs.push(carry as u32);
+        }
+        Natural(limbs)
+    }
+
+    // self - other, for other not above self
+    fn sub(&self, other: &Natural) -> Natural {
+        let mut limbs = Vec::with_capacity(self.0.len());
+        let mut borrow = 0;
+        for i in 0..self.0.len() {
+            let mut difference =
+                i64::from(self.0[i]) - i64::from(*other.0.get(i).unwrap_or(&0)) - borrow;
+            borrow = 0;
+            if difference < 0 {
+                difference += BASE as i64;
+                borrow = 1;
+            }
+            limbs.push(difference as u32);
+        }
+        Natural(limbs).trimmed()
+    }
+
+    fn mul(&self, other: &Natural) -> Natural {
+        if self.is_zero() || other.is_zero() {
+            return Natural::zero();
+        }
+        let mut limbs = vec![0u64; self.0.len() + other.0.len()];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry = 0;
+            for (j, b) in other.0.iter().enumerate() {
+                let product = limbs[i + j] + u64::from(*a) * u64::from(*b) + carry;
+                limbs[i + j] = product % BASE;
+                carry = product / BASE;
+            }
+            limbs[i + other.0.len()] += carry;
+        }
+        Natural(limbs.into_iter().map(|limb| limb as u32).collect()).trimmed()
+    }
+
+    fn mul_small(&self, factor: u32) -> Natural {
+        self.mul(&Natural::from(u64::from(factor)))
+    }
+
+    // Times 10^places
+    fn shifted(&self, places: usize) -> Natural {
+        if self.is_zero() {
+            return Natural::zero();
+        }
+        let mut limbs = vec![0; places / 9];
+        limbs.extend_from_slice(&self.0);
+        Natural(limbs).mul_small(10u32.pow((places % 9) as u32))
+    }
+
+    fn div_small(&self, divisor: u32) -> (Natural, u32) {
+        let mut limbs = vec![0; self.0.len()];
+        let mut remainder = 0u64;
+        for i in (0..self.0.len()).rev() {
+            let current = remainder * BASE + u64::from(self.0[i]);
+            limbs[i] = (current / u64::from(divisor)) as u32;
+            remainder = current % u64::from(divisor);
+        }
+        (Natural(limbs).trimmed(), remainder as u32)
+    }
+
+    // Quotient and remainder by a nonzero divisor, by long division one
+    // limb at a time
+    fn div_rem(&self, divisor: &Natural) -> (Natural, Natural) {
+        if divisor.0.len() == 1 {
+            let (quotient, remainder) = self.div_small(divisor.0[0]);
+            return (quotient, Natural::from(u64::from(remainder)));
+        }
+        let mut quotient = vec![0; self.0.len()];
+        let mut remainder = Natural::zero();
+        for i in (0..self.0.len()).rev() {
+            remainder.0.insert(0, self.0[i]);
+            remainder = remainder.trimmed();
+            // Largest limb q with divisor * q <= remainder
+            let (mut low, mut high) = (0, BASE as u32 - 1);
+            while low < high {
+                let middle = low + (high - low).div_ceil(2);
+                if divisor.mul_small(middle).cmp(&remainder) == Ordering::Greater {
+                    high = middle - 1;
+                } else {
+                    low = middle;
+                }
+            }
+            quotient[i] = low;
+            remainder = remainder.sub(&divisor.mul_small(low));
+        }
+        (Natural(quotient).trimmed(), remainder)
+    }
+}
+
+impl fmt::Display for Natural {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.split_last() {
+            None => write!(f, "0"),
+            Some((top, rest)) => {
+                write!(f, "{}", top)?;
+                for limb in rest.iter().rev() {
+                    write!(f, "{:09}", limb)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        Decimal::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(decimal("12.3400").to_string(), "12.34");
+        assert_eq!(decimal("-0.000").to_string(), "0");
+        assert_eq!(decimal(".5").to_string(), "0.5");
+        assert_eq!(decimal("-0.0025").to_string(), "-0.0025");
+        assert_eq!(Decimal::from_f64(0.1).unwrap().to_string(), "0.1");
+        assert_eq!(
+            Decimal::from_f64(1e20).unwrap().to_string(),
+            "100000000000000000000"
+        );
+        assert_eq!(Decimal::from_f64(f64::NAN), None);
+        assert_eq!(Decimal::parse("1e5"), None);
+        assert_eq!(Decimal::parse("-"), None);
+    }
+
+    #[test]
+    fn test_exact_operations() {
+        assert_eq!(
+            decimal("123456789012345678901234567890.123456789")
+                .add(&decimal("0.876543211"))
+                .to_string(),
+            "123456789012345678901234567891"
+        );
+        assert_eq!(decimal("0.1").sub(&decimal("0.3")).to_string(), "-0.2");
+        assert_eq!(
+            decimal("1234567890.5")
+                .mul(&decimal("-987654321.25"))
+                .to_string(),
+            "-1219326311928821823.125"
+        );
+        let big = decimal("12345678901234567890.12345");
+        assert_eq!(big.mul(&big).div(&big), Some(big.clone()));
+        assert_eq!(
+            decimal("1").div(&decimal("3")).unwrap().to_string(),
+            format!("0.{}", "3".repeat(34))
+        );
+        assert_eq!(
+            decimal("2").div(&decimal("3")).unwrap().to_string(),
+            format!("0.{}7", "6".repeat(33))
+        );
+        assert_eq!(decimal("1").div(&decimal("0")), None);
+        assert_eq!(decimal("7.5").div_floor(&decimal("2")), Some(decimal("3")));
+        assert_eq!(
+            decimal("-7.5").div_floor(&decimal("2")),
+            Some(decimal("-4"))
+        );
+        assert_eq!(decimal("2.675").round(2).to_string(), "2.68");
+        assert_eq!(decimal("-2.5").round(0).to_string(), "-3");
+        assert_eq!(decimal("1250").round(-2).to_string(), "1300");
+        assert_eq!(decimal("5").round(-1).to_string(), "10");
+        assert_eq!(decimal("1.5").round(-2), Decimal::zero());
+        assert_eq!(decimal("1.5").round(i32::MIN), Decimal::zero());
+    }
+
+    #[test]
+    fn test_floating_point_traps() {
+        let float = Arithmetic::Float;
+        let exact = Arithmetic::Decimal;
+        let f = |x: f64| Number::Float(x);
+        let d = |text: &str| Number::Exact(decimal(text));
+        assert_ne!(float.add(&f(0.1), &f(0.2)), f(0.3));
+        assert_eq!(exact.add(&f(0.1), &f(0.2)), d("0.3"));
+        assert_eq!(exact.sub(&f(1.0), &f(0.9)), d("0.1"));
+        assert_eq!(exact.mul(&f(1.1), &f(1.1)), d("1.21"));
+        assert_eq!(exact.div(&f(0.3), &f(0.1)), d("3"));
+        assert_eq!(exact.sum(&vec![f(0.1); 10]), d("1"));
+        assert_ne!(float.sum(&vec![f(0.1); 10]), f(1.0));
+        assert_eq!(float.round(&f(0.285), 2), f(0.28));
+        assert_eq!(exact.round(&f(0.285), 2), d("0.29"));
+        assert_eq!(exact.div_floor(&f(0.3), &f(0.1)), d("3"));
+        assert_eq!(float.div_floor(&f(0.3), &f(0.1)), f(2.0));
+        assert_eq!(exact.add(&f(1e308), &f(1e308)), f(f64::INFINITY));
+        assert_eq!(exact.mul(&f(1e-200), &f(1e-200)), f(0.0));
+    }
+
+    #[test]
+    fn test_more_digits_than_floats_hold() {
+        let exact = Arithmetic::Decimal;
+        let d = |text: &str| Number::Exact(decimal(text));
+        assert_eq!(
+            exact.add(&d("10000000000000001"), &d("1")),
+            d("10000000000000002")
+        );
+        assert_eq!(
+            exact.add(&d("12345678901234567.5"), &d("0.25")),
+            d("12345678901234567.75")
+        );
+        assert_eq!(
+            exact.sum(&[d("0.000000000000000001"), d("1"), d("-1")]),
+            d("0.000000000000000001")
+        );
+        // Results keep 34 significant digits
+        let third = exact.div(&d("1"), &d("3"));
+        assert_eq!(
+            exact.mul(&third, &d("3")),
+            d(&format!("0.{}", "9".repeat(34)))
+        );
+        assert_eq!(
+            exact.mul(&d("100000000000000001"), &d("100000000000000001")),
+            d("10000000000000000200000000000000000")
+        );
+        assert_eq!(
+            exact.add(&d("1"), &d(&format!("0.{}5", "0".repeat(33)))),
+            d(&format!("1.{}1", "0".repeat(32)))
+        );
+        assert_eq!(
+            d("10000000000000001").compare(&d("10000000000000000.5")),
+            Some(Ordering::Greater)
+        );
+    }
+}
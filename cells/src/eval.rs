@@ -0,0 +1,513 @@
+use crate::CellValue;
+use crate::decimal::{Arithmetic, Decimal, Number};
+use crate::formula::{BinaryOp, Cell, Expr, Range};
+use crate::functions;
+use crate::script::Script;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Intermediate result of evaluating an expression. References stay
+// unresolved so that functions like INDEX can return part of a range.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    // Number in decimal mode, see `decimal`
+    Decimal(Decimal),
+    Text(String),
+    Range(Range),
+    // Computed block of values, e.g. from A0:A9*2 or SEQUENCE(10)
+    Array(Array),
+}
+
+impl From<Number> for Value {
+    fn from(number: Number) -> Self {
+        match number {
+            Number::Float(n) => Value::Number(n),
+            Number::Exact(d) => Value::Decimal(d),
+        }
+    }
+}
+
+// Rows of values, all of the same nonzero length. Elements that failed to
+// evaluate hold their error.
+pub type Array = Vec<Vec<CellValue>>;
+
+pub struct Evaluator<'a> {
+    values: &'a HashMap<Cell, CellValue>,
+    arithmetic: Arithmetic,
+    // Functions defined by the sheet's script, called like built-ins
+    script: Option<&'a Script>,
+    // Directory of the sheet's file, against which IMPORTCSV reads relative
+    // paths
+    directory: Option<&'a Path>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(values: &'a HashMap<Cell, CellValue>) -> Self {
+        Self::with_arithmetic(values, Arithmetic::Float)
+    }
+
+    pub fn with_arithmetic(values: &'a HashMap<Cell, CellValue>, arithmetic: Arithmetic) -> Self {
+        Self {
+            values,
+            arithmetic,
+            script: None,
+            directory: None,
+        }
+    }
+
+    pub fn with_script(mut self, script: &'a Script) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    pub fn with_directory(mut self, directory: Option<&'a Path>) -> Self {
+        self.directory = directory;
+        self
+    }
+
+    pub fn arithmetic(&self) -> Arithmetic {
+        self.arithmetic
+    }
+
+    pub fn script(&self) -> Option<&'a Script> {
+        self.script
+    }
+
+    pub fn directory(&self) -> Option<&'a Path> {
+        self.directory
+    }
+
+    pub fn cell_value(&self, cell: Cell) -> Option<&CellValue> {
+        self.values.get(&cell)
+    }
+
+    pub fn eval(&self, expr: &Expr) -> Result<Value, String> {
+        match expr {
+            // Numbers written in a formula are decimals in decimal mode
+            Expr::Number(num) => Ok(match self.arithmetic {
+                Arithmetic::Decimal => {
+                    Decimal::from_f64(*num).map_or(Value::Number(*num), Value::Decimal)
+                }
+                Arithmetic::Float => Value::Number(*num),
+            }),
+            Expr::Decimal(num) => Ok(match self.arithmetic {
+                Arithmetic::Decimal => Value::Decimal(num.clone()),
+                Arithmetic::Float => Value::Number(num.to_f64()),
+            }),
+            Expr::Text(text) => Ok(Value::Text(text.clone())),
+            Expr::Ref(cell) => Ok(Value::Range(Range::new(*cell, *cell))),
+            Expr::Range(range) => Ok(Value::Range(*range)),
+            Expr::Name(_) => Err("NAME".to_string()),
+            Expr::Neg(inner) => match self.eval(inner)? {
+                value if is_block(&value) => {
+                    Ok(Value::Array(map(self.array(value)?, |x| negate(Ok(x)))))
+                }
+                value => negate(self.resolve(value)),
+            },
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right);
+                // Operators apply to every element of arrays and ranges
+                if is_block(&left) || right.as_ref().is_ok_and(is_block) {
+                    let left = self.array(left)?;
+                    let right = self.array(right?)?;
+                    return Ok(Value::Array(combine(&left, &right, |l, r| {
+                        operate(*op, self.arithmetic, l, r)
+                    })));
+                }
+                operate(
+                    *op,
+                    self.arithmetic,
+                    self.resolve(left),
+                    right.and_then(|r| self.resolve(r)),
+                )
+            }
+            Expr::Call(name, args) => functions::call(self, name, args),
+        }
+    }
+
+    // Evaluate to a number or text, reading through single-cell references
+    pub fn scalar(&self, expr: &Expr) -> Result<Value, String> {
+        self.resolve(self.eval(expr)?)
+    }
+
+    pub fn resolve(&self, value: Value) -> Result<Value, String> {
+        match value {
+            Value::Range(range) if range.rows() == 1 && range.cols() == 1 => self.cell(range.start),
+            Value::Range(_) => Err("VALUE".to_string()),
+            Value::Array(array) if array.len() == 1 && array[0].len() == 1 => element(&array[0][0]),
+            Value::Array(_) => Err("VALUE".to_string()),
+            value => Ok(value),
+        }
+    }
+
+    // Block of values of a range or array, or a single value as a 1x1
+    // block. Empty cells read as zero.
+    pub fn array(&self, value: Value) -> Result<Array, String> {
+        match value {
+            Value::Number(n) => Ok(vec![vec![CellValue::Number(n)]]),
+            Value::Decimal(d) => Ok(vec![vec![CellValue::Decimal(d)]]),
+            Value::Text(text) => Ok(vec![vec![CellValue::Text(text)]]),
+            Value::Range(range) => Ok((range.start.0..=range.end.0)
+                .map(|row| {
+                    (range.start.1..=range.end.1)
+                        .map(|col| {
+                            self.values
+                                .get(&(row, col))
+                                .cloned()
+                                .unwrap_or(CellValue::Number(0.0))
+                        })
+                        .collect()
+                })
+                .collect()),
+            Value::Array(array) => Ok(array),
+        }
+    }
+
+    // Evaluate an argument to a block of values
+    pub fn array_arg(&self, expr: &Expr) -> Result<Array, String> {
+        self.array(self.eval(expr)?)
+    }
+
+    // Value of a single cell; empty cells read as zero
+    pub fn cell(&self, cell: Cell) -> Result<Value, String> {
+        match self.values.get(&cell) {
+            Some(CellValue::Error(e)) => Err(e.clone()),
+            Some(value) => element(value),
+            None => Ok(Value::Number(0.0)),
+        }
+    }
+
+    pub fn number(&self, expr: &Expr) -> Result<f64, String> {
+        self.exact(expr).map(|n| n.to_f64())
+    }
+
+    // Like `number`, keeping decimals exact
+    pub fn exact(&self, expr: &Expr) -> Result<Number, String> {
+        number_of(&self.scalar(expr)?).ok_or_else(|| "TEXT".to_string())
+    }
+
+    // Numbers from a list of arguments. Referenced text and empty cells are
+    // skipped the way spreadsheet aggregates do, while text given directly
+    // as an argument is an error.
+    pub fn numbers(&self, args: &[Expr]) -> Result<Vec<f64>, String> {
+        Ok(self
+            .exact_numbers(args)?
+            .iter()
+            .map(Number::to_f64)
+            .collect())
+    }
+
+    // Like `numbers`, keeping decimals exact
+    pub fn exact_numbers(&self, args: &[Expr]) -> Result<Vec<Number>, String> {
+        let mut numbers = Vec::new();
+        for arg in args {
+            match self.eval(arg)? {
+                Value::Text(_) => return Err("TEXT".to_string()),
+                Value::Range(range) => {
+                    for cell in range.cells() {
+                        match self.values.get(&cell) {
+                            Some(CellValue::Error(e)) => return Err(e.clone()),
+                            Some(value) => numbers.extend(value.as_exact()),
+                            None => {}
+                        }
+                    }
+                }
+                Value::Array(array) => {
+                    for value in array.iter().flatten() {
+                        match value {
+                            CellValue::Error(e) => return Err(e.clone()),
+                            value => numbers.extend(value.as_exact()),
+                        }
+                    }
+                }
+                value => numbers.extend(number_of(&value)),
+            }
+        }
+        Ok(numbers)
+    }
+
+    // Argument that must be a reference, e.g. the table of a lookup
+    pub fn range(&self, expr: &Expr) -> Result<Range, String> {
+        match self.eval(expr)? {
+            Value::Range(range) => Ok(range),
+            _ => Err("VALUE".to_string()),
+        }
+    }
+}
+
+// Whether a value holds more than one cell
+fn is_block(value: &Value) -> bool {
+    match value {
+        Value::Range(range) => range.rows() > 1 || range.cols() > 1,
+        Value::Array(array) => array.len() > 1 || array[0].len() > 1,
+        _ => false,
+    }
+}
+
+// Number held by a value, if it is one
+pub fn number_of(value: &Value) -> Option<Number> {
+    match value {
+        Value::Number(n) => Some(Number::Float(*n)),
+        Value::Decimal(d) => Some(Number::Exact(d.clone())),
+        _ => None,
+    }
+}
+
+// Value of an element of an array
+pub fn element(value: &CellValue) -> Result<Value, String> {
+    match value {
+        CellValue::Number(n) => Ok(Value::Number(*n)),
+        CellValue::Decimal(d) => Ok(Value::Decimal(d.clone())),
+        CellValue::Text(text) => Ok(Value::Text(text.clone())),
+        CellValue::Error(e) => Err(e.clone()),
+    }
+}
+
+// Element holding the result of an operation, or its error
+pub fn to_element(result: Result<Value, String>) -> CellValue {
+    match result {
+        Ok(Value::Number(n)) => CellValue::Number(n),
+        Ok(Value::Decimal(d)) => CellValue::Decimal(d),
+        Ok(Value::Text(text)) => CellValue::Text(text),
+        Ok(Value::Range(_) | Value::Array(_)) => CellValue::Error("VALUE".to_string()),
+        Err(e) => CellValue::Error(e),
+    }
+}
+
+// Applies `f` to every element of an array
+fn map(array: Array, f: impl Fn(Value) -> Result<Value, String>) -> Array {
+    array
+        .into_iter()
+        .map(|row| {
+            row.iter()
+                .map(|value| to_element(element(value).and_then(&f)))
+                .collect()
+        })
+        .collect()
+}
+
+// Applies `f` to the elements of two arrays pairwise. A single row or column
+// is repeated to match the other array; elements missing from both are #N/A.
+fn combine(
+    left: &Array,
+    right: &Array,
+    f: impl Fn(Result<Value, String>, Result<Value, String>) -> Result<Value, String>,
+) -> Array {
+    let rows = left.len().max(right.len());
+    let cols = left[0].len().max(right[0].len());
+    let get = |array: &Array, row: usize, col: usize| {
+        let row = if array.len() == 1 { 0 } else { row };
+        let col = if array[0].len() == 1 { 0 } else { col };
+        array
+            .get(row)
+            .and_then(|r| r.get(col))
+            .map_or(Err("N/A".to_string()), element)
+    };
+    (0..rows)
+        .map(|row| {
+            (0..cols)
+                .map(|col| to_element(f(get(left, row, col), get(right, row, col))))
+                .collect()
+        })
+        .collect()
+}
+
+fn negate(value: Result<Value, String>) -> Result<Value, String> {
+    match value? {
+        Value::Number(n) => Ok(Value::Number(-n)),
+        Value::Decimal(d) => Ok(Value::Decimal(d.negated())),
+        _ => Err("TEXT".to_string()),
+    }
+}
+
+// Result of a binary operator on two values, after reading through
+// references. Errors of the left operand take precedence.
+fn operate(
+    op: BinaryOp,
+    arithmetic: Arithmetic,
+    left: Result<Value, String>,
+    right: Result<Value, String>,
+) -> Result<Value, String> {
+    let (left, right) = (left?, right?);
+    if op.is_comparison() {
+        let holds = match (op, compare_values(&left, &right)) {
+            (BinaryOp::Eq, ordering) => ordering == Some(Ordering::Equal),
+            (BinaryOp::Ne, ordering) => ordering != Some(Ordering::Equal),
+            // Numbers and text have no order between them
+            (_, None) => return Err("VALUE".to_string()),
+            (BinaryOp::Lt, Some(ordering)) => ordering.is_lt(),
+            (BinaryOp::Le, Some(ordering)) => ordering.is_le(),
+            (BinaryOp::Gt, Some(ordering)) => ordering.is_gt(),
+            (_, Some(ordering)) => ordering.is_ge(),
+        };
+        return Ok(Value::Number(if holds { 1.0 } else { 0.0 }));
+    }
+
+    let (Some(left), Some(right)) = (number_of(&left), number_of(&right)) else {
+        return Err("TEXT".to_string());
+    };
+    match op {
+        BinaryOp::Add => Ok(arithmetic.add(&left, &right).into()),
+        BinaryOp::Sub => Ok(arithmetic.sub(&left, &right).into()),
+        BinaryOp::Mul => Ok(arithmetic.mul(&left, &right).into()),
+        BinaryOp::Div => {
+            if right.to_f64() == 0.0 {
+                Err("DIV0".to_string())
+            } else {
+                Ok(arithmetic.div(&left, &right).into())
+            }
+        }
+        _ => unreachable!("comparisons are handled above"),
+    }
+}
+
+// Ordering used by lookups: numbers compare numerically, text compares
+// case-insensitively, and numbers never match text
+pub fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Text(a), Value::Text(b)) => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+        _ => number_of(a)?.compare(&number_of(b)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::parse_expression;
+
+    fn eval(values: &HashMap<Cell, CellValue>, input: &str) -> Result<Value, String> {
+        Evaluator::new(values).scalar(&parse_expression(input)?)
+    }
+
+    #[test]
+    fn test_eval_precedence() {
+        let values = HashMap::new();
+        assert_eq!(eval(&values, "2+3*4"), Ok(Value::Number(14.0)));
+        assert_eq!(eval(&values, "(2+3)*4"), Ok(Value::Number(20.0)));
+        assert_eq!(eval(&values, "10-4-3"), Ok(Value::Number(3.0)));
+        assert_eq!(eval(&values, "-2*-3"), Ok(Value::Number(6.0)));
+    }
+
+    #[test]
+    fn test_eval_comparisons() {
+        let mut values = HashMap::new();
+        values.insert((0, 0), CellValue::Number(3.0));
+        values.insert((1, 0), CellValue::Text("Abc".to_string()));
+
+        assert_eq!(eval(&values, "A0>2"), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&values, "A0<=2"), Ok(Value::Number(0.0)));
+        assert_eq!(eval(&values, "A0>=3"), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&values, "1+2=A0"), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&values, "A0<>3"), Ok(Value::Number(0.0)));
+        assert_eq!(eval(&values, "A1=\"abc\""), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&values, "A1<\"b\""), Ok(Value::Number(1.0)));
+        // Numbers never equal text, and cannot be ordered against it
+        assert_eq!(eval(&values, "A1=3"), Ok(Value::Number(0.0)));
+        assert_eq!(eval(&values, "A1<>3"), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&values, "A1>3"), Err("VALUE".to_string()));
+    }
+
+    #[test]
+    fn test_eval_references() {
+        let mut values = HashMap::new();
+        values.insert((0, 0), CellValue::Number(3.0));
+        values.insert((1, 0), CellValue::Text("x".to_string()));
+        values.insert((2, 0), CellValue::Error("DIV0".to_string()));
+
+        assert_eq!(eval(&values, "A0*2"), Ok(Value::Number(6.0)));
+        assert_eq!(eval(&values, "A1"), Ok(Value::Text("x".to_string())));
+        assert_eq!(eval(&values, "A1+1"), Err("TEXT".to_string()));
+        assert_eq!(eval(&values, "A2+1"), Err("DIV0".to_string()));
+        assert_eq!(eval(&values, "A9"), Ok(Value::Number(0.0)));
+        assert_eq!(eval(&values, "A0:A1"), Err("VALUE".to_string()));
+    }
+
+    #[test]
+    fn test_eval_arrays() {
+        let mut values = HashMap::new();
+        values.insert((0, 0), CellValue::Number(1.0));
+        values.insert((1, 0), CellValue::Text("x".to_string()));
+        values.insert((0, 1), CellValue::Number(4.0));
+        let evaluator = Evaluator::new(&values);
+        let eval = |input: &str| evaluator.eval(&parse_expression(input).unwrap());
+        let n = CellValue::Number;
+
+        // Ranges and arrays combine element by element, single rows and
+        // columns repeat, and errors stay in their element
+        assert_eq!(
+            eval("A0:A2*2"),
+            Ok(Value::Array(vec![
+                vec![n(2.0)],
+                vec![CellValue::Error("TEXT".to_string())],
+                vec![n(0.0)],
+            ]))
+        );
+        assert_eq!(
+            eval("A0:B0+A0:A1"),
+            Ok(Value::Array(vec![
+                vec![n(2.0), n(5.0)],
+                vec![CellValue::Error("TEXT".to_string()); 2],
+            ]))
+        );
+        assert_eq!(
+            eval("-(A0:B0>2)"),
+            Ok(Value::Array(vec![vec![n(-0.0), n(-1.0)]]))
+        );
+        assert_eq!(
+            eval("A0:A1+A0:A2").map(|value| evaluator.array(value).unwrap()[2][0].clone()),
+            Ok(CellValue::Error("N/A".to_string()))
+        );
+        assert_eq!(
+            evaluator.numbers(&[parse_expression("A0:B1*2").unwrap()]),
+            Err("TEXT".to_string())
+        );
+        assert_eq!(
+            evaluator.numbers(&[parse_expression("A0:B0*2").unwrap()]),
+            Ok(vec![2.0, 8.0])
+        );
+    }
+
+    #[test]
+    fn test_eval_unknown_name() {
+        let values = HashMap::new();
+        assert_eq!(eval(&values, "FOO"), Err("NAME".to_string()));
+        assert_eq!(eval(&values, "FOO(1)"), Err("NAME".to_string()));
+    }
+
+    #[test]
+    fn test_numbers_skips_text_in_ranges() {
+        let mut values = HashMap::new();
+        values.insert((0, 0), CellValue::Number(1.0));
+        values.insert((1, 0), CellValue::Text("x".to_string()));
+        values.insert((3, 0), CellValue::Number(2.0));
+        let evaluator = Evaluator::new(&values);
+
+        let args = [parse_expression("A0:A3").unwrap(), Expr::Number(5.0)];
+        assert_eq!(evaluator.numbers(&args), Ok(vec![1.0, 2.0, 5.0]));
+
+        let args = [Expr::Text("x".to_string())];
+        assert_eq!(evaluator.numbers(&args), Err("TEXT".to_string()));
+
+        values.insert((2, 0), CellValue::Error("DIV0".to_string()));
+        let args = [parse_expression("A0:A3").unwrap()];
+        assert_eq!(
+            Evaluator::new(&values).numbers(&args),
+            Err("DIV0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_compare_values() {
+        let a = Value::Text("Apple".to_string());
+        let b = Value::Text("apple".to_string());
+        assert_eq!(compare_values(&a, &b), Some(Ordering::Equal));
+        assert_eq!(
+            compare_values(&Value::Number(1.0), &Value::Number(2.0)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(compare_values(&a, &Value::Number(1.0)), None);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::Sheet;
+use crate::chart::Chart;
+use crate::comment::Comment;
+use crate::decimal::Arithmetic;
+use crate::format::Rule;
+use crate::formula::{cell_name, parse_cell_reference, parse_range};
+use crate::print::PrintSetup;
+use crate::sheet::{Iteration, MAX_COLS, MAX_ROWS};
+use crate::validation::Validation;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+// On-disk form of a sheet: its size, the formula of every non-empty cell,
+// keyed by cell name, the named ranges, the conditional formats, the charts,
+// the number formats, the merged ranges, the wrapped cells, the validation
+// rules, the comments, the arithmetic its formulas use, the settings of
+// iterative calculation, the script defining functions and the print setup.
+// Values are not stored, they are recalculated on load.
+//
+//     { "rows": 100, "cols": 26, "cells": { "A0": "5", "B3": "=A0*2" },
+//       "names": { "RATES": "B0:B9" }, "merges": ["A0:C0"] }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SheetFile {
+    #[serde(default = "default_rows")]
+    pub rows: usize,
+    #[serde(default = "default_cols")]
+    pub cols: usize,
+    #[serde(default)]
+    pub cells: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub formats: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub charts: Vec<Chart>,
+    // Number format codes by cell name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub number_formats: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merges: Vec<String>,
+    // Names of the cells whose text wraps
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub wrapped: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub validations: Vec<Validation>,
+    // Comments by cell name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub comments: BTreeMap<String, Comment>,
+    #[serde(default, skip_serializing_if = "Arithmetic::is_float")]
+    pub arithmetic: Arithmetic,
+    // Present when circular references are calculated iteratively
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iteration: Option<Iteration>,
+    // Source of the script, see `script`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub script: String,
+    #[serde(default, skip_serializing_if = "PrintSetup::is_default")]
+    pub print: PrintSetup,
+}
+
+// Size of the GUI grid, used when a file leaves it out
+fn default_rows() -> usize {
+    100
+}
+
+fn default_cols() -> usize {
+    26
+}
+
+impl SheetFile {
+    pub fn from_sheet(sheet: &Sheet) -> Self {
+        Self {
+            rows: sheet.rows(),
+            cols: sheet.cols(),
+            cells: sheet
+                .formulas
+                .iter()
+                .map(|(cell, formula)| (cell_name(*cell), formula.clone()))
+                .collect(),
+            names: sheet
+                .names()
+                .iter()
+                .map(|(name, range)| (name.clone(), range.to_string()))
+                .collect(),
+            formats: sheet.formats.clone(),
+            charts: sheet.charts.clone(),
+            number_formats: sheet
+                .number_formats
+                .iter()
+                .map(|(cell, code)| (cell_name(*cell), code.clone()))
+                .collect(),
+            merges: sheet.merges().iter().map(ToString::to_string).collect(),
+            wrapped: sheet.wrapped.iter().map(|cell| cell_name(*cell)).collect(),
+            validations: sheet.validations.clone(),
+            comments: sheet
+                .comments
+                .iter()
+                .map(|(cell, comment)| (cell_name(*cell), comment.clone()))
+                .collect(),
+            arithmetic: sheet.arithmetic(),
+            iteration: sheet.iteration(),
+            script: sheet.script().source().to_string(),
+            print: sheet.print.clone(),
+        }
+    }
+
+    // Builds the sheet and evaluates every formula
+    pub fn into_sheet(self) -> Result<Sheet, String> {
+        if !(1..=MAX_ROWS).contains(&self.rows) || !(1..=MAX_COLS).contains(&self.cols) {
+            return Err(format!(
+                "a sheet of {} rows and {} columns is not supported, only 1 to {} rows and columns A to Z",
+                self.rows, self.cols, MAX_ROWS
+            ));
+        }
+        let mut sheet = Sheet::with_arithmetic(self.rows, self.cols, self.arithmetic);
+        sheet
+            .set_script(&self.script)
+            .map_err(|e| format!("script: {}", e))?;
+        for (name, range) in &self.names {
+            sheet.define_name(name, range)?;
+        }
+        let cell = |name: &String| {
+            parse_cell_reference(name)
+                .filter(|(row, col)| *row < self.rows && *col < self.cols)
+                .ok_or_else(|| format!("invalid cell {:?}", name))
+        };
+        for (name, formula) in &self.cells {
+            sheet.edit(cell(name)?, formula);
+        }
+        for (name, code) in &self.number_formats {
+            sheet.number_formats.insert(cell(name)?, code.clone());
+        }
+        for range in &self.merges {
+            let merge = parse_range(range).ok_or_else(|| format!("invalid range {:?}", range))?;
+            sheet.merge(merge)?;
+        }
+        for name in &self.wrapped {
+            sheet.wrapped.insert(cell(name)?);
+        }
+        for (name, comment) in self.comments {
+            sheet.comments.insert(cell(&name)?, comment);
+        }
+        sheet.set_iteration(self.iteration);
+        sheet.formats = self.formats;
+        sheet.charts = self.charts;
+        sheet.validations = self.validations;
+        sheet.print = self.print;
+        Ok(sheet)
+    }
+}
+
+pub fn from_json(json: &str) -> Result<Sheet, String> {
+    let file: SheetFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    file.into_sheet()
+}
+
+pub fn to_json(sheet: &Sheet) -> String {
+    serde_json::to_string_pretty(&SheetFile::from_sheet(sheet))
+        .expect("sheet files always serialize")
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Sheet, String> {
+    let path = path.as_ref();
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut sheet = from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let roots = sheet.set_directory(path.parent().map(Path::to_path_buf));
+    sheet.recalculate(roots);
+    Ok(sheet)
+}
+
+pub fn save(sheet: &Sheet, path: impl AsRef<Path>) -> Result<(), String> {
+    let path = path.as_ref();
+    std::fs::write(path, to_json(sheet))
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CellValue;
+    use crate::chart::ChartKind;
+    use crate::decimal::Decimal;
+    use crate::format::{Condition, Format, Rgb};
+    use crate::formula::Range;
+    use crate::validation::{Criterion, Severity};
+
+    #[test]
+    fn test_round_trip() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "5");
+        sheet.set_formula((1, 0), "label");
+        sheet.set_formula((2, 1), "=A0*2+TOTAL");
+        sheet.define_name("total", "A0").unwrap();
+        sheet.formats.push(Rule {
+            range: Range::new((0, 0), (9, 2)),
+            format: Format::Highlight {
+                condition: Condition::Errors,
+                color: Rgb(255, 0, 0),
+            },
+        });
+
+        sheet.charts.push(Chart {
+            kind: ChartKind::Pie,
+            range: Range::new((0, 0), (1, 1)),
+            title: "Share".to_string(),
+        });
+
+        sheet.number_formats.insert((0, 0), "0.0%".to_string());
+        sheet.merge(Range::new((3, 0), (3, 2))).unwrap();
+        sheet.wrapped.insert((1, 0));
+        sheet.validations.push(Validation {
+            range: Range::new((0, 0), (9, 0)),
+            criterion: Criterion::List {
+                values: vec!["5".to_string(), "label".to_string()],
+            },
+            severity: Severity::Warn,
+            message: String::new(),
+        });
+        sheet.comments.insert(
+            (0, 0),
+            Comment {
+                text: "From the March report".to_string(),
+                author: "ana".to_string(),
+                time: "2024-05-01T09:30:00Z".to_string(),
+            },
+        );
+
+        let loaded = from_json(&to_json(&sheet)).unwrap();
+        assert_eq!((loaded.rows(), loaded.cols()), (10, 3));
+        assert_eq!(loaded.formulas, sheet.formulas);
+        assert_eq!(loaded.values, sheet.values);
+        assert_eq!(loaded.names(), sheet.names());
+        assert_eq!(loaded.formats, sheet.formats);
+        assert_eq!(loaded.charts, sheet.charts);
+        assert_eq!(loaded.number_formats, sheet.number_formats);
+        assert_eq!(loaded.merges(), sheet.merges());
+        assert_eq!(loaded.wrapped, sheet.wrapped);
+        assert_eq!(loaded.validations, sheet.validations);
+        assert_eq!(loaded.comments, sheet.comments);
+        assert_eq!(loaded.value((2, 1)), Some(&CellValue::Number(15.0)));
+    }
+
+    #[test]
+    fn test_arithmetic_round_trip() {
+        let mut sheet = Sheet::with_arithmetic(10, 3, Arithmetic::Decimal);
+        sheet.set_formula((0, 0), "=0.1+0.2");
+        let json = to_json(&sheet);
+        assert!(json.contains(r#""arithmetic": "decimal""#));
+        let loaded = from_json(&json).unwrap();
+        assert_eq!(loaded.arithmetic(), Arithmetic::Decimal);
+        let exact = Decimal::parse("0.3").unwrap();
+        assert_eq!(loaded.value((0, 0)), Some(&CellValue::Decimal(exact)));
+
+        // Float sheets leave the field out
+        assert!(!to_json(&Sheet::new(10, 3)).contains("arithmetic"));
+        assert_eq!(from_json("{}").unwrap().arithmetic(), Arithmetic::Float);
+    }
+
+    #[test]
+    fn test_iteration_round_trip() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "=A1/2");
+        sheet.set_formula((1, 0), "=A0+1");
+        sheet.set_iteration(Some(Iteration {
+            max_iterations: 20,
+            max_change: 1e-6,
+        }));
+        let json = to_json(&sheet);
+        assert!(json.contains(r#""max_iterations": 20"#));
+        let loaded = from_json(&json).unwrap();
+        assert_eq!(loaded.iteration(), sheet.iteration());
+        assert_eq!(loaded.values, sheet.values);
+
+        // Settings left out take their defaults
+        let loaded = from_json(r#"{ "iteration": { "max_change": 0.5 } }"#).unwrap();
+        assert_eq!(
+            loaded.iteration(),
+            Some(Iteration {
+                max_iterations: 100,
+                max_change: 0.5
+            })
+        );
+        assert!(!to_json(&Sheet::new(10, 3)).contains("iteration"));
+    }
+
+    #[test]
+    fn test_script_round_trip() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_script("fn double(x) { x * 2.0 }").unwrap();
+        sheet.set_formula((0, 0), "=DOUBLE(21)");
+        let json = to_json(&sheet);
+        assert!(json.contains(r#""script": "fn double(x) { x * 2.0 }""#));
+        let loaded = from_json(&json).unwrap();
+        assert_eq!(loaded.value((0, 0)), Some(&CellValue::Number(42.0)));
+        assert!(!to_json(&Sheet::new(10, 3)).contains("script"));
+
+        let err = from_json(r#"{ "script": "fn broken(" }"#).unwrap_err();
+        assert!(err.starts_with("script: "), "{}", err);
+    }
+
+    #[test]
+    fn test_print_setup_round_trip() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.print.area = parse_range("A0:B5");
+        sheet.print.row_breaks.extend([3, 7]);
+        sheet.print.landscape = true;
+        let json = to_json(&sheet);
+        assert!(json.contains(r#""area": "A0:B5""#));
+        assert_eq!(from_json(&json).unwrap().print, sheet.print);
+        assert!(!to_json(&Sheet::new(10, 3)).contains("print"));
+    }
+
+    #[test]
+    fn test_formulas_are_evaluated_on_load() {
+        // Dependents may come before their inputs in the file
+        let sheet =
+            from_json(r#"{ "cells": { "A0": "=B0+1", "B0": "=C0*2", "C0": "4" } }"#).unwrap();
+        assert_eq!((sheet.rows(), sheet.cols()), (100, 26));
+        assert_eq!(sheet.value((0, 0)), Some(&CellValue::Number(9.0)));
+    }
+
+    #[test]
+    fn test_load_imports_from_the_file_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("data.csv"), "5\n").unwrap();
+        let path = dir.path().join("sheet.json");
+        std::fs::write(
+            &path,
+            r#"{ "cells": { "A0": "=IMPORTCSV(\"data.csv\")*2" } }"#,
+        )
+        .unwrap();
+        let sheet = load(&path).unwrap();
+        assert_eq!(sheet.directory(), Some(dir.path()));
+        assert_eq!(sheet.value((0, 0)), Some(&CellValue::Number(10.0)));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert!(from_json("{").is_err());
+        assert!(from_json(r#"{ "cells": { "AA1": "1" } }"#).is_err());
+        assert!(from_json(r#"{ "rows": 5, "cells": { "A5": "1" } }"#).is_err());
+        assert!(from_json(r#"{ "merges": ["A0:B0", "B0:C1"] }"#).is_err());
+        // Sizes formulas and the GUI cannot reach
+        for size in [
+            r#""rows": 5, "cols": 300"#,
+            r#""rows": 0"#,
+            r#""cols": 0"#,
+            r#""rows": 100000000"#,
+        ] {
+            let json = format!(
+                r#"{{ {}, "cells": {{ "A0": "=SEQUENCE(1, 250)" }} }}"#,
+                size
+            );
+            assert!(from_json(&json).is_err(), "{size}");
+        }
+    }
+}
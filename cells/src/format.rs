@@ -0,0 +1,295 @@
+use crate::eval::{Evaluator, number_of};
+use crate::formula::{Cell, Range};
+use crate::{CellValue, Sheet};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Color used by conditional formats, independent of the GUI toolkit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    // Linear interpolation towards `other`, t in [0, 1]
+    pub fn mix(self, other: Rgb, t: f64) -> Rgb {
+        let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+        Rgb(
+            channel(self.0, other.0),
+            channel(self.1, other.1),
+            channel(self.2, other.2),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Greater,
+    Less,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn holds(self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Greater => a > b,
+            Comparison::Less => a < b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    // Numbers compared with a constant
+    Compare { comparison: Comparison, value: f64 },
+    // A formula written for the top-left cell of the range, such as
+    // "=A0>AVERAGE(A0:A9)". For the other cells its references are shifted
+    // as if it was copied there. Holds when it evaluates to a nonzero number.
+    Formula { formula: String },
+    // Cells showing an error such as #DIV0
+    Errors,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Format {
+    // Fills the cells meeting the condition
+    Highlight { condition: Condition, color: Rgb },
+    // Fills numbers with a color between `low` (smallest number of the
+    // range) and `high` (largest)
+    ColorScale { low: Rgb, high: Rgb },
+    // Draws a bar whose length follows the number
+    DataBar { color: Rgb },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub range: Range,
+    pub format: Format,
+}
+
+// How the rules make a cell look
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Appearance {
+    pub fill: Option<Rgb>,
+    // Length of the data bar as a fraction of the cell width, and its color
+    pub bar: Option<(f64, Rgb)>,
+}
+
+// Appearance of every cell affected by the sheet's rules, against its
+// current values. When rules overlap the first one wins.
+pub fn apply(sheet: &Sheet) -> HashMap<Cell, Appearance> {
+    let mut appearance: HashMap<Cell, Appearance> = HashMap::new();
+    for rule in &sheet.formats {
+        let Some(range) = sheet.clip(rule.range) else {
+            continue;
+        };
+        match &rule.format {
+            Format::Highlight { condition, color } => {
+                for cell in matching(sheet, range, condition) {
+                    let entry = appearance.entry(cell).or_default();
+                    entry.fill.get_or_insert(*color);
+                }
+            }
+            Format::ColorScale { low, high } => {
+                let numbers = numbers(sheet, range);
+                let (min, max) = bounds(&numbers);
+                for (cell, n) in numbers {
+                    let t = if max > min {
+                        (n - min) / (max - min)
+                    } else {
+                        0.5
+                    };
+                    let entry = appearance.entry(cell).or_default();
+                    entry.fill.get_or_insert(low.mix(*high, t));
+                }
+            }
+            Format::DataBar { color } => {
+                // Bars start from zero, or from the smallest number when
+                // some are negative
+                let numbers = numbers(sheet, range);
+                let (min, max) = bounds(&numbers);
+                let (low, high) = (min.min(0.0), max.max(0.0));
+                for (cell, n) in numbers {
+                    let fraction = if high > low {
+                        (n - low) / (high - low)
+                    } else {
+                        0.0
+                    };
+                    let entry = appearance.entry(cell).or_default();
+                    entry.bar.get_or_insert((fraction, *color));
+                }
+            }
+        }
+    }
+    appearance
+}
+
+fn numbers(sheet: &Sheet, range: Range) -> Vec<(Cell, f64)> {
+    range
+        .cells()
+        .filter_map(|cell| Some((cell, sheet.value(cell)?.as_number()?)))
+        .collect()
+}
+
+fn bounds(numbers: &[(Cell, f64)]) -> (f64, f64) {
+    numbers
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), (_, n)| {
+            (min.min(*n), max.max(*n))
+        })
+}
+
+fn matching(sheet: &Sheet, range: Range, condition: &Condition) -> Vec<Cell> {
+    match condition {
+        Condition::Compare { comparison, value } => numbers(sheet, range)
+            .into_iter()
+            .filter(|(_, n)| comparison.holds(*n, *value))
+            .map(|(cell, _)| cell)
+            .collect(),
+        Condition::Errors => range
+            .cells()
+            .filter(|cell| matches!(sheet.value(*cell), Some(CellValue::Error(_))))
+            .collect(),
+        Condition::Formula { formula } => {
+            let Some(Ok(expr)) = formula.trim().strip_prefix('=').map(|f| sheet.compile(f)) else {
+                return Vec::new();
+            };
+            let evaluator = Evaluator::with_arithmetic(&sheet.values, sheet.arithmetic())
+                .with_script(sheet.script())
+                .with_directory(sheet.directory());
+            range
+                .cells()
+                .filter(|cell| {
+                    let (rows, cols) = (cell.0 - range.start.0, cell.1 - range.start.1);
+                    let expr = expr.clone().shifted(rows, cols);
+                    evaluator
+                        .scalar(&expr)
+                        .is_ok_and(|value| number_of(&value).is_some_and(|n| n.to_f64() != 0.0))
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::parse_range;
+
+    const RED: Rgb = Rgb(255, 0, 0);
+    const WHITE: Rgb = Rgb(255, 255, 255);
+
+    // A0:A4 holds 1, 2, 3, 4, 10, B0 an error and B1 text
+    fn sheet(formats: Vec<(&str, Format)>) -> Sheet {
+        let mut sheet = Sheet::new(10, 3);
+        for (row, n) in ["1", "2", "3", "4", "10"].iter().enumerate() {
+            sheet.set_formula((row, 0), n);
+        }
+        sheet.set_formula((0, 1), "=1/0");
+        sheet.set_formula((1, 1), "text");
+        sheet.formats = formats
+            .into_iter()
+            .map(|(range, format)| Rule {
+                range: parse_range(range).unwrap(),
+                format,
+            })
+            .collect();
+        sheet
+    }
+
+    fn highlight(condition: Condition) -> Format {
+        Format::Highlight {
+            condition,
+            color: RED,
+        }
+    }
+
+    fn filled(appearance: &HashMap<Cell, Appearance>) -> Vec<Cell> {
+        let mut cells: Vec<Cell> = appearance
+            .iter()
+            .filter(|(_, a)| a.fill.is_some())
+            .map(|(cell, _)| *cell)
+            .collect();
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn test_compare() {
+        let condition = Condition::Compare {
+            comparison: Comparison::Greater,
+            value: 3.0,
+        };
+        let appearance = apply(&sheet(vec![("A0:B4", highlight(condition))]));
+        assert_eq!(filled(&appearance), vec![(3, 0), (4, 0)]);
+        assert_eq!(appearance[&(3, 0)].fill, Some(RED));
+    }
+
+    #[test]
+    fn test_formula_condition_is_relative() {
+        // Each number compared with the one below it; A4 reads the empty
+        // A5 as 0
+        let condition = Condition::Formula {
+            formula: "=A0>A1".to_string(),
+        };
+        let mut sheet = sheet(vec![("A0:A4", highlight(condition))]);
+        assert_eq!(filled(&apply(&sheet)), vec![(4, 0)]);
+
+        sheet.formats[0].format = highlight(Condition::Formula {
+            formula: "=MOD(A0, 2)".to_string(),
+        });
+        assert_eq!(filled(&apply(&sheet)), vec![(0, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let appearance = apply(&sheet(vec![("A0:C9", highlight(Condition::Errors))]));
+        assert_eq!(filled(&appearance), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_color_scale() {
+        let scale = Format::ColorScale {
+            low: WHITE,
+            high: RED,
+        };
+        let appearance = apply(&sheet(vec![("A0:B4", scale)]));
+        assert_eq!(appearance[&(0, 0)].fill, Some(WHITE));
+        assert_eq!(appearance[&(4, 0)].fill, Some(RED));
+        assert_eq!(appearance[&(3, 0)].fill, Some(Rgb(255, 170, 170)));
+        assert!(!appearance.contains_key(&(0, 1)));
+    }
+
+    #[test]
+    fn test_data_bar() {
+        let appearance = apply(&sheet(vec![("A0:A4", Format::DataBar { color: RED })]));
+        assert_eq!(appearance[&(4, 0)].bar, Some((1.0, RED)));
+        assert_eq!(appearance[&(1, 0)].bar, Some((0.2, RED)));
+    }
+
+    #[test]
+    fn test_first_rule_wins() {
+        let first = Condition::Compare {
+            comparison: Comparison::Equal,
+            value: 2.0,
+        };
+        let second = Format::Highlight {
+            condition: Condition::Compare {
+                comparison: Comparison::NotEqual,
+                value: 0.0,
+            },
+            color: WHITE,
+        };
+        let appearance = apply(&sheet(vec![
+            ("A0:A4", highlight(first)),
+            ("A0:Z99", second),
+            ("A50:B60", Format::DataBar { color: RED }),
+        ]));
+        assert_eq!(appearance[&(1, 0)].fill, Some(RED));
+        assert_eq!(appearance[&(0, 0)].fill, Some(WHITE));
+    }
+}
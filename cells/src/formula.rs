@@ -0,0 +1,852 @@
+use crate::decimal::Decimal;
+use crate::functions;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+
+pub type Cell = (usize, usize);
+
+pub fn col_to_letter(col: usize) -> String {
+    ((b'A' + col as u8) as char).to_string()
+}
+
+pub fn letter_to_col(letter: char) -> Option<usize> {
+    let letter = letter.to_ascii_uppercase();
+    if letter.is_ascii_uppercase() {
+        Some((letter as u8 - b'A') as usize)
+    } else {
+        None
+    }
+}
+
+// Parses a reference such as "B5" into (row, col). Whether the cell exists
+// depends on the size of the sheet, which is checked when a formula is set.
+pub fn parse_cell_reference(s: &str) -> Option<Cell> {
+    let s = s.trim();
+    let mut chars = s.chars();
+    let col = letter_to_col(chars.next()?)?;
+
+    let row_str = chars.as_str();
+    if row_str.is_empty() || !row_str.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let row = row_str.parse::<usize>().ok()?;
+
+    Some((row, col))
+}
+
+// Name of a cell as typed in formulas, e.g. "B5"
+pub fn cell_name(cell: Cell) -> String {
+    format!("{}{}", col_to_letter(cell.1), cell.0)
+}
+
+// Every cell a formula reads. Plain values and unparsable formulas have no
+// dependencies.
+pub fn parse_dependencies(formula: &str) -> HashSet<Cell> {
+    let mut deps = HashSet::new();
+    let formula = formula.trim();
+
+    // Ranges register every cell they cover, so a lookup is re-evaluated
+    // whenever any cell of its table changes
+    if let Some(expr) = formula.strip_prefix('=')
+        && let Ok(expr) = parse_expression(expr)
+    {
+        expr.references(&mut deps);
+    }
+
+    deps
+}
+
+// A rectangular block of cells, always stored with `start` as the top-left
+// corner and `end` as the bottom-right corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: Cell,
+    pub end: Cell,
+}
+
+impl Range {
+    pub fn new(a: Cell, b: Cell) -> Self {
+        Self {
+            start: (a.0.min(b.0), a.1.min(b.1)),
+            end: (a.0.max(b.0), a.1.max(b.1)),
+        }
+    }
+
+    // Sizes saturate, as a range typed as text may reach the largest index
+    pub fn rows(&self) -> usize {
+        (self.end.0 - self.start.0).saturating_add(1)
+    }
+
+    pub fn cols(&self) -> usize {
+        (self.end.1 - self.start.1).saturating_add(1)
+    }
+
+    // Cell at a zero-based offset inside the range
+    pub fn cell(&self, row: usize, col: usize) -> Cell {
+        (self.start.0 + row, self.start.1 + col)
+    }
+
+    // Single row of the range (zero-based)
+    pub fn row(&self, row: usize) -> Range {
+        let r = self.start.0 + row;
+        Range::new((r, self.start.1), (r, self.end.1))
+    }
+
+    // Single column of the range (zero-based)
+    pub fn col(&self, col: usize) -> Range {
+        let c = self.start.1 + col;
+        Range::new((self.start.0, c), (self.end.0, c))
+    }
+
+    // Cells in row-major order
+    pub fn cells(&self) -> impl Iterator<Item = Cell> + '_ {
+        (self.start.0..=self.end.0)
+            .flat_map(move |row| (self.start.1..=self.end.1).map(move |col| (row, col)))
+    }
+
+    pub fn contains(&self, cell: Cell) -> bool {
+        (self.start.0..=self.end.0).contains(&cell.0)
+            && (self.start.1..=self.end.1).contains(&cell.1)
+    }
+
+    // Whether the ranges share at least one cell
+    pub fn intersects(&self, other: &Range) -> bool {
+        self.start.0 <= other.end.0
+            && other.start.0 <= self.end.0
+            && self.start.1 <= other.end.1
+            && other.start.1 <= self.end.1
+    }
+}
+
+// Parses "B2:C5", or a single cell such as "B2"
+pub fn parse_range(s: &str) -> Option<Range> {
+    match parse_expression(s) {
+        Ok(Expr::Ref(cell)) => Some(Range::new(cell, cell)),
+        Ok(Expr::Range(range)) => Some(range),
+        _ => None,
+    }
+}
+
+// "B2:C5", or "B2" for a single cell
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", cell_name(self.start))
+        } else {
+            write!(f, "{}:{}", cell_name(self.start), cell_name(self.end))
+        }
+    }
+}
+
+// Ranges are stored in files in their text form
+impl Serialize for Range {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Range {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_range(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid range {:?}", s)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    // Comparisons give TRUE (1) or FALSE (0)
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    // Number literal with more digits than an f64 holds, exact in decimal
+    // mode
+    Decimal(Decimal),
+    Text(String),
+    Ref(Cell),
+    Range(Range),
+    Name(String),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    // Visits this expression and all of its subexpressions
+    pub fn walk(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        match self {
+            Expr::Neg(inner) => inner.walk(f),
+            Expr::Binary(_, left, right) => {
+                left.walk(f);
+                right.walk(f);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.walk(f);
+                }
+            }
+            Expr::Number(_)
+            | Expr::Decimal(_)
+            | Expr::Text(_)
+            | Expr::Ref(_)
+            | Expr::Range(_)
+            | Expr::Name(_) => {}
+        }
+    }
+
+    // Every cell this expression reads, with ranges expanded to their cells
+    pub fn references(&self, deps: &mut HashSet<Cell>) {
+        self.walk(&mut |expr| match expr {
+            Expr::Ref(cell) => {
+                deps.insert(*cell);
+            }
+            Expr::Range(range) => deps.extend(range.cells()),
+            _ => {}
+        });
+    }
+
+    // Rebuilds the expression bottom-up, passing every subexpression
+    // through `f`
+    pub fn map(self, f: &mut impl FnMut(Expr) -> Expr) -> Expr {
+        let expr = match self {
+            Expr::Neg(inner) => Expr::Neg(Box::new(inner.map(f))),
+            Expr::Binary(op, left, right) => {
+                Expr::Binary(op, Box::new(left.map(f)), Box::new(right.map(f)))
+            }
+            Expr::Call(name, args) => {
+                Expr::Call(name, args.into_iter().map(|arg| arg.map(f)).collect())
+            }
+            other => other,
+        };
+        f(expr)
+    }
+
+    // The expression with every reference moved down and right, as when a
+    // formula is copied to another cell
+    pub fn shifted(self, rows: usize, cols: usize) -> Expr {
+        let shift = |cell: Cell| (cell.0 + rows, cell.1 + cols);
+        self.map(&mut |expr| match expr {
+            Expr::Ref(cell) => Expr::Ref(shift(cell)),
+            Expr::Range(range) => Expr::Range(Range::new(shift(range.start), shift(range.end))),
+            other => other,
+        })
+    }
+
+    // Whether the expression calls a volatile function such as RAND
+    pub fn is_volatile(&self) -> bool {
+        let mut volatile = false;
+        self.walk(&mut |expr| {
+            if let Expr::Call(name, _) = expr {
+                volatile |= functions::is_volatile(name);
+            }
+        });
+        volatile
+    }
+
+    // Files read by IMPORTCSV calls whose path is written out as text
+    pub fn imported_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        self.walk(&mut |expr| {
+            if let Expr::Call(name, args) = expr
+                && name == "IMPORTCSV"
+                && let Some(Expr::Text(path)) = args.first()
+            {
+                files.push(PathBuf::from(path));
+            }
+        });
+        files
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Decimal(Decimal),
+    Text(String),
+    Ident(String),
+    Colon,
+    Comma,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            ' ' | '\t' => i += 1,
+            '0'..='9' | '.' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let literal: String = chars[start..i].iter().collect();
+                let num = literal.parse::<f64>().map_err(|_| "ERR".to_string())?;
+                // Literals an f64 would round keep their digits
+                match Decimal::parse(&literal) {
+                    Some(decimal) if Decimal::from_f64(num).as_ref() != Some(&decimal) => {
+                        tokens.push(Token::Decimal(decimal))
+                    }
+                    _ => tokens.push(Token::Number(num)),
+                }
+            }
+            '"' => {
+                // String literal, with "" as an escaped quote
+                let mut literal = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('"') if chars.get(i + 1) == Some(&'"') => {
+                            literal.push('"');
+                            i += 2;
+                        }
+                        Some('"') => {
+                            i += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            literal.push(*c);
+                            i += 1;
+                        }
+                        None => return Err("ERR".to_string()),
+                    }
+                }
+                tokens.push(Token::Text(literal));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                let ident: String = chars[start..i].iter().collect();
+                tokens.push(Token::Ident(ident.to_ascii_uppercase()));
+            }
+            ':' => {
+                tokens.push(Token::Colon);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '+' => {
+                tokens.push(Token::Plus);
+                i += 1;
+            }
+            '-' => {
+                tokens.push(Token::Minus);
+                i += 1;
+            }
+            '*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            '/' => {
+                tokens.push(Token::Slash);
+                i += 1;
+            }
+            '=' => {
+                tokens.push(Token::Equal);
+                i += 1;
+            }
+            '<' | '>' => {
+                // Two-character operators first: <=, <> and >=
+                let token = match (ch, chars.get(i + 1)) {
+                    ('<', Some('=')) => Token::LessEqual,
+                    ('<', Some('>')) => Token::NotEqual,
+                    ('>', Some('=')) => Token::GreaterEqual,
+                    ('<', _) => Token::Less,
+                    _ => Token::Greater,
+                };
+                i += if matches!(token, Token::Less | Token::Greater) {
+                    1
+                } else {
+                    2
+                };
+                tokens.push(token);
+            }
+            _ => return Err("ERR".to_string()),
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Parse the part of a formula after the leading '='
+pub fn parse_expression(input: &str) -> Result<Expr, String> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let expr = parser.expression()?;
+    if parser.pos != parser.tokens.len() {
+        return Err("ERR".to_string());
+    }
+    Ok(expr)
+}
+
+// Recursive descent parser:
+//   expression := sum (('=' | '<>' | '<' | '<=' | '>' | '>=') sum)*
+//   sum        := term (('+' | '-') term)*
+//   term       := unary (('*' | '/') unary)*
+//   unary      := ('-' | '+') unary | primary
+//   primary    := number | text | reference (':' reference)?
+//               | name '(' arguments ')' | name | '(' expression ')'
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Depth of the expression being parsed, see `MAX_DEPTH`
+    depth: usize,
+}
+
+// Deepest nesting of a formula, past which parsing or evaluating it could
+// overflow the stack. Every operator of a chain such as 1+2+3 counts as a
+// level, and parentheses, calls and signs, which take several nested calls
+// to parse, as `NESTING` levels.
+const MAX_DEPTH: usize = 512;
+const NESTING: usize = 4;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // Goes levels deeper, failing past `MAX_DEPTH`
+    fn descend(&mut self, levels: usize) -> Result<(), String> {
+        self.depth += levels;
+        if self.depth > MAX_DEPTH {
+            return Err("ERR".to_string());
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            Err("ERR".to_string())
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut left = self.sum()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Equal) => BinaryOp::Eq,
+                Some(Token::NotEqual) => BinaryOp::Ne,
+                Some(Token::Less) => BinaryOp::Lt,
+                Some(Token::LessEqual) => BinaryOp::Le,
+                Some(Token::Greater) => BinaryOp::Gt,
+                Some(Token::GreaterEqual) => BinaryOp::Ge,
+                _ => {
+                    self.depth = depth;
+                    return Ok(left);
+                }
+            };
+            self.pos += 1;
+            self.descend(1)?;
+            let right = self.sum()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinaryOp::Add,
+                Some(Token::Minus) => BinaryOp::Sub,
+                _ => {
+                    self.depth = depth;
+                    return Ok(left);
+                }
+            };
+            self.pos += 1;
+            self.descend(1)?;
+            let right = self.term()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => BinaryOp::Mul,
+                Some(Token::Slash) => BinaryOp::Div,
+                _ => {
+                    self.depth = depth;
+                    return Ok(left);
+                }
+            };
+            self.pos += 1;
+            self.descend(1)?;
+            let right = self.unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.pos += 1;
+                self.descend(NESTING)?;
+                let inner = self.unary()?;
+                self.depth -= NESTING;
+                Ok(Expr::Neg(Box::new(inner)))
+            }
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.descend(NESTING)?;
+                let inner = self.unary()?;
+                self.depth -= NESTING;
+                Ok(inner)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(num)) => Ok(Expr::Number(num)),
+            Some(Token::Decimal(num)) => Ok(Expr::Decimal(num)),
+            Some(Token::Text(text)) => Ok(Expr::Text(text)),
+            Some(Token::LParen) => {
+                self.descend(NESTING)?;
+                let expr = self.expression()?;
+                self.expect(Token::RParen)?;
+                self.depth -= NESTING;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.pos += 1;
+                    self.descend(NESTING)?;
+                    let args = self.arguments()?;
+                    self.depth -= NESTING;
+                    return Ok(Expr::Call(name, args));
+                }
+
+                if let Some(cell) = parse_cell_reference(&name) {
+                    if self.peek() == Some(&Token::Colon) {
+                        self.pos += 1;
+                        let end = match self.next() {
+                            Some(Token::Ident(end)) => parse_cell_reference(&end),
+                            _ => None,
+                        };
+                        return match end {
+                            Some(end) => Ok(Expr::Range(Range::new(cell, end))),
+                            None => Err("ERR".to_string()),
+                        };
+                    }
+                    return Ok(Expr::Ref(cell));
+                }
+
+                match name.as_str() {
+                    "TRUE" => Ok(Expr::Number(1.0)),
+                    "FALSE" => Ok(Expr::Number(0.0)),
+                    _ if looks_like_reference(&name) => Err("REF".to_string()),
+                    _ => Ok(Expr::Name(name)),
+                }
+            }
+            _ => Err("ERR".to_string()),
+        }
+    }
+
+    // Comma-separated arguments up to and including the closing parenthesis
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression()?);
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RParen) => return Ok(args),
+                _ => return Err("ERR".to_string()),
+            }
+        }
+    }
+}
+
+// Looks like a cell reference (letter followed by digits) but was not
+// accepted by `parse_cell_reference` because the row number overflows
+fn looks_like_reference(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_uppercase())
+        && name.len() > 1
+        && chars.all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: f64) -> Box<Expr> {
+        Box::new(Expr::Number(n))
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = parse_range("c5:B2").unwrap();
+        assert_eq!(range, Range::new((2, 1), (5, 2)));
+        assert_eq!(range.to_string(), "B2:C5");
+        assert_eq!(parse_range("B2").unwrap().to_string(), "B2");
+        assert!(range.contains((3, 2)));
+        assert!(!range.contains((6, 2)));
+        assert_eq!(parse_range("B2+1"), None);
+        assert_eq!(parse_range("SUM(A0:A1)"), None);
+    }
+
+    #[test]
+    fn test_shifted() {
+        let expr = parse_expression("A0*2 + SUM(B1:C2)").unwrap().shifted(3, 1);
+        assert_eq!(expr, parse_expression("B3*2 + SUM(C4:D5)").unwrap());
+    }
+
+    #[test]
+    fn test_parse_comparisons() {
+        // Comparisons bind looser than arithmetic
+        assert_eq!(
+            parse_expression("1+2>=3"),
+            Ok(Expr::Binary(
+                BinaryOp::Ge,
+                Box::new(Expr::Binary(BinaryOp::Add, num(1.0), num(2.0))),
+                num(3.0)
+            ))
+        );
+        assert_eq!(
+            parse_expression("1<>2"),
+            Ok(Expr::Binary(BinaryOp::Ne, num(1.0), num(2.0)))
+        );
+        assert_eq!(
+            parse_expression("1 < 2"),
+            Ok(Expr::Binary(BinaryOp::Lt, num(1.0), num(2.0)))
+        );
+        assert!(parse_expression("1=>2").is_err());
+        assert!(parse_expression("1<").is_err());
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_expression("42"), Ok(Expr::Number(42.0)));
+        assert_eq!(parse_expression(" 4.5 "), Ok(Expr::Number(4.5)));
+        assert_eq!(parse_expression("0.10"), Ok(Expr::Number(0.1)));
+        // Digits past what an f64 holds are kept
+        assert_eq!(
+            parse_expression("10000000000000001"),
+            Ok(Expr::Decimal(Decimal::parse("10000000000000001").unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        // 1 + (2 * 3), not (1 + 2) * 3
+        assert_eq!(
+            parse_expression("1+2*3"),
+            Ok(Expr::Binary(
+                BinaryOp::Add,
+                num(1.0),
+                Box::new(Expr::Binary(BinaryOp::Mul, num(2.0), num(3.0)))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_left_associative() {
+        // (8 - 2) - 1
+        assert_eq!(
+            parse_expression("8-2-1"),
+            Ok(Expr::Binary(
+                BinaryOp::Sub,
+                Box::new(Expr::Binary(BinaryOp::Sub, num(8.0), num(2.0))),
+                num(1.0)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_parentheses_and_negation() {
+        assert_eq!(
+            parse_expression("-(1+2)"),
+            Ok(Expr::Neg(Box::new(Expr::Binary(
+                BinaryOp::Add,
+                num(1.0),
+                num(2.0)
+            ))))
+        );
+    }
+
+    #[test]
+    fn test_parse_reference_and_range() {
+        assert_eq!(parse_expression("b5"), Ok(Expr::Ref((5, 1))));
+        assert_eq!(
+            parse_expression("C3:A1"),
+            Ok(Expr::Range(Range::new((1, 0), (3, 2))))
+        );
+    }
+
+    #[test]
+    fn test_parse_function_call() {
+        assert_eq!(
+            parse_expression("index(A0:B2, 2, 1)"),
+            Ok(Expr::Call(
+                "INDEX".to_string(),
+                vec![
+                    Expr::Range(Range::new((0, 0), (2, 1))),
+                    Expr::Number(2.0),
+                    Expr::Number(1.0)
+                ]
+            ))
+        );
+        assert_eq!(
+            parse_expression("NOW()"),
+            Ok(Expr::Call("NOW".to_string(), vec![]))
+        );
+    }
+
+    #[test]
+    fn test_parse_text_literal() {
+        assert_eq!(
+            parse_expression("\"say \"\"hi\"\"\""),
+            Ok(Expr::Text("say \"hi\"".to_string()))
+        );
+        assert!(parse_expression("\"open").is_err());
+    }
+
+    #[test]
+    fn test_parse_booleans_and_names() {
+        assert_eq!(parse_expression("TRUE"), Ok(Expr::Number(1.0)));
+        assert_eq!(parse_expression("false"), Ok(Expr::Number(0.0)));
+        assert_eq!(parse_expression("ABC"), Ok(Expr::Name("ABC".to_string())));
+        assert_eq!(parse_expression("A100"), Ok(Expr::Ref((100, 0))));
+        assert_eq!(
+            parse_expression("A99999999999999999999999"),
+            Err("REF".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_expression("").is_err());
+        assert!(parse_expression("1+").is_err());
+        assert!(parse_expression("(1").is_err());
+        assert!(parse_expression("SUM(1,").is_err());
+        assert!(parse_expression("A1:").is_err());
+        assert!(parse_expression("1 2").is_err());
+        assert!(parse_expression("1 # 2").is_err());
+    }
+
+    #[test]
+    fn test_references_expand_ranges() {
+        let expr = parse_expression("VLOOKUP(D0, A0:B2, 2) + C9").unwrap();
+        let mut deps = HashSet::new();
+        expr.references(&mut deps);
+        assert_eq!(deps.len(), 8);
+        assert!(deps.contains(&(0, 3)));
+        assert!(deps.contains(&(2, 1)));
+        assert!(deps.contains(&(9, 2)));
+    }
+
+    #[test]
+    fn test_cell_name() {
+        assert_eq!(cell_name((0, 0)), "A0");
+        assert_eq!(cell_name((42, 25)), "Z42");
+        assert_eq!(parse_cell_reference(&cell_name((7, 3))), Some((7, 3)));
+    }
+
+    #[test]
+    fn test_parse_cell_reference_rows() {
+        assert_eq!(parse_cell_reference("c12"), Some((12, 2)));
+        assert_eq!(parse_cell_reference("A3846"), Some((3846, 0)));
+        assert_eq!(parse_cell_reference("A1B"), None);
+        assert_eq!(parse_cell_reference("A+1"), None);
+    }
+
+    #[test]
+    fn test_is_volatile() {
+        assert!(parse_expression("RAND()").unwrap().is_volatile());
+        assert!(
+            parse_expression("1 + ROUND(RANDBETWEEN(1, 6))")
+                .unwrap()
+                .is_volatile()
+        );
+        assert!(!parse_expression("ROUND(A1, 2)").unwrap().is_volatile());
+    }
+
+    #[test]
+    fn test_range_helpers() {
+        let range = Range::new((2, 3), (0, 1));
+        assert_eq!(range.start, (0, 1));
+        assert_eq!(range.end, (2, 3));
+        assert_eq!(range.rows(), 3);
+        assert_eq!(range.cols(), 3);
+        assert_eq!(range.cell(1, 2), (1, 3));
+        assert_eq!(range.row(1), Range::new((1, 1), (1, 3)));
+        assert_eq!(range.col(0), Range::new((0, 1), (2, 1)));
+        assert_eq!(range.cells().count(), 9);
+        assert_eq!(range.cells().next(), Some((0, 1)));
+        let whole = parse_range("A0:A18446744073709551615").unwrap();
+        assert_eq!(whole.rows(), usize::MAX);
+    }
+}
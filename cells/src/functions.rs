@@ -0,0 +1,182 @@
+mod array;
+mod import;
+mod lookup;
+mod math;
+mod stats;
+
+use crate::eval::{Evaluator, Value};
+use crate::formula::Expr;
+use math::unary;
+
+pub use import::import_path;
+
+pub fn call(eval: &Evaluator, name: &str, args: &[Expr]) -> Result<Value, String> {
+    match name {
+        // Lookup and reference
+        "INDEX" => lookup::index(eval, args),
+        "MATCH" => lookup::match_position(eval, args),
+        "VLOOKUP" => lookup::vlookup(eval, args),
+        "HLOOKUP" => lookup::hlookup(eval, args),
+        "XLOOKUP" => lookup::xlookup(eval, args),
+
+        // Arrays, which spill into the cells below and to the right
+        "SEQUENCE" => array::sequence(eval, args),
+        "TRANSPOSE" => array::transpose(eval, args),
+        "FILTER" => array::filter(eval, args),
+        "SORT" => array::sort(eval, args),
+        "UNIQUE" => array::unique(eval, args),
+
+        // Data read from files, spilling like arrays
+        "IMPORTCSV" => import::import_csv(eval, args),
+
+        // Math
+        "ABS" => unary(eval, args, |x| Ok(x.abs())),
+        "ROUND" => math::round(eval, args),
+        "FLOOR" => math::floor(eval, args),
+        "CEILING" => math::ceiling(eval, args),
+        "SQRT" => unary(eval, args, |x| Ok(non_negative(x)?.sqrt())),
+        "POWER" => math::power(eval, args),
+        "MOD" => math::modulo(eval, args),
+        "EXP" => unary(eval, args, |x| Ok(x.exp())),
+        "LN" => unary(eval, args, |x| Ok(positive(x)?.ln())),
+        "LOG" => math::log(eval, args),
+        "LOG10" => unary(eval, args, |x| Ok(positive(x)?.log10())),
+        "PI" => math::pi(args),
+        "SIN" => unary(eval, args, |x| Ok(x.sin())),
+        "COS" => unary(eval, args, |x| Ok(x.cos())),
+        "TAN" => unary(eval, args, |x| Ok(x.tan())),
+        "ASIN" => unary(eval, args, |x| Ok(unit(x)?.asin())),
+        "ACOS" => unary(eval, args, |x| Ok(unit(x)?.acos())),
+        "ATAN" => unary(eval, args, |x| Ok(x.atan())),
+        "ATAN2" => math::atan2(eval, args),
+        "DEGREES" => unary(eval, args, |x| Ok(x.to_degrees())),
+        "RADIANS" => unary(eval, args, |x| Ok(x.to_radians())),
+        "RAND" => math::rand(args),
+        "RANDBETWEEN" => math::randbetween(eval, args),
+
+        // Statistics
+        "SUM" => stats::sum(eval, args),
+        "AVERAGE" => stats::average(eval, args),
+        "MIN" => stats::min(eval, args),
+        "MAX" => stats::max(eval, args),
+        "COUNT" => stats::count(eval, args),
+        "VAR" | "VAR.S" => stats::var(eval, args, true),
+        "VAR.P" => stats::var(eval, args, false),
+        "STDEV" | "STDEV.S" => stats::stdev(eval, args, true),
+        "STDEV.P" => stats::stdev(eval, args, false),
+        "MEDIAN" => stats::median(eval, args),
+        "PERCENTILE" => stats::percentile(eval, args),
+
+        // Functions of the sheet's script
+        _ => match eval.script() {
+            Some(script) => script.call(eval, name, args),
+            None => Err("NAME".to_string()),
+        },
+    }
+}
+
+// Every function `call` knows, for autocompletion
+pub const NAMES: &[&str] = &[
+    "INDEX",
+    "MATCH",
+    "VLOOKUP",
+    "HLOOKUP",
+    "XLOOKUP",
+    "SEQUENCE",
+    "TRANSPOSE",
+    "FILTER",
+    "SORT",
+    "UNIQUE",
+    "IMPORTCSV",
+    "ABS",
+    "ROUND",
+    "FLOOR",
+    "CEILING",
+    "SQRT",
+    "POWER",
+    "MOD",
+    "EXP",
+    "LN",
+    "LOG",
+    "LOG10",
+    "PI",
+    "SIN",
+    "COS",
+    "TAN",
+    "ASIN",
+    "ACOS",
+    "ATAN",
+    "ATAN2",
+    "DEGREES",
+    "RADIANS",
+    "RAND",
+    "RANDBETWEEN",
+    "SUM",
+    "AVERAGE",
+    "MIN",
+    "MAX",
+    "COUNT",
+    "VAR",
+    "VAR.S",
+    "VAR.P",
+    "STDEV",
+    "STDEV.S",
+    "STDEV.P",
+    "MEDIAN",
+    "PERCENTILE",
+];
+
+// Functions that produce a new value on every recalculation, even when none
+// of their inputs changed
+pub fn is_volatile(name: &str) -> bool {
+    matches!(name, "RAND" | "RANDBETWEEN")
+}
+
+fn check_arity(args: &[Expr], min: usize, max: usize) -> Result<(), String> {
+    if args.len() < min || args.len() > max {
+        Err("ERR".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn non_negative(x: f64) -> Result<f64, String> {
+    if x < 0.0 {
+        Err("NUM".to_string())
+    } else {
+        Ok(x)
+    }
+}
+
+fn positive(x: f64) -> Result<f64, String> {
+    if x <= 0.0 {
+        Err("NUM".to_string())
+    } else {
+        Ok(x)
+    }
+}
+
+fn unit(x: f64) -> Result<f64, String> {
+    if (-1.0..=1.0).contains(&x) {
+        Ok(x)
+    } else {
+        Err("NUM".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CellValue;
+    use crate::formula::Cell;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_names_are_known() {
+        let values: HashMap<Cell, CellValue> = HashMap::new();
+        let eval = Evaluator::new(&values);
+        for name in NAMES {
+            assert_ne!(call(&eval, name, &[]), Err("NAME".to_string()), "{}", name);
+        }
+    }
+}
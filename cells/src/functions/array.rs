@@ -0,0 +1,319 @@
+use super::check_arity;
+use crate::CellValue;
+use crate::decimal::Number;
+use crate::eval::{Array, Evaluator, Value};
+use crate::formula::Expr;
+use std::cmp::Ordering;
+
+// Largest array a function may build, far more than a sheet can show
+const MAX_ELEMENTS: usize = 1 << 20;
+
+// Optional numeric argument with a default
+fn optional(eval: &Evaluator, args: &[Expr], i: usize, default: f64) -> Result<f64, String> {
+    match args.get(i) {
+        Some(expr) => eval.number(expr),
+        None => Ok(default),
+    }
+}
+
+// Like `optional`, keeping decimals exact
+fn optional_exact(
+    eval: &Evaluator,
+    args: &[Expr],
+    i: usize,
+    default: f64,
+) -> Result<Number, String> {
+    match args.get(i) {
+        Some(expr) => eval.exact(expr),
+        None => Ok(Number::Float(default)),
+    }
+}
+
+fn transposed(array: &Array) -> Array {
+    (0..array[0].len())
+        .map(|col| array.iter().map(|row| row[col].clone()).collect())
+        .collect()
+}
+
+// SEQUENCE(rows, [cols], [start], [step]) counts from start in steps, along
+// each row and then down
+pub fn sequence(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 4)?;
+    let rows = eval.number(&args[0])?.trunc();
+    let cols = optional(eval, args, 1, 1.0)?.trunc();
+    let start = optional_exact(eval, args, 2, 1.0)?;
+    let step = optional_exact(eval, args, 3, 1.0)?;
+    // Written so that NaN, as typed into a cell, is refused too
+    if !(rows >= 1.0 && cols >= 1.0 && rows * cols <= MAX_ELEMENTS as f64) {
+        return Err("NUM".to_string());
+    }
+    let cols = cols as usize;
+    let arithmetic = eval.arithmetic();
+    Ok(Value::Array(
+        (0..rows as usize)
+            .map(|row| {
+                (0..cols)
+                    .map(|col| {
+                        let index = Number::Float((row * cols + col) as f64);
+                        arithmetic
+                            .add(&start, &arithmetic.mul(&step, &index))
+                            .into()
+                    })
+                    .collect()
+            })
+            .collect(),
+    ))
+}
+
+// TRANSPOSE(array) swaps rows and columns
+pub fn transpose(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 1)?;
+    Ok(Value::Array(transposed(&eval.array_arg(&args[0])?)))
+}
+
+// FILTER(array, include, [if_empty]) keeps the rows of the array whose
+// value in `include`, a column as tall as the array, is nonzero; or its
+// columns, when `include` is a row as wide as the array
+pub fn filter(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 3)?;
+    let array = eval.array_arg(&args[0])?;
+    let include = eval.array_arg(&args[1])?;
+
+    let keep = |values: Vec<&CellValue>| -> Result<Vec<bool>, String> {
+        values
+            .into_iter()
+            .map(|value| match value {
+                CellValue::Text(_) => Err("VALUE".to_string()),
+                CellValue::Error(e) => Err(e.clone()),
+                number => Ok(number.as_number() != Some(0.0)),
+            })
+            .collect()
+    };
+    let filtered: Array = if include[0].len() == 1 && include.len() == array.len() {
+        let keep = keep(include.iter().map(|row| &row[0]).collect())?;
+        array
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .map(|(row, _)| row)
+            .collect()
+    } else if include.len() == 1 && include[0].len() == array[0].len() {
+        let keep = keep(include[0].iter().collect())?;
+        array
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .zip(&keep)
+                    .filter(|(_, keep)| **keep)
+                    .map(|(value, _)| value)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|row| !row.is_empty())
+            .collect()
+    } else {
+        return Err("VALUE".to_string());
+    };
+
+    match (filtered.is_empty(), args.get(2)) {
+        (false, _) => Ok(Value::Array(filtered)),
+        (true, Some(if_empty)) => eval.eval(if_empty),
+        (true, None) => Err("CALC".to_string()),
+    }
+}
+
+// Order of values when sorting: numbers, then text ignoring case, then
+// errors
+fn sort_order(a: &CellValue, b: &CellValue) -> Ordering {
+    let rank = |value: &CellValue| match value {
+        CellValue::Number(_) | CellValue::Decimal(_) => 0,
+        CellValue::Text(_) => 1,
+        CellValue::Error(_) => 2,
+    };
+    match (a, b) {
+        (CellValue::Text(a), CellValue::Text(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        _ => match (a.as_exact(), b.as_exact()) {
+            (Some(a), Some(b)) => a.compare(&b).unwrap_or(Ordering::Equal),
+            _ => rank(a).cmp(&rank(b)),
+        },
+    }
+}
+
+// SORT(array, [index], [order], [by_col]) sorts the rows of the array by
+// their value in column `index`, counted from 1, ascending for order 1 and
+// descending for -1. With by_col nonzero it sorts columns by a row instead.
+pub fn sort(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 4)?;
+    let array = eval.array_arg(&args[0])?;
+    let index = optional(eval, args, 1, 1.0)?.trunc();
+    let descending = match optional(eval, args, 2, 1.0)? {
+        1.0 => false,
+        -1.0 => true,
+        _ => return Err("VALUE".to_string()),
+    };
+    let by_col = optional(eval, args, 3, 0.0)? != 0.0;
+
+    let mut lines = if by_col { transposed(&array) } else { array };
+    if !(1.0..=lines[0].len() as f64).contains(&index) {
+        return Err("VALUE".to_string());
+    }
+    let index = index as usize - 1;
+    lines.sort_by(|a, b| {
+        let ordering = sort_order(&a[index], &b[index]);
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    Ok(Value::Array(if by_col {
+        transposed(&lines)
+    } else {
+        lines
+    }))
+}
+
+// UNIQUE(array, [by_col], [exactly_once]) keeps the first of every distinct
+// row, ignoring case, or only the rows appearing once. With by_col nonzero
+// it compares columns instead.
+pub fn unique(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 3)?;
+    let array = eval.array_arg(&args[0])?;
+    let by_col = optional(eval, args, 1, 0.0)? != 0.0;
+    let exactly_once = optional(eval, args, 2, 0.0)? != 0.0;
+
+    let lines = if by_col { transposed(&array) } else { array };
+    let same =
+        |a: &[CellValue], b: &[CellValue]| a.iter().zip(b).all(|(a, b)| a == b || same_text(a, b));
+    let count = |line: &[CellValue]| lines.iter().filter(|other| same(line, other)).count();
+
+    let mut distinct: Array = Vec::new();
+    for line in &lines {
+        if distinct.iter().any(|seen| same(seen, line)) {
+            continue;
+        }
+        if !exactly_once || count(line) == 1 {
+            distinct.push(line.clone());
+        }
+    }
+    if distinct.is_empty() {
+        return Err("CALC".to_string());
+    }
+    Ok(Value::Array(if by_col {
+        transposed(&distinct)
+    } else {
+        distinct
+    }))
+}
+
+fn same_text(a: &CellValue, b: &CellValue) -> bool {
+    matches!((a, b), (CellValue::Text(a), CellValue::Text(b)) if a.eq_ignore_ascii_case(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CellValue;
+    use crate::eval::{Array, Evaluator, Value};
+    use crate::formula::{Cell, parse_expression};
+    use std::collections::HashMap;
+
+    // A0:B3 holds names and scores, and C0 NaN
+    fn values() -> HashMap<Cell, CellValue> {
+        let mut values = HashMap::new();
+        for (row, (name, score)) in [("ann", 3.0), ("Bob", 1.0), ("Ann", 3.0), ("cy", 2.0)]
+            .into_iter()
+            .enumerate()
+        {
+            values.insert((row, 0), CellValue::Text(name.to_string()));
+            values.insert((row, 1), CellValue::Number(score));
+        }
+        values.insert((0, 2), CellValue::Number(f64::NAN));
+        values
+    }
+
+    fn eval(input: &str) -> Result<Value, String> {
+        Evaluator::new(&values()).eval(&parse_expression(input)?)
+    }
+
+    // Rows of an array result, written as numbers or text
+    fn rows(input: &str) -> Vec<Vec<String>> {
+        match eval(input) {
+            Ok(Value::Array(array)) => array
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect())
+                .collect(),
+            other => panic!("{} = {:?}, expected an array", input, other),
+        }
+    }
+
+    fn numbers(array: &[&[f64]]) -> Array {
+        array
+            .iter()
+            .map(|row| row.iter().map(|n| CellValue::Number(*n)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_sequence() {
+        assert_eq!(
+            eval("SEQUENCE(3)"),
+            Ok(Value::Array(numbers(&[&[1.0], &[2.0], &[3.0]])))
+        );
+        assert_eq!(
+            eval("SEQUENCE(2, 3, 0, 5)"),
+            Ok(Value::Array(numbers(&[
+                &[0.0, 5.0, 10.0],
+                &[15.0, 20.0, 25.0]
+            ])))
+        );
+        assert_eq!(eval("SEQUENCE(0)"), Err("NUM".to_string()));
+        assert_eq!(eval("SEQUENCE(100000, 100000)"), Err("NUM".to_string()));
+        assert_eq!(eval("SEQUENCE(C0)"), Err("NUM".to_string()));
+        assert_eq!(eval("SEQUENCE(2, C0)"), Err("NUM".to_string()));
+    }
+
+    #[test]
+    fn test_transpose() {
+        assert_eq!(rows("TRANSPOSE(A0:B1)"), [["ann", "Bob"], ["3", "1"]]);
+        assert_eq!(rows("TRANSPOSE(SEQUENCE(1, 3))"), [["1"], ["2"], ["3"]]);
+    }
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(rows("FILTER(A0:A3, B0:B3>1)"), [["ann"], ["Ann"], ["cy"]]);
+        assert_eq!(rows("FILTER(A0:B1, SEQUENCE(1, 2)=2)"), [["3"], ["1"]]);
+        assert_eq!(eval("FILTER(A0:A3, B0:B3>5)"), Err("CALC".to_string()));
+        assert_eq!(
+            eval("FILTER(A0:A3, B0:B3>5, \"none\")"),
+            Ok(Value::Text("none".to_string()))
+        );
+        assert_eq!(eval("FILTER(A0:A3, B0:B1>1)"), Err("VALUE".to_string()));
+        assert_eq!(eval("FILTER(A0:A3, A0:A3)"), Err("VALUE".to_string()));
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(
+            rows("SORT(A0:B3, 2)"),
+            [["Bob", "1"], ["cy", "2"], ["ann", "3"], ["Ann", "3"]]
+        );
+        assert_eq!(
+            rows("SORT(A0:A3, 1, -1)"),
+            [["cy"], ["Bob"], ["ann"], ["Ann"]]
+        );
+        assert_eq!(rows("SORT(SEQUENCE(1, 3), 1, -1, 1)"), [["3", "2", "1"]]);
+        assert_eq!(eval("SORT(A0:B3, 3)"), Err("VALUE".to_string()));
+        assert_eq!(eval("SORT(A0:B3, C0)"), Err("VALUE".to_string()));
+        assert_eq!(eval("SORT(A0:B3, 1, 0)"), Err("VALUE".to_string()));
+    }
+
+    #[test]
+    fn test_unique() {
+        assert_eq!(
+            rows("UNIQUE(A0:B3)"),
+            [["ann", "3"], ["Bob", "1"], ["cy", "2"]]
+        );
+        assert_eq!(rows("UNIQUE(B0:B3, 0, 1)"), [["1"], ["2"]]);
+        assert_eq!(rows("UNIQUE(TRANSPOSE(B0:B3), 1)"), [["3", "1", "2"]]);
+        assert_eq!(eval("UNIQUE(B0:B3*0, 0, 1)"), Err("CALC".to_string()));
+    }
+}
@@ -0,0 +1,178 @@
+use super::check_arity;
+use crate::eval::{Array, Evaluator, Value};
+use crate::formula::{Expr, Range, parse_cell_reference, parse_range};
+use crate::sheet::constant;
+use std::path::{Path, PathBuf};
+
+// Largest block a file may fill, far more than a sheet can show
+const MAX_ELEMENTS: usize = 1 << 20;
+
+// IMPORTCSV(path, [range]) reads a local CSV file into an array that spills
+// like SEQUENCE. The range picks a block of the file in the sheet's own
+// notation, with the first line of the file as row 0 and its first field as
+// column A. Fields read like input typed into a cell, so numbers become
+// numbers. A relative path is read from the directory of the sheet's file. A
+// file that cannot be read gives #REF.
+pub fn import_csv(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 2)?;
+    let path = text(eval, &args[0])?;
+    let block = match args.get(1) {
+        Some(expr) => {
+            let block = text(eval, expr)?;
+            let block = block.trim();
+            Some(
+                parse_range(block)
+                    .or_else(|| parse_cell_reference(block).map(|cell| Range::new(cell, cell)))
+                    .ok_or_else(|| "VALUE".to_string())?,
+            )
+        }
+        None => None,
+    };
+    let contents = std::fs::read_to_string(import_path(eval.directory(), &path))
+        .map_err(|_| "REF".to_string())?;
+    let records = records(&contents);
+
+    let block = match block {
+        Some(block) => block,
+        None => {
+            let width = records.iter().map(Vec::len).max().unwrap_or(0);
+            if width == 0 {
+                return Err("CALC".to_string());
+            }
+            Range::new((0, 0), (records.len() - 1, width - 1))
+        }
+    };
+    // Blocks reaching past the limit are refused before their size is worked
+    // out, so no end given as text can overflow it
+    if block.end.0 >= MAX_ELEMENTS
+        || block.end.1 >= MAX_ELEMENTS
+        || block
+            .rows()
+            .checked_mul(block.cols())
+            .is_none_or(|size| size > MAX_ELEMENTS)
+    {
+        return Err("NUM".to_string());
+    }
+
+    // Fields past the end of the file or of a line are empty
+    let array: Array = (block.start.0..=block.end.0)
+        .map(|row| {
+            (block.start.1..=block.end.1)
+                .map(|col| {
+                    let field = records.get(row).and_then(|record| record.get(col));
+                    constant(field.map_or("", |field| field.trim()), eval.arithmetic())
+                })
+                .collect()
+        })
+        .collect();
+    Ok(Value::Array(array))
+}
+
+// File a path given to IMPORTCSV names, relative to the directory of the
+// sheet's file when the sheet has one
+pub fn import_path(directory: Option<&Path>, path: impl AsRef<Path>) -> PathBuf {
+    match directory {
+        Some(directory) => directory.join(path),
+        None => path.as_ref().to_path_buf(),
+    }
+}
+
+fn text(eval: &Evaluator, expr: &Expr) -> Result<String, String> {
+    match eval.scalar(expr)? {
+        Value::Text(text) => Ok(text),
+        _ => Err("VALUE".to_string()),
+    }
+}
+
+// Fields of every line of CSV text. Fields in double quotes may hold commas,
+// line breaks and doubled quotes; a line break ending the text adds no line.
+fn records(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CellValue, Sheet};
+
+    #[test]
+    fn test_records() {
+        assert_eq!(
+            records("a,\"b, \"\"c\"\"\"\r\n1,2\n\n\"multi\nline\",3"),
+            [
+                vec!["a", "b, \"c\""],
+                vec!["1", "2"],
+                vec![""],
+                vec!["multi\nline", "3"],
+            ]
+        );
+        assert_eq!(records("x,y\n"), [vec!["x", "y"]]);
+        assert_eq!(records(""), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("import.csv");
+        std::fs::write(&path, "name,qty\nbolts, 12\nnuts,30,extra\n").unwrap();
+        let path = path.display().to_string();
+
+        let mut sheet = Sheet::new(10, 5);
+        sheet.set_formula((0, 0), &format!("=IMPORTCSV(\"{}\")", path));
+        assert_eq!(sheet.value((1, 1)), Some(&CellValue::Number(12.0)));
+        assert_eq!(
+            sheet.value((2, 2)),
+            Some(&CellValue::Text("extra".to_string()))
+        );
+        assert_eq!(sheet.value((1, 2)), Some(&CellValue::Text(String::new())));
+        sheet.set_formula((4, 0), &format!("=SUM(IMPORTCSV(\"{}\", \"B1:B2\"))", path));
+        assert_eq!(sheet.value((4, 0)), Some(&CellValue::Number(42.0)));
+        sheet.set_formula((5, 0), &format!("=IMPORTCSV(\"{}\", \"A2\")", path));
+        assert_eq!(
+            sheet.value((5, 0)),
+            Some(&CellValue::Text("nuts".to_string()))
+        );
+
+        let error = |code: &str| CellValue::Error(code.to_string());
+        sheet.set_formula((6, 0), &format!("=IMPORTCSV(\"{}\", \"B1:\")", path));
+        assert_eq!(sheet.value((6, 0)), Some(&error("VALUE")));
+        std::fs::remove_file(&path).unwrap();
+        sheet.set_formula((7, 0), &format!("=IMPORTCSV(\"{}\", \"A0\")", path));
+        assert_eq!(sheet.value((7, 0)), Some(&error("REF")));
+        sheet.set_formula((8, 0), "=IMPORTCSV(1)");
+        assert_eq!(sheet.value((8, 0)), Some(&error("VALUE")));
+
+        // Blocks too large to fill are refused without reading their size
+        std::fs::write(&path, "1\n").unwrap();
+        for block in ["A0:A18446744073709551615", "A1048576", "A0:Z1000000"] {
+            sheet.set_formula((9, 0), &format!("=IMPORTCSV(\"{}\", \"{}\")", path, block));
+            assert_eq!(sheet.value((9, 0)), Some(&error("NUM")), "{block}");
+        }
+    }
+}
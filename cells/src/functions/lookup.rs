@@ -0,0 +1,498 @@
+use super::check_arity;
+use crate::eval::{Evaluator, Value, compare_values};
+use crate::formula::{Cell, Expr, Range};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MatchMode {
+    Exact,
+    // Exact match, otherwise the largest value below the lookup value
+    NextSmaller,
+    // Exact match, otherwise the smallest value above the lookup value
+    NextLarger,
+}
+
+// Zero-based position of `needle` among `cells`. Empty cells, error cells and
+// values of a different type than the needle never match.
+fn find(
+    eval: &Evaluator,
+    needle: &Value,
+    cells: &[Cell],
+    mode: MatchMode,
+    reverse: bool,
+) -> Option<usize> {
+    let mut best: Option<(usize, Value)> = None;
+
+    let mut positions: Vec<usize> = (0..cells.len()).collect();
+    if reverse {
+        positions.reverse();
+    }
+
+    for i in positions {
+        if eval.cell_value(cells[i]).is_none() {
+            continue;
+        }
+        let Ok(candidate) = eval.cell(cells[i]) else {
+            continue;
+        };
+
+        let wanted = match (compare_values(&candidate, needle), mode) {
+            (Some(Ordering::Equal), _) => return Some(i),
+            (Some(Ordering::Less), MatchMode::NextSmaller) => Ordering::Greater,
+            (Some(Ordering::Greater), MatchMode::NextLarger) => Ordering::Less,
+            _ => continue,
+        };
+
+        // Keep the closest candidate seen so far
+        let closer = match &best {
+            Some((_, current)) => compare_values(&candidate, current) == Some(wanted),
+            None => true,
+        };
+        if closer {
+            best = Some((i, candidate));
+        }
+    }
+
+    best.map(|(i, _)| i)
+}
+
+// Cells of a single row or column, in order
+fn line(range: Range) -> Option<Vec<Cell>> {
+    if range.rows() == 1 || range.cols() == 1 {
+        Some(range.cells().collect())
+    } else {
+        None
+    }
+}
+
+// One-based index argument; fractions are truncated
+fn index_arg(eval: &Evaluator, expr: &Expr) -> Result<usize, String> {
+    let n = eval.number(expr)?;
+    if n < 0.0 {
+        Err("VALUE".to_string())
+    } else {
+        Ok(n.trunc() as usize)
+    }
+}
+
+// INDEX(range, row, [col]) returns the cell at a one-based position. A zero
+// row or column selects the whole column or row of the range.
+pub fn index(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 3)?;
+    let range = eval.range(&args[0])?;
+    let first = index_arg(eval, &args[1])?;
+
+    let (row, col) = match args.get(2) {
+        Some(expr) => (first, index_arg(eval, expr)?),
+        // With a single row the lone index picks a column
+        None if range.rows() == 1 => (0, first),
+        None => (first, 0),
+    };
+
+    if row > range.rows() || col > range.cols() {
+        return Err("REF".to_string());
+    }
+
+    let rows = if row == 0 {
+        (range.start.0, range.end.0)
+    } else {
+        (range.start.0 + row - 1, range.start.0 + row - 1)
+    };
+    let cols = if col == 0 {
+        (range.start.1, range.end.1)
+    } else {
+        (range.start.1 + col - 1, range.start.1 + col - 1)
+    };
+
+    Ok(Value::Range(Range::new((rows.0, cols.0), (rows.1, cols.1))))
+}
+
+// MATCH(value, range, [type]) returns the one-based position of a value in a
+// single row or column. Type 1 (default) finds the largest value less than or
+// equal, 0 an exact match and -1 the smallest value greater than or equal.
+pub fn match_position(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 3)?;
+    let needle = eval.scalar(&args[0])?;
+    let cells = line(eval.range(&args[1])?).ok_or("N/A")?;
+    let match_type = match args.get(2) {
+        Some(expr) => eval.number(expr)?,
+        None => 1.0,
+    };
+
+    let mode = if match_type > 0.0 {
+        MatchMode::NextSmaller
+    } else if match_type < 0.0 {
+        MatchMode::NextLarger
+    } else {
+        MatchMode::Exact
+    };
+
+    match find(eval, &needle, &cells, mode, false) {
+        Some(i) => Ok(Value::Number((i + 1) as f64)),
+        None => Err("N/A".to_string()),
+    }
+}
+
+// VLOOKUP(value, table, col, [approximate]) searches the first column of the
+// table and returns the value from column `col` of the matching row
+pub fn vlookup(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 3, 4)?;
+    let needle = eval.scalar(&args[0])?;
+    let table = eval.range(&args[1])?;
+    let col = index_arg(eval, &args[2])?;
+    let mode = lookup_mode(eval, args.get(3))?;
+
+    if col < 1 {
+        return Err("VALUE".to_string());
+    }
+    if col > table.cols() {
+        return Err("REF".to_string());
+    }
+
+    let keys: Vec<Cell> = table.col(0).cells().collect();
+    match find(eval, &needle, &keys, mode, false) {
+        Some(row) => eval.cell(table.cell(row, col - 1)),
+        None => Err("N/A".to_string()),
+    }
+}
+
+// HLOOKUP(value, table, row, [approximate]) is VLOOKUP turned sideways: it
+// searches the first row and returns the value from row `row`
+pub fn hlookup(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 3, 4)?;
+    let needle = eval.scalar(&args[0])?;
+    let table = eval.range(&args[1])?;
+    let row = index_arg(eval, &args[2])?;
+    let mode = lookup_mode(eval, args.get(3))?;
+
+    if row < 1 {
+        return Err("VALUE".to_string());
+    }
+    if row > table.rows() {
+        return Err("REF".to_string());
+    }
+
+    let keys: Vec<Cell> = table.row(0).cells().collect();
+    match find(eval, &needle, &keys, mode, false) {
+        Some(col) => eval.cell(table.cell(row - 1, col)),
+        None => Err("N/A".to_string()),
+    }
+}
+
+// The optional last argument of VLOOKUP/HLOOKUP, TRUE by default
+fn lookup_mode(eval: &Evaluator, expr: Option<&Expr>) -> Result<MatchMode, String> {
+    let approximate = match expr {
+        Some(expr) => eval.number(expr)? != 0.0,
+        None => true,
+    };
+    Ok(if approximate {
+        MatchMode::NextSmaller
+    } else {
+        MatchMode::Exact
+    })
+}
+
+// XLOOKUP(value, lookup, return, [if_not_found], [match_mode], [search_mode])
+// finds a value in a single row or column and returns the matching row or
+// column of the return range
+pub fn xlookup(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 3, 6)?;
+    let needle = eval.scalar(&args[0])?;
+    let lookup = eval.range(&args[1])?;
+    let results = eval.range(&args[2])?;
+
+    let mode = match args.get(4) {
+        Some(expr) => match eval.number(expr)? as i64 {
+            0 => MatchMode::Exact,
+            -1 => MatchMode::NextSmaller,
+            1 => MatchMode::NextLarger,
+            // Wildcard matching (2) is not supported
+            _ => return Err("VALUE".to_string()),
+        },
+        None => MatchMode::Exact,
+    };
+    let reverse = match args.get(5) {
+        // Binary search modes (2, -2) give the same answer as a linear scan
+        Some(expr) => match eval.number(expr)? as i64 {
+            1 | 2 => false,
+            -1 | -2 => true,
+            _ => return Err("VALUE".to_string()),
+        },
+        None => false,
+    };
+
+    let cells = line(lookup).ok_or("VALUE")?;
+    let vertical = lookup.cols() == 1 && results.rows() == lookup.rows();
+    let horizontal = lookup.rows() == 1 && results.cols() == lookup.cols();
+    if !vertical && !horizontal {
+        return Err("VALUE".to_string());
+    }
+
+    match find(eval, &needle, &cells, mode, reverse) {
+        Some(i) if vertical => Ok(Value::Range(results.row(i))),
+        Some(i) => Ok(Value::Range(results.col(i))),
+        None => match args.get(3) {
+            Some(expr) => eval.eval(expr),
+            None => Err("N/A".to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CellValue;
+    use crate::eval::{Evaluator, Value};
+    use crate::formula::{Cell, parse_expression};
+    use std::collections::HashMap;
+
+    // Price list in A0:C3:
+    //   A       B     C
+    // 0 apple   1.5   10
+    // 1 banana  0.5   20
+    // 2 cherry  4     30
+    // 3 date    3     40
+    fn price_list() -> HashMap<Cell, CellValue> {
+        let mut values = HashMap::new();
+        let rows = [
+            ("apple", 1.5, 10.0),
+            ("banana", 0.5, 20.0),
+            ("cherry", 4.0, 30.0),
+            ("date", 3.0, 40.0),
+        ];
+        for (row, (name, price, stock)) in rows.iter().enumerate() {
+            values.insert((row, 0), CellValue::Text(name.to_string()));
+            values.insert((row, 1), CellValue::Number(*price));
+            values.insert((row, 2), CellValue::Number(*stock));
+        }
+        values
+    }
+
+    fn eval(values: &HashMap<Cell, CellValue>, input: &str) -> Result<Value, String> {
+        Evaluator::new(values).scalar(&parse_expression(input)?)
+    }
+
+    fn number(n: f64) -> Result<Value, String> {
+        Ok(Value::Number(n))
+    }
+
+    fn text(s: &str) -> Result<Value, String> {
+        Ok(Value::Text(s.to_string()))
+    }
+
+    fn error(e: &str) -> Result<Value, String> {
+        Err(e.to_string())
+    }
+
+    #[test]
+    fn test_index() {
+        let values = price_list();
+        assert_eq!(eval(&values, "INDEX(A0:C3, 3, 2)"), number(4.0));
+        assert_eq!(eval(&values, "INDEX(C0:C3, 2)"), number(20.0));
+        assert_eq!(eval(&values, "INDEX(A1:C1, 3)"), number(20.0));
+        assert_eq!(eval(&values, "INDEX(A0:C3, 5, 1)"), error("REF"));
+        assert_eq!(eval(&values, "INDEX(A0:C3, 1, 4)"), error("REF"));
+        assert_eq!(eval(&values, "INDEX(A0:C3, -1, 1)"), error("VALUE"));
+        assert_eq!(eval(&values, "INDEX(5, 1)"), error("VALUE"));
+    }
+
+    #[test]
+    fn test_index_returns_range() {
+        let values = price_list();
+        let evaluator = Evaluator::new(&values);
+        let whole_row = evaluator.eval(&parse_expression("INDEX(A0:C3, 2, 0)").unwrap());
+        assert!(matches!(whole_row, Ok(Value::Range(r)) if r.start == (1, 0) && r.end == (1, 2)));
+
+        let whole_col = evaluator.eval(&parse_expression("INDEX(A0:C3, 0, 3)").unwrap());
+        assert!(matches!(whole_col, Ok(Value::Range(r)) if r.start == (0, 2) && r.end == (3, 2)));
+
+        // A multi-cell result cannot be shown in a single cell
+        assert_eq!(eval(&values, "INDEX(A0:C3, 2, 0)"), error("VALUE"));
+        // But it can feed another lookup
+        assert_eq!(eval(&values, "INDEX(INDEX(A0:C3, 0, 2), 4)"), number(3.0));
+    }
+
+    #[test]
+    fn test_match_exact() {
+        let values = price_list();
+        assert_eq!(eval(&values, "MATCH(\"cherry\", A0:A3, 0)"), number(3.0));
+        assert_eq!(eval(&values, "MATCH(\"CHERRY\", A0:A3, 0)"), number(3.0));
+        assert_eq!(eval(&values, "MATCH(40, C0:C3, 0)"), number(4.0));
+        assert_eq!(eval(&values, "MATCH(\"fig\", A0:A3, 0)"), error("N/A"));
+        assert_eq!(eval(&values, "MATCH(35, C0:C3, 0)"), error("N/A"));
+    }
+
+    #[test]
+    fn test_match_approximate() {
+        let values = price_list();
+        // Largest value <= 35 in ascending C0:C3 is 30
+        assert_eq!(eval(&values, "MATCH(35, C0:C3)"), number(3.0));
+        assert_eq!(eval(&values, "MATCH(35, C0:C3, 1)"), number(3.0));
+        assert_eq!(eval(&values, "MATCH(100, C0:C3, 1)"), number(4.0));
+        assert_eq!(eval(&values, "MATCH(5, C0:C3, 1)"), error("N/A"));
+        // Smallest value >= 35 is 40
+        assert_eq!(eval(&values, "MATCH(35, C0:C3, -1)"), number(4.0));
+        assert_eq!(eval(&values, "MATCH(50, C0:C3, -1)"), error("N/A"));
+    }
+
+    #[test]
+    fn test_match_horizontal_and_errors() {
+        let values = price_list();
+        assert_eq!(eval(&values, "MATCH(0.5, A1:C1, 0)"), number(2.0));
+        // Two-dimensional ranges are not searchable
+        assert_eq!(eval(&values, "MATCH(1, A0:C3, 0)"), error("N/A"));
+        assert_eq!(eval(&values, "MATCH(1)"), error("ERR"));
+    }
+
+    #[test]
+    fn test_match_skips_empty_and_error_cells() {
+        let mut values = HashMap::new();
+        values.insert((0, 0), CellValue::Error("DIV0".to_string()));
+        values.insert((2, 0), CellValue::Number(0.0));
+        // A1 is empty and must not be mistaken for zero
+        assert_eq!(eval(&values, "MATCH(0, A0:A2, 0)"), number(3.0));
+    }
+
+    #[test]
+    fn test_vlookup() {
+        let values = price_list();
+        assert_eq!(
+            eval(&values, "VLOOKUP(\"banana\", A0:C3, 2, FALSE)"),
+            number(0.5)
+        );
+        assert_eq!(
+            eval(&values, "VLOOKUP(\"date\", A0:C3, 3, 0)"),
+            number(40.0)
+        );
+        assert_eq!(
+            eval(&values, "VLOOKUP(\"date\", A0:C3, 1, FALSE)"),
+            text("date")
+        );
+        assert_eq!(
+            eval(&values, "VLOOKUP(\"fig\", A0:C3, 2, FALSE)"),
+            error("N/A")
+        );
+        assert_eq!(
+            eval(&values, "VLOOKUP(\"apple\", A0:C3, 4, FALSE)"),
+            error("REF")
+        );
+        assert_eq!(
+            eval(&values, "VLOOKUP(\"apple\", A0:C3, 0, FALSE)"),
+            error("VALUE")
+        );
+    }
+
+    #[test]
+    fn test_vlookup_approximate() {
+        let values = price_list();
+        // Sorted stock levels in C0:C3 as a bracket table
+        assert_eq!(eval(&values, "VLOOKUP(25, C0:C3, 1)"), number(20.0));
+        assert_eq!(eval(&values, "VLOOKUP(25, C0:C3, 1, TRUE)"), number(20.0));
+        assert_eq!(eval(&values, "VLOOKUP(5, C0:C3, 1, TRUE)"), error("N/A"));
+        // Text keys are sorted alphabetically
+        assert_eq!(eval(&values, "VLOOKUP(\"coconut\", A0:C3, 2)"), number(4.0));
+    }
+
+    #[test]
+    fn test_hlookup() {
+        let mut values = HashMap::new();
+        // Quarter headers in row 0, revenue in row 1
+        for (col, (quarter, revenue)) in [("Q1", 100.0), ("Q2", 150.0), ("Q3", 90.0)]
+            .iter()
+            .enumerate()
+        {
+            values.insert((0, col), CellValue::Text(quarter.to_string()));
+            values.insert((1, col), CellValue::Number(*revenue));
+        }
+        assert_eq!(
+            eval(&values, "HLOOKUP(\"Q2\", A0:C1, 2, FALSE)"),
+            number(150.0)
+        );
+        assert_eq!(
+            eval(&values, "HLOOKUP(\"Q4\", A0:C1, 2, FALSE)"),
+            error("N/A")
+        );
+        assert_eq!(eval(&values, "HLOOKUP(\"Q4\", A0:C1, 2)"), number(90.0));
+        assert_eq!(
+            eval(&values, "HLOOKUP(\"Q1\", A0:C1, 3, FALSE)"),
+            error("REF")
+        );
+    }
+
+    #[test]
+    fn test_xlookup() {
+        let values = price_list();
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"cherry\", A0:A3, B0:B3)"),
+            number(4.0)
+        );
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"fig\", A0:A3, B0:B3)"),
+            error("N/A")
+        );
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"fig\", A0:A3, B0:B3, \"none\")"),
+            text("none")
+        );
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"fig\", A0:A3, B0:B3, C3)"),
+            number(40.0)
+        );
+        // Mismatched lookup and return ranges
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"date\", A0:A3, B0:B2)"),
+            error("VALUE")
+        );
+        // Wildcard mode is not supported
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"d*\", A0:A3, B0:B3, 0, 2)"),
+            error("VALUE")
+        );
+    }
+
+    #[test]
+    fn test_xlookup_match_and_search_modes() {
+        let mut values = price_list();
+        values.insert((4, 0), CellValue::Text("apple".to_string()));
+        values.insert((4, 1), CellValue::Number(2.0));
+
+        assert_eq!(
+            eval(&values, "XLOOKUP(25, C0:C3, A0:A3, 0, -1)"),
+            text("banana")
+        );
+        assert_eq!(
+            eval(&values, "XLOOKUP(25, C0:C3, A0:A3, 0, 1)"),
+            text("cherry")
+        );
+        // First and last occurrence of a duplicated key
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"apple\", A0:A4, B0:B4)"),
+            number(1.5)
+        );
+        assert_eq!(
+            eval(&values, "XLOOKUP(\"apple\", A0:A4, B0:B4, 0, 0, -1)"),
+            number(2.0)
+        );
+    }
+
+    #[test]
+    fn test_xlookup_returns_row() {
+        let values = price_list();
+        let result = Evaluator::new(&values)
+            .eval(&parse_expression("XLOOKUP(\"date\", A0:A3, B0:C3)").unwrap());
+        assert!(matches!(result, Ok(Value::Range(r)) if r.start == (3, 1) && r.end == (3, 2)));
+        assert_eq!(
+            eval(&values, "INDEX(XLOOKUP(\"date\", A0:A3, B0:C3), 2)"),
+            number(40.0)
+        );
+    }
+
+    #[test]
+    fn test_xlookup_horizontal() {
+        let mut values = HashMap::new();
+        for col in 0..3 {
+            values.insert((0, col), CellValue::Number(col as f64 * 10.0));
+            values.insert((1, col), CellValue::Number(col as f64 + 100.0));
+        }
+        assert_eq!(eval(&values, "XLOOKUP(20, A0:C0, A1:C1)"), number(102.0));
+    }
+}
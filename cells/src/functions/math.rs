@@ -0,0 +1,321 @@
+use super::check_arity;
+use crate::decimal::Number;
+use crate::eval::{Evaluator, Value};
+use crate::formula::Expr;
+
+// Wraps a computed number, turning NaN and infinities into #NUM
+fn finite(n: f64) -> Result<Value, String> {
+    if n.is_finite() {
+        Ok(Value::Number(n))
+    } else {
+        Err("NUM".to_string())
+    }
+}
+
+// Like `finite`, keeping decimals exact
+fn exact(n: Number) -> Result<Value, String> {
+    match n {
+        Number::Float(n) => finite(n),
+        exact => Ok(exact.into()),
+    }
+}
+
+// Applies a one-argument function such as ABS or SIN
+pub fn unary(
+    eval: &Evaluator,
+    args: &[Expr],
+    f: impl Fn(f64) -> Result<f64, String>,
+) -> Result<Value, String> {
+    check_arity(args, 1, 1)?;
+    finite(f(eval.number(&args[0])?)?)
+}
+
+// Optional numeric argument with a default
+fn optional(eval: &Evaluator, args: &[Expr], i: usize, default: f64) -> Result<f64, String> {
+    match args.get(i) {
+        Some(expr) => eval.number(expr),
+        None => Ok(default),
+    }
+}
+
+// Like `optional`, keeping decimals exact
+fn optional_exact(
+    eval: &Evaluator,
+    args: &[Expr],
+    i: usize,
+    default: f64,
+) -> Result<Number, String> {
+    match args.get(i) {
+        Some(expr) => eval.exact(expr),
+        None => Ok(Number::Float(default)),
+    }
+}
+
+// ROUND(x, [digits]) rounds half away from zero; negative digits round to
+// the left of the decimal point
+pub fn round(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 2)?;
+    let x = eval.exact(&args[0])?;
+    // Places beyond the range of f64 change nothing further
+    let digits = optional(eval, args, 1, 0.0)?.trunc().clamp(-308.0, 308.0) as i32;
+    exact(eval.arithmetic().round(&x, digits))
+}
+
+// FLOOR(x, [significance]) rounds down to a multiple of the significance
+pub fn floor(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 2)?;
+    let x = eval.exact(&args[0])?;
+    let significance = optional_exact(eval, args, 1, 1.0)?;
+    if significance.to_f64() == 0.0 {
+        return Err("DIV0".to_string());
+    }
+    if x.to_f64() > 0.0 && significance.to_f64() < 0.0 {
+        return Err("NUM".to_string());
+    }
+    let arithmetic = eval.arithmetic();
+    exact(arithmetic.mul(&arithmetic.div_floor(&x, &significance), &significance))
+}
+
+// CEILING(x, [significance]) rounds up to a multiple of the significance
+pub fn ceiling(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 2)?;
+    let x = eval.exact(&args[0])?;
+    let significance = optional_exact(eval, args, 1, 1.0)?;
+    if significance.to_f64() == 0.0 {
+        return Ok(Value::Number(0.0));
+    }
+    if x.to_f64() > 0.0 && significance.to_f64() < 0.0 {
+        return Err("NUM".to_string());
+    }
+    let arithmetic = eval.arithmetic();
+    let multiple = arithmetic.div_floor(&x.negated(), &significance).negated();
+    exact(arithmetic.mul(&multiple, &significance))
+}
+
+// POWER(base, exponent)
+pub fn power(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 2)?;
+    let base = eval.number(&args[0])?;
+    let exponent = eval.number(&args[1])?;
+    if base == 0.0 && exponent < 0.0 {
+        return Err("DIV0".to_string());
+    }
+    finite(base.powf(exponent))
+}
+
+// MOD(n, divisor) takes the sign of the divisor
+pub fn modulo(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 2)?;
+    let n = eval.exact(&args[0])?;
+    let divisor = eval.exact(&args[1])?;
+    if divisor.to_f64() == 0.0 {
+        return Err("DIV0".to_string());
+    }
+    let arithmetic = eval.arithmetic();
+    let multiple = arithmetic.mul(&divisor, &arithmetic.div_floor(&n, &divisor));
+    exact(arithmetic.sub(&n, &multiple))
+}
+
+// LOG(x, [base]) defaults to base 10
+pub fn log(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, 2)?;
+    let x = eval.number(&args[0])?;
+    let base = optional(eval, args, 1, 10.0)?;
+    if x <= 0.0 || base <= 0.0 {
+        return Err("NUM".to_string());
+    }
+    if base == 1.0 {
+        return Err("DIV0".to_string());
+    }
+    finite(x.log(base))
+}
+
+// ATAN2(x, y) is the angle of the point (x, y), in spreadsheet argument order
+pub fn atan2(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 2)?;
+    let x = eval.number(&args[0])?;
+    let y = eval.number(&args[1])?;
+    if x == 0.0 && y == 0.0 {
+        return Err("DIV0".to_string());
+    }
+    finite(y.atan2(x))
+}
+
+pub fn pi(args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 0, 0)?;
+    Ok(Value::Number(std::f64::consts::PI))
+}
+
+// RAND() is a uniformly distributed number in [0, 1)
+pub fn rand(args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 0, 0)?;
+    Ok(Value::Number(rand::random::<f64>()))
+}
+
+// RANDBETWEEN(low, high) is a random integer in the inclusive range
+pub fn randbetween(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 2)?;
+    let low = eval.number(&args[0])?.ceil();
+    let high = eval.number(&args[1])?.floor();
+    // NaN and infinite bounds would give an empty range once converted
+    if !low.is_finite() || !high.is_finite() {
+        return Err("NUM".to_string());
+    }
+    let (low, high) = (low as i64, high as i64);
+    if low > high {
+        return Err("NUM".to_string());
+    }
+    Ok(Value::Number(rand::random_range(low..=high) as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CellValue;
+    use crate::eval::{Evaluator, Value};
+    use crate::formula::{Cell, parse_expression};
+    use std::collections::HashMap;
+
+    fn eval(input: &str) -> Result<Value, String> {
+        let values: HashMap<Cell, CellValue> = HashMap::new();
+        Evaluator::new(&values).scalar(&parse_expression(input)?)
+    }
+
+    fn assert_close(input: &str, expected: f64) {
+        match eval(input) {
+            Ok(Value::Number(n)) => assert!(
+                (n - expected).abs() < 1e-9,
+                "{} = {}, expected {}",
+                input,
+                n,
+                expected
+            ),
+            other => panic!("{} = {:?}, expected {}", input, other, expected),
+        }
+    }
+
+    fn assert_error(input: &str, expected: &str) {
+        assert_eq!(eval(input), Err(expected.to_string()), "{}", input);
+    }
+
+    #[test]
+    fn test_round() {
+        assert_close("ROUND(2.5)", 3.0);
+        assert_close("ROUND(-2.5)", -3.0);
+        assert_close("ROUND(2.71828, 2)", 2.72);
+        assert_close("ROUND(1234.5, -2)", 1200.0);
+        assert_close("ROUND(1.005, 1)", 1.0);
+        assert_close("ROUND(1.5, -100000000)", 0.0);
+        assert_close("ROUND(1.5, 100000000)", 1.5);
+        assert_error("ROUND(\"a\")", "TEXT");
+        assert_error("ROUND()", "ERR");
+    }
+
+    #[test]
+    fn test_floor_and_ceiling() {
+        assert_close("FLOOR(2.7)", 2.0);
+        assert_close("FLOOR(7, 3)", 6.0);
+        assert_close("FLOOR(-2.5, 2)", -4.0);
+        assert_close("FLOOR(-2.5, -2)", -2.0);
+        assert_close("FLOOR(0.234, 0.01)", 0.23);
+        assert_error("FLOOR(2.5, -2)", "NUM");
+        assert_error("FLOOR(2.5, 0)", "DIV0");
+
+        assert_close("CEILING(2.1)", 3.0);
+        assert_close("CEILING(7, 3)", 9.0);
+        assert_close("CEILING(-2.5, 2)", -2.0);
+        assert_close("CEILING(2.5, 0)", 0.0);
+        assert_error("CEILING(2.5, -2)", "NUM");
+    }
+
+    #[test]
+    fn test_abs_sqrt_power() {
+        assert_close("ABS(-4.5)", 4.5);
+        assert_close("SQRT(16)", 4.0);
+        assert_close("SQRT(2)", std::f64::consts::SQRT_2);
+        assert_error("SQRT(-1)", "NUM");
+        assert_close("POWER(2, 10)", 1024.0);
+        assert_close("POWER(27, 1/3)", 3.0);
+        assert_close("POWER(4, -0.5)", 0.5);
+        assert_error("POWER(0, -1)", "DIV0");
+        assert_error("POWER(-8, 0.5)", "NUM");
+        assert_error("POWER(2)", "ERR");
+    }
+
+    #[test]
+    fn test_mod() {
+        assert_close("MOD(10, 3)", 1.0);
+        assert_close("MOD(-10, 3)", 2.0);
+        assert_close("MOD(10, -3)", -2.0);
+        assert_close("MOD(5.5, 2)", 1.5);
+        assert_error("MOD(1, 0)", "DIV0");
+    }
+
+    #[test]
+    fn test_logarithms() {
+        assert_close("LN(EXP(1))", 1.0);
+        assert_close("LN(10)", std::f64::consts::LN_10);
+        assert_close("EXP(2)", 7.38905609893065);
+        assert_close("LOG(1000)", 3.0);
+        assert_close("LOG(8, 2)", 3.0);
+        assert_close("LOG10(0.01)", -2.0);
+        assert_error("LN(0)", "NUM");
+        assert_error("LOG(-1)", "NUM");
+        assert_error("LOG(5, 1)", "DIV0");
+        assert_error("EXP(1000)", "NUM");
+    }
+
+    #[test]
+    fn test_trigonometry() {
+        assert_close("PI()", std::f64::consts::PI);
+        assert_close("SIN(PI()/2)", 1.0);
+        assert_close("COS(PI())", -1.0);
+        assert_close("TAN(PI()/4)", 1.0);
+        assert_close("ASIN(1)", std::f64::consts::FRAC_PI_2);
+        assert_close("ACOS(0.5)", std::f64::consts::FRAC_PI_3);
+        assert_close("ATAN(1)", std::f64::consts::FRAC_PI_4);
+        assert_close("ATAN2(1, 1)", std::f64::consts::FRAC_PI_4);
+        assert_close("ATAN2(-1, 0)", 0.0_f64.atan2(-1.0));
+        assert_close("DEGREES(PI())", 180.0);
+        assert_close("RADIANS(90)", std::f64::consts::FRAC_PI_2);
+        assert_error("ASIN(2)", "NUM");
+        assert_error("ACOS(-1.5)", "NUM");
+        assert_error("ATAN2(0, 0)", "DIV0");
+        assert_error("PI(1)", "ERR");
+    }
+
+    #[test]
+    fn test_rand() {
+        for _ in 0..100 {
+            match eval("RAND()") {
+                Ok(Value::Number(n)) => assert!((0.0..1.0).contains(&n)),
+                other => panic!("RAND() = {:?}", other),
+            }
+            match eval("RANDBETWEEN(1.5, 4)") {
+                Ok(Value::Number(n)) => {
+                    assert_eq!(n.fract(), 0.0);
+                    assert!((2.0..=4.0).contains(&n));
+                }
+                other => panic!("RANDBETWEEN = {:?}", other),
+            }
+        }
+        assert_close("RANDBETWEEN(7, 7)", 7.0);
+        assert_error("RANDBETWEEN(5, 1)", "NUM");
+        assert_error("RAND(1)", "ERR");
+
+        let values = HashMap::from([
+            ((0, 0), CellValue::Number(f64::NAN)),
+            ((0, 1), CellValue::Number(f64::INFINITY)),
+        ]);
+        for input in [
+            "RANDBETWEEN(1, A0)",
+            "RANDBETWEEN(A0, 1)",
+            "RANDBETWEEN(1, B0)",
+        ] {
+            assert_eq!(
+                Evaluator::new(&values).scalar(&parse_expression(input).unwrap()),
+                Err("NUM".to_string()),
+                "{input}"
+            );
+        }
+    }
+}
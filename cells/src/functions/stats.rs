@@ -0,0 +1,243 @@
+use super::check_arity;
+use crate::decimal::{Arithmetic, Number};
+use crate::eval::{Evaluator, Value};
+use crate::formula::Expr;
+use std::cmp::Ordering;
+
+pub fn sum(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    Ok(eval.arithmetic().sum(&eval.exact_numbers(args)?).into())
+}
+
+pub fn average(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    let numbers = eval.exact_numbers(args)?;
+    mean(eval.arithmetic(), &numbers).map(Value::from)
+}
+
+pub fn min(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    let numbers = sorted(eval.exact_numbers(args)?);
+    Ok(numbers
+        .into_iter()
+        .next()
+        .map_or(Value::Number(0.0), Value::from))
+}
+
+pub fn max(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    let numbers = sorted(eval.exact_numbers(args)?);
+    Ok(numbers
+        .into_iter()
+        .next_back()
+        .map_or(Value::Number(0.0), Value::from))
+}
+
+// COUNT(values...) counts numbers and never fails on text or errors
+pub fn count(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    let mut count = 0;
+    for arg in args {
+        match eval.eval(arg) {
+            Ok(Value::Number(_) | Value::Decimal(_)) => count += 1,
+            Ok(Value::Range(range)) => {
+                count += range
+                    .cells()
+                    .filter_map(|cell| eval.cell_value(cell))
+                    .filter(|value| value.as_number().is_some())
+                    .count();
+            }
+            Ok(Value::Array(array)) => {
+                count += array
+                    .iter()
+                    .flatten()
+                    .filter(|value| value.as_number().is_some())
+                    .count();
+            }
+            Ok(Value::Text(_)) | Err(_) => {}
+        }
+    }
+    Ok(Value::Number(count as f64))
+}
+
+fn mean(arithmetic: Arithmetic, numbers: &[Number]) -> Result<Number, String> {
+    if numbers.is_empty() {
+        return Err("DIV0".to_string());
+    }
+    let count = Number::Float(numbers.len() as f64);
+    Ok(arithmetic.div(&arithmetic.sum(numbers), &count))
+}
+
+// Variance of a sample (n - 1 denominator) or of a whole population
+fn variance(arithmetic: Arithmetic, numbers: &[Number], sample: bool) -> Result<Number, String> {
+    let n = numbers.len();
+    if n == 0 || (sample && n < 2) {
+        return Err("DIV0".to_string());
+    }
+    let mean = mean(arithmetic, numbers)?;
+    let squares: Vec<Number> = numbers
+        .iter()
+        .map(|x| {
+            let deviation = arithmetic.sub(x, &mean);
+            arithmetic.mul(&deviation, &deviation)
+        })
+        .collect();
+    let denominator = Number::Float(if sample { n - 1 } else { n } as f64);
+    Ok(arithmetic.div(&arithmetic.sum(&squares), &denominator))
+}
+
+// VAR and VAR.S estimate from a sample, VAR.P uses the whole population
+pub fn var(eval: &Evaluator, args: &[Expr], sample: bool) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    variance(eval.arithmetic(), &eval.exact_numbers(args)?, sample).map(Value::from)
+}
+
+// STDEV and STDEV.S estimate from a sample, STDEV.P uses the whole population
+pub fn stdev(eval: &Evaluator, args: &[Expr], sample: bool) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    variance(eval.arithmetic(), &eval.exact_numbers(args)?, sample)
+        .map(|v| Value::Number(v.to_f64().sqrt()))
+}
+
+// Sorted copy of the numbers, for order statistics
+fn sorted(mut numbers: Vec<Number>) -> Vec<Number> {
+    numbers.sort_by(|a, b| a.compare(b).unwrap_or(Ordering::Equal));
+    numbers
+}
+
+// Linear interpolation between closest ranks, k in [0, 1]
+fn percentile_of(arithmetic: Arithmetic, sorted: &[Number], k: &Number) -> Result<Number, String> {
+    if sorted.is_empty() || !(0.0..=1.0).contains(&k.to_f64()) {
+        return Err("NUM".to_string());
+    }
+    let rank = arithmetic.mul(k, &Number::Float((sorted.len() - 1) as f64));
+    let lower = rank.to_f64().floor() as usize;
+    let upper = rank.to_f64().ceil() as usize;
+    let fraction = arithmetic.sub(&rank, &Number::Float(lower as f64));
+    let gap = arithmetic.sub(&sorted[upper], &sorted[lower]);
+    Ok(arithmetic.add(&sorted[lower], &arithmetic.mul(&fraction, &gap)))
+}
+
+pub fn median(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 1, usize::MAX)?;
+    let numbers = sorted(eval.exact_numbers(args)?);
+    percentile_of(eval.arithmetic(), &numbers, &Number::Float(0.5)).map(Value::from)
+}
+
+// PERCENTILE(values, k) is inclusive: k = 0 is the minimum, k = 1 the maximum
+pub fn percentile(eval: &Evaluator, args: &[Expr]) -> Result<Value, String> {
+    check_arity(args, 2, 2)?;
+    let numbers = sorted(eval.exact_numbers(&args[..1])?);
+    let k = eval.exact(&args[1])?;
+    percentile_of(eval.arithmetic(), &numbers, &k).map(Value::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CellValue;
+    use crate::eval::{Evaluator, Value};
+    use crate::formula::{Cell, parse_expression};
+    use std::collections::HashMap;
+
+    // A0:A7 holds 2, 4, 4, 4, 5, 5, 7, 9 (mean 5, population stdev 2)
+    // and B0:B2 holds 1, "n/a", 3 with B3 left empty
+    fn sample() -> HashMap<Cell, CellValue> {
+        let mut values = HashMap::new();
+        for (row, n) in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].iter().enumerate() {
+            values.insert((row, 0), CellValue::Number(*n));
+        }
+        values.insert((0, 1), CellValue::Number(1.0));
+        values.insert((1, 1), CellValue::Text("n/a".to_string()));
+        values.insert((2, 1), CellValue::Number(3.0));
+        values
+    }
+
+    fn eval(values: &HashMap<Cell, CellValue>, input: &str) -> Result<Value, String> {
+        Evaluator::new(values).scalar(&parse_expression(input)?)
+    }
+
+    fn assert_close(input: &str, expected: f64) {
+        match eval(&sample(), input) {
+            Ok(Value::Number(n)) => assert!(
+                (n - expected).abs() < 1e-9,
+                "{} = {}, expected {}",
+                input,
+                n,
+                expected
+            ),
+            other => panic!("{} = {:?}, expected {}", input, other, expected),
+        }
+    }
+
+    fn assert_error(input: &str, expected: &str) {
+        assert_eq!(
+            eval(&sample(), input),
+            Err(expected.to_string()),
+            "{}",
+            input
+        );
+    }
+
+    #[test]
+    fn test_sum_average_min_max() {
+        assert_close("SUM(A0:A7)", 40.0);
+        assert_close("SUM(A0:A1, 10, B0:B3)", 20.0);
+        assert_close("AVERAGE(A0:A7)", 5.0);
+        assert_close("AVERAGE(B0:B3)", 2.0);
+        assert_close("MIN(A0:A7, 3)", 2.0);
+        assert_close("MAX(A0:A7, B0:B3)", 9.0);
+        assert_close("MAX(C0:C5)", 0.0);
+        assert_error("AVERAGE(C0:C5)", "DIV0");
+        assert_error("SUM(1, \"x\")", "TEXT");
+        assert_error("SUM()", "ERR");
+    }
+
+    #[test]
+    fn test_count() {
+        assert_close("COUNT(A0:B7)", 10.0);
+        assert_close("COUNT(B0:B3, 5, \"x\")", 3.0);
+    }
+
+    #[test]
+    fn test_variance_and_stdev() {
+        assert_close("VAR.P(A0:A7)", 4.0);
+        assert_close("STDEV.P(A0:A7)", 2.0);
+        assert_close("VAR(A0:A7)", 32.0 / 7.0);
+        assert_close("VAR.S(A0:A7)", 32.0 / 7.0);
+        assert_close("STDEV(A0:A7)", (32.0f64 / 7.0).sqrt());
+        assert_close("STDEV.S(1, 2, 3, 4)", 1.2909944487358056);
+        assert_close("VAR.P(7)", 0.0);
+        assert_error("VAR(7)", "DIV0");
+        assert_error("STDEV.P(C0:C1)", "DIV0");
+    }
+
+    #[test]
+    fn test_median() {
+        assert_close("MEDIAN(A0:A7)", 4.5);
+        assert_close("MEDIAN(3, 1, 2)", 2.0);
+        assert_close("MEDIAN(B0:B3)", 2.0);
+        assert_error("MEDIAN(C0:C3)", "NUM");
+    }
+
+    #[test]
+    fn test_percentile() {
+        assert_close("PERCENTILE(A0:A7, 0)", 2.0);
+        assert_close("PERCENTILE(A0:A7, 1)", 9.0);
+        assert_close("PERCENTILE(A0:A7, 0.5)", 4.5);
+        // Rank 0.25 * 7 = 1.75 lies between 4 and 4
+        assert_close("PERCENTILE(A0:A7, 0.25)", 4.0);
+        // Rank 0.9 * 7 = 6.3 lies between 7 and 9
+        assert_close("PERCENTILE(A0:A7, 0.9)", 7.6);
+        assert_error("PERCENTILE(A0:A7, 1.5)", "NUM");
+        assert_error("PERCENTILE(A0:A7, \"x\")", "TEXT");
+        assert_error("PERCENTILE(A0:A7)", "ERR");
+    }
+
+    #[test]
+    fn test_errors_in_range_propagate() {
+        let mut values = sample();
+        values.insert((3, 1), CellValue::Error("N/A".to_string()));
+        assert_eq!(eval(&values, "SUM(B0:B3)"), Err("N/A".to_string()));
+        assert_eq!(eval(&values, "COUNT(B0:B3)"), Ok(Value::Number(2.0)));
+    }
+}
@@ -0,0 +1,29 @@
+pub mod chart;
+pub mod cli;
+pub mod collab;
+pub mod comment;
+pub mod decimal;
+pub mod eval;
+pub mod file;
+pub mod format;
+pub mod formula;
+pub mod functions;
+pub mod number_format;
+pub mod ods;
+mod pdf;
+pub mod print;
+#[cfg(test)]
+mod properties;
+pub mod recovery;
+pub mod script;
+pub mod sheet;
+pub mod syntax;
+pub mod validation;
+pub mod whatif;
+pub mod workbook;
+pub mod xlsx;
+mod xml;
+mod zip;
+
+pub use decimal::Arithmetic;
+pub use sheet::{CellValue, Iteration, RecalcMode, Sheet};
@@ -1,141 +1,2579 @@
-use iced::widget::{Column, Id, Row, button, container, scrollable, text, text_input};
-use iced::{Element, Length, Task};
-use std::collections::{HashMap, HashSet};
+use cells::chart::{Chart, ChartData, ChartKind};
+use cells::collab::{self, Edit, Peer, Replica, Update};
+use cells::comment::{self, Comment};
+use cells::file;
+use cells::format::{self, Appearance, Comparison, Condition, Format, Rgb, Rule};
+use cells::formula::{Cell, Range, cell_name, col_to_letter, parse_cell_reference, parse_range};
+use cells::functions;
+use cells::number_format;
+use cells::print;
+use cells::recovery::{self, Recovered, Recovery};
+use cells::sheet::Results;
+use cells::syntax::{self, TokenKind};
+use cells::validation::{self, Criterion, Severity, Validation};
+use cells::whatif;
+use cells::{Arithmetic, CellValue, Iteration, RecalcMode, Sheet};
+use iced::advanced::text::highlighter;
+use iced::futures::channel::{mpsc as futures_mpsc, oneshot};
+use iced::futures::{Stream, StreamExt, stream};
+use iced::widget::{
+    Column, Id, Row, Stack, button, canvas, checkbox, column, container, mouse_area, operation,
+    pick_list, pin, row, scrollable, space, stack, text, text_editor, text_input, tooltip,
+};
+use iced::{
+    Alignment, Color, Element, Event, Length, Point, Radians, Rectangle, Size, Subscription, Task,
+    Theme, keyboard, mouse,
+};
+use notify::Watcher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
 fn main() -> iced::Result {
+    // `cells eval ...` evaluates a sheet file, `cells convert ...` converts
+    // between file formats, `cells export ...` prints a sheet to HTML or PDF
+    // and `cells serve ...` serves collaborative editing, without opening a
+    // window
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("eval") => {
+            let code = cells::cli::eval(&args[1..], &mut std::io::stdout(), &mut std::io::stderr());
+            std::process::exit(code);
+        }
+        Some("convert") => {
+            std::process::exit(cells::cli::convert(&args[1..], &mut std::io::stderr()))
+        }
+        Some("export") => {
+            let code =
+                cells::cli::export(&args[1..], &mut std::io::stdout(), &mut std::io::stderr());
+            std::process::exit(code);
+        }
+        Some("serve") => {
+            let code =
+                cells::cli::serve(&args[1..], &mut std::io::stdout(), &mut std::io::stderr());
+            std::process::exit(code);
+        }
+        _ => {}
+    }
+
     iced::application(App::new, App::update, App::view)
+        .subscription(App::subscription)
         .window_size((800.0, 600.0))
         .run()
 }
 
-const ROWS: usize = 100;
-const COLS: usize = 26;
+const ROWS: usize = 100;
+const COLS: usize = 26;
+
+// Size of grid cells, and width of the row headers. Rows with wrapped text
+// grow taller, by a line height per line of text.
+const CELL_WIDTH: f32 = 80.0;
+const ROW_HEIGHT: f32 = 30.0;
+const HEADER_WIDTH: f32 = 60.0;
+const LINE_HEIGHT: f32 = 18.0;
+// Average width of a character at the grid's text size
+const CHAR_WIDTH: f32 = 7.5;
+// Size of the mark on commented cells
+const COMMENT_MARK: f32 = 6.0;
+
+// Edits that make at least this many cells dirty are recalculated on a
+// worker thread so the window stays responsive
+const BACKGROUND_THRESHOLD: usize = 256;
+
+#[derive(Debug, Clone)]
+enum Message {
+    CellClicked(usize, usize),
+    FormulaChanged(String),
+    // Edit in the formula bar
+    FormulaEdited(text_editor::Action),
+    CompletionChosen(String),
+    // Pressing a cell while an operand is expected inserts its reference,
+    // and dragging to another cell turns it into a range
+    ReferencePressed(Cell),
+    ReferenceEntered(Cell),
+    MouseReleased,
+    FinishEditing,
+    // Value picked from the dropdown of a list-validated cell
+    ChoiceSelected(String),
+    // Answers to the prompt about input breaking a validation rule: keep it
+    // (warnings only), edit it again or throw it away
+    KeepInvalid,
+    RetryInput,
+    CancelInput,
+    // Moves the cell being edited by rows and columns, e.g. (1, 0) for the
+    // cell below, with merges counting as one cell
+    Navigate(isize, isize),
+    MergeRangeChanged(String),
+    MergeCells,
+    // Splits the merge covering the cell being edited
+    Unmerge,
+    WrapToggled(bool),
+    ParallelToggled(bool),
+    DecimalToggled(bool),
+    IterationToggled(bool),
+    MaxIterationsChanged(String),
+    MaxChangeChanged(String),
+    TraceToggled(bool),
+    NameChanged(String),
+    NameRangeChanged(String),
+    DefineName,
+    FormatsToggled(bool),
+    RuleRangeChanged(String),
+    RuleKindSelected(RuleKind),
+    RuleValueChanged(String),
+    RuleColorSelected(NamedColor),
+    AddRule,
+    RemoveRule(usize),
+    ValidationsToggled(bool),
+    ValidationRangeChanged(String),
+    ValidationKindSelected(ValidationKind),
+    ValidationFirstChanged(String),
+    ValidationSecondChanged(String),
+    ValidationWarnToggled(bool),
+    ValidationMessageChanged(String),
+    AddValidation,
+    RemoveValidation(usize),
+    ChartsToggled(bool),
+    ChartRangeChanged(String),
+    ChartKindSelected(ChartKind),
+    ChartTitleChanged(String),
+    AddChart,
+    RemoveChart(usize),
+    CommentsToggled(bool),
+    ScriptToggled(bool),
+    ScriptEdited(text_editor::Action),
+    // Compiles the script being edited and makes its functions callable
+    ApplyScript,
+    CommentEdited(Cell, String),
+    RemoveComment(Cell),
+    PrintToggled(bool),
+    PrintAreaChanged(String),
+    PageBreaksChanged(String),
+    // Sets the print area and page breaks as typed
+    ApplyPrintSetup,
+    LandscapeToggled(bool),
+    ExportRangeChanged(String),
+    ExportPathChanged(String),
+    Export,
+    FilePathChanged(String),
+    OpenFile,
+    SaveFile,
+    // Writes unsaved work to the recovery file
+    Autosave,
+    // Answers to the offer of restoring work from an earlier session
+    RestoreRecovered,
+    DiscardRecovered,
+    WhatIfToggled(bool),
+    SeekTargetChanged(String),
+    SeekGoalChanged(String),
+    SeekInputChanged(String),
+    GoalSeek,
+    // Outcome of the goal seek for the input cell, from its worker thread
+    GoalSought(Cell, Result<whatif::Solution, String>),
+    TableOutputsChanged(String),
+    TableDownInputChanged(String),
+    TableDownValuesChanged(String),
+    TableAcrossInputChanged(String),
+    TableAcrossValuesChanged(String),
+    ComputeTable,
+    CollabAddressChanged(String),
+    CollabUserChanged(String),
+    Connect,
+    Disconnect,
+    // Update from the collaboration server
+    Remote(Update),
+    // The connection with the given id was closed
+    Disconnected(u64),
+    // A file imported by formulas changed on disk
+    ImportedFileChanged(PathBuf),
+    // New values from the background recalculation with the given generation
+    Recalculated(u64, Results),
+}
+
+struct App {
+    // Formulas, values and dependency graph
+    sheet: Sheet,
+    // Currently editing cell
+    editing_cell: Option<(usize, usize)>,
+    // Current formula being edited
+    editing_formula: String,
+    // Formula bar mirroring `editing_formula`, with syntax highlighting
+    formula_editor: text_editor::Content,
+    // Name and range typed into the "define name" inputs
+    new_name: String,
+    new_name_range: String,
+    name_error: Option<String>,
+    // Range typed into the "merge cells" input
+    new_merge_range: String,
+    merge_error: Option<String>,
+    // Conditional formatting panel and the rule being added in it
+    show_formats: bool,
+    new_rule: NewRule,
+    rule_error: Option<String>,
+    // Look of the cells matched by conditional formats, refreshed after
+    // every recalculation
+    appearance: HashMap<Cell, Appearance>,
+    // Validation panel and the rule being added in it
+    show_validations: bool,
+    new_validation: NewValidation,
+    validation_error: Option<String>,
+    // Shown while the input being committed breaks a validation rule
+    validation_prompt: Option<ValidationPrompt>,
+    // Charts panel and the chart being added in it
+    show_charts: bool,
+    new_chart_range: String,
+    new_chart_kind: ChartKind,
+    new_chart_title: String,
+    chart_error: Option<String>,
+    // Panel listing the comments of the sheet
+    show_comments: bool,
+    // Script panel, where functions for formulas are defined
+    show_script: bool,
+    script_editor: text_editor::Content,
+    script_error: Option<String>,
+    // Print panel, with the print setup as typed and the export to make
+    show_print: bool,
+    printing: Printing,
+    // Settings of iterative calculation as typed, applied while it is on
+    max_iterations: String,
+    max_change: String,
+    iteration_error: Option<String>,
+    // What-if panel with its goal seek and data table forms
+    show_whatif: bool,
+    whatif: WhatIf,
+    // Sheet file typed, and the outcome of the last open or save
+    file_path: String,
+    file_status: Option<Result<String, String>>,
+    // File the sheet was last opened from or saved to
+    saved_to: Option<PathBuf>,
+    // The sheet in file form as last opened or saved, and as last written
+    // to the recovery file, to tell when there is unsaved work to write
+    saved_json: String,
+    autosaved_json: Option<String>,
+    // Where unsaved work is written, if anywhere
+    recovery: Option<Recovery>,
+    // Work from an earlier session, until restored or discarded
+    recovered: Option<Recovered>,
+    // Server and user name typed for collaborative editing, and the
+    // connection while collaborating
+    collab_address: String,
+    collab_user: String,
+    collab: Option<Collaboration>,
+    collab_error: Option<String>,
+    // Reference being inserted by dragging across cells
+    reference_drag: Option<ReferenceDrag>,
+    // In trace mode clicking a cell traces it instead of editing it
+    tracing: bool,
+    traced_cell: Option<Cell>,
+    // Background recalculation in flight, if any
+    recalculation: Option<Recalculation>,
+    // Incremented for every background recalculation, to recognize the
+    // results of superseded ones
+    generation: u64,
+}
+
+// How a cell relates to the traced cell, with the depth of the link
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TraceRole {
+    Traced,
+    Precedent(usize),
+    Dependent(usize),
+}
+
+struct ReferenceDrag {
+    // Cell where the drag started
+    anchor: Cell,
+    // Where the reference was inserted in the formula being edited
+    inserted: std::ops::Range<usize>,
+}
+
+// Inputs of the "add rule" form
+#[derive(Debug, Clone, Default)]
+struct NewRule {
+    range: String,
+    kind: RuleKind,
+    // Number compared with, or formula
+    value: String,
+    color: NamedColor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum RuleKind {
+    #[default]
+    GreaterThan,
+    LessThan,
+    EqualTo,
+    NotEqualTo,
+    Formula,
+    Errors,
+    ColorScale,
+    DataBar,
+}
+
+impl RuleKind {
+    const ALL: [RuleKind; 8] = [
+        RuleKind::GreaterThan,
+        RuleKind::LessThan,
+        RuleKind::EqualTo,
+        RuleKind::NotEqualTo,
+        RuleKind::Formula,
+        RuleKind::Errors,
+        RuleKind::ColorScale,
+        RuleKind::DataBar,
+    ];
+}
+
+impl std::fmt::Display for RuleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleKind::GreaterThan => write!(f, "Greater than"),
+            RuleKind::LessThan => write!(f, "Less than"),
+            RuleKind::EqualTo => write!(f, "Equal to"),
+            RuleKind::NotEqualTo => write!(f, "Not equal to"),
+            RuleKind::Formula => write!(f, "Formula"),
+            RuleKind::Errors => write!(f, "Errors"),
+            RuleKind::ColorScale => write!(f, "Color scale"),
+            RuleKind::DataBar => write!(f, "Data bar"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NewValidation {
+    range: String,
+    kind: ValidationKind,
+    // Minimum, allowed values, pattern or formula
+    first: String,
+    // Maximum
+    second: String,
+    warn: bool,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ValidationKind {
+    #[default]
+    Number,
+    WholeNumber,
+    List,
+    Pattern,
+    Formula,
+}
+
+impl ValidationKind {
+    const ALL: [ValidationKind; 5] = [
+        ValidationKind::Number,
+        ValidationKind::WholeNumber,
+        ValidationKind::List,
+        ValidationKind::Pattern,
+        ValidationKind::Formula,
+    ];
+}
+
+impl std::fmt::Display for ValidationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationKind::Number => write!(f, "Number"),
+            ValidationKind::WholeNumber => write!(f, "Whole number"),
+            ValidationKind::List => write!(f, "List"),
+            ValidationKind::Pattern => write!(f, "Pattern"),
+            ValidationKind::Formula => write!(f, "Formula"),
+        }
+    }
+}
+
+// Print setup as typed and the export of the print panel
+#[derive(Debug, Default)]
+struct Printing {
+    // Print area, blank for the used part of the sheet
+    area: String,
+    // Rows starting a page, separated by commas
+    breaks: String,
+    setup_error: Option<String>,
+    // Range exported, blank for the print area, and the file written
+    range: String,
+    path: String,
+    // Outcome of the last export
+    result: Option<Result<String, String>>,
+}
+
+// Inputs of the what-if panel and what it last computed
+#[derive(Debug, Default)]
+struct WhatIf {
+    // Goal seek: bring `target` to `goal` by changing `input`
+    target: String,
+    goal: String,
+    input: String,
+    // Outcome of the last goal seek
+    seek_result: Option<Result<String, String>>,
+    // Whether a goal seek is running
+    seeking: bool,
+    // Data table: output cells, the input varied down the side with its
+    // values and, for a two-variable table, the input varied across the top
+    outputs: String,
+    down_input: String,
+    down_values: String,
+    across_input: String,
+    across_values: String,
+    table: Option<Result<DataTable, String>>,
+}
+
+// Data table ready to show, with the input values heading its rows and the
+// outputs or second input values heading its columns
+#[derive(Debug, PartialEq)]
+struct DataTable {
+    header: Vec<String>,
+    rows: Vec<(String, Vec<String>)>,
+}
+
+struct ValidationPrompt {
+    message: String,
+    // Warnings let the input be kept
+    warn: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum NamedColor {
+    #[default]
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Blue,
+}
+
+impl NamedColor {
+    const ALL: [NamedColor; 5] = [
+        NamedColor::Red,
+        NamedColor::Orange,
+        NamedColor::Yellow,
+        NamedColor::Green,
+        NamedColor::Blue,
+    ];
+
+    fn rgb(self) -> Rgb {
+        match self {
+            NamedColor::Red => Rgb(248, 105, 107),
+            NamedColor::Orange => Rgb(255, 165, 80),
+            NamedColor::Yellow => Rgb(255, 220, 90),
+            NamedColor::Green => Rgb(99, 190, 123),
+            NamedColor::Blue => Rgb(90, 140, 230),
+        }
+    }
+}
+
+impl std::fmt::Display for NamedColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// Editing the sheet together with other users through a server
+struct Collaboration {
+    inbox: Inbox,
+    // Updates for the server; dropping it closes the connection
+    outbox: mpsc::Sender<Update>,
+    replica: Replica,
+    // Other users by client id
+    peers: BTreeMap<u64, Peer>,
+    // Selection the server was last told about
+    selection: Option<Cell>,
+}
+
+// Updates received on a connection, taken by the subscription listening to
+// it. Inboxes are told apart by the id of their connection.
+#[derive(Clone)]
+struct Inbox {
+    id: u64,
+    receiver: Arc<Mutex<Option<futures_mpsc::UnboundedReceiver<Update>>>>,
+}
+
+impl Hash for Inbox {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl Collaboration {
+    fn new(
+        outbox: mpsc::Sender<Update>,
+        receiver: futures_mpsc::UnboundedReceiver<Update>,
+    ) -> Self {
+        static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+        Self {
+            inbox: Inbox {
+                id: CONNECTIONS.fetch_add(1, Ordering::Relaxed),
+                receiver: Arc::new(Mutex::new(Some(receiver))),
+            },
+            outbox,
+            replica: Replica::default(),
+            peers: BTreeMap::new(),
+            selection: None,
+        }
+    }
+}
+
+// Messages for the updates arriving in an inbox, until its connection closes
+fn listen(inbox: &Inbox) -> impl Stream<Item = Message> + use<> {
+    let id = inbox.id;
+    let receiver = inbox.receiver.lock().unwrap().take();
+    stream::iter(receiver)
+        .flatten()
+        .map(Message::Remote)
+        .chain(stream::once(async move { Message::Disconnected(id) }))
+}
+
+// Messages for changes to the imported files. Their directories are
+// watched, since editors often replace a file instead of writing to it.
+fn watch(files: &BTreeSet<PathBuf>) -> impl Stream<Item = Message> + use<> {
+    let files: Vec<(PathBuf, PathBuf)> = files
+        .iter()
+        .filter_map(|file| Some((std::path::absolute(file).ok()?, file.clone())))
+        .collect();
+    let dirs: BTreeSet<PathBuf> = files
+        .iter()
+        .filter_map(|(absolute, _)| Some(absolute.parent()?.to_path_buf()))
+        .collect();
+    let (sender, receiver) = futures_mpsc::unbounded();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if event.kind.is_access() {
+            return;
+        }
+        for (absolute, file) in &files {
+            if event.paths.contains(absolute) {
+                let _ = sender.unbounded_send(Message::ImportedFileChanged(file.clone()));
+            }
+        }
+    });
+    let watcher = watcher.ok().map(|mut watcher| {
+        for dir in &dirs {
+            let _ = watcher.watch(dir, notify::RecursiveMode::NonRecursive);
+        }
+        watcher
+    });
+    // Dropping the watcher stops it, so it lives as long as the stream
+    receiver.map(move |message| {
+        let _ = &watcher;
+        message
+    })
+}
+
+// Color marking the selection of another user
+fn peer_color(client: u64) -> Color {
+    SERIES_COLORS[client as usize % SERIES_COLORS.len()]
+}
+
+struct Recalculation {
+    generation: u64,
+    // Roots whose downstream values are still stale in the sheet
+    roots: Vec<Cell>,
+    // Cells displayed as "calculating…" until the results arrive
+    pending: HashSet<Cell>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl App {
+    fn new() -> (Self, Task<Message>) {
+        let sheet = Sheet::new(ROWS, COLS);
+        let saved_json = file::to_json(&sheet);
+        let mut app = Self {
+            sheet,
+            editing_cell: None,
+            editing_formula: String::new(),
+            formula_editor: text_editor::Content::new(),
+            new_name: String::new(),
+            new_name_range: String::new(),
+            name_error: None,
+            new_merge_range: String::new(),
+            merge_error: None,
+            show_formats: false,
+            new_rule: NewRule::default(),
+            rule_error: None,
+            appearance: HashMap::new(),
+            show_validations: false,
+            new_validation: NewValidation::default(),
+            validation_error: None,
+            validation_prompt: None,
+            show_charts: false,
+            new_chart_range: String::new(),
+            new_chart_kind: ChartKind::Line,
+            new_chart_title: String::new(),
+            chart_error: None,
+            show_comments: false,
+            show_script: false,
+            script_editor: text_editor::Content::new(),
+            script_error: None,
+            show_print: false,
+            printing: Printing::default(),
+            max_iterations: Iteration::default().max_iterations.to_string(),
+            max_change: Iteration::default().max_change.to_string(),
+            iteration_error: None,
+            show_whatif: false,
+            whatif: WhatIf::default(),
+            file_path: String::new(),
+            file_status: None,
+            saved_to: None,
+            saved_json,
+            autosaved_json: None,
+            // Tests never touch the user's recovery file
+            recovery: if cfg!(test) {
+                None
+            } else {
+                Recovery::directory().and_then(|dir| Recovery::start(&dir).ok())
+            },
+            recovered: None,
+            collab_address: format!("ws://localhost:{}", collab::DEFAULT_PORT),
+            collab_user: comment::current_author(),
+            collab: None,
+            collab_error: None,
+            reference_drag: None,
+            tracing: false,
+            traced_cell: None,
+            recalculation: None,
+            generation: 0,
+        };
+        app.offer_recovery();
+        (app, Task::none())
+    }
+
+    // Other users see the cell selected after every message
+    fn update(&mut self, message: Message) -> Task<Message> {
+        let task = self.handle(message);
+        self.share_selection();
+        task
+    }
+
+    fn handle(&mut self, message: Message) -> Task<Message> {
+        let mut task = Task::none();
+        match message {
+            Message::CellClicked(row, col) => {
+                // Cells under a merge select the merge
+                let (row, col) = self.sheet.anchor((row, col));
+                // If clicking a different cell while editing, finish current
+                // edit, unless it breaks a validation rule
+                if self.editing_cell.is_some_and(|cell| cell != (row, col)) {
+                    match self.commit_edit() {
+                        Some(committed) => task = committed,
+                        None => return Task::none(),
+                    }
+                }
+
+                if self.tracing {
+                    self.traced_cell = Some((row, col));
+                } else if self.editing_cell != Some((row, col)) {
+                    // Single-click to edit - start editing the cell immediately
+                    let formula = self
+                        .sheet
+                        .formulas
+                        .get(&(row, col))
+                        .cloned()
+                        .unwrap_or_default();
+                    self.editing_cell = Some((row, col));
+                    self.set_editing_formula(formula);
+                }
+            }
+            Message::FormulaChanged(new_formula) => {
+                self.set_editing_formula(new_formula);
+            }
+            Message::FormulaEdited(action) => {
+                self.formula_editor.perform(action);
+                self.editing_formula = self.formula_editor.text();
+            }
+            Message::CompletionChosen(completion) => {
+                if let Some((word, _)) = syntax::word_at(&self.editing_formula, self.cursor()) {
+                    let mut formula = self.editing_formula.clone();
+                    formula.replace_range(word.clone(), &completion);
+
+                    // Continue typing right after the completion
+                    self.set_editing_formula_at(formula, word.start + completion.len());
+                    task = operation::focus(Id::new("formula-bar"));
+                }
+            }
+            Message::ReferencePressed(cell) => {
+                let cursor = self.cursor();
+                let reference = cell_name(cell);
+                let mut formula = self.editing_formula.clone();
+                formula.insert_str(cursor, &reference);
+                self.reference_drag = Some(ReferenceDrag {
+                    anchor: cell,
+                    inserted: cursor..cursor + reference.len(),
+                });
+                task = self.insert_reference(formula, cursor + reference.len());
+            }
+            Message::ReferenceEntered(cell) => {
+                if let Some(drag) = &mut self.reference_drag {
+                    let range = Range::new(drag.anchor, cell);
+                    let reference = if range.start == range.end {
+                        cell_name(range.start)
+                    } else {
+                        format!("{}:{}", cell_name(range.start), cell_name(range.end))
+                    };
+                    let mut formula = self.editing_formula.clone();
+                    formula.replace_range(drag.inserted.clone(), &reference);
+                    drag.inserted.end = drag.inserted.start + reference.len();
+                    let cursor = drag.inserted.end;
+                    task = self.insert_reference(formula, cursor);
+                }
+            }
+            Message::MouseReleased => {
+                self.reference_drag = None;
+            }
+            Message::FinishEditing => {
+                if let Some(committed) = self.commit_edit() {
+                    task = committed;
+                }
+            }
+            Message::ChoiceSelected(choice) => {
+                self.set_editing_formula(choice);
+                if let Some(committed) = self.commit_edit() {
+                    task = committed;
+                }
+            }
+            Message::KeepInvalid => {
+                if self.validation_prompt.as_ref().is_some_and(|p| p.warn) {
+                    task = self.apply_edit();
+                }
+            }
+            Message::RetryInput => {
+                self.validation_prompt = None;
+                if let Some((row, col)) = self.editing_cell {
+                    task = operation::focus(Id::from(format!("cell-input-{}-{}", row, col)));
+                }
+            }
+            Message::CancelInput => {
+                self.validation_prompt = None;
+                self.editing_cell = None;
+                self.editing_formula.clear();
+            }
+            Message::Navigate(rows, cols) => {
+                if let Some(cell) = self.editing_cell
+                    && let Some(next) = self.sheet.neighbor(cell, rows, cols)
+                {
+                    let Some(finished) = self.commit_edit() else {
+                        return Task::none();
+                    };
+                    let formula = self.sheet.formulas.get(&next).cloned().unwrap_or_default();
+                    self.editing_cell = Some(next);
+                    self.set_editing_formula(formula);
+                    let input = Id::from(format!("cell-input-{}-{}", next.0, next.1));
+                    task = Task::batch([finished, operation::focus(input)]);
+                }
+            }
+            Message::MergeRangeChanged(range) => {
+                self.new_merge_range = range;
+            }
+            Message::MergeCells => {
+                let merged = parse_range(self.new_merge_range.trim())
+                    .ok_or_else(|| format!("invalid range {:?}", self.new_merge_range.trim()))
+                    .and_then(|range| self.sheet.merge(range));
+                match merged {
+                    Ok(()) => {
+                        self.new_merge_range.clear();
+                        self.merge_error = None;
+                        // A cell hidden by the merge cannot stay in edit
+                        if let Some(cell) = self.editing_cell
+                            && self.sheet.anchor(cell) != cell
+                        {
+                            task = self.handle(Message::FinishEditing);
+                        }
+                    }
+                    Err(err) => self.merge_error = Some(err),
+                }
+            }
+            Message::Unmerge => {
+                if let Some(cell) = self.editing_cell {
+                    self.sheet.unmerge(cell);
+                }
+            }
+            Message::WrapToggled(wrap) => {
+                if let Some(cell) = self.editing_cell {
+                    if wrap {
+                        self.sheet.wrapped.insert(cell);
+                    } else {
+                        self.sheet.wrapped.remove(&cell);
+                    }
+                }
+            }
+            Message::ParallelToggled(parallel) => {
+                self.sheet.set_recalc_mode(if parallel {
+                    RecalcMode::Parallel
+                } else {
+                    RecalcMode::Serial
+                });
+            }
+            Message::DecimalToggled(decimal) => {
+                self.cancel_recalculation();
+                self.sheet.set_arithmetic(if decimal {
+                    Arithmetic::Decimal
+                } else {
+                    Arithmetic::Float
+                });
+                self.refresh_formats();
+            }
+            Message::IterationToggled(iterate) => {
+                self.apply_iteration(iterate);
+            }
+            Message::MaxIterationsChanged(max_iterations) => {
+                self.max_iterations = max_iterations;
+                self.apply_iteration(self.sheet.iteration().is_some());
+            }
+            Message::MaxChangeChanged(max_change) => {
+                self.max_change = max_change;
+                self.apply_iteration(self.sheet.iteration().is_some());
+            }
+            Message::NameChanged(name) => {
+                self.new_name = name;
+            }
+            Message::NameRangeChanged(range) => {
+                self.new_name_range = range;
+            }
+            Message::DefineName => {
+                self.cancel_recalculation();
+                match self.sheet.define_name(&self.new_name, &self.new_name_range) {
+                    Ok(()) => {
+                        self.new_name.clear();
+                        self.new_name_range.clear();
+                        self.name_error = None;
+                    }
+                    Err(err) => self.name_error = Some(err),
+                }
+                self.refresh_formats();
+            }
+            Message::FormatsToggled(show) => {
+                self.show_formats = show;
+            }
+            Message::RuleRangeChanged(range) => {
+                self.new_rule.range = range;
+            }
+            Message::RuleKindSelected(kind) => {
+                self.new_rule.kind = kind;
+            }
+            Message::RuleValueChanged(value) => {
+                self.new_rule.value = value;
+            }
+            Message::RuleColorSelected(color) => {
+                self.new_rule.color = color;
+            }
+            Message::AddRule => match self.build_rule() {
+                Ok(rule) => {
+                    self.sheet.formats.push(rule);
+                    self.new_rule = NewRule::default();
+                    self.rule_error = None;
+                    self.refresh_formats();
+                }
+                Err(err) => self.rule_error = Some(err),
+            },
+            Message::RemoveRule(index) => {
+                if index < self.sheet.formats.len() {
+                    self.sheet.formats.remove(index);
+                    self.refresh_formats();
+                }
+            }
+            Message::ValidationsToggled(show) => {
+                self.show_validations = show;
+            }
+            Message::ValidationRangeChanged(range) => {
+                self.new_validation.range = range;
+            }
+            Message::ValidationKindSelected(kind) => {
+                self.new_validation.kind = kind;
+            }
+            Message::ValidationFirstChanged(value) => {
+                self.new_validation.first = value;
+            }
+            Message::ValidationSecondChanged(value) => {
+                self.new_validation.second = value;
+            }
+            Message::ValidationWarnToggled(warn) => {
+                self.new_validation.warn = warn;
+            }
+            Message::ValidationMessageChanged(message) => {
+                self.new_validation.message = message;
+            }
+            Message::AddValidation => match self.build_validation() {
+                Ok(validation) => {
+                    self.sheet.validations.push(validation);
+                    self.new_validation = NewValidation::default();
+                    self.validation_error = None;
+                }
+                Err(err) => self.validation_error = Some(err),
+            },
+            Message::RemoveValidation(index) => {
+                if index < self.sheet.validations.len() {
+                    self.sheet.validations.remove(index);
+                }
+            }
+            Message::ChartsToggled(show) => {
+                self.show_charts = show;
+            }
+            Message::ChartRangeChanged(range) => {
+                self.new_chart_range = range;
+            }
+            Message::ChartKindSelected(kind) => {
+                self.new_chart_kind = kind;
+            }
+            Message::ChartTitleChanged(title) => {
+                self.new_chart_title = title;
+            }
+            Message::AddChart => match parse_range(self.new_chart_range.trim()) {
+                Some(range) => {
+                    self.sheet.charts.push(Chart {
+                        kind: self.new_chart_kind,
+                        range,
+                        title: self.new_chart_title.trim().to_string(),
+                    });
+                    self.new_chart_range.clear();
+                    self.new_chart_title.clear();
+                    self.chart_error = None;
+                }
+                None => {
+                    self.chart_error = Some(format!("invalid range {:?}", self.new_chart_range));
+                }
+            },
+            Message::RemoveChart(index) => {
+                if index < self.sheet.charts.len() {
+                    self.sheet.charts.remove(index);
+                }
+            }
+            Message::CommentsToggled(show) => {
+                self.show_comments = show;
+            }
+            Message::CommentEdited(cell, text) => {
+                // Clearing a comment deletes it, editing one makes the
+                // current user its author
+                if text.is_empty() {
+                    self.sheet.comments.remove(&cell);
+                } else {
+                    self.sheet.comments.insert(cell, Comment::new(text));
+                }
+            }
+            Message::RemoveComment(cell) => {
+                self.sheet.comments.remove(&cell);
+            }
+            Message::ScriptToggled(show) => {
+                self.show_script = show;
+            }
+            Message::ScriptEdited(action) => {
+                self.script_editor.perform(action);
+            }
+            Message::ApplyScript => match self.sheet.replace_script(&self.script_editor.text()) {
+                Ok(()) => {
+                    self.script_error = None;
+                    task = self.recalculate(self.sheet.formula_cells());
+                }
+                Err(err) => self.script_error = Some(err),
+            },
+            Message::PrintToggled(show) => {
+                self.show_print = show;
+                // The panel opens on the sheet's print setup
+                if show {
+                    let setup = &self.sheet.print;
+                    self.printing.area = setup.area.map(|a| a.to_string()).unwrap_or_default();
+                    self.printing.breaks = setup
+                        .row_breaks
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    self.printing.setup_error = None;
+                }
+            }
+            Message::PrintAreaChanged(area) => {
+                self.printing.area = area;
+            }
+            Message::PageBreaksChanged(breaks) => {
+                self.printing.breaks = breaks;
+            }
+            Message::ApplyPrintSetup => {
+                let printing = &mut self.printing;
+                let area = match printing.area.trim() {
+                    "" => Ok(None),
+                    area => parse_range(area)
+                        .filter(|range| self.sheet.clip(*range) == Some(*range))
+                        .map(Some)
+                        .ok_or_else(|| format!("invalid print area {:?}", area)),
+                };
+                let breaks = printing
+                    .breaks
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|row| !row.is_empty())
+                    .map(|row| {
+                        row.parse::<usize>()
+                            .ok()
+                            .filter(|row| *row > 0 && *row < self.sheet.rows())
+                            .ok_or_else(|| format!("invalid page break {:?}", row))
+                    })
+                    .collect::<Result<_, _>>();
+                match area.and_then(|area| Ok((area, breaks?))) {
+                    Ok((area, breaks)) => {
+                        self.sheet.print.area = area;
+                        self.sheet.print.row_breaks = breaks;
+                        printing.setup_error = None;
+                    }
+                    Err(err) => printing.setup_error = Some(err),
+                }
+            }
+            Message::LandscapeToggled(landscape) => {
+                self.sheet.print.landscape = landscape;
+            }
+            Message::ExportRangeChanged(range) => {
+                self.printing.range = range;
+            }
+            Message::ExportPathChanged(path) => {
+                self.printing.path = path;
+            }
+            Message::Export => {
+                let printing = &mut self.printing;
+                let range = match printing.range.trim() {
+                    "" => Ok(None),
+                    range => parse_range(range)
+                        .map(Some)
+                        .ok_or_else(|| format!("invalid range {:?}", range)),
+                };
+                let path = printing.path.trim();
+                printing.result = Some(if path.is_empty() {
+                    Err("enter a file ending in .html or .pdf".to_string())
+                } else {
+                    range
+                        .and_then(|range| print::export(&self.sheet, range, path))
+                        .map(|pages| {
+                            let plural = if pages == 1 { "" } else { "s" };
+                            format!("wrote {} page{} to {}", pages, plural, path)
+                        })
+                });
+            }
+            Message::FilePathChanged(path) => {
+                self.file_path = path;
+            }
+            Message::OpenFile => {
+                let path = PathBuf::from(self.file_path.trim());
+                // Loading refuses sheets larger than the grid can lay out
+                let opened = file::load(&path).and_then(|sheet| self.replace_sheet(sheet));
+                self.file_status = Some(opened.map(|()| {
+                    self.saved_json = file::to_json(&self.sheet);
+                    self.forget_unsaved_work();
+                    let status = format!("opened {}", path.display());
+                    self.saved_to = Some(path);
+                    status
+                }));
+            }
+            Message::SaveFile => {
+                let path = PathBuf::from(self.file_path.trim());
+                self.file_status = Some(file::save(&self.sheet, &path).map(|()| {
+                    self.saved_json = file::to_json(&self.sheet);
+                    self.forget_unsaved_work();
+                    // Relative imports are read from the new file's directory
+                    let roots = self
+                        .sheet
+                        .set_directory(path.parent().map(Path::to_path_buf));
+                    task = self.recalculate(roots);
+                    let status = format!("saved {}", path.display());
+                    self.saved_to = Some(path);
+                    status
+                }));
+            }
+            Message::Autosave => {
+                self.autosave();
+            }
+            Message::RestoreRecovered => {
+                if let Some(recovered) = self.recovered.take() {
+                    match self.replace_sheet(recovered.sheet) {
+                        Ok(()) => {
+                            self.saved_json.clear();
+                            self.file_path = recovered
+                                .file
+                                .as_ref()
+                                .map(|file| file.display().to_string())
+                                .unwrap_or_default();
+                            let roots = self.sheet.set_directory(
+                                recovered
+                                    .file
+                                    .as_deref()
+                                    .and_then(Path::parent)
+                                    .map(Path::to_path_buf),
+                            );
+                            task = self.recalculate(roots);
+                            self.saved_to = recovered.file;
+                            self.file_status = Some(Ok("restored unsaved work".to_string()));
+                            // The work, unsaved until saved explicitly, moves to
+                            // this session's recovery file
+                            self.autosaved_json = None;
+                            self.autosave();
+                            if self.autosaved_json.is_some()
+                                && let Err(err) = recovered.source.discard()
+                            {
+                                self.file_status = Some(Err(err));
+                            }
+                        }
+                        Err(err) => self.file_status = Some(Err(err)),
+                    }
+                }
+            }
+            Message::DiscardRecovered => {
+                if let Some(recovered) = self.recovered.take()
+                    && let Err(err) = recovered.source.discard()
+                {
+                    self.file_status = Some(Err(err));
+                }
+            }
+            Message::WhatIfToggled(show) => {
+                self.show_whatif = show;
+            }
+            Message::SeekTargetChanged(target) => {
+                self.whatif.target = target;
+            }
+            Message::SeekGoalChanged(goal) => {
+                self.whatif.goal = goal;
+            }
+            Message::SeekInputChanged(input) => {
+                self.whatif.input = input;
+            }
+            Message::GoalSeek if self.whatif.seeking => {}
+            Message::GoalSeek => match self.seek_request() {
+                // The search recalculates a copy of the sheet many times, so
+                // it runs on a worker thread. The copy first catches up with
+                // any background recalculation still in flight.
+                Ok((target, goal, input)) => {
+                    let roots = self
+                        .recalculation
+                        .as_ref()
+                        .map_or_else(Vec::new, |r| r.roots.clone());
+                    self.whatif.seeking = true;
+                    self.whatif.seek_result = None;
+                    task = Task::future(seek_in_background(
+                        self.sheet.clone(),
+                        roots,
+                        target,
+                        goal,
+                        input,
+                    ))
+                    .map(move |result| Message::GoalSought(input, result));
+                }
+                Err(err) => self.whatif.seek_result = Some(Err(err)),
+            },
+            Message::GoalSought(input, result) => {
+                self.whatif.seeking = false;
+                match result {
+                    // The input found is entered like any edit
+                    Ok(solution) => {
+                        self.whatif.seek_result = Some(Ok(format!(
+                            "{} = {} gives {}",
+                            cell_name(input),
+                            solution.input,
+                            solution.result
+                        )));
+                        task = self.update_cell(input.0, input.1, solution.input.to_string());
+                    }
+                    Err(err) => self.whatif.seek_result = Some(Err(err)),
+                }
+            }
+            Message::TableOutputsChanged(outputs) => {
+                self.whatif.outputs = outputs;
+            }
+            Message::TableDownInputChanged(input) => {
+                self.whatif.down_input = input;
+            }
+            Message::TableDownValuesChanged(values) => {
+                self.whatif.down_values = values;
+            }
+            Message::TableAcrossInputChanged(input) => {
+                self.whatif.across_input = input;
+            }
+            Message::TableAcrossValuesChanged(values) => {
+                self.whatif.across_values = values;
+            }
+            Message::ComputeTable => {
+                self.whatif.table = Some(self.build_data_table());
+            }
+            Message::TraceToggled(tracing) => {
+                self.tracing = tracing;
+                self.traced_cell = None;
+            }
+            Message::CollabAddressChanged(address) => {
+                self.collab_address = address;
+            }
+            Message::CollabUserChanged(user) => {
+                self.collab_user = user;
+            }
+            Message::Connect => {
+                // Servers run on the local network, so connecting blocks
+                // only briefly
+                let (sender, receiver) = futures_mpsc::unbounded();
+                let user = self.collab_user.trim();
+                let user = if user.is_empty() { "anonymous" } else { user };
+                match collab::connect(self.collab_address.trim(), user, move |update| {
+                    let _ = sender.unbounded_send(update);
+                }) {
+                    Ok(outbox) => {
+                        self.collab = Some(Collaboration::new(outbox, receiver));
+                        self.collab_error = None;
+                    }
+                    Err(err) => self.collab_error = Some(err),
+                }
+            }
+            Message::Disconnect => {
+                self.collab = None;
+            }
+            Message::Remote(update) => {
+                task = self.receive(update);
+            }
+            Message::Disconnected(id) => {
+                if self.collab.as_ref().is_some_and(|c| c.inbox.id == id) {
+                    self.collab = None;
+                    self.collab_error = Some("disconnected from the server".to_string());
+                }
+            }
+            Message::ImportedFileChanged(path) => {
+                let roots = self.sheet.importers(&path);
+                task = self.recalculate(roots);
+            }
+            Message::Recalculated(generation, results) => {
+                // Results of a superseded recalculation are stale
+                if self
+                    .recalculation
+                    .as_ref()
+                    .is_some_and(|r| r.generation == generation)
+                {
+                    self.sheet.apply(results);
+                    self.recalculation = None;
+                    self.refresh_formats();
+                }
+            }
+        }
+        task
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        let mut subscriptions = Vec::new();
+        // Releases anywhere end a drag, even outside the grid
+        if self.reference_drag.is_some() {
+            subscriptions.push(iced::event::listen_with(
+                |event, _status, _window| match event {
+                    Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) => {
+                        Some(Message::MouseReleased)
+                    }
+                    _ => None,
+                },
+            ));
+        }
+        // Up and down arrows and Tab move to the next cell while editing,
+        // unless the formula bar uses the key
+        if self.editing_cell.is_some() {
+            subscriptions.push(iced::event::listen_with(|event, status, _window| {
+                let Event::Keyboard(keyboard::Event::KeyPressed { key, modifiers, .. }) = event
+                else {
+                    return None;
+                };
+                if status == iced::event::Status::Captured {
+                    return None;
+                }
+                match key {
+                    keyboard::Key::Named(keyboard::key::Named::ArrowUp) => {
+                        Some(Message::Navigate(-1, 0))
+                    }
+                    keyboard::Key::Named(keyboard::key::Named::ArrowDown) => {
+                        Some(Message::Navigate(1, 0))
+                    }
+                    keyboard::Key::Named(keyboard::key::Named::Tab) if modifiers.shift() => {
+                        Some(Message::Navigate(0, -1))
+                    }
+                    keyboard::Key::Named(keyboard::key::Named::Tab) => {
+                        Some(Message::Navigate(0, 1))
+                    }
+                    _ => None,
+                }
+            }));
+        }
+        if let Some(collab) = &self.collab {
+            subscriptions.push(Subscription::run_with(collab.inbox.clone(), listen));
+        }
+        if self.recovery.is_some() {
+            subscriptions.push(iced::time::every(recovery::INTERVAL).map(|_| Message::Autosave));
+        }
+        let imported = self.sheet.imported_files();
+        if !imported.is_empty() {
+            subscriptions.push(Subscription::run_with(imported, watch));
+        }
+        Subscription::batch(subscriptions)
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        // Create complete grid (headers + data, all together)
+        let grid = self.create_complete_grid();
+
+        let scrollable_grid = scrollable(grid)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .direction(scrollable::Direction::Both {
+                vertical: scrollable::Scrollbar::default(),
+                horizontal: scrollable::Scrollbar::default(),
+            });
+
+        let controls = row![
+            self.file_controls(),
+            checkbox(self.sheet.recalc_mode() == RecalcMode::Parallel)
+                .label("Parallel recalculation")
+                .on_toggle(Message::ParallelToggled)
+                .text_size(14),
+            checkbox(self.sheet.arithmetic() == Arithmetic::Decimal)
+                .label("Exact decimals")
+                .on_toggle(Message::DecimalToggled)
+                .text_size(14),
+            checkbox(self.sheet.iteration().is_some())
+                .label("Iterative calculation")
+                .on_toggle(Message::IterationToggled)
+                .text_size(14),
+            text_input("Max iterations", &self.max_iterations)
+                .on_input(Message::MaxIterationsChanged)
+                .size(14)
+                .width(60),
+            text_input("Max change", &self.max_change)
+                .on_input(Message::MaxChangeChanged)
+                .size(14)
+                .width(70),
+            text(self.iteration_error.clone().unwrap_or_default())
+                .size(14)
+                .style(text::danger),
+            checkbox(self.tracing)
+                .label("Trace dependencies")
+                .on_toggle(Message::TraceToggled)
+                .text_size(14),
+            checkbox(self.show_formats)
+                .label("Conditional formatting")
+                .on_toggle(Message::FormatsToggled)
+                .text_size(14),
+            checkbox(self.show_validations)
+                .label("Validation")
+                .on_toggle(Message::ValidationsToggled)
+                .text_size(14),
+            checkbox(self.show_charts)
+                .label("Charts")
+                .on_toggle(Message::ChartsToggled)
+                .text_size(14),
+            checkbox(self.show_comments)
+                .label("Comments")
+                .on_toggle(Message::CommentsToggled)
+                .text_size(14),
+            checkbox(self.show_whatif)
+                .label("What-if")
+                .on_toggle(Message::WhatIfToggled)
+                .text_size(14),
+            checkbox(self.show_script)
+                .label("Script")
+                .on_toggle(Message::ScriptToggled)
+                .text_size(14),
+            checkbox(self.show_print)
+                .label("Print")
+                .on_toggle(Message::PrintToggled)
+                .text_size(14),
+            text_input("Name", &self.new_name)
+                .on_input(Message::NameChanged)
+                .on_submit(Message::DefineName)
+                .size(14)
+                .width(100),
+            text_input("Range, e.g. A0:A9", &self.new_name_range)
+                .on_input(Message::NameRangeChanged)
+                .on_submit(Message::DefineName)
+                .size(14)
+                .width(140),
+            button(text("Define name").size(14)).on_press(Message::DefineName),
+            text(self.name_error.clone().unwrap_or_default())
+                .size(14)
+                .style(text::danger),
+            text_input("Merge, e.g. A0:C0", &self.new_merge_range)
+                .on_input(Message::MergeRangeChanged)
+                .on_submit(Message::MergeCells)
+                .size(14)
+                .width(140),
+            button(text("Merge cells").size(14)).on_press(Message::MergeCells),
+            text(self.merge_error.clone().unwrap_or_default())
+                .size(14)
+                .style(text::danger),
+            self.collab_controls(),
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center)
+        .wrap();
+
+        let mut body = row![scrollable_grid].spacing(10);
+        if let Some(cell) = self.traced_cell {
+            body = body.push(self.trace_panel(cell));
+        }
+        if self.show_formats {
+            body = body.push(self.formats_panel());
+        }
+        if self.show_validations {
+            body = body.push(self.validations_panel());
+        }
+        if self.show_charts {
+            body = body.push(self.charts_panel());
+        }
+        if self.show_comments {
+            body = body.push(self.comments_panel());
+        }
+        if self.show_whatif {
+            body = body.push(self.whatif_panel());
+        }
+        if self.show_script {
+            body = body.push(self.script_panel());
+        }
+        if self.show_print {
+            body = body.push(self.print_panel());
+        }
+
+        let mut content = column![controls].spacing(10);
+        if let Some(recovered) = &self.recovered {
+            content = content.push(recovery_prompt(recovered));
+        }
+        if let Some(cell) = self.editing_cell {
+            content = content.push(self.formula_bar(cell));
+        }
+        if let Some(prompt) = &self.validation_prompt {
+            content = content.push(validation_prompt(prompt));
+        }
+
+        container(content.push(body))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding(10)
+            .into()
+    }
+
+    // Server and name to collaborate with, or the other users while
+    // collaborating
+    // Sheet file to open or save, and how the last attempt went
+    fn file_controls(&self) -> Element<'_, Message> {
+        let status = match &self.file_status {
+            Some(Ok(status)) => text(status.clone()).size(14),
+            Some(Err(err)) => text(err.clone()).size(14).style(text::danger),
+            None => text(""),
+        };
+        row![
+            text_input("Sheet file, e.g. budget.json", &self.file_path)
+                .on_input(Message::FilePathChanged)
+                .on_submit(Message::SaveFile)
+                .size(14)
+                .width(180),
+            button(text("Open").size(14)).on_press(Message::OpenFile),
+            button(text("Save").size(14)).on_press(Message::SaveFile),
+            status,
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center)
+        .into()
+    }
+
+    fn collab_controls(&self) -> Element<'_, Message> {
+        let Some(collab) = &self.collab else {
+            return row![
+                text_input("Server, e.g. ws://localhost:9001", &self.collab_address)
+                    .on_input(Message::CollabAddressChanged)
+                    .on_submit(Message::Connect)
+                    .size(14)
+                    .width(200),
+                text_input("Your name", &self.collab_user)
+                    .on_input(Message::CollabUserChanged)
+                    .on_submit(Message::Connect)
+                    .size(14)
+                    .width(100),
+                button(text("Collaborate").size(14)).on_press(Message::Connect),
+                text(self.collab_error.clone().unwrap_or_default())
+                    .size(14)
+                    .style(text::danger),
+            ]
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .into();
+        };
+        let mut users = row![text(format!("Editing as {}", self.collab_user)).size(14)].spacing(10);
+        for peer in collab.peers.values() {
+            let cell = peer.cell.as_deref().unwrap_or("-");
+            users = users.push(
+                text(format!("{} ({})", peer.user, cell))
+                    .size(14)
+                    .color(peer_color(peer.client)),
+            );
+        }
+        users
+            .push(button(text("Stop collaborating").size(14)).on_press(Message::Disconnect))
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    // Highlighted editor for the formula of the cell being edited, with
+    // completions for the function or name being typed and the first problem
+    // with the formula. Enter finishes editing, Tab takes the first
+    // completion.
+    fn formula_bar(&self, cell: Cell) -> Element<'_, Message> {
+        let completions = self.completions();
+        let first = completions.first().cloned();
+
+        let editor = text_editor(&self.formula_editor)
+            .id(Id::new("formula-bar"))
+            .on_action(Message::FormulaEdited)
+            .size(14)
+            .padding(5)
+            .key_binding(move |key_press| match key_press.key.as_ref() {
+                keyboard::Key::Named(keyboard::key::Named::Enter) => {
+                    Some(text_editor::Binding::Custom(Message::FinishEditing))
+                }
+                keyboard::Key::Named(keyboard::key::Named::Tab) => first
+                    .clone()
+                    .map(|c| text_editor::Binding::Custom(Message::CompletionChosen(c))),
+                _ => text_editor::Binding::from_key_press(key_press),
+            })
+            .highlight_with::<FormulaHighlighter>((), format_token);
+
+        let suggestions = Row::with_children(completions.into_iter().map(|completion| {
+            button(text(completion.clone()).size(12))
+                .padding([2, 6])
+                .on_press(Message::CompletionChosen(completion))
+                .into()
+        }))
+        .spacing(4);
+
+        let problem = self
+            .sheet
+            .check_formula(&self.editing_formula)
+            .unwrap_or_default();
+        // Input into a spilled cell blocks the formula spilling there
+        let spill_note = match self.sheet.spilled_from(cell) {
+            Some(anchor) => format!("Spilled from {}", cell_name(anchor)),
+            None => String::new(),
+        };
+
+        let mut options = row![
+            checkbox(self.sheet.wrapped.contains(&cell))
+                .label("Wrap text")
+                .on_toggle(Message::WrapToggled)
+                .text_size(14)
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center);
+        if let Some(merge) = self.sheet.merged(cell) {
+            options = options.push(
+                button(text(format!("Unmerge {}", merge)).size(14)).on_press(Message::Unmerge),
+            );
+        }
+        let comment = self
+            .sheet
+            .comments
+            .get(&cell)
+            .map_or("", |c| c.text.as_str());
+        options = options.push(
+            text_input("Comment", comment)
+                .on_input(move |text| Message::CommentEdited(cell, text))
+                .size(14)
+                .width(200),
+        );
+
+        column![
+            row![text(cell_name(cell)).size(14).width(40), editor, options]
+                .spacing(10)
+                .align_y(Alignment::Center),
+            suggestions,
+            text(problem).size(14).style(text::danger),
+            text(spill_note).size(14),
+        ]
+        .spacing(4)
+        .into()
+    }
+
+    // Replaces the formula being edited and mirrors it in the formula bar,
+    // with the cursor at the end
+    fn set_editing_formula(&mut self, formula: String) {
+        self.formula_editor = text_editor::Content::with_text(&formula);
+        self.formula_editor
+            .perform(text_editor::Action::Move(text_editor::Motion::DocumentEnd));
+        self.editing_formula = formula;
+    }
+
+    // Like `set_editing_formula`, with the cursor at byte `cursor`
+    fn set_editing_formula_at(&mut self, formula: String, cursor: usize) {
+        let chars = formula[..cursor].chars().count();
+        self.set_editing_formula(formula);
+        self.formula_editor.perform(text_editor::Action::Move(
+            text_editor::Motion::DocumentStart,
+        ));
+        for _ in 0..chars {
+            self.formula_editor
+                .perform(text_editor::Action::Move(text_editor::Motion::Right));
+        }
+    }
+
+    // Takes a formula with a reference inserted by clicking and gives the
+    // focus back to the cell being edited, with the cursor after the
+    // reference
+    fn insert_reference(&mut self, formula: String, cursor: usize) -> Task<Message> {
+        let chars = formula[..cursor].chars().count();
+        self.set_editing_formula_at(formula, cursor);
+        match self.editing_cell {
+            Some((row, col)) => {
+                let id = format!("cell-input-{}-{}", row, col);
+                Task::batch([
+                    operation::focus(id.clone()),
+                    operation::move_cursor_to(id, chars),
+                ])
+            }
+            None => Task::none(),
+        }
+    }
+
+    // Whether pressing a cell inserts its reference instead of finishing the
+    // edit: the formula being edited expects an operand at the cursor, or a
+    // reference is being dragged
+    fn inserting_references(&self) -> bool {
+        self.editing_cell.is_some()
+            && !self.tracing
+            && (self.reference_drag.is_some()
+                || syntax::at_operand(&self.editing_formula, self.cursor()))
+    }
+
+    // Byte offset of the formula bar cursor in the formula being edited
+    fn cursor(&self) -> usize {
+        let column = self.formula_editor.cursor().position.column;
+        self.editing_formula
+            .char_indices()
+            .nth(column)
+            .map_or(self.editing_formula.len(), |(i, _)| i)
+    }
+
+    // Functions and named ranges completing the word before the cursor
+    fn completions(&self) -> Vec<String> {
+        match syntax::word_at(&self.editing_formula, self.cursor()) {
+            Some((_, word)) => syntax::completions(
+                word,
+                functions::NAMES
+                    .iter()
+                    .copied()
+                    .chain(self.sheet.script().names()),
+                self.sheet.names().keys().map(String::as_str),
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    // Lists the precedents and dependents of the traced cell, indented by
+    // depth, with their formulas and values
+    fn trace_panel(&self, cell: Cell) -> Element<'_, Message> {
+        let line = |cell: Cell, depth: usize| {
+            let formula = self.sheet.formulas.get(&cell).cloned().unwrap_or_default();
+            text(format!(
+                "{}{}  {}  → {}",
+                "    ".repeat(depth.saturating_sub(1)),
+                cell_name(cell),
+                formula,
+                self.get_cell_display(cell.0, cell.1)
+            ))
+            .size(14)
+        };
+
+        let mut panel = column![text("Trace").size(16), line(cell, 0)].spacing(4);
+        for (title, cells) in [
+            ("Precedents", self.sheet.trace_precedents(cell)),
+            ("Dependents", self.sheet.trace_dependents(cell)),
+        ] {
+            panel = panel.push(text(title).size(16));
+            if cells.is_empty() {
+                panel = panel.push(text("none").size(14));
+            }
+            for (cell, depth) in cells {
+                panel = panel.push(line(cell, depth));
+            }
+        }
+
+        scrollable(panel).width(260).height(Length::Fill).into()
+    }
+
+    // Rules of the sheet, in the order they apply, and a form to add one
+    fn formats_panel(&self) -> Element<'_, Message> {
+        let mut panel = column![text("Conditional formatting").size(16)].spacing(6);
+        if self.sheet.formats.is_empty() {
+            panel = panel.push(text("no rules").size(14));
+        }
+        for (index, rule) in self.sheet.formats.iter().enumerate() {
+            panel = panel.push(
+                row![
+                    text(describe_rule(rule)).size(14).width(Length::Fill),
+                    button(text("Remove").size(12)).on_press(Message::RemoveRule(index)),
+                ]
+                .spacing(6)
+                .align_y(Alignment::Center),
+            );
+        }
+
+        let kind = self.new_rule.kind;
+        let value_placeholder = match kind {
+            RuleKind::Formula => Some("Formula, e.g. =A0>A1"),
+            RuleKind::GreaterThan
+            | RuleKind::LessThan
+            | RuleKind::EqualTo
+            | RuleKind::NotEqualTo => Some("Number"),
+            RuleKind::Errors | RuleKind::ColorScale | RuleKind::DataBar => None,
+        };
+
+        panel = panel
+            .push(text("New rule").size(16))
+            .push(
+                text_input("Range, e.g. A0:A9", &self.new_rule.range)
+                    .on_input(Message::RuleRangeChanged)
+                    .size(14),
+            )
+            .push(
+                pick_list(&RuleKind::ALL[..], Some(kind), Message::RuleKindSelected).text_size(14),
+            );
+        if let Some(placeholder) = value_placeholder {
+            panel = panel.push(
+                text_input(placeholder, &self.new_rule.value)
+                    .on_input(Message::RuleValueChanged)
+                    .on_submit(Message::AddRule)
+                    .size(14),
+            );
+        }
+        // Color scales always run from green to red
+        if kind != RuleKind::ColorScale {
+            panel = panel.push(
+                pick_list(
+                    &NamedColor::ALL[..],
+                    Some(self.new_rule.color),
+                    Message::RuleColorSelected,
+                )
+                .text_size(14),
+            );
+        }
+        panel = panel
+            .push(button(text("Add rule").size(14)).on_press(Message::AddRule))
+            .push(
+                text(self.rule_error.clone().unwrap_or_default())
+                    .size(14)
+                    .style(text::danger),
+            );
 
-#[derive(Debug, Clone)]
-enum Message {
-    CellClicked(usize, usize),
-    FormulaChanged(String),
-    FinishEditing,
-}
+        scrollable(panel).width(260).height(Length::Fill).into()
+    }
 
-#[derive(Debug, Clone, PartialEq)]
-enum CellValue {
-    Number(f64),
-    Text(String),
-    Error(String),
-}
+    // Validation rules of the sheet and a form to add one
+    fn validations_panel(&self) -> Element<'_, Message> {
+        let mut panel = column![text("Validation").size(16)].spacing(6);
+        if self.sheet.validations.is_empty() {
+            panel = panel.push(text("no rules").size(14));
+        }
+        for (index, validation) in self.sheet.validations.iter().enumerate() {
+            panel = panel.push(
+                row![
+                    text(describe_validation(validation))
+                        .size(14)
+                        .width(Length::Fill),
+                    button(text("Remove").size(12)).on_press(Message::RemoveValidation(index)),
+                ]
+                .spacing(6)
+                .align_y(Alignment::Center),
+            );
+        }
 
-struct App {
-    // Cell data: formula input by user
-    formulas: HashMap<(usize, usize), String>,
-    // Cell data: evaluated value
-    values: HashMap<(usize, usize), CellValue>,
-    // Dependency tracking: which cells does each cell depend on
-    dependencies: HashMap<(usize, usize), HashSet<(usize, usize)>>,
-    // Reverse dependencies: which cells depend on this cell
-    dependents: HashMap<(usize, usize), HashSet<(usize, usize)>>,
-    // Currently editing cell
-    editing_cell: Option<(usize, usize)>,
-    // Current formula being edited
-    editing_formula: String,
-}
+        let new_validation = &self.new_validation;
+        let kind = new_validation.kind;
+        let (first, second) = match kind {
+            ValidationKind::Number | ValidationKind::WholeNumber => ("Minimum", Some("Maximum")),
+            ValidationKind::List => ("Values, e.g. Open, Closed", None),
+            ValidationKind::Pattern => ("Pattern, e.g. [A-Z]{3}-\\d+", None),
+            ValidationKind::Formula => ("Formula, e.g. =A0<B0", None),
+        };
+
+        panel = panel
+            .push(text("New rule").size(16))
+            .push(
+                text_input("Range, e.g. A0:A9", &new_validation.range)
+                    .on_input(Message::ValidationRangeChanged)
+                    .size(14),
+            )
+            .push(
+                pick_list(
+                    &ValidationKind::ALL[..],
+                    Some(kind),
+                    Message::ValidationKindSelected,
+                )
+                .text_size(14),
+            )
+            .push(
+                text_input(first, &new_validation.first)
+                    .on_input(Message::ValidationFirstChanged)
+                    .on_submit(Message::AddValidation)
+                    .size(14),
+            );
+        if let Some(second) = second {
+            panel = panel.push(
+                text_input(second, &new_validation.second)
+                    .on_input(Message::ValidationSecondChanged)
+                    .on_submit(Message::AddValidation)
+                    .size(14),
+            );
+        }
+        panel = panel
+            .push(
+                text_input("Message (optional)", &new_validation.message)
+                    .on_input(Message::ValidationMessageChanged)
+                    .on_submit(Message::AddValidation)
+                    .size(14),
+            )
+            .push(
+                checkbox(new_validation.warn)
+                    .label("Only warn")
+                    .on_toggle(Message::ValidationWarnToggled)
+                    .text_size(14),
+            )
+            .push(button(text("Add rule").size(14)).on_press(Message::AddValidation))
+            .push(
+                text(self.validation_error.clone().unwrap_or_default())
+                    .size(14)
+                    .style(text::danger),
+            );
 
-impl App {
-    fn new() -> (Self, Task<Message>) {
-        (
-            Self {
-                formulas: HashMap::new(),
-                values: HashMap::new(),
-                dependencies: HashMap::new(),
-                dependents: HashMap::new(),
-                editing_cell: None,
-                editing_formula: String::new(),
+        scrollable(panel).width(260).height(Length::Fill).into()
+    }
+
+    // Every comment of the sheet, editable in place. Clicking a cell name
+    // selects the cell.
+    fn comments_panel(&self) -> Element<'_, Message> {
+        let mut panel = column![text("Comments").size(16)].spacing(6);
+        if self.sheet.comments.is_empty() {
+            panel = panel.push(text("no comments").size(14));
+        }
+        for (&cell, comment) in &self.sheet.comments {
+            panel = panel.push(
+                row![
+                    button(text(cell_name(cell)).size(12))
+                        .padding([2, 6])
+                        .on_press(Message::CellClicked(cell.0, cell.1)),
+                    text(describe_comment(comment)).size(12).width(Length::Fill),
+                    button(text("Remove").size(12)).on_press(Message::RemoveComment(cell)),
+                ]
+                .spacing(6)
+                .align_y(Alignment::Center),
+            );
+            panel = panel.push(
+                text_input("Comment", &comment.text)
+                    .on_input(move |text| Message::CommentEdited(cell, text))
+                    .size(14),
+            );
+        }
+
+        scrollable(panel).width(260).height(Length::Fill).into()
+    }
+
+    // Editor for the script defining functions, applied on demand, with the
+    // functions it defines
+    fn script_panel(&self) -> Element<'_, Message> {
+        let mut panel = column![
+            text("Script").size(16),
+            text("Functions written in Rhai are called from formulas in upper case").size(12),
+            text_editor(&self.script_editor)
+                .placeholder("fn double(x) { x * 2.0 }")
+                .on_action(Message::ScriptEdited)
+                .font(iced::Font::MONOSPACE)
+                .size(13)
+                .height(240),
+            button(text("Apply script").size(14)).on_press(Message::ApplyScript),
+        ]
+        .spacing(6);
+        if let Some(err) = &self.script_error {
+            panel = panel.push(text(err.clone()).size(12).style(text::danger));
+        }
+        for signature in self.sheet.script().signatures() {
+            panel = panel.push(text(signature).size(12));
+        }
+        scrollable(panel).width(300).height(Length::Fill).into()
+    }
+
+    // Print setup saved with the sheet, and export of a range to HTML or
+    // PDF following it
+    fn print_panel(&self) -> Element<'_, Message> {
+        let printing = &self.printing;
+        let mut panel = column![
+            text("Print").size(16),
+            text_input("Print area, e.g. A0:F40", &printing.area)
+                .on_input(Message::PrintAreaChanged)
+                .on_submit(Message::ApplyPrintSetup)
+                .size(14),
+            text_input("Page breaks before rows, e.g. 20, 40", &printing.breaks)
+                .on_input(Message::PageBreaksChanged)
+                .on_submit(Message::ApplyPrintSetup)
+                .size(14),
+            button(text("Apply").size(14)).on_press(Message::ApplyPrintSetup),
+            text(printing.setup_error.clone().unwrap_or_default())
+                .size(14)
+                .style(text::danger),
+            checkbox(self.sheet.print.landscape)
+                .label("Landscape")
+                .on_toggle(Message::LandscapeToggled)
+                .text_size(14),
+            text("Export").size(16),
+            text_input("Range, blank for the print area", &printing.range)
+                .on_input(Message::ExportRangeChanged)
+                .on_submit(Message::Export)
+                .size(14),
+            text_input("File, e.g. report.pdf or report.html", &printing.path)
+                .on_input(Message::ExportPathChanged)
+                .on_submit(Message::Export)
+                .size(14),
+            button(text("Export").size(14)).on_press(Message::Export),
+        ]
+        .spacing(6);
+        match &printing.result {
+            Some(Ok(written)) => panel = panel.push(text(written.clone()).size(14)),
+            Some(Err(err)) => panel = panel.push(text(err.clone()).size(14).style(text::danger)),
+            None => {}
+        }
+        scrollable(panel).width(260).height(Length::Fill).into()
+    }
+
+    // Goal seek, which enters the input it finds, and data tables, which
+    // leave the sheet as it is
+    fn whatif_panel(&self) -> Element<'_, Message> {
+        let whatif = &self.whatif;
+        let outcome = |result: &Option<Result<String, String>>| match result {
+            Some(Ok(found)) => text(found.clone()).size(14),
+            Some(Err(err)) => text(err.clone()).size(14).style(text::danger),
+            None => text(""),
+        };
+        let field = |placeholder, value, on_input: fn(String) -> Message, on_submit| {
+            text_input(placeholder, value)
+                .on_input(on_input)
+                .on_submit(on_submit)
+                .size(14)
+        };
+
+        let mut panel = column![
+            text("Goal seek").size(16),
+            field(
+                "Set cell, e.g. B3",
+                &whatif.target,
+                Message::SeekTargetChanged,
+                Message::GoalSeek
+            ),
+            field(
+                "To value",
+                &whatif.goal,
+                Message::SeekGoalChanged,
+                Message::GoalSeek
+            ),
+            field(
+                "By changing cell, e.g. A0",
+                &whatif.input,
+                Message::SeekInputChanged,
+                Message::GoalSeek
+            ),
+            button(
+                text(if whatif.seeking {
+                    "Seeking…"
+                } else {
+                    "Goal seek"
+                })
+                .size(14)
+            )
+            .on_press_maybe((!whatif.seeking).then_some(Message::GoalSeek)),
+            outcome(&whatif.seek_result),
+            text("Data table").size(16),
+            field(
+                "Output cells, e.g. B3, C3",
+                &whatif.outputs,
+                Message::TableOutputsChanged,
+                Message::ComputeTable
+            ),
+            field(
+                "Input cell down the side",
+                &whatif.down_input,
+                Message::TableDownInputChanged,
+                Message::ComputeTable
+            ),
+            field(
+                "Its values, e.g. 1, 2, 3",
+                &whatif.down_values,
+                Message::TableDownValuesChanged,
+                Message::ComputeTable
+            ),
+            field(
+                "Input cell across the top (optional)",
+                &whatif.across_input,
+                Message::TableAcrossInputChanged,
+                Message::ComputeTable
+            ),
+            field(
+                "Its values",
+                &whatif.across_values,
+                Message::TableAcrossValuesChanged,
+                Message::ComputeTable
+            ),
+            button(text("Compute table").size(14)).on_press(Message::ComputeTable),
+        ]
+        .spacing(6);
+        match &whatif.table {
+            Some(Ok(table)) => panel = panel.push(data_table(table)),
+            Some(Err(err)) => panel = panel.push(text(err.clone()).size(14).style(text::danger)),
+            None => {}
+        }
+
+        scrollable(panel).width(280).height(Length::Fill).into()
+    }
+
+    // Every chart of the sheet, drawn from the current values of its range,
+    // and a form to add one
+    fn charts_panel(&self) -> Element<'_, Message> {
+        let mut panel = column![text("Charts").size(16)].spacing(6);
+        for (index, chart) in self.sheet.charts.iter().enumerate() {
+            let title = if chart.title.is_empty() {
+                format!("{} of {}", chart.kind, chart.range)
+            } else {
+                chart.title.clone()
+            };
+            panel = panel
+                .push(
+                    row![
+                        text(title).size(14).width(Length::Fill),
+                        button(text("Remove").size(12)).on_press(Message::RemoveChart(index)),
+                    ]
+                    .spacing(6)
+                    .align_y(Alignment::Center),
+                )
+                .push(
+                    canvas(ChartCanvas {
+                        kind: chart.kind,
+                        data: chart.data(&self.sheet),
+                    })
+                    .width(Length::Fill)
+                    .height(160),
+                );
+        }
+
+        panel = panel
+            .push(text("New chart").size(16))
+            .push(
+                text_input("Range, e.g. A0:B9", &self.new_chart_range)
+                    .on_input(Message::ChartRangeChanged)
+                    .on_submit(Message::AddChart)
+                    .size(14),
+            )
+            .push(
+                pick_list(
+                    &ChartKind::ALL[..],
+                    Some(self.new_chart_kind),
+                    Message::ChartKindSelected,
+                )
+                .text_size(14),
+            )
+            .push(
+                text_input("Title (optional)", &self.new_chart_title)
+                    .on_input(Message::ChartTitleChanged)
+                    .on_submit(Message::AddChart)
+                    .size(14),
+            )
+            .push(button(text("Add chart").size(14)).on_press(Message::AddChart))
+            .push(
+                text(self.chart_error.clone().unwrap_or_default())
+                    .size(14)
+                    .style(text::danger),
+            );
+
+        scrollable(panel).width(280).height(Length::Fill).into()
+    }
+
+    // Rule described by the "add rule" form
+    fn build_rule(&self) -> Result<Rule, String> {
+        let new_rule = &self.new_rule;
+        let range = parse_range(new_rule.range.trim())
+            .ok_or_else(|| format!("invalid range {:?}", new_rule.range))?;
+        let color = new_rule.color.rgb();
+        let compare = |comparison| {
+            let value = new_rule
+                .value
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| "enter a number to compare with".to_string())?;
+            Ok::<_, String>(Condition::Compare { comparison, value })
+        };
+        let format = match new_rule.kind {
+            RuleKind::GreaterThan => Format::Highlight {
+                condition: compare(Comparison::Greater)?,
+                color,
             },
-            Task::none(),
-        )
+            RuleKind::LessThan => Format::Highlight {
+                condition: compare(Comparison::Less)?,
+                color,
+            },
+            RuleKind::EqualTo => Format::Highlight {
+                condition: compare(Comparison::Equal)?,
+                color,
+            },
+            RuleKind::NotEqualTo => Format::Highlight {
+                condition: compare(Comparison::NotEqual)?,
+                color,
+            },
+            RuleKind::Formula => {
+                let formula = new_rule.value.trim();
+                if !formula.starts_with('=') {
+                    return Err("formulas start with =".to_string());
+                }
+                if let Some(problem) = self.sheet.check_formula(formula) {
+                    return Err(problem);
+                }
+                Format::Highlight {
+                    condition: Condition::Formula {
+                        formula: formula.to_string(),
+                    },
+                    color,
+                }
+            }
+            RuleKind::Errors => Format::Highlight {
+                condition: Condition::Errors,
+                color,
+            },
+            RuleKind::ColorScale => Format::ColorScale {
+                low: NamedColor::Green.rgb(),
+                high: NamedColor::Red.rgb(),
+            },
+            RuleKind::DataBar => Format::DataBar { color },
+        };
+        Ok(Rule { range, format })
     }
 
-    fn update(&mut self, message: Message) -> Task<Message> {
-        match message {
-            Message::CellClicked(row, col) => {
-                // If clicking a different cell while editing, finish current edit
-                if let Some((editing_row, editing_col)) = self.editing_cell
-                    && (editing_row, editing_col) != (row, col)
+    // Goal seek described by the what-if panel: the target cell, the goal
+    // and the input cell
+    fn seek_request(&self) -> Result<(Cell, f64, Cell), String> {
+        let whatif = &self.whatif;
+        let target = parse_cell_reference(&whatif.target)
+            .ok_or_else(|| format!("invalid cell {:?}", whatif.target))?;
+        let input = parse_cell_reference(&whatif.input)
+            .ok_or_else(|| format!("invalid cell {:?}", whatif.input))?;
+        let goal = whatif
+            .goal
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| "enter a number to reach".to_string())?;
+        Ok((target, goal, input))
+    }
+
+    // Data table described by the what-if panel, with one output and a
+    // column per value across the top when an input is given there
+    fn build_data_table(&self) -> Result<DataTable, String> {
+        let whatif = &self.whatif;
+        let cell = |text: &str| {
+            parse_cell_reference(text).ok_or_else(|| format!("invalid cell {:?}", text.trim()))
+        };
+        let values = |text: &str| -> Vec<String> {
+            text.split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let outputs = whatif
+            .outputs
+            .split(',')
+            .map(cell)
+            .collect::<Result<Vec<_>, _>>()?;
+        let down_input = cell(&whatif.down_input)?;
+        let down_values = values(&whatif.down_values);
+
+        let (header, table, formats): (Vec<String>, _, Vec<Cell>) =
+            if whatif.across_input.trim().is_empty() {
+                let table = whatif::data_table(&self.sheet, down_input, &down_values, &outputs)?;
+                let header = outputs.iter().map(|output| cell_name(*output)).collect();
+                (header, table, outputs)
+            } else {
+                let [output] = outputs[..] else {
+                    return Err("a table with two inputs has a single output cell".to_string());
+                };
+                let across_values = values(&whatif.across_values);
+                let table = whatif::data_table_2d(
+                    &self.sheet,
+                    output,
+                    (down_input, &down_values),
+                    (cell(&whatif.across_input)?, &across_values),
+                )?;
+                let formats = vec![output; across_values.len()];
+                (across_values, table, formats)
+            };
+        // Results show in the number format of their output cell
+        let rows = down_values
+            .into_iter()
+            .zip(table)
+            .map(|(value, results)| {
+                let results = results
+                    .iter()
+                    .zip(&formats)
+                    .map(|(result, cell)| self.format_value(*cell, result))
+                    .collect();
+                (value, results)
+            })
+            .collect();
+        Ok(DataTable { header, rows })
+    }
+
+    fn build_validation(&self) -> Result<Validation, String> {
+        let new_validation = &self.new_validation;
+        let range = parse_range(new_validation.range.trim())
+            .ok_or_else(|| format!("invalid range {:?}", new_validation.range))?;
+        let bound = |text: &str| match text.trim() {
+            "" => Ok(None),
+            text => text
+                .parse::<f64>()
+                .map(Some)
+                .map_err(|_| format!("{:?} is not a number", text)),
+        };
+        let first = new_validation.first.trim();
+        let criterion = match new_validation.kind {
+            ValidationKind::Number | ValidationKind::WholeNumber => {
+                let (min, max) = (bound(first)?, bound(&new_validation.second)?);
+                if let (Some(min), Some(max)) = (min, max)
+                    && min > max
                 {
-                    self.update_cell(editing_row, editing_col, self.editing_formula.clone());
-                    self.editing_cell = None;
-                    self.editing_formula.clear();
+                    return Err("the minimum is above the maximum".to_string());
+                }
+                if new_validation.kind == ValidationKind::Number {
+                    Criterion::Number { min, max }
+                } else {
+                    Criterion::Integer { min, max }
+                }
+            }
+            ValidationKind::List => {
+                let values: Vec<String> = first
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .map(String::from)
+                    .collect();
+                if values.is_empty() {
+                    return Err("enter the allowed values, separated by commas".to_string());
                 }
+                Criterion::List { values }
+            }
+            ValidationKind::Pattern => {
+                if regex::Regex::new(first).is_err() {
+                    return Err(format!("invalid pattern {:?}", first));
+                }
+                Criterion::Regex {
+                    pattern: first.to_string(),
+                }
+            }
+            ValidationKind::Formula => {
+                if !first.starts_with('=') {
+                    return Err("formulas start with =".to_string());
+                }
+                if let Some(problem) = self.sheet.check_formula(first) {
+                    return Err(problem);
+                }
+                Criterion::Formula {
+                    formula: first.to_string(),
+                }
+            }
+        };
+        Ok(Validation {
+            range,
+            criterion,
+            severity: if new_validation.warn {
+                Severity::Warn
+            } else {
+                Severity::Reject
+            },
+            message: new_validation.message.trim().to_string(),
+        })
+    }
 
-                // Single-click to edit - start editing the cell immediately
-                if self.editing_cell != Some((row, col)) {
-                    let formula = self.formulas.get(&(row, col)).cloned().unwrap_or_default();
-                    self.editing_cell = Some((row, col));
-                    self.editing_formula = formula;
+    // Commits the cell being edited. Changed input breaking a validation
+    // rule opens a prompt instead and the cell stays in edit; None then.
+    fn commit_edit(&mut self) -> Option<Task<Message>> {
+        let Some(cell) = self.editing_cell else {
+            return Some(Task::none());
+        };
+        let current = self.sheet.formulas.get(&cell).map_or("", String::as_str);
+        if current != self.editing_formula
+            && let Some(rule) = validation::check(&self.sheet, cell, &self.editing_formula)
+        {
+            self.validation_prompt = Some(ValidationPrompt {
+                message: rule.explanation(),
+                warn: rule.severity == Severity::Warn,
+            });
+            return None;
+        }
+        Some(self.apply_edit())
+    }
+
+    // Commits the cell being edited without checking it
+    fn apply_edit(&mut self) -> Task<Message> {
+        self.validation_prompt = None;
+        let Some((row, col)) = self.editing_cell else {
+            return Task::none();
+        };
+        let task = self.update_cell(row, col, self.editing_formula.clone());
+        self.editing_cell = None;
+        self.editing_formula.clear();
+        task
+    }
+
+    // Applies an update from the collaboration server
+    fn receive(&mut self, update: Update) -> Task<Message> {
+        let Some(collab) = &mut self.collab else {
+            return Task::none();
+        };
+        match update {
+            // Joining takes the formulas of the server's sheet
+            Update::Welcome {
+                client,
+                edits,
+                peers,
+            } => {
+                collab.replica = Replica::welcomed(client, &edits);
+                collab.peers = peers.into_iter().map(|p| (p.client, p)).collect();
+                let mut formulas: BTreeMap<Cell, String> = self
+                    .sheet
+                    .formulas
+                    .keys()
+                    .map(|cell| (*cell, String::new()))
+                    .collect();
+                for edit in edits {
+                    if let Some(cell) = Self::remote_cell(&self.sheet, &edit) {
+                        formulas.insert(cell, edit.formula);
+                    }
                 }
+                self.enter(formulas.into_iter().collect())
             }
-            Message::FormulaChanged(new_formula) => {
-                self.editing_formula = new_formula;
+            Update::Edit(edit) => match Self::remote_cell(&self.sheet, &edit) {
+                Some(cell) if collab.replica.merge(cell, edit.stamp) => {
+                    self.enter(vec![(cell, edit.formula)])
+                }
+                _ => Task::none(),
+            },
+            Update::Select(peer) => {
+                collab.peers.insert(peer.client, peer);
+                Task::none()
             }
-            Message::FinishEditing => {
-                if let Some((row, col)) = self.editing_cell {
-                    self.update_cell(row, col, self.editing_formula.clone());
-                    self.editing_cell = None;
-                    self.editing_formula.clear();
+            Update::Leave { client } => {
+                collab.peers.remove(&client);
+                Task::none()
+            }
+            Update::Hello { .. } => Task::none(),
+        }
+    }
+
+    // Cell of an edit from the server, unless it lies outside the sheet,
+    // where it is dropped
+    fn remote_cell(sheet: &Sheet, edit: &Edit) -> Option<Cell> {
+        let cell = parse_cell_reference(&edit.cell).filter(|cell| sheet.contains(*cell));
+        if cell.is_none() {
+            eprintln!(
+                "cells: dropped an edit of {:?}, not a cell of the sheet",
+                edit.cell
+            );
+        }
+        cell
+    }
+
+    // Tells other users about a new selection
+    fn share_selection(&mut self) {
+        if let Some(collab) = &mut self.collab
+            && collab.selection != self.editing_cell
+        {
+            collab.selection = self.editing_cell;
+            let _ = collab.outbox.send(Update::Select(Peer {
+                client: collab.replica.client(),
+                user: self.collab_user.clone(),
+                cell: self.editing_cell.map(cell_name),
+            }));
+        }
+    }
+
+    // Other users who selected the cell
+    fn peers_at(&self, cell: Cell) -> Vec<&Peer> {
+        let name = cell_name(cell);
+        self.collab
+            .iter()
+            .flat_map(|collab| collab.peers.values())
+            .filter(|peer| peer.cell.as_ref() == Some(&name))
+            .collect()
+    }
+
+    // Offers restoring the latest work that sessions which have ended left
+    // in their recovery files
+    fn offer_recovery(&mut self) {
+        let Some(dir) = self.recovery.as_ref().and_then(|r| r.path().parent()) else {
+            return;
+        };
+        for orphan in Recovery::orphans(dir) {
+            match orphan.pending() {
+                Ok(Some(recovered))
+                    if self
+                        .recovered
+                        .as_ref()
+                        .is_none_or(|offered| offered.time < recovered.time) =>
+                {
+                    self.recovered = Some(recovered)
                 }
+                Ok(_) => {}
+                Err(err) => self.file_status = Some(Err(err)),
             }
         }
-        Task::none()
     }
 
-    fn view(&self) -> Element<'_, Message> {
-        // Create complete grid (headers + data, all together)
-        let grid = self.create_complete_grid();
+    // Writes the sheet to the recovery file when it has changed since it
+    // was last saved or written there, and removes the recovery file when
+    // the sheet is back to what was saved. Work waiting to be restored is
+    // never overwritten.
+    fn autosave(&mut self) {
+        let Some(recovery) = &self.recovery else {
+            return;
+        };
+        if self.recovered.is_some() {
+            return;
+        }
+        let json = file::to_json(&self.sheet);
+        if json == self.saved_json {
+            if self.autosaved_json.is_some() {
+                self.forget_unsaved_work();
+            }
+        } else if self.autosaved_json.as_ref() != Some(&json) {
+            match recovery.write(&self.sheet, self.saved_to.as_deref()) {
+                Ok(()) => self.autosaved_json = Some(json),
+                Err(err) => self.file_status = Some(Err(err)),
+            }
+        }
+    }
 
-        let scrollable_grid = scrollable(grid)
-            .width(Length::Fill)
-            .height(Length::Fill)
-            .direction(scrollable::Direction::Both {
-                vertical: scrollable::Scrollbar::default(),
-                horizontal: scrollable::Scrollbar::default(),
-            });
+    // Removes the recovery file
+    fn forget_unsaved_work(&mut self) {
+        self.autosaved_json = None;
+        if let Some(recovery) = &self.recovery
+            && let Err(err) = recovery.discard()
+        {
+            self.file_status = Some(Err(err));
+        }
+    }
 
-        container(scrollable_grid)
-            .width(Length::Fill)
-            .height(Length::Fill)
-            .padding(10)
-            .into()
+    // Edits another sheet in place of the current one, unless collaborating,
+    // where the sheet is shared
+    fn replace_sheet(&mut self, sheet: Sheet) -> Result<(), String> {
+        if self.collab.is_some() {
+            return Err("stop collaborating to edit another sheet".to_string());
+        }
+        self.cancel_recalculation();
+        self.sheet = sheet;
+        self.editing_cell = None;
+        self.editing_formula.clear();
+        self.validation_prompt = None;
+        self.traced_cell = None;
+        self.script_editor = text_editor::Content::with_text(self.sheet.script().source());
+        self.script_error = None;
+        if let Some(iteration) = self.sheet.iteration() {
+            self.max_iterations = iteration.max_iterations.to_string();
+            self.max_change = iteration.max_change.to_string();
+        }
+        self.refresh_formats();
+        Ok(())
+    }
+
+    // Whole-sheet recalculations supersede any background recalculation
+    fn cancel_recalculation(&mut self) {
+        if let Some(recalculation) = self.recalculation.take() {
+            recalculation.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Turns iterative calculation on with the settings typed, unless they
+    // are invalid, or off
+    fn apply_iteration(&mut self, iterate: bool) {
+        let settings = (
+            self.max_iterations.trim().parse(),
+            self.max_change.trim().parse(),
+        );
+        let iteration = match settings {
+            _ if !iterate => None,
+            (Ok(max_iterations), Ok(max_change)) if max_iterations > 0 && max_change >= 0.0 => {
+                Some(Iteration {
+                    max_iterations,
+                    max_change,
+                })
+            }
+            _ => {
+                self.iteration_error =
+                    Some("enter a positive number of iterations and a change".to_string());
+                return;
+            }
+        };
+        self.iteration_error = None;
+        if iteration != self.sheet.iteration() {
+            self.cancel_recalculation();
+            self.sheet.set_iteration(iteration);
+            self.refresh_formats();
+        }
+    }
+
+    fn refresh_formats(&mut self) {
+        self.appearance = format::apply(&self.sheet);
+    }
+
+    // Role of every cell linked to the traced cell
+    fn trace_roles(&self) -> HashMap<Cell, TraceRole> {
+        let mut roles = HashMap::new();
+        if let Some(traced) = self.traced_cell {
+            for (cell, depth) in self.sheet.trace_dependents(traced) {
+                roles.insert(cell, TraceRole::Dependent(depth));
+            }
+            for (cell, depth) in self.sheet.trace_precedents(traced) {
+                roles.insert(cell, TraceRole::Precedent(depth));
+            }
+            roles.insert(traced, TraceRole::Traced);
+        }
+        roles
     }
 
     fn create_complete_grid(&self) -> Element<'_, Message> {
         let mut grid = Column::new();
+        let trace_roles = self.trace_roles();
+
+        // Cells read by the formula being typed
+        let referenced = match self.editing_cell {
+            Some(_) => syntax::references(&self.editing_formula, self.sheet.names()),
+            None => HashSet::new(),
+        };
+        let inserting = self.inserting_references();
 
         // Add column headers as first row
         let mut header_row = Row::new();
 
         // Corner cell
-        header_row = header_row.push(button(text("")).width(60).height(30).padding(5).style(
-            |theme: &iced::Theme, _status| {
-                let palette = theme.palette();
-                button::Style {
-                    background: Some(iced::Background::Color(palette.background)),
-                    border: iced::Border {
-                        color: palette.text.scale_alpha(0.3),
-                        width: 0.5,
-                        radius: 0.0.into(),
-                    },
-                    text_color: palette.text,
-                    ..Default::default()
-                }
-            },
-        ));
+        header_row = header_row.push(
+            button(text(""))
+                .width(HEADER_WIDTH)
+                .height(ROW_HEIGHT)
+                .padding(5)
+                .style(|theme: &iced::Theme, _status| {
+                    let palette = theme.palette();
+                    button::Style {
+                        background: Some(iced::Background::Color(palette.background)),
+                        border: iced::Border {
+                            color: palette.text.scale_alpha(0.3),
+                            width: 0.5,
+                            radius: 0.0.into(),
+                        },
+                        text_color: palette.text,
+                        ..Default::default()
+                    }
+                }),
+        );
 
         // Column headers (A, B, C...)
-        for col in 0..COLS {
+        for col in 0..self.sheet.cols() {
             header_row = header_row.push(
                 button(text(col_to_letter(col)).size(14))
-                    .width(80)
-                    .height(30)
+                    .width(CELL_WIDTH)
+                    .height(ROW_HEIGHT)
                     .padding(5)
                     .style(|theme: &iced::Theme, _status| {
                         let palette = theme.palette();
@@ -154,15 +2592,19 @@ impl App {
         }
         grid = grid.push(header_row);
 
-        // Data rows
-        for row in 0..ROWS {
+        // Data rows. Merged cells leave a gap in their rows and are laid
+        // over the grid, spanning the rows and columns they cover.
+        let heights = self.row_heights();
+        let mut merged = Vec::new();
+        let mut y = ROW_HEIGHT;
+        for (row, &height) in heights.iter().enumerate() {
             let mut data_row = Row::new();
 
             // Row header
             data_row = data_row.push(
                 button(text(format!("{}", row)).size(14))
-                    .width(60)
-                    .height(30)
+                    .width(HEADER_WIDTH)
+                    .height(height)
                     .padding(5)
                     .style(|theme: &iced::Theme, _status| {
                         let palette = theme.palette();
@@ -180,327 +2622,800 @@ impl App {
             );
 
             // Data cells
-            for col in 0..COLS {
-                let is_editing = self.editing_cell == Some((row, col));
-
-                // Show editing formula if this cell is being edited, otherwise show the value
-                let cell_content = if is_editing {
-                    self.editing_formula.clone()
-                } else {
-                    self.get_cell_display(row, col)
+            for col in 0..self.sheet.cols() {
+                let cell_widget = match self.sheet.merged((row, col)) {
+                    Some(merge) => {
+                        if merge.start == (row, col) {
+                            let width = merge.cols() as f32 * CELL_WIDTH;
+                            let height = heights[row..=merge.end.0.min(self.sheet.rows() - 1)]
+                                .iter()
+                                .sum();
+                            let x = HEADER_WIDTH + col as f32 * CELL_WIDTH;
+                            let widget = self.cell_widget(
+                                (row, col),
+                                Size::new(width, height),
+                                &trace_roles,
+                                &referenced,
+                                inserting,
+                            );
+                            merged.push(pin(widget).x(x).y(y).into());
+                        }
+                        space().width(CELL_WIDTH).height(height).into()
+                    }
+                    None => self.cell_widget(
+                        (row, col),
+                        Size::new(CELL_WIDTH, height),
+                        &trace_roles,
+                        &referenced,
+                        inserting,
+                    ),
                 };
+                data_row = data_row.push(cell_widget);
+            }
+            grid = grid.push(data_row);
+            y += height;
+        }
+
+        if merged.is_empty() {
+            grid.into()
+        } else {
+            Stack::with_children(std::iter::once(grid.into()).chain(merged)).into()
+        }
+    }
 
-                // Determine alignment: numbers right, text left
-                let is_number = self.is_cell_number(row, col);
+    // Widget of a data cell, or of a merge when given its top-left cell
+    fn cell_widget(
+        &self,
+        (row, col): Cell,
+        size: Size,
+        trace_roles: &HashMap<Cell, TraceRole>,
+        referenced: &HashSet<Cell>,
+        inserting: bool,
+    ) -> Element<'_, Message> {
+        let is_editing = self.editing_cell == Some((row, col));
+
+        // Show editing formula if this cell is being edited, otherwise show the value
+        let cell_content = if is_editing {
+            self.editing_formula.clone()
+        } else {
+            self.get_cell_display(row, col)
+        };
+
+        // Determine alignment: numbers right, text left
+        let is_number = self.is_cell_number(row, col);
+
+        // Cells validated against a list pick from a dropdown
+        let choices = validation::choices(&self.sheet, (row, col));
+        if is_editing && let Some(choices) = choices {
+            let selected = choices
+                .iter()
+                .find(|choice| choice.eq_ignore_ascii_case(self.editing_formula.trim()))
+                .cloned();
+            return pick_list(choices.to_vec(), selected, Message::ChoiceSelected)
+                .text_size(14)
+                .width(size.width)
+                .into();
+        }
 
-                // Create cell widget with consistent structure
-                let cell_widget: Element<'_, Message> = if is_editing {
-                    // Editing cell - show text input with primary border
-                    let cell_id: &'static str =
-                        Box::leak(format!("cell-input-{}-{}", row, col).into_boxed_str());
-                    container(
-                        text_input("", &cell_content)
-                            .on_input(Message::FormulaChanged)
-                            .on_submit(Message::FinishEditing)
-                            .size(14)
-                            .padding([5, 5])
-                            .id(Id::new(cell_id))
-                            .style(|theme: &iced::Theme, _status| {
-                                let palette = theme.palette();
-                                text_input::Style {
-                                    background: iced::Background::Color(palette.background),
-                                    border: iced::Border {
-                                        color: iced::Color::TRANSPARENT,
-                                        width: 0.0,
-                                        radius: 0.0.into(),
-                                    },
-                                    icon: palette.text,
-                                    placeholder: palette.text,
-                                    value: palette.text,
-                                    selection: palette.primary,
-                                }
-                            }),
-                    )
-                    .width(80)
-                    .height(30)
-                    .style(|theme: &iced::Theme| {
+        // Create cell widget with consistent structure
+        if is_editing {
+            // Editing cell - show text input with primary border
+            let cell_id: &'static str =
+                Box::leak(format!("cell-input-{}-{}", row, col).into_boxed_str());
+            container(
+                text_input("", &cell_content)
+                    .on_input(Message::FormulaChanged)
+                    .on_submit(Message::FinishEditing)
+                    .size(14)
+                    .padding([5, 5])
+                    .id(Id::new(cell_id))
+                    .style(|theme: &iced::Theme, _status| {
                         let palette = theme.palette();
-                        container::Style {
+                        text_input::Style {
+                            background: iced::Background::Color(palette.background),
                             border: iced::Border {
-                                color: palette.primary,
-                                width: 1.5,
+                                color: iced::Color::TRANSPARENT,
+                                width: 0.0,
                                 radius: 0.0.into(),
                             },
-                            background: Some(iced::Background::Color(palette.background)),
-                            ..Default::default()
+                            icon: palette.text,
+                            placeholder: palette.text,
+                            value: palette.text,
+                            selection: palette.primary,
                         }
-                    })
-                    .into()
-                } else {
-                    // Normal cell - show as button with aligned text
-                    let text_widget = if is_number {
-                        // Numbers: right-aligned
-                        container(text(cell_content.clone()).size(14))
-                            .width(Length::Fill)
-                            .align_right(70)
-                    } else {
-                        // Text: left-aligned
-                        container(text(cell_content.clone()).size(14))
-                            .width(Length::Fill)
-                            .align_left(5)
-                    };
+                    }),
+            )
+            .width(size.width)
+            .height(size.height)
+            .style(|theme: &iced::Theme| {
+                let palette = theme.palette();
+                container::Style {
+                    border: iced::Border {
+                        color: palette.primary,
+                        width: 1.5,
+                        radius: 0.0.into(),
+                    },
+                    background: Some(iced::Background::Color(palette.background)),
+                    ..Default::default()
+                }
+            })
+            .into()
+        } else {
+            // Normal cell - show as button with aligned text, on one line
+            // unless wrapping is turned on for it
+            let wrapping = if self.sheet.wrapped.contains(&(row, col)) {
+                text::Wrapping::Word
+            } else {
+                text::Wrapping::None
+            };
+            let label = text(cell_content.clone()).size(14).wrapping(wrapping);
+            let text_widget = if is_number {
+                // Numbers: right-aligned
+                container(label)
+                    .width(Length::Fill)
+                    .align_right(size.width - 10.0)
+            } else {
+                // Text: left-aligned
+                container(label).width(Length::Fill).align_left(5)
+            };
 
-                    let cell_id: &'static str =
-                        Box::leak(format!("cell-{}-{}", row, col).into_boxed_str());
+            // Data bars are drawn behind the value
+            let appearance = self.appearance.get(&(row, col)).copied();
+            let text_widget: Element<'_, Message> = match appearance.and_then(|a| a.bar) {
+                Some((fraction, color)) => stack![
                     container(
-                        button(text_widget)
-                            .on_press(Message::CellClicked(row, col))
-                            .width(80)
-                            .height(30)
-                            .padding(5)
-                            .style(|theme: &iced::Theme, _status| {
-                                let palette = theme.palette();
-                                button::Style {
-                                    background: Some(iced::Background::Color(palette.background)),
-                                    border: iced::Border {
-                                        color: palette.text.scale_alpha(0.3),
-                                        width: 0.5,
-                                        radius: 0.0.into(),
-                                    },
-                                    text_color: palette.text,
-                                    ..Default::default()
-                                }
-                            }),
+                        container(space())
+                            .width(fraction as f32 * (size.width - 10.0))
+                            .height(Length::Fill)
+                            .style(move |_theme| {
+                                container::background(to_color(color).scale_alpha(0.6))
+                            })
                     )
-                    .id(Id::new(cell_id))
+                    .width(Length::Fill)
+                    .height(Length::Fill),
+                    text_widget,
+                ]
+                .into(),
+                None => text_widget.into(),
+            };
+            let fill = appearance.and_then(|a| a.fill);
+
+            // Traced cells: precedents tinted with the primary color,
+            // dependents with the success color, fading with depth.
+            // Tracing takes precedence over conditional fills.
+            let role = trace_roles.get(&(row, col)).copied();
+            let is_referenced = referenced.contains(&(row, col));
+            // Cells selected by other users get their color
+            let peers = self.peers_at((row, col));
+            let peer = peers.first().map(|peer| peer_color(peer.client));
+
+            let cell_id: &'static str = Box::leak(format!("cell-{}-{}", row, col).into_boxed_str());
+            let cell_button = button(text_widget)
+                .on_press_maybe((!inserting).then_some(Message::CellClicked(row, col)))
+                .width(size.width)
+                .height(size.height)
+                .padding(5)
+                .style(move |theme: &iced::Theme, _status| {
+                    let palette = theme.palette();
+                    let fade = |depth: usize| if depth == 1 { 0.4 } else { 0.15 };
+                    let background = match role {
+                        Some(TraceRole::Precedent(depth)) => {
+                            palette.primary.scale_alpha(fade(depth))
+                        }
+                        Some(TraceRole::Dependent(depth)) => {
+                            palette.success.scale_alpha(fade(depth))
+                        }
+                        None => match fill {
+                            Some(fill) => to_color(fill).scale_alpha(0.6),
+                            None => palette.background,
+                        },
+                        Some(TraceRole::Traced) => palette.background,
+                    };
+                    let (border_color, border_width) = match (role, peer) {
+                        (Some(TraceRole::Traced), _) => (palette.primary, 2.0),
+                        _ if is_referenced => (palette.warning, 2.0),
+                        (_, Some(color)) => (color, 2.0),
+                        _ => (palette.text.scale_alpha(0.3), 0.5),
+                    };
+                    button::Style {
+                        background: Some(iced::Background::Color(background)),
+                        border: iced::Border {
+                            color: border_color,
+                            width: border_width,
+                            radius: 0.0.into(),
+                        },
+                        text_color: palette.text,
+                        ..Default::default()
+                    }
+                });
+
+            // While inserting references the button is disabled, so
+            // that presses reach the mouse area right away instead of
+            // on release
+            let cell_button: Element<'_, Message> = if inserting {
+                mouse_area(cell_button)
+                    .on_press(Message::ReferencePressed((row, col)))
+                    .on_enter(Message::ReferenceEntered((row, col)))
                     .into()
-                };
+            } else {
+                cell_button.into()
+            };
 
-                data_row = data_row.push(cell_widget);
+            let cell_element: Element<'_, Message> =
+                container(cell_button).id(Id::new(cell_id)).into();
+
+            // Commented cells get a mark in their top-right corner. Hovering
+            // them shows the comment, and hovering cells selected by other
+            // users shows who they are.
+            let comment = self.sheet.comments.get(&(row, col));
+            if comment.is_none() && peers.is_empty() {
+                return cell_element;
             }
-            grid = grid.push(data_row);
+            let mut popup = Column::new().spacing(4).max_width(240);
+            for peer in &peers {
+                popup = popup.push(
+                    text(format!("{} is here", peer.user))
+                        .size(12)
+                        .color(peer_color(peer.client)),
+                );
+            }
+            let mut cell_element = stack![cell_element];
+            if let Some(comment) = comment {
+                let mark = container(space())
+                    .width(COMMENT_MARK)
+                    .height(COMMENT_MARK)
+                    .style(|theme: &iced::Theme| container::background(theme.palette().danger));
+                cell_element = cell_element.push(pin(mark).x(size.width - COMMENT_MARK));
+                popup = popup
+                    .push(text(describe_comment(comment)).size(12))
+                    .push(text(comment.text.clone()).size(14));
+            }
+            tooltip(
+                cell_element,
+                container(popup).padding(8).style(container::bordered_box),
+                tooltip::Position::Bottom,
+            )
+            .into()
         }
+    }
 
-        grid.into()
+    // Height of every row: rows holding wrapped text grow to fit it, except
+    // under merges spanning several rows, which keep the height they span
+    fn row_heights(&self) -> Vec<f32> {
+        let mut heights = vec![ROW_HEIGHT; self.sheet.rows()];
+        for &cell in &self.sheet.wrapped {
+            let span = self.sheet.merged(cell).unwrap_or(Range::new(cell, cell));
+            if span.start != cell || span.rows() > 1 || cell.0 >= self.sheet.rows() {
+                continue;
+            }
+            let width = span.cols() as f32 * CELL_WIDTH - 10.0;
+            let lines = wrapped_lines(&self.get_cell_display(cell.0, cell.1), width);
+            heights[cell.0] = heights[cell.0].max(lines as f32 * LINE_HEIGHT + 12.0);
+        }
+        heights
     }
 
     fn get_cell_display(&self, row: usize, col: usize) -> String {
-        match self.values.get(&(row, col)) {
-            Some(CellValue::Number(value)) => format!("{:.2}", value),
-            Some(CellValue::Text(text)) => text.clone(),
-            Some(CellValue::Error(err)) => format!("#{}", err),
+        if self.is_pending(row, col) {
+            return "calculating…".to_string();
+        }
+        match self.sheet.value((row, col)) {
+            Some(value) => self.format_value((row, col), value),
             None => String::new(),
         }
     }
 
-    fn is_cell_number(&self, row: usize, col: usize) -> bool {
-        matches!(self.values.get(&(row, col)), Some(CellValue::Number(_)))
+    // Text shown for a value in the number format of a cell
+    fn format_value(&self, cell: Cell, value: &CellValue) -> String {
+        let code = self.sheet.number_formats.get(&cell).map(String::as_str);
+        number_format::display(value, code)
     }
 
-    fn update_cell(&mut self, row: usize, col: usize, formula: String) {
-        let formula = formula.trim().to_string();
-
-        // Remove old dependencies
-        if let Some(old_deps) = self.dependencies.remove(&(row, col)) {
-            for dep in old_deps {
-                if let Some(rev_deps) = self.dependents.get_mut(&dep) {
-                    rev_deps.remove(&(row, col));
-                }
-            }
-        }
-
-        // Update or remove the formula
-        if formula.is_empty() {
-            self.formulas.remove(&(row, col));
-            self.values.remove(&(row, col));
-        } else {
-            self.formulas.insert((row, col), formula.clone());
+    fn is_cell_number(&self, row: usize, col: usize) -> bool {
+        !self.is_pending(row, col)
+            && self
+                .sheet
+                .value((row, col))
+                .is_some_and(|value| value.as_number().is_some())
+    }
 
-            // Parse and evaluate the formula
-            let deps = parse_dependencies(&formula);
-            if !deps.is_empty() {
-                self.dependencies.insert((row, col), deps.clone());
-                for dep in deps {
-                    self.dependents.entry(dep).or_default().insert((row, col));
-                }
-            }
+    // Whether the cell is waiting for a background recalculation
+    fn is_pending(&self, row: usize, col: usize) -> bool {
+        self.recalculation
+            .as_ref()
+            .is_some_and(|r| r.pending.contains(&(row, col)))
+    }
 
-            let value = self.evaluate_formula(&formula);
-            self.values.insert((row, col), value);
+    // Enters a formula typed here, sharing it with other users when
+    // collaborating
+    fn update_cell(&mut self, row: usize, col: usize, formula: String) -> Task<Message> {
+        let current = self
+            .sheet
+            .formulas
+            .get(&(row, col))
+            .map_or("", String::as_str);
+        if let Some(collab) = &mut self.collab
+            && current != formula.trim()
+        {
+            let edit = Edit {
+                cell: cell_name((row, col)),
+                formula: formula.clone(),
+                stamp: collab.replica.stamp((row, col)),
+            };
+            let _ = collab.outbox.send(Update::Edit(edit));
         }
-
-        // Propagate changes to dependent cells
-        self.propagate_changes(row, col);
+        self.enter(vec![((row, col), formula)])
     }
 
-    fn evaluate_formula(&self, formula: &str) -> CellValue {
-        let formula = formula.trim();
+    fn enter(&mut self, edits: Vec<(Cell, String)>) -> Task<Message> {
+        let mut roots = Vec::new();
+        for (cell, formula) in edits {
+            roots.extend(self.sheet.edit(cell, &formula));
+        }
+        self.recalculate(roots)
+    }
 
-        // Empty formula
-        if formula.is_empty() {
-            return CellValue::Text(String::new());
+    // Small recalculations are done right away. Heavy ones, those calling
+    // functions of the script, which may each run up to its time limit, and
+    // any made while a background recalculation is still running, are done
+    // on a snapshot of the sheet in a worker thread. A new one cancels the
+    // recalculation in flight and takes over its roots.
+    fn recalculate(&mut self, mut roots: Vec<Cell>) -> Task<Message> {
+        if let Some(previous) = self.recalculation.take() {
+            previous.cancel.store(true, Ordering::Relaxed);
+            roots.extend(previous.roots);
         }
 
-        // If it starts with '=', it's a formula
-        if let Some(stripped) = formula.strip_prefix('=') {
-            match self.evaluate_expression(stripped) {
-                Ok(num) => CellValue::Number(num),
-                Err(err) => CellValue::Error(err),
-            }
-        } else {
-            // Try to parse as a number
-            match formula.parse::<f64>() {
-                Ok(num) => CellValue::Number(num),
-                Err(_) => CellValue::Text(formula.to_string()), // It's text/label
-            }
+        let mut pending = self.sheet.dirty_cells(roots.clone());
+        if pending.len() < BACKGROUND_THRESHOLD && !self.sheet.calls_script(&pending) {
+            self.sheet.recalculate(roots);
+            self.refresh_formats();
+            return Task::none();
         }
+
+        // Constants are already up to date
+        pending.retain(|cell| {
+            self.sheet
+                .formulas
+                .get(cell)
+                .is_some_and(|formula| formula.starts_with('='))
+        });
+
+        self.generation += 1;
+        let generation = self.generation;
+        let cancel = Arc::new(AtomicBool::new(false));
+        let task = Task::future(recalculate_in_background(
+            self.sheet.clone(),
+            roots.clone(),
+            cancel.clone(),
+        ))
+        .and_then(move |results| Task::done(Message::Recalculated(generation, results)));
+
+        self.recalculation = Some(Recalculation {
+            generation,
+            roots,
+            pending,
+            cancel,
+        });
+        task
     }
+}
 
-    fn evaluate_expression(&self, expr: &str) -> Result<f64, String> {
-        let expr = expr.trim();
+// Colors of successive series and pie slices
+const SERIES_COLORS: [Color; 6] = [
+    Color::from_rgb(0.26, 0.52, 0.96),
+    Color::from_rgb(0.96, 0.42, 0.26),
+    Color::from_rgb(0.30, 0.69, 0.31),
+    Color::from_rgb(0.98, 0.75, 0.18),
+    Color::from_rgb(0.61, 0.35, 0.71),
+    Color::from_rgb(0.36, 0.75, 0.82),
+];
+
+// Draws a chart with a snapshot of its data taken when the view was built,
+// so it is redrawn with every change to the values of its range
+struct ChartCanvas {
+    kind: ChartKind,
+    data: ChartData,
+}
 
-        // Try to parse as a simple number first
-        if let Ok(num) = expr.parse::<f64>() {
-            return Ok(num);
+impl canvas::Program<Message> for ChartCanvas {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        renderer: &iced::Renderer,
+        theme: &Theme,
+        bounds: Rectangle,
+        _cursor: mouse::Cursor,
+    ) -> Vec<canvas::Geometry> {
+        let mut frame = canvas::Frame::new(renderer, bounds.size());
+        let palette = theme.palette();
+        frame.fill_rectangle(Point::ORIGIN, bounds.size(), palette.background);
+
+        if self.kind == ChartKind::Pie {
+            self.draw_pie(&mut frame);
+            return vec![frame.into_geometry()];
         }
 
-        // Check if it's a cell reference (e.g., "A0", "Z99")
-        if let Some(cell_ref) = parse_cell_reference(expr) {
-            return match self.values.get(&cell_ref) {
-                Some(CellValue::Number(n)) => Ok(*n),
-                Some(CellValue::Text(_)) => Err("TEXT".to_string()),
-                Some(CellValue::Error(e)) => Err(e.clone()),
-                None => Ok(0.0),
-            };
+        let points = || self.data.series.iter().flat_map(|s| s.points.iter());
+        if points().next().is_none() {
+            return vec![frame.into_geometry()];
+        }
+
+        // Bars and lines grow from zero; scatter plots fit their x values
+        let (mut x_min, mut x_max) = match self.kind {
+            ChartKind::Scatter => points()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), p| {
+                    (lo.min(p.0), hi.max(p.0))
+                }),
+            _ => (0.0, self.data.labels.len().saturating_sub(1) as f64),
+        };
+        let (y_min, mut y_max) =
+            points().fold((0.0f64, 0.0f64), |(lo, hi), p| (lo.min(p.1), hi.max(p.1)));
+        if x_max <= x_min {
+            x_min -= 1.0;
+            x_max += 1.0;
         }
+        if y_max <= y_min {
+            y_max = y_min + 1.0;
+        }
+
+        let margin = 10.0;
+        let plot = Rectangle::new(
+            Point::new(margin, margin),
+            Size::new(bounds.width - 2.0 * margin, bounds.height - 2.0 * margin),
+        );
+        // Bars take one slot per label, points are spread edge to edge
+        let slots = self.data.labels.len().max(1) as f32;
+        let x_of = |x: f64| match self.kind {
+            ChartKind::Bar => plot.x + (x as f32 + 0.5) / slots * plot.width,
+            _ => plot.x + ((x - x_min) / (x_max - x_min)) as f32 * plot.width,
+        };
+        let y_of =
+            |y: f64| plot.y + plot.height - ((y - y_min) / (y_max - y_min)) as f32 * plot.height;
+
+        let axis = canvas::Stroke::default()
+            .with_color(palette.text.scale_alpha(0.5))
+            .with_width(1.0);
+        frame.stroke(
+            &canvas::Path::line(
+                Point::new(plot.x, y_of(0.0)),
+                Point::new(plot.x + plot.width, y_of(0.0)),
+            ),
+            axis,
+        );
+        frame.stroke(
+            &canvas::Path::line(
+                Point::new(plot.x, plot.y),
+                Point::new(plot.x, plot.y + plot.height),
+            ),
+            axis,
+        );
 
-        // Handle arithmetic expressions
-        // Support: +, -, *, /
-        for (i, ch) in expr.char_indices() {
-            if ch == '+' || ch == '-' || ch == '*' || ch == '/' {
-                let left = self.evaluate_expression(&expr[..i])?;
-                let right = self.evaluate_expression(&expr[i + 1..])?;
-
-                return match ch {
-                    '+' => Ok(left + right),
-                    '-' => Ok(left - right),
-                    '*' => Ok(left * right),
-                    '/' => {
-                        if right == 0.0 {
-                            Err("DIV0".to_string())
-                        } else {
-                            Ok(left / right)
+        let series_count = self.data.series.len().max(1) as f32;
+        for (index, series) in self.data.series.iter().enumerate() {
+            let color = SERIES_COLORS[index % SERIES_COLORS.len()];
+            match self.kind {
+                ChartKind::Line => {
+                    let path = canvas::Path::new(|builder| {
+                        for (i, (x, y)) in series.points.iter().enumerate() {
+                            let point = Point::new(x_of(*x), y_of(*y));
+                            if i == 0 {
+                                builder.move_to(point);
+                            } else {
+                                builder.line_to(point);
+                            }
                         }
+                    });
+                    frame.stroke(
+                        &path,
+                        canvas::Stroke::default().with_color(color).with_width(2.0),
+                    );
+                }
+                ChartKind::Scatter => {
+                    for (x, y) in &series.points {
+                        frame.fill(
+                            &canvas::Path::circle(Point::new(x_of(*x), y_of(*y)), 3.0),
+                            color,
+                        );
                     }
-                    _ => unreachable!(),
-                };
+                }
+                ChartKind::Bar => {
+                    // Series side by side within each slot
+                    let width = plot.width / slots * 0.8 / series_count;
+                    for (x, y) in &series.points {
+                        let left = x_of(*x) - width * series_count / 2.0 + width * index as f32;
+                        let (top, bottom) = (y_of(y.max(0.0)), y_of(y.min(0.0)));
+                        frame.fill_rectangle(
+                            Point::new(left, top),
+                            Size::new(width, bottom - top),
+                            color,
+                        );
+                    }
+                }
+                ChartKind::Pie => unreachable!("pie charts are drawn above"),
             }
         }
 
-        Err("ERR".to_string())
+        vec![frame.into_geometry()]
     }
+}
 
-    fn propagate_changes(&mut self, row: usize, col: usize) {
-        let mut to_update: Vec<(usize, usize)> = Vec::new();
-        let mut visited: HashSet<(usize, usize)> = HashSet::new();
+impl ChartCanvas {
+    // One slice per positive value of the first series
+    fn draw_pie(&self, frame: &mut canvas::Frame) {
+        let Some(series) = self.data.series.first() else {
+            return;
+        };
+        let slices: Vec<f64> = series.points.iter().map(|p| p.1.max(0.0)).collect();
+        let total: f64 = slices.iter().sum();
+        if total <= 0.0 {
+            return;
+        }
 
-        // Collect all dependent cells
-        if let Some(deps) = self.dependents.get(&(row, col)) {
-            to_update.extend(deps.iter().copied());
+        let center = frame.center();
+        let radius = frame.width().min(frame.height()) / 2.0 - 10.0;
+        let mut start = -std::f32::consts::FRAC_PI_2;
+        for (index, slice) in slices.iter().enumerate() {
+            let end = start + (slice / total) as f32 * std::f32::consts::TAU;
+            let path = canvas::Path::new(|builder| {
+                builder.move_to(center);
+                builder.arc(canvas::path::Arc {
+                    center,
+                    radius,
+                    start_angle: Radians(start),
+                    end_angle: Radians(end),
+                });
+                builder.close();
+            });
+            frame.fill(&path, SERIES_COLORS[index % SERIES_COLORS.len()]);
+            start = end;
         }
+    }
+}
 
-        while let Some(cell) = to_update.pop() {
-            if visited.contains(&cell) {
-                continue;
+// Lines taken by text wrapped at word boundaries to a width in pixels.
+// Words longer than a line are broken.
+fn wrapped_lines(text: &str, width: f32) -> usize {
+    let per_line = ((width / CHAR_WIDTH) as usize).max(1);
+    text.split('\n')
+        .map(|line| {
+            let (mut lines, mut used) = (1, 0);
+            for word in line.split_whitespace() {
+                let len = word.chars().count();
+                if used > 0 && used + 1 + len > per_line {
+                    lines += 1;
+                    used = 0;
+                }
+                used += if used > 0 { len + 1 } else { len };
+                while used > per_line {
+                    lines += 1;
+                    used -= per_line;
+                }
             }
-            visited.insert(cell);
+            lines
+        })
+        .sum()
+}
+
+fn to_color(rgb: Rgb) -> Color {
+    Color::from_rgb8(rgb.0, rgb.1, rgb.2)
+}
 
-            // Re-evaluate this cell
-            if let Some(formula) = self.formulas.get(&cell).cloned() {
-                let value = self.evaluate_formula(&formula);
-                self.values.insert(cell, value);
+// Short description of a conditional formatting rule, e.g. "A0:A9  > 3"
+// Author and time of a comment, e.g. "ana, 2024-05-01 09:30 UTC"
+fn describe_comment(comment: &Comment) -> String {
+    let time = comment
+        .time
+        .get(..16)
+        .map(|time| format!("{} UTC", time.replace('T', " ")))
+        .unwrap_or_else(|| comment.time.clone());
+    match (comment.author.is_empty(), time.is_empty()) {
+        (true, _) => time,
+        (false, true) => comment.author.clone(),
+        (false, false) => format!("{}, {}", comment.author, time),
+    }
+}
 
-                // Add its dependents to the queue
-                if let Some(deps) = self.dependents.get(&cell) {
-                    to_update.extend(deps.iter().copied());
-                }
-            }
+fn describe_validation(validation: &Validation) -> String {
+    let rule = match &validation.criterion {
+        Criterion::Number { min, max } | Criterion::Integer { min, max } => {
+            let kind = if matches!(validation.criterion, Criterion::Number { .. }) {
+                "number"
+            } else {
+                "whole number"
+            };
+            let bound = |b: &Option<f64>| b.map(|b| b.to_string()).unwrap_or_default();
+            format!("{} {}..{}", kind, bound(min), bound(max))
         }
-    }
+        Criterion::List { values } => values.join(", "),
+        Criterion::Regex { pattern } => format!("matches {}", pattern),
+        Criterion::Formula { formula } => formula.clone(),
+    };
+    let severity = match validation.severity {
+        Severity::Reject => "",
+        Severity::Warn => " (warning)",
+    };
+    format!("{}  {}{}", validation.range, rule, severity)
 }
 
-fn col_to_letter(col: usize) -> String {
-    ((b'A' + col as u8) as char).to_string()
+// Explains why the input being committed was refused, with the ways out
+// Offer of restoring the work an earlier session did not save
+fn recovery_prompt(recovered: &Recovered) -> Element<'_, Message> {
+    let seconds = recovered
+        .time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let time = comment::timestamp(seconds);
+    let source = match &recovered.file {
+        Some(file) => format!(" to {}", file.display()),
+        None => String::new(),
+    };
+    row![
+        text(format!(
+            "Changes{} were not saved, last written {} UTC",
+            source,
+            &time[..16].replace('T', " ")
+        ))
+        .size(14)
+        .style(text::warning),
+        button(text("Restore").size(14)).on_press(Message::RestoreRecovered),
+        button(text("Discard").size(14)).on_press(Message::DiscardRecovered),
+    ]
+    .spacing(10)
+    .align_y(Alignment::Center)
+    .into()
 }
 
-fn letter_to_col(letter: char) -> Option<usize> {
-    let letter = letter.to_ascii_uppercase();
-    if letter.is_ascii_uppercase() {
-        Some((letter as u8 - b'A') as usize)
+fn validation_prompt(prompt: &ValidationPrompt) -> Element<'_, Message> {
+    let mut actions = row![text(&prompt.message).size(14).style(if prompt.warn {
+        text::warning
     } else {
-        None
+        text::danger
+    })]
+    .spacing(10)
+    .align_y(Alignment::Center);
+    if prompt.warn {
+        actions = actions.push(button(text("Keep").size(14)).on_press(Message::KeepInvalid));
     }
+    actions
+        .push(button(text("Edit").size(14)).on_press(Message::RetryInput))
+        .push(button(text("Discard").size(14)).on_press(Message::CancelInput))
+        .into()
 }
 
-fn parse_cell_reference(s: &str) -> Option<(usize, usize)> {
-    let s = s.trim();
-    if s.is_empty() {
-        return None;
+// Grid of a data table's results, headed by its input values
+fn data_table(table: &DataTable) -> Element<'_, Message> {
+    let line = |heading: &str, cells: &[String]| {
+        Row::with_children(
+            std::iter::once(heading)
+                .chain(cells.iter().map(String::as_str))
+                .map(|value| text(value.to_string()).size(12).width(60).into()),
+        )
+        .spacing(4)
+    };
+    let mut grid = column![line("", &table.header)].spacing(2);
+    for (value, results) in &table.rows {
+        grid = grid.push(line(value, results));
     }
+    scrollable(grid)
+        .direction(scrollable::Direction::Horizontal(
+            scrollable::Scrollbar::default(),
+        ))
+        .into()
+}
+
+fn describe_rule(rule: &Rule) -> String {
+    let format = match &rule.format {
+        Format::Highlight { condition, .. } => match condition {
+            Condition::Compare { comparison, value } => {
+                let op = match comparison {
+                    Comparison::Greater => ">",
+                    Comparison::Less => "<",
+                    Comparison::Equal => "=",
+                    Comparison::NotEqual => "<>",
+                };
+                format!("{} {}", op, value)
+            }
+            Condition::Formula { formula } => formula.clone(),
+            Condition::Errors => "errors".to_string(),
+        },
+        Format::ColorScale { .. } => "color scale".to_string(),
+        Format::DataBar { .. } => "data bar".to_string(),
+    };
+    format!("{}  {}", rule.range, format)
+}
 
-    let mut chars = s.chars();
-    let first_char = chars.next()?;
-    let col = letter_to_col(first_char)?;
+// Colors formula tokens in the formula bar. Formulas are a single line, so
+// there is no state to carry from one line to the next.
+struct FormulaHighlighter {
+    current_line: usize,
+}
 
-    let row_str: String = chars.collect();
-    let row = row_str.parse::<usize>().ok()?;
+impl text::Highlighter for FormulaHighlighter {
+    type Settings = ();
+    type Highlight = TokenKind;
+    type Iterator<'a> = std::vec::IntoIter<(std::ops::Range<usize>, TokenKind)>;
 
-    if row < ROWS && col < COLS {
-        Some((row, col))
-    } else {
-        None
+    fn new(_settings: &()) -> Self {
+        Self { current_line: 0 }
     }
-}
 
-fn parse_dependencies(formula: &str) -> HashSet<(usize, usize)> {
-    let mut deps = HashSet::new();
-    let formula = formula.trim();
+    fn update(&mut self, _new_settings: &()) {}
 
-    if let Some(expr) = formula.strip_prefix('=') {
-        // Simple parser: look for patterns like A0, B5, Z99
-        let mut i = 0;
-        let chars: Vec<char> = expr.chars().collect();
-        while i < chars.len() {
-            if chars[i].is_ascii_uppercase() {
-                let col_char = chars[i];
-                i += 1;
+    fn change_line(&mut self, line: usize) {
+        self.current_line = line;
+    }
 
-                // Collect digits for row number
-                let mut row_str = String::new();
-                while i < chars.len() && chars[i].is_ascii_digit() {
-                    row_str.push(chars[i]);
-                    i += 1;
-                }
+    fn highlight_line(&mut self, line: &str) -> Self::Iterator<'_> {
+        self.current_line += 1;
+        syntax::tokens(line).into_iter()
+    }
 
-                if !row_str.is_empty()
-                    && let Some(cell_ref) =
-                        parse_cell_reference(&format!("{}{}", col_char, row_str))
-                {
-                    deps.insert(cell_ref);
-                }
-            } else {
-                i += 1;
-            }
-        }
+    fn current_line(&self) -> usize {
+        self.current_line
+    }
+}
+
+fn format_token(kind: &TokenKind, theme: &iced::Theme) -> highlighter::Format<iced::Font> {
+    let palette = theme.palette();
+    let color = match kind {
+        TokenKind::Reference => palette.primary,
+        TokenKind::Number => palette.warning,
+        TokenKind::Text => palette.success,
+        TokenKind::Function => Color::from_rgb8(0x9b, 0x59, 0xb6),
+        TokenKind::Name => Color::from_rgb8(0x16, 0xa0, 0x85),
+        TokenKind::Operator => palette.text,
+        TokenKind::Invalid => palette.danger,
+    };
+    highlighter::Format {
+        color: Some(color),
+        font: None,
     }
+}
+
+// Recalculates a snapshot of the sheet on its own thread and returns the new
+// values of the recalculated cells, or None if it was cancelled
+async fn recalculate_in_background(
+    mut snapshot: Sheet,
+    roots: Vec<Cell>,
+    cancel: Arc<AtomicBool>,
+) -> Option<Results> {
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let results = snapshot
+            .recalculate_cancellable(roots, &cancel)
+            .map(|order| snapshot.results(&order));
+        let _ = sender.send(results);
+    });
+    receiver.await.ok().flatten()
+}
 
-    deps
+// Goal seek on a snapshot of the sheet on its own thread, after bringing the
+// values downstream of the roots up to date
+async fn seek_in_background(
+    mut snapshot: Sheet,
+    roots: Vec<Cell>,
+    target: Cell,
+    goal: f64,
+    input: Cell,
+) -> Result<whatif::Solution, String> {
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        snapshot.recalculate(roots);
+        let _ = sender.send(whatif::goal_seek(&snapshot, target, goal, input));
+    });
+    receiver
+        .await
+        .unwrap_or_else(|_| Err("goal seek stopped".to_string()))
 }
 
 #[cfg(test)]
 #[allow(unused_must_use)]
 mod tests {
     use super::*;
+    use cells::formula::{letter_to_col, parse_cell_reference, parse_dependencies};
 
     use iced::Settings;
     use iced_test::{Error, Simulator};
@@ -614,7 +3529,7 @@ mod tests {
         assert_eq!(parse_cell_reference("A0"), Some((0, 0)));
         assert_eq!(parse_cell_reference("B5"), Some((5, 1)));
         assert_eq!(parse_cell_reference("Z99"), Some((99, 25)));
-        assert_eq!(parse_cell_reference("A100"), None); // Row out of bounds
+        assert_eq!(parse_cell_reference("A100"), Some((100, 0))); // Bounds are checked by the sheet
         assert_eq!(parse_cell_reference("AA0"), None); // Invalid format
         assert_eq!(parse_cell_reference(""), None);
         assert_eq!(parse_cell_reference("A"), None);
@@ -662,63 +3577,63 @@ mod tests {
     #[test]
     fn test_evaluate_formula_number() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("123");
+        let result = cells.sheet.evaluate_formula("123");
         assert!(matches!(result, CellValue::Number(n) if (n - 123.0).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_decimal() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("45.67");
+        let result = cells.sheet.evaluate_formula("45.67");
         assert!(matches!(result, CellValue::Number(n) if (n - 45.67).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_text() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("Hello");
+        let result = cells.sheet.evaluate_formula("Hello");
         assert!(matches!(result, CellValue::Text(s) if s == "Hello"));
     }
 
     #[test]
     fn test_evaluate_formula_simple_addition() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("=5+3");
+        let result = cells.sheet.evaluate_formula("=5+3");
         assert!(matches!(result, CellValue::Number(n) if (n - 8.0).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_subtraction() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("=10-4");
+        let result = cells.sheet.evaluate_formula("=10-4");
         assert!(matches!(result, CellValue::Number(n) if (n - 6.0).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_multiplication() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("=6*7");
+        let result = cells.sheet.evaluate_formula("=6*7");
         assert!(matches!(result, CellValue::Number(n) if (n - 42.0).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_division() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("=20/4");
+        let result = cells.sheet.evaluate_formula("=20/4");
         assert!(matches!(result, CellValue::Number(n) if (n - 5.0).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_division_by_zero() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("=10/0");
+        let result = cells.sheet.evaluate_formula("=10/0");
         assert!(matches!(result, CellValue::Error(e) if e == "DIV0"));
     }
 
     #[test]
     fn test_evaluate_formula_invalid() {
         let cells = App::new().0;
-        let result = cells.evaluate_formula("=ABC");
+        let result = cells.sheet.evaluate_formula("=ABC");
         assert!(matches!(result, CellValue::Error(_)));
     }
 
@@ -726,19 +3641,19 @@ mod tests {
     fn test_evaluate_formula_cell_reference() {
         let mut cells = App::new().0;
         // Set A0 to 42
-        cells.values.insert((0, 0), CellValue::Number(42.0));
+        cells.sheet.values.insert((0, 0), CellValue::Number(42.0));
 
-        let result = cells.evaluate_formula("=A0");
+        let result = cells.sheet.evaluate_formula("=A0");
         assert!(matches!(result, CellValue::Number(n) if (n - 42.0).abs() < 0.001));
     }
 
     #[test]
     fn test_evaluate_formula_cell_reference_addition() {
         let mut cells = App::new().0;
-        cells.values.insert((0, 0), CellValue::Number(10.0));
-        cells.values.insert((1, 1), CellValue::Number(20.0));
+        cells.sheet.values.insert((0, 0), CellValue::Number(10.0));
+        cells.sheet.values.insert((1, 1), CellValue::Number(20.0));
 
-        let result = cells.evaluate_formula("=A0+B1");
+        let result = cells.sheet.evaluate_formula("=A0+B1");
         assert!(matches!(result, CellValue::Number(n) if (n - 30.0).abs() < 0.001));
     }
 
@@ -746,10 +3661,11 @@ mod tests {
     fn test_evaluate_formula_text_in_formula() {
         let mut cells = App::new().0;
         cells
+            .sheet
             .values
             .insert((0, 0), CellValue::Text("Hello".to_string()));
 
-        let result = cells.evaluate_formula("=A0+5");
+        let result = cells.sheet.evaluate_formula("=A0+5");
         assert!(matches!(result, CellValue::Error(e) if e == "TEXT"));
     }
 
@@ -758,9 +3674,9 @@ mod tests {
         let mut cells = App::new().0;
         cells.update_cell(0, 0, "42".to_string());
 
-        assert_eq!(cells.formulas.get(&(0, 0)), Some(&"42".to_string()));
+        assert_eq!(cells.sheet.formulas.get(&(0, 0)), Some(&"42".to_string()));
         assert!(
-            matches!(cells.values.get(&(0, 0)), Some(CellValue::Number(n)) if (n - 42.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(0, 0)), Some(CellValue::Number(n)) if (n - 42.0).abs() < 0.001)
         );
     }
 
@@ -769,8 +3685,13 @@ mod tests {
         let mut cells = App::new().0;
         cells.update_cell(0, 0, "Hello".to_string());
 
-        assert_eq!(cells.formulas.get(&(0, 0)), Some(&"Hello".to_string()));
-        assert!(matches!(cells.values.get(&(0, 0)), Some(CellValue::Text(s)) if s == "Hello"));
+        assert_eq!(
+            cells.sheet.formulas.get(&(0, 0)),
+            Some(&"Hello".to_string())
+        );
+        assert!(
+            matches!(cells.sheet.values.get(&(0, 0)), Some(CellValue::Text(s)) if s == "Hello")
+        );
     }
 
     #[test]
@@ -778,9 +3699,9 @@ mod tests {
         let mut cells = App::new().0;
         cells.update_cell(0, 0, "=5+3".to_string());
 
-        assert_eq!(cells.formulas.get(&(0, 0)), Some(&"=5+3".to_string()));
+        assert_eq!(cells.sheet.formulas.get(&(0, 0)), Some(&"=5+3".to_string()));
         assert!(
-            matches!(cells.values.get(&(0, 0)), Some(CellValue::Number(n)) if (n - 8.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(0, 0)), Some(CellValue::Number(n)) if (n - 8.0).abs() < 0.001)
         );
     }
 
@@ -790,8 +3711,8 @@ mod tests {
         cells.update_cell(0, 0, "42".to_string());
         cells.update_cell(0, 0, "".to_string());
 
-        assert_eq!(cells.formulas.get(&(0, 0)), None);
-        assert_eq!(cells.values.get(&(0, 0)), None);
+        assert_eq!(cells.sheet.formulas.get(&(0, 0)), None);
+        assert_eq!(cells.sheet.values.get(&(0, 0)), None);
     }
 
     #[test]
@@ -801,9 +3722,23 @@ mod tests {
         cells.update_cell(1, 1, "=A0+5".to_string());
 
         // B1 should depend on A0
-        assert!(cells.dependencies.get(&(1, 1)).unwrap().contains(&(0, 0)));
+        assert!(
+            cells
+                .sheet
+                .dependencies
+                .get(&(1, 1))
+                .unwrap()
+                .contains(&(0, 0))
+        );
         // A0 should have B1 as a dependent
-        assert!(cells.dependents.get(&(0, 0)).unwrap().contains(&(1, 1)));
+        assert!(
+            cells
+                .sheet
+                .dependents
+                .get(&(0, 0))
+                .unwrap()
+                .contains(&(1, 1))
+        );
     }
 
     #[test]
@@ -814,7 +3749,7 @@ mod tests {
 
         // B1 should be 20
         assert!(
-            matches!(cells.values.get(&(1, 1)), Some(CellValue::Number(n)) if (n - 20.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(1, 1)), Some(CellValue::Number(n)) if (n - 20.0).abs() < 0.001)
         );
 
         // Update A0
@@ -822,7 +3757,7 @@ mod tests {
 
         // B1 should now be 30
         assert!(
-            matches!(cells.values.get(&(1, 1)), Some(CellValue::Number(n)) if (n - 30.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(1, 1)), Some(CellValue::Number(n)) if (n - 30.0).abs() < 0.001)
         );
     }
 
@@ -835,7 +3770,7 @@ mod tests {
 
         // C2 should be 20 (5*2+10)
         assert!(
-            matches!(cells.values.get(&(2, 2)), Some(CellValue::Number(n)) if (n - 20.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(2, 2)), Some(CellValue::Number(n)) if (n - 20.0).abs() < 0.001)
         );
 
         // Update A0
@@ -843,17 +3778,17 @@ mod tests {
 
         // B1 should be 20, C2 should be 30
         assert!(
-            matches!(cells.values.get(&(1, 1)), Some(CellValue::Number(n)) if (n - 20.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(1, 1)), Some(CellValue::Number(n)) if (n - 20.0).abs() < 0.001)
         );
         assert!(
-            matches!(cells.values.get(&(2, 2)), Some(CellValue::Number(n)) if (n - 30.0).abs() < 0.001)
+            matches!(cells.sheet.values.get(&(2, 2)), Some(CellValue::Number(n)) if (n - 30.0).abs() < 0.001)
         );
     }
 
     #[test]
     fn test_get_cell_display_number() {
         let mut cells = App::new().0;
-        cells.values.insert((0, 0), CellValue::Number(42.5));
+        cells.sheet.values.insert((0, 0), CellValue::Number(42.5));
 
         assert_eq!(cells.get_cell_display(0, 0), "42.50");
     }
@@ -861,7 +3796,7 @@ mod tests {
     #[test]
     fn test_get_cell_display_integer() {
         let mut cells = App::new().0;
-        cells.values.insert((0, 0), CellValue::Number(42.0));
+        cells.sheet.values.insert((0, 0), CellValue::Number(42.0));
 
         assert_eq!(cells.get_cell_display(0, 0), "42.00");
     }
@@ -870,6 +3805,7 @@ mod tests {
     fn test_get_cell_display_text() {
         let mut cells = App::new().0;
         cells
+            .sheet
             .values
             .insert((0, 0), CellValue::Text("Hello".to_string()));
 
@@ -880,6 +3816,7 @@ mod tests {
     fn test_get_cell_display_error() {
         let mut cells = App::new().0;
         cells
+            .sheet
             .values
             .insert((0, 0), CellValue::Error("DIV0".to_string()));
 
@@ -895,11 +3832,13 @@ mod tests {
     #[test]
     fn test_is_cell_number() {
         let mut cells = App::new().0;
-        cells.values.insert((0, 0), CellValue::Number(42.0));
+        cells.sheet.values.insert((0, 0), CellValue::Number(42.0));
         cells
+            .sheet
             .values
             .insert((1, 1), CellValue::Text("Hello".to_string()));
         cells
+            .sheet
             .values
             .insert((2, 2), CellValue::Error("ERR".to_string()));
 
@@ -916,17 +3855,29 @@ mod tests {
         cells.update_cell(1, 1, "=A0+5".to_string());
 
         // B1 depends on A0
-        assert!(cells.dependencies.get(&(1, 1)).unwrap().contains(&(0, 0)));
+        assert!(
+            cells
+                .sheet
+                .dependencies
+                .get(&(1, 1))
+                .unwrap()
+                .contains(&(0, 0))
+        );
 
         // Change B1 to not depend on A0
         cells.update_cell(1, 1, "20".to_string());
 
         // B1 should no longer have dependencies
-        assert_eq!(cells.dependencies.get(&(1, 1)), None);
+        assert_eq!(cells.sheet.dependencies.get(&(1, 1)), None);
         // A0 should no longer have B1 as dependent
         assert!(
-            !cells.dependents.contains_key(&(0, 0))
-                || !cells.dependents.get(&(0, 0)).unwrap().contains(&(1, 1))
+            !cells.sheet.dependents.contains_key(&(0, 0))
+                || !cells
+                    .sheet
+                    .dependents
+                    .get(&(0, 0))
+                    .unwrap()
+                    .contains(&(1, 1))
         );
     }
 
@@ -943,4 +3894,1061 @@ mod tests {
         let debug_str = format!("{:?}", val);
         assert!(debug_str.contains("Number"));
     }
+
+    #[test]
+    fn test_evaluate_formula_operator_precedence() {
+        let cells = App::new().0;
+        let result = cells.sheet.evaluate_formula("=2+3*4");
+        assert!(matches!(result, CellValue::Number(n) if (n - 14.0).abs() < 0.001));
+    }
+
+    #[test]
+    fn test_parse_dependencies_range() {
+        let deps = parse_dependencies("=VLOOKUP(E0, A0:B4, 2, FALSE)");
+        assert_eq!(deps.len(), 11);
+        assert!(deps.contains(&(0, 4)));
+        assert!(deps.contains(&(4, 1)));
+    }
+
+    #[test]
+    fn test_lookup_propagation() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "apple".to_string());
+        cells.update_cell(0, 1, "3".to_string());
+        cells.update_cell(1, 0, "pear".to_string());
+        cells.update_cell(1, 1, "5".to_string());
+        cells.update_cell(0, 3, "=VLOOKUP(\"pear\", A0:B2, 2, FALSE)".to_string());
+        assert_eq!(cells.get_cell_display(0, 3), "5.00");
+
+        // Changing any cell inside the lookup table re-evaluates the lookup
+        cells.update_cell(1, 1, "7".to_string());
+        assert_eq!(cells.get_cell_display(0, 3), "7.00");
+
+        // A key added later inside the range is found as well
+        cells.update_cell(1, 0, "plum".to_string());
+        assert_eq!(cells.get_cell_display(0, 3), "#N/A");
+        cells.update_cell(2, 0, "pear".to_string());
+        cells.update_cell(2, 1, "9".to_string());
+        assert_eq!(cells.get_cell_display(0, 3), "9.00");
+    }
+
+    #[test]
+    fn test_lookup_text_result() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "10".to_string());
+        cells.update_cell(0, 1, "Low".to_string());
+        cells.update_cell(1, 0, "50".to_string());
+        cells.update_cell(1, 1, "High".to_string());
+        cells.update_cell(3, 0, "=INDEX(B0:B1, MATCH(60, A0:A1))".to_string());
+        assert_eq!(cells.get_cell_display(3, 0), "High");
+        assert!(!cells.is_cell_number(3, 0));
+    }
+
+    #[test]
+    fn test_math_and_stats_functions() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "3".to_string());
+        cells.update_cell(1, 0, "4".to_string());
+        cells.update_cell(2, 0, "=SQRT(POWER(A0, 2) + POWER(A1, 2))".to_string());
+        cells.update_cell(3, 0, "=ROUND(AVERAGE(A0:A2), 1)".to_string());
+        assert_eq!(cells.get_cell_display(2, 0), "5.00");
+        assert_eq!(cells.get_cell_display(3, 0), "4.00");
+
+        cells.update_cell(0, 0, "6".to_string());
+        assert_eq!(cells.get_cell_display(2, 0), "7.21");
+        assert_eq!(cells.get_cell_display(3, 0), "5.70");
+
+        cells.update_cell(4, 0, "=SQRT(-A0)".to_string());
+        assert_eq!(cells.get_cell_display(4, 0), "#NUM");
+    }
+
+    #[test]
+    fn test_volatile_cells_recalculate_on_any_change() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "=RAND()".to_string());
+        cells.update_cell(0, 1, "=A0*1000000".to_string());
+        assert!(cells.sheet.volatile.contains(&(0, 0)));
+
+        let before = cells.sheet.values.get(&(0, 1)).cloned();
+        // An edit unrelated to A0 still produces a new random number
+        cells.update_cell(5, 5, "1".to_string());
+        assert_ne!(cells.sheet.values.get(&(0, 1)).cloned(), before);
+
+        cells.update_cell(0, 0, "2".to_string());
+        assert!(!cells.sheet.volatile.contains(&(0, 0)));
+        assert_eq!(cells.get_cell_display(0, 1), "2000000.00");
+    }
+
+    #[test]
+    fn test_reference_outside_grid() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "=A100".to_string());
+        assert_eq!(cells.get_cell_display(0, 0), "#REF");
+    }
+
+    #[test]
+    fn test_parallel_toggle() {
+        let mut cells = App::new().0;
+        cells.update(Message::ParallelToggled(true));
+        assert_eq!(cells.sheet.recalc_mode(), RecalcMode::Parallel);
+
+        cells.update_cell(0, 0, "2".to_string());
+        cells.update_cell(1, 0, "=A0*21".to_string());
+        assert_eq!(cells.get_cell_display(1, 0), "42.00");
+
+        cells.update(Message::ParallelToggled(false));
+        assert_eq!(cells.sheet.recalc_mode(), RecalcMode::Serial);
+    }
+
+    #[test]
+    fn test_decimal_toggle() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "=0.1+0.2".to_string());
+        cells.update_cell(1, 0, "=A0=0.3".to_string());
+        assert_eq!(cells.get_cell_display(1, 0), "0.00");
+
+        cells.update(Message::DecimalToggled(true));
+        assert_eq!(cells.sheet.arithmetic(), Arithmetic::Decimal);
+        assert_eq!(cells.get_cell_display(1, 0), "1.00");
+
+        cells.update(Message::DecimalToggled(false));
+        assert_eq!(cells.get_cell_display(1, 0), "0.00");
+    }
+
+    #[test]
+    fn test_iteration_toggle() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "=A1/2".to_string());
+        cells.update_cell(1, 0, "=A0+3".to_string());
+        assert_eq!(cells.get_cell_display(0, 0), "#CYCLE");
+
+        cells.update(Message::IterationToggled(true));
+        assert_eq!(cells.sheet.iteration(), Some(Iteration::default()));
+        assert_eq!(cells.get_cell_display(0, 0), "3.00");
+
+        // Settings apply as they are typed, once valid
+        cells.update(Message::MaxIterationsChanged("".to_string()));
+        assert!(cells.iteration_error.is_some());
+        cells.update(Message::MaxIterationsChanged("2".to_string()));
+        assert_eq!(cells.iteration_error, None);
+        // Settled values stay settled; moving them takes more rounds
+        assert_eq!(cells.get_cell_display(0, 0), "3.00");
+        cells.update_cell(1, 0, "=A0+300".to_string());
+        assert_eq!(cells.get_cell_display(0, 0), "#CONVERGE");
+
+        cells.update(Message::IterationToggled(false));
+        assert_eq!(cells.sheet.iteration(), None);
+        assert_eq!(cells.get_cell_display(0, 0), "#CYCLE");
+    }
+
+    #[test]
+    fn test_goal_seek_enters_input() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "1".to_string());
+        cells.update_cell(0, 1, "=A0*4+2".to_string());
+        cells.update(Message::SeekTargetChanged("B0".to_string()));
+        cells.update(Message::SeekGoalChanged("30".to_string()));
+        cells.update(Message::SeekInputChanged("A0".to_string()));
+        cells.update(Message::GoalSeek);
+        assert!(cells.whatif.seeking);
+        assert_eq!(cells.sheet.formulas.get(&(0, 0)), Some(&"1".to_string()));
+        finish_goal_seek(&mut cells);
+        assert!(!cells.whatif.seeking);
+        assert_eq!(cells.sheet.formulas.get(&(0, 0)), Some(&"7".to_string()));
+        assert_eq!(cells.get_cell_display(0, 1), "30.00");
+        assert_eq!(
+            cells.whatif.seek_result,
+            Some(Ok("A0 = 7 gives 30".to_string()))
+        );
+
+        cells.update(Message::SeekInputChanged("B0".to_string()));
+        cells.update(Message::GoalSeek);
+        finish_goal_seek(&mut cells);
+        assert_eq!(
+            cells.whatif.seek_result,
+            Some(Err("B0 must hold a number, not a formula".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_data_tables() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "2".to_string());
+        cells.update_cell(1, 0, "3".to_string());
+        cells.update_cell(0, 1, "=A0*A1".to_string());
+        cells.update_cell(1, 1, "=A0+A1".to_string());
+        cells.sheet.number_formats.insert((1, 1), "0".to_string());
+
+        cells.update(Message::TableOutputsChanged("B0, B1".to_string()));
+        cells.update(Message::TableDownInputChanged("A0".to_string()));
+        cells.update(Message::TableDownValuesChanged("1, 5".to_string()));
+        cells.update(Message::ComputeTable);
+        let Some(Ok(table)) = &cells.whatif.table else {
+            panic!("{:?}", cells.whatif.table);
+        };
+        assert_eq!(table.header, ["B0", "B1"]);
+        assert_eq!(
+            table.rows,
+            [
+                ("1".to_string(), vec!["3.00".to_string(), "4".to_string()]),
+                ("5".to_string(), vec!["15.00".to_string(), "8".to_string()]),
+            ]
+        );
+        // The sheet keeps its values
+        assert_eq!(cells.get_cell_display(0, 1), "6.00");
+
+        cells.update(Message::TableAcrossInputChanged("A1".to_string()));
+        cells.update(Message::TableAcrossValuesChanged("10, 20".to_string()));
+        cells.update(Message::ComputeTable);
+        assert!(matches!(&cells.whatif.table, Some(Err(_))));
+
+        cells.update(Message::TableOutputsChanged("B0".to_string()));
+        cells.update(Message::ComputeTable);
+        let Some(Ok(table)) = &cells.whatif.table else {
+            panic!("{:?}", cells.whatif.table);
+        };
+        assert_eq!(table.header, ["10", "20"]);
+        assert_eq!(table.rows[1].1, ["50.00", "100.00"]);
+    }
+
+    // Every cell of columns B-D reads A0, so editing A0 dirties 301 cells
+    fn heavy_app() -> App {
+        let mut cells = App::new().0;
+        cells.sheet.set_formula((0, 0), "1");
+        for row in 0..ROWS {
+            for col in 1..4 {
+                cells.sheet.set_formula((row, col), &format!("=A0+{}", row));
+            }
+        }
+        cells
+    }
+
+    // Runs the recalculation in flight to completion and delivers its result
+    fn finish_recalculation(cells: &mut App) {
+        let recalculation = cells.recalculation.as_ref().unwrap();
+        let generation = recalculation.generation;
+        let results = iced::futures::executor::block_on(recalculate_in_background(
+            cells.sheet.clone(),
+            recalculation.roots.clone(),
+            recalculation.cancel.clone(),
+        ))
+        .unwrap();
+        cells.update(Message::Recalculated(generation, results));
+    }
+
+    // Runs the goal seek in flight to completion and delivers its outcome
+    fn finish_goal_seek(cells: &mut App) {
+        assert!(cells.whatif.seeking);
+        let (target, goal, input) = cells.seek_request().unwrap();
+        let roots = cells
+            .recalculation
+            .as_ref()
+            .map_or_else(Vec::new, |r| r.roots.clone());
+        let result = iced::futures::executor::block_on(seek_in_background(
+            cells.sheet.clone(),
+            roots,
+            target,
+            goal,
+            input,
+        ));
+        cells.update(Message::GoalSought(input, result));
+    }
+
+    #[test]
+    fn test_goal_seek_during_background_recalculation() {
+        let mut cells = heavy_app();
+        cells.sheet.set_formula((0, 4), "=B9*10");
+        cells.update_cell(0, 0, "2".to_string());
+        assert_eq!(cells.get_cell_display(9, 1), "calculating…");
+
+        // E0 is 10 * (A0 + 9), so A0 = 1 brings it to 100
+        cells.update(Message::SeekTargetChanged("E0".to_string()));
+        cells.update(Message::SeekGoalChanged("100".to_string()));
+        cells.update(Message::SeekInputChanged("A0".to_string()));
+        cells.update(Message::GoalSeek);
+        // A second request waits for the first
+        cells.update(Message::GoalSeek);
+        finish_goal_seek(&mut cells);
+        assert_eq!(
+            cells.whatif.seek_result,
+            Some(Ok("A0 = 1 gives 100".to_string()))
+        );
+        finish_recalculation(&mut cells);
+        assert_eq!(cells.get_cell_display(0, 4), "100.00");
+    }
+
+    #[test]
+    fn test_heavy_edit_recalculates_in_background() {
+        let mut cells = heavy_app();
+        cells.update_cell(0, 0, "2".to_string());
+
+        assert_eq!(cells.get_cell_display(0, 0), "2.00");
+        assert_eq!(cells.get_cell_display(5, 1), "calculating…");
+        assert!(!cells.is_cell_number(5, 1));
+
+        finish_recalculation(&mut cells);
+        assert!(cells.recalculation.is_none());
+        assert_eq!(cells.get_cell_display(5, 1), "7.00");
+        assert_eq!(cells.get_cell_display(99, 3), "101.00");
+    }
+
+    #[test]
+    fn test_new_edit_supersedes_background_recalculation() {
+        let mut cells = heavy_app();
+        cells.update_cell(0, 0, "2".to_string());
+        let first = cells.recalculation.as_ref().unwrap();
+        let (first_generation, first_cancel) = (first.generation, first.cancel.clone());
+
+        // Even a small edit joins the recalculation in flight
+        cells.update_cell(0, 5, "=B0*10".to_string());
+        assert!(first_cancel.load(Ordering::Relaxed));
+        assert_eq!(cells.get_cell_display(0, 5), "calculating…");
+
+        // A late result of the cancelled run is ignored
+        cells.update(Message::Recalculated(
+            first_generation,
+            Results {
+                values: vec![((5, 1), Some(CellValue::Number(-1.0)))],
+                ..Results::default()
+            },
+        ));
+        assert_eq!(cells.get_cell_display(5, 1), "calculating…");
+
+        finish_recalculation(&mut cells);
+        assert_eq!(cells.get_cell_display(5, 1), "7.00");
+        assert_eq!(cells.get_cell_display(0, 5), "20.00");
+    }
+
+    #[test]
+    fn test_trace_mode() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "1".to_string());
+        cells.update_cell(0, 1, "=A0*2".to_string());
+        cells.update_cell(0, 2, "=B0+1".to_string());
+
+        cells.update(Message::TraceToggled(true));
+        cells.update(Message::CellClicked(0, 1));
+        assert_eq!(cells.editing_cell, None);
+        assert_eq!(cells.traced_cell, Some((0, 1)));
+
+        let roles = cells.trace_roles();
+        assert_eq!(roles.get(&(0, 0)), Some(&TraceRole::Precedent(1)));
+        assert_eq!(roles.get(&(0, 1)), Some(&TraceRole::Traced));
+        assert_eq!(roles.get(&(0, 2)), Some(&TraceRole::Dependent(1)));
+        assert_eq!(roles.len(), 3);
+
+        cells.update(Message::TraceToggled(false));
+        assert!(cells.trace_roles().is_empty());
+        cells.update(Message::CellClicked(0, 1));
+        assert_eq!(cells.editing_cell, Some((0, 1)));
+    }
+
+    #[test]
+    fn test_formula_bar_completion() {
+        let mut cells = App::new().0;
+        cells.update(Message::CellClicked(0, 0));
+        cells.update(Message::FormulaChanged("=1+av".to_string()));
+        assert_eq!(cells.formula_editor.text(), "=1+av");
+        assert_eq!(cells.completions(), vec!["AVERAGE("]);
+
+        cells.update(Message::CompletionChosen("AVERAGE(".to_string()));
+        assert_eq!(cells.editing_formula, "=1+AVERAGE(");
+        assert!(cells.completions().is_empty());
+
+        // Typing in the formula bar continues after the completion
+        for c in "2,4)".chars() {
+            cells.update(Message::FormulaEdited(text_editor::Action::Edit(
+                text_editor::Edit::Insert(c),
+            )));
+        }
+        assert_eq!(cells.editing_formula, "=1+AVERAGE(2,4)");
+        assert_eq!(cells.sheet.check_formula(&cells.editing_formula), None);
+
+        cells.update(Message::FinishEditing);
+        assert_eq!(cells.get_cell_display(0, 0), "4.00");
+    }
+
+    #[test]
+    fn test_define_name() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "2".to_string());
+        cells.update_cell(1, 0, "3".to_string());
+        cells.update_cell(0, 1, "=SUM(prices)".to_string());
+        assert_eq!(cells.get_cell_display(0, 1), "#NAME");
+
+        cells.update(Message::NameChanged("A1".to_string()));
+        cells.update(Message::NameRangeChanged("A0:A1".to_string()));
+        cells.update(Message::DefineName);
+        assert!(cells.name_error.is_some());
+
+        cells.update(Message::NameChanged("prices".to_string()));
+        cells.update(Message::DefineName);
+        assert_eq!(cells.name_error, None);
+        assert_eq!(cells.get_cell_display(0, 1), "5.00");
+
+        // Named ranges are offered as completions
+        cells.update(Message::CellClicked(2, 2));
+        cells.update(Message::FormulaChanged("=PR".to_string()));
+        assert_eq!(cells.completions(), vec!["PRICES"]);
+    }
+
+    #[test]
+    fn test_conditional_formatting() {
+        let mut cells = App::new().0;
+        for row in 0..3 {
+            cells.update_cell(row, 0, (row * 5).to_string());
+        }
+
+        cells.update(Message::RuleRangeChanged("A0:A9".to_string()));
+        cells.update(Message::RuleValueChanged("many".to_string()));
+        cells.update(Message::AddRule);
+        assert!(cells.rule_error.is_some());
+        assert!(cells.sheet.formats.is_empty());
+
+        cells.update(Message::RuleValueChanged("4".to_string()));
+        cells.update(Message::RuleColorSelected(NamedColor::Blue));
+        cells.update(Message::AddRule);
+        assert_eq!(cells.rule_error, None);
+        assert_eq!(describe_rule(&cells.sheet.formats[0]), "A0:A9  > 4");
+        assert_eq!(cells.appearance[&(1, 0)].fill, Some(NamedColor::Blue.rgb()));
+        assert!(!cells.appearance.contains_key(&(0, 0)));
+
+        // Rules follow recalculation
+        cells.update_cell(0, 0, "=A2+1".to_string());
+        assert!(cells.appearance.contains_key(&(0, 0)));
+
+        cells.update(Message::RuleRangeChanged("B0:B2".to_string()));
+        cells.update(Message::RuleKindSelected(RuleKind::DataBar));
+        cells.update(Message::AddRule);
+        cells.update_cell(1, 1, "=A1*2".to_string());
+        assert_eq!(
+            cells.appearance[&(1, 1)].bar,
+            Some((1.0, NamedColor::Red.rgb()))
+        );
+
+        cells.update(Message::RemoveRule(0));
+        assert_eq!(cells.sheet.formats.len(), 1);
+        assert!(!cells.appearance.contains_key(&(0, 0)));
+    }
+
+    #[test]
+    fn test_formula_rule_is_checked() {
+        let mut cells = App::new().0;
+        cells.update(Message::RuleRangeChanged("A0:A4".to_string()));
+        cells.update(Message::RuleKindSelected(RuleKind::Formula));
+        cells.update(Message::RuleValueChanged("=NOPE(A0)".to_string()));
+        cells.update(Message::AddRule);
+        assert_eq!(cells.rule_error.as_deref(), Some("unknown function NOPE"));
+
+        cells.update(Message::RuleValueChanged("=A0<>A1".to_string()));
+        cells.update(Message::AddRule);
+        assert_eq!(cells.rule_error, None);
+        cells.update_cell(3, 0, "1".to_string());
+        let mut filled: Vec<Cell> = cells.appearance.keys().copied().collect();
+        filled.sort();
+        assert_eq!(filled, vec![(2, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn test_number_formats() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "1234.5".to_string());
+        cells.update_cell(1, 0, "=A0/10000".to_string());
+        cells.update_cell(2, 0, "=A0".to_string());
+        assert_eq!(cells.get_cell_display(0, 0), "1234.50");
+
+        // Formats imported from other spreadsheet applications
+        cells
+            .sheet
+            .number_formats
+            .insert((0, 0), "#,##0".to_string());
+        cells
+            .sheet
+            .number_formats
+            .insert((1, 0), "0.0%".to_string());
+        cells
+            .sheet
+            .number_formats
+            .insert((2, 0), "yyyy-mm-dd".to_string());
+        assert_eq!(cells.get_cell_display(0, 0), "1,235");
+        assert_eq!(cells.get_cell_display(1, 0), "12.3%");
+        assert_eq!(cells.get_cell_display(2, 0), "1234.50");
+    }
+
+    #[test]
+    fn test_merged_cells() {
+        let mut cells = App::new().0;
+        cells.update(Message::MergeRangeChanged("A0:C0".to_string()));
+        cells.update(Message::MergeCells);
+        assert_eq!(cells.merge_error, None);
+        cells.update(Message::MergeRangeChanged("C0:D1".to_string()));
+        cells.update(Message::MergeCells);
+        assert!(cells.merge_error.is_some());
+
+        // Clicking anywhere in a merge edits its top-left cell
+        cells.update(Message::CellClicked(0, 2));
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+        cells.update(Message::FormulaChanged("Header".to_string()));
+
+        // Navigation steps over the merge
+        cells.update(Message::Navigate(0, 1));
+        assert_eq!(cells.editing_cell, Some((0, 3)));
+        assert_eq!(cells.get_cell_display(0, 0), "Header");
+        cells.update(Message::Navigate(0, -1));
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+        cells.update(Message::Navigate(-1, 0));
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+
+        cells.update(Message::Unmerge);
+        assert!(cells.sheet.merges().is_empty());
+    }
+
+    #[test]
+    fn test_wrapped_text_grows_rows() {
+        let mut cells = App::new().0;
+        let label = "A long label that needs several lines";
+        cells.update_cell(2, 0, label.to_string());
+        assert_eq!(cells.row_heights()[2], ROW_HEIGHT);
+
+        cells.update(Message::CellClicked(2, 0));
+        cells.update(Message::WrapToggled(true));
+        let heights = cells.row_heights();
+        assert!(heights[2] > ROW_HEIGHT);
+        assert_eq!(heights[1], ROW_HEIGHT);
+
+        // Wider merged cells need fewer lines
+        cells.update(Message::MergeRangeChanged("A2:D2".to_string()));
+        cells.update(Message::MergeCells);
+        assert!(cells.row_heights()[2] < heights[2]);
+
+        assert_eq!(wrapped_lines("one two three", 40.0 * CHAR_WIDTH), 1);
+        assert_eq!(wrapped_lines("one two three", 8.0 * CHAR_WIDTH), 2);
+        assert_eq!(wrapped_lines("abcdefghij\nx", 4.0 * CHAR_WIDTH), 4);
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut cells = App::new().0;
+        cells.update(Message::ValidationRangeChanged("A0:A9".to_string()));
+        cells.update(Message::ValidationKindSelected(ValidationKind::WholeNumber));
+        cells.update(Message::ValidationFirstChanged("1".to_string()));
+        cells.update(Message::ValidationSecondChanged("x".to_string()));
+        cells.update(Message::AddValidation);
+        assert!(cells.validation_error.is_some());
+        cells.update(Message::ValidationSecondChanged("10".to_string()));
+        cells.update(Message::AddValidation);
+        assert_eq!(cells.validation_error, None);
+
+        // Rejected input keeps the cell in edit, even when clicking away
+        cells.update(Message::CellClicked(0, 0));
+        cells.update(Message::FormulaChanged("12".to_string()));
+        cells.update(Message::FinishEditing);
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+        assert!(cells.validation_prompt.as_ref().is_some_and(|p| !p.warn));
+        cells.update(Message::CellClicked(1, 1));
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+        cells.update(Message::KeepInvalid);
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+
+        cells.update(Message::RetryInput);
+        cells.update(Message::FormulaChanged("7".to_string()));
+        cells.update(Message::FinishEditing);
+        assert!(cells.validation_prompt.is_none());
+        assert_eq!(cells.get_cell_display(0, 0), "7.00");
+
+        // Warnings can be overridden
+        cells.update(Message::ValidationRangeChanged("B0".to_string()));
+        cells.update(Message::ValidationKindSelected(ValidationKind::List));
+        cells.update(Message::ValidationFirstChanged("Open, Closed".to_string()));
+        cells.update(Message::ValidationWarnToggled(true));
+        cells.update(Message::AddValidation);
+        cells.update(Message::CellClicked(0, 1));
+        cells.update(Message::FormulaChanged("Pending".to_string()));
+        cells.update(Message::FinishEditing);
+        assert!(cells.validation_prompt.as_ref().is_some_and(|p| p.warn));
+        cells.update(Message::KeepInvalid);
+        assert_eq!(cells.editing_cell, None);
+        assert_eq!(cells.get_cell_display(0, 1), "Pending");
+
+        // Picking from the dropdown commits right away
+        cells.update(Message::CellClicked(0, 1));
+        cells.update(Message::ChoiceSelected("Closed".to_string()));
+        assert_eq!(cells.editing_cell, None);
+        assert_eq!(cells.get_cell_display(0, 1), "Closed");
+
+        // Discarding throws the input away
+        cells.update(Message::CellClicked(2, 0));
+        cells.update(Message::FormulaChanged("0".to_string()));
+        cells.update(Message::FinishEditing);
+        cells.update(Message::CancelInput);
+        assert_eq!(cells.editing_cell, None);
+        assert_eq!(cells.get_cell_display(2, 0), "");
+    }
+
+    #[test]
+    fn test_spilled_cells() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "=SEQUENCE(3, 2)".to_string());
+        cells.update_cell(0, 2, "=SUM(A0:B2)".to_string());
+        assert_eq!(cells.get_cell_display(2, 1), "6.00");
+        assert_eq!(cells.get_cell_display(0, 2), "21.00");
+
+        // Typing into a spilled cell blocks the spill
+        cells.update(Message::CellClicked(1, 1));
+        assert_eq!(cells.editing_formula, "");
+        cells.update(Message::FormulaChanged("x".to_string()));
+        cells.update(Message::FinishEditing);
+        assert_eq!(cells.get_cell_display(0, 0), "#SPILL");
+        assert_eq!(cells.get_cell_display(2, 1), "");
+        assert_eq!(cells.get_cell_display(0, 2), "#SPILL");
+
+        cells.update_cell(1, 1, String::new());
+        assert_eq!(cells.get_cell_display(1, 1), "4.00");
+        assert_eq!(cells.get_cell_display(0, 2), "21.00");
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut cells = App::new().0;
+        cells.update(Message::CellClicked(2, 1));
+        cells.update(Message::CommentEdited((2, 1), "Estimate".to_string()));
+        cells.update(Message::CommentEdited(
+            (2, 1),
+            "Estimated from Q1".to_string(),
+        ));
+        let comment = &cells.sheet.comments[&(2, 1)];
+        assert_eq!(comment.text, "Estimated from Q1");
+        assert!(!comment.author.is_empty());
+        assert!(describe_comment(comment).ends_with(" UTC"));
+
+        // Comments don't touch the cell being edited
+        assert_eq!(cells.editing_cell, Some((2, 1)));
+        cells.update(Message::FinishEditing);
+        assert_eq!(cells.sheet.formulas.get(&(2, 1)), None);
+
+        cells.update(Message::CommentEdited((0, 0), "Source".to_string()));
+        cells.update(Message::CommentEdited((0, 0), String::new()));
+        assert!(!cells.sheet.comments.contains_key(&(0, 0)));
+        cells.update(Message::RemoveComment((2, 1)));
+        assert!(cells.sheet.comments.is_empty());
+    }
+
+    #[test]
+    fn test_describe_comment() {
+        let comment = Comment {
+            text: "Checked".to_string(),
+            author: "ana".to_string(),
+            time: "2024-05-01T09:30:00Z".to_string(),
+        };
+        assert_eq!(describe_comment(&comment), "ana, 2024-05-01 09:30 UTC");
+        let comment = Comment {
+            author: String::new(),
+            ..comment
+        };
+        assert_eq!(describe_comment(&comment), "2024-05-01 09:30 UTC");
+    }
+
+    #[test]
+    fn test_charts() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "3".to_string());
+        cells.update_cell(1, 0, "=A0*2".to_string());
+
+        cells.update(Message::ChartRangeChanged("A0:".to_string()));
+        cells.update(Message::AddChart);
+        assert!(cells.chart_error.is_some());
+
+        cells.update(Message::ChartRangeChanged("A0:A1".to_string()));
+        cells.update(Message::ChartKindSelected(ChartKind::Bar));
+        cells.update(Message::AddChart);
+        assert_eq!(cells.chart_error, None);
+        assert_eq!(cells.sheet.charts[0].kind, ChartKind::Bar);
+
+        // Charts read the values as they are recalculated
+        let points = |cells: &App| {
+            cells.sheet.charts[0].data(&cells.sheet).series[0]
+                .points
+                .clone()
+        };
+        assert_eq!(points(&cells), vec![(0.0, 3.0), (1.0, 6.0)]);
+        cells.update_cell(0, 0, "5".to_string());
+        assert_eq!(points(&cells), vec![(0.0, 5.0), (1.0, 10.0)]);
+
+        cells.update(Message::RemoveChart(0));
+        assert!(cells.sheet.charts.is_empty());
+    }
+
+    #[test]
+    fn test_click_to_insert_references() {
+        let mut cells = App::new().0;
+        for row in 0..4 {
+            cells.update_cell(row, 1, (row + 1).to_string());
+        }
+
+        cells.update(Message::CellClicked(0, 0));
+        cells.update(Message::FormulaChanged("=B0+".to_string()));
+        assert!(cells.inserting_references());
+
+        // Pressing B1 and dragging down to B3 inserts a range
+        cells.update(Message::ReferencePressed((1, 1)));
+        assert_eq!(cells.editing_formula, "=B0+B1");
+        cells.update(Message::ReferenceEntered((3, 1)));
+        assert_eq!(cells.editing_formula, "=B0+B1:B3");
+        cells.update(Message::ReferenceEntered((2, 1)));
+        assert_eq!(cells.editing_formula, "=B0+B1:B2");
+        cells.update(Message::MouseReleased);
+        assert_eq!(cells.editing_cell, Some((0, 0)));
+
+        // Without an operand expected, hovering does nothing and a click
+        // finishes the edit
+        assert!(!cells.inserting_references());
+        cells.update(Message::ReferenceEntered((3, 1)));
+        assert_eq!(cells.editing_formula, "=B0+B1:B2");
+
+        cells.update(Message::FormulaChanged("=B0+SUM(".to_string()));
+        cells.update(Message::ReferencePressed((3, 1)));
+        cells.update(Message::MouseReleased);
+        cells.update(Message::FormulaChanged(format!(
+            "{})",
+            cells.editing_formula
+        )));
+        assert_eq!(cells.editing_formula, "=B0+SUM(B3)");
+
+        cells.update(Message::CellClicked(5, 5));
+        assert_eq!(cells.get_cell_display(0, 0), "5.00");
+    }
+
+    // Collaborating through a fake server whose outbox the test reads
+    fn collaborating(cells: &mut App) -> mpsc::Receiver<Update> {
+        let (outbox, sent) = mpsc::channel();
+        let (_, receiver) = futures_mpsc::unbounded();
+        cells.collab = Some(Collaboration::new(outbox, receiver));
+        sent
+    }
+
+    fn stamp(counter: u64, client: u64) -> collab::Stamp {
+        collab::Stamp { counter, client }
+    }
+
+    #[test]
+    fn test_collaboration_updates() {
+        let mut cells = App::new().0;
+        cells.collab_user = "bob".to_string();
+        cells.update_cell(5, 5, "local".to_string());
+        let sent = collaborating(&mut cells);
+
+        // Joining takes the server's formulas
+        let edit = |cell: &str, formula: &str, stamp| Edit {
+            cell: cell.to_string(),
+            formula: formula.to_string(),
+            stamp,
+        };
+        cells.update(Message::Remote(Update::Welcome {
+            client: 2,
+            edits: vec![
+                edit("A0", "5", collab::Stamp::default()),
+                edit("B0", "=A0*2", stamp(1, 1)),
+                edit("A100", "outside", stamp(1, 1)),
+            ],
+            peers: vec![Peer {
+                client: 1,
+                user: "ann".to_string(),
+                cell: Some("B0".to_string()),
+            }],
+        }));
+        assert_eq!(cells.get_cell_display(0, 1), "10.00");
+        assert_eq!(cells.sheet.formulas.get(&(5, 5)), None);
+        assert_eq!(cells.sheet.formulas.get(&(100, 0)), None);
+        assert_eq!(cells.peers_at((0, 1))[0].user, "ann");
+
+        // Remote edits are recalculated here, unless a later edit won
+        cells.update(Message::Remote(Update::Edit(edit("A0", "7", stamp(2, 1)))));
+        assert_eq!(cells.get_cell_display(0, 1), "14.00");
+        cells.update(Message::Remote(Update::Edit(edit("A0", "6", stamp(1, 3)))));
+        assert_eq!(cells.get_cell_display(0, 1), "14.00");
+        // Edits of cells outside the sheet are dropped
+        cells.update(Message::Remote(Update::Edit(edit(
+            "Z100",
+            "1",
+            stamp(2, 1),
+        ))));
+        assert_eq!(cells.sheet.formulas.get(&(100, 25)), None);
+
+        // Selections and edits made here are shared, stamped after the
+        // edits seen
+        cells.update(Message::CellClicked(0, 0));
+        cells.update(Message::FormulaChanged("8".to_string()));
+        cells.update(Message::FinishEditing);
+        let updates: Vec<Update> = sent.try_iter().collect();
+        let select = |cell: Option<&str>| {
+            Update::Select(Peer {
+                client: 2,
+                user: "bob".to_string(),
+                cell: cell.map(String::from),
+            })
+        };
+        assert_eq!(
+            updates,
+            [
+                select(Some("A0")),
+                Update::Edit(edit("A0", "8", stamp(3, 2))),
+                select(None),
+            ]
+        );
+        // Unchanged formulas are not shared
+        cells.update(Message::CellClicked(0, 1));
+        cells.update(Message::FinishEditing);
+        assert!(!sent.try_iter().any(|u| matches!(u, Update::Edit(_))));
+
+        cells.update(Message::Remote(Update::Leave { client: 1 }));
+        assert!(cells.peers_at((0, 1)).is_empty());
+        let id = cells.collab.as_ref().unwrap().inbox.id;
+        cells.update(Message::Disconnected(id));
+        assert!(cells.collab.is_none());
+        assert!(cells.collab_error.is_some());
+    }
+
+    #[test]
+    fn test_collaborating_through_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("ws://{}", listener.local_addr().unwrap());
+        let mut sheet = Sheet::new(ROWS, COLS);
+        sheet.set_formula((0, 0), "21");
+        std::thread::spawn(move || collab::serve(listener, &sheet));
+
+        let mut ann = App::new().0;
+        ann.update(Message::CollabAddressChanged(address));
+        ann.update(Message::CollabUserChanged("ann".to_string()));
+        ann.update(Message::Connect);
+        assert_eq!(ann.collab_error, None);
+
+        // Updates are read from the inbox as the subscription would
+        let mut inbox = ann
+            .collab
+            .as_ref()
+            .unwrap()
+            .inbox
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap();
+        let welcome = iced::futures::executor::block_on(inbox.next()).unwrap();
+        ann.update(Message::Remote(welcome));
+        assert_eq!(ann.get_cell_display(0, 0), "21.00");
+
+        ann.update(Message::Disconnect);
+        assert!(ann.collab.is_none());
+        assert_eq!(iced::futures::executor::block_on(inbox.next()), None);
+
+        ann.update(Message::CollabAddressChanged("localhost".to_string()));
+        ann.update(Message::Connect);
+        assert!(ann.collab.is_none());
+        assert!(ann.collab_error.is_some());
+    }
+
+    #[test]
+    fn test_script_panel() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "=TRIPLE(2)".to_string());
+        let type_script = |cells: &mut App, source: &str| {
+            cells.update(Message::ScriptEdited(text_editor::Action::SelectAll));
+            cells.update(Message::ScriptEdited(text_editor::Action::Edit(
+                text_editor::Edit::Paste(Arc::new(source.to_string())),
+            )));
+            cells.update(Message::ApplyScript);
+        };
+
+        type_script(&mut cells, "fn triple(x) { x * 3.0 }");
+        assert_eq!(cells.script_error, None);
+        // Formulas calling the script are recalculated in the background
+        assert_eq!(cells.get_cell_display(0, 0), "calculating…");
+        finish_recalculation(&mut cells);
+        assert_eq!(cells.get_cell_display(0, 0), "6.00");
+        cells.update_cell(1, 0, "=TRIPLE(A0)".to_string());
+        assert_eq!(cells.get_cell_display(1, 0), "calculating…");
+        finish_recalculation(&mut cells);
+        assert_eq!(cells.get_cell_display(1, 0), "18.00");
+        cells.update_cell(2, 0, "=A0+1".to_string());
+        assert_eq!(cells.get_cell_display(2, 0), "7.00");
+
+        // Completions offer the script's functions
+        cells.update(Message::CellClicked(1, 0));
+        cells.set_editing_formula("=TRI".to_string());
+        assert_eq!(cells.completions(), ["TRIPLE("]);
+        cells.update(Message::FinishEditing);
+
+        // Broken scripts keep the functions that worked
+        type_script(&mut cells, "fn triple(x) { x * 3.0");
+        assert!(cells.script_error.is_some());
+        assert_eq!(cells.get_cell_display(0, 0), "6.00");
+    }
+
+    #[test]
+    fn test_autosave_and_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = |dir: &Path| {
+            let mut cells = App::new().0;
+            cells.recovery = Some(Recovery::start(dir).unwrap());
+            cells.offer_recovery();
+            cells
+        };
+        let recovery_path = |cells: &App| cells.recovery.as_ref().unwrap().path().to_path_buf();
+        let saved = dir.path().join("plan.json");
+
+        let mut cells = session(dir.path());
+        let first = recovery_path(&cells);
+        // Nothing is written while nothing changed
+        cells.update(Message::Autosave);
+        assert!(!first.exists());
+
+        cells.update_cell(0, 0, "2".to_string());
+        cells.update_cell(0, 1, "=A0*21".to_string());
+        cells.update(Message::Autosave);
+        assert!(first.exists());
+
+        // Another window open at the same time is not offered the work
+        let other = session(dir.path());
+        assert!(other.recovered.is_none());
+        drop(other);
+
+        // A crash leaves the work to restore in the next session, where it
+        // moves to that session's recovery file
+        drop(cells);
+        let mut next = session(dir.path());
+        assert!(next.recovered.is_some());
+        next.update(Message::RestoreRecovered);
+        assert_eq!(next.get_cell_display(0, 1), "42.00");
+        assert_eq!(
+            next.file_status,
+            Some(Ok("restored unsaved work".to_string()))
+        );
+        assert!(!first.exists());
+        let recovery = recovery_path(&next);
+        assert!(recovery.exists());
+
+        // Saving removes the recovery file, and the saved file is opened
+        // again as it was
+        next.update(Message::FilePathChanged(saved.display().to_string()));
+        next.update(Message::SaveFile);
+        assert!(!recovery.exists());
+        next.update(Message::Autosave);
+        assert!(!recovery.exists());
+        let mut opened = App::new().0;
+        opened.update(Message::FilePathChanged(saved.display().to_string()));
+        opened.update(Message::OpenFile);
+        assert_eq!(opened.get_cell_display(0, 1), "42.00");
+        assert_eq!(opened.saved_to.as_deref(), Some(saved.as_path()));
+
+        // Unsaved edits to a saved file are offered until discarded
+        next.update_cell(1, 0, "more".to_string());
+        next.update(Message::Autosave);
+        drop(next);
+        let mut last = session(dir.path());
+        let recovered = last.recovered.as_ref().unwrap();
+        assert_eq!(recovered.file.as_deref(), Some(saved.as_path()));
+        last.update(Message::DiscardRecovered);
+        assert!(last.recovered.is_none());
+        assert!(!recovery.exists());
+
+        opened.update(Message::FilePathChanged(
+            dir.path().join("missing.json").display().to_string(),
+        ));
+        opened.update(Message::OpenFile);
+        assert!(matches!(opened.file_status, Some(Err(_))));
+        assert_eq!(opened.get_cell_display(0, 1), "42.00");
+
+        // Sheets of other sizes, such as imported workbooks, open as they are
+        let tall = dir.path().join("tall.json");
+        let mut sheet = Sheet::new(150, 10);
+        sheet.set_formula((149, 9), "7");
+        file::save(&sheet, &tall).unwrap();
+        opened.update(Message::FilePathChanged(tall.display().to_string()));
+        opened.update(Message::OpenFile);
+        assert_eq!(opened.sheet.rows(), 150);
+        assert_eq!(opened.row_heights().len(), 150);
+        assert_eq!(opened.get_cell_display(149, 9), "7.00");
+
+        // Sheets too long to lay out are refused, keeping the open one
+        let huge = dir.path().join("huge.json");
+        std::fs::write(&huge, r#"{ "rows": 100000000, "cells": {} }"#).unwrap();
+        opened.update(Message::FilePathChanged(huge.display().to_string()));
+        opened.update(Message::OpenFile);
+        assert!(matches!(opened.file_status, Some(Err(_))));
+        assert_eq!(opened.sheet.rows(), 150);
+    }
+
+    #[test]
+    fn test_imported_files_are_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watch.csv");
+        std::fs::write(&path, "region,sales\nnorth,10\n").unwrap();
+        let mut cells = App::new().0;
+        cells.update_cell(
+            0,
+            0,
+            format!("=IMPORTCSV(\"{}\", \"A1:B9\")", path.display()),
+        );
+        cells.update_cell(0, 3, "=SUM(B0:B8)".to_string());
+        assert_eq!(cells.get_cell_display(0, 0), "north");
+        assert_eq!(cells.get_cell_display(0, 3), "10.00");
+
+        // Writing the file is noticed, and recalculates what imports it
+        let mut changes = watch(&cells.sheet.imported_files());
+        std::fs::write(&path, "region,sales\nnorth,10\nsouth,32\n").unwrap();
+        let message = iced::futures::executor::block_on(changes.next()).unwrap();
+        assert!(matches!(&message, Message::ImportedFileChanged(file) if *file == path));
+        cells.update(message);
+        assert_eq!(cells.get_cell_display(1, 0), "south");
+        assert_eq!(cells.get_cell_display(0, 3), "42.00");
+
+        // A relative path is read from the directory the sheet is saved in
+        cells.update_cell(0, 4, "=IMPORTCSV(\"watch.csv\", \"B2\")".to_string());
+        assert_eq!(cells.get_cell_display(0, 4), "#REF");
+        let saved = path.with_file_name("watch.json");
+        cells.update(Message::FilePathChanged(saved.display().to_string()));
+        cells.update(Message::SaveFile);
+        assert_eq!(cells.get_cell_display(0, 4), "32.00");
+    }
+
+    #[test]
+    fn test_print_panel() {
+        let mut cells = App::new().0;
+        cells.update_cell(0, 0, "Total".to_string());
+        cells.update_cell(30, 1, "42".to_string());
+        cells.update(Message::PrintToggled(true));
+        cells.update(Message::PrintAreaChanged("A0:B30".to_string()));
+        cells.update(Message::PageBreaksChanged("10, 20,".to_string()));
+        cells.update(Message::ApplyPrintSetup);
+        cells.update(Message::LandscapeToggled(true));
+        assert_eq!(cells.printing.setup_error, None);
+        assert_eq!(cells.sheet.print.area, parse_range("A0:B30"));
+        assert_eq!(cells.sheet.print.row_breaks, [10, 20].into());
+        assert!(cells.sheet.print.landscape);
+
+        // Invalid settings leave the print setup as it was
+        cells.update(Message::PageBreaksChanged("10, x".to_string()));
+        cells.update(Message::ApplyPrintSetup);
+        assert!(cells.printing.setup_error.is_some());
+        assert_eq!(cells.sheet.print.row_breaks, [10, 20].into());
+        cells.update(Message::PrintToggled(false));
+        cells.update(Message::PrintToggled(true));
+        assert_eq!(cells.printing.breaks, "10, 20");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("print-panel.pdf");
+        cells.update(Message::ExportPathChanged(
+            path.to_string_lossy().to_string(),
+        ));
+        cells.update(Message::Export);
+        assert_eq!(
+            cells.printing.result,
+            Some(Ok(format!("wrote 3 pages to {}", path.display())))
+        );
+        assert!(std::fs::read(&path).unwrap().starts_with(b"%PDF"));
+
+        cells.update(Message::ExportRangeChanged("A0:".to_string()));
+        cells.update(Message::Export);
+        assert!(matches!(cells.printing.result, Some(Err(_))));
+    }
 }
@@ -0,0 +1,141 @@
+// Number format codes as used by Excel and LibreOffice, such as "0.00",
+// "#,##0" or "0.0%". Only the first section of a code is used, and codes
+// for dates, times and scientific notation are kept but not applied.
+
+use crate::CellValue;
+
+// Text shown for a value: numbers in the number format of their cell, or
+// with two decimals, and errors with a leading '#'
+pub fn display(value: &CellValue, code: Option<&str>) -> String {
+    match value {
+        CellValue::Number(n) => code
+            .and_then(|code| format_number(*n, code))
+            .unwrap_or_else(|| format!("{:.2}", n)),
+        // Decimals keep every digit unless a format code is applied
+        CellValue::Decimal(d) => code
+            .and_then(|code| format_number(d.to_f64(), code))
+            .unwrap_or_else(|| {
+                let text = d.round(2).to_string();
+                match text.split_once('.') {
+                    Some((whole, fraction)) => format!("{}.{:0<2}", whole, fraction),
+                    None => format!("{}.00", text),
+                }
+            }),
+        CellValue::Text(text) => text.clone(),
+        CellValue::Error(err) => format!("#{}", err),
+    }
+}
+
+// The number formatted with the code, or None when the code is not
+// understood and the number should be shown as usual
+pub fn format_number(n: f64, code: &str) -> Option<String> {
+    let code = code.split(';').next().unwrap_or_default();
+    let (prefix, pattern, suffix) = split(code)?;
+
+    let percent = prefix.contains('%') || suffix.contains('%');
+    let value = if percent { n * 100.0 } else { n };
+    let (integer, decimals) = pattern.split_once('.').unwrap_or((pattern, ""));
+    let places = decimals.chars().filter(|c| matches!(c, '0' | '#')).count();
+
+    // Halves round away from zero, as in Excel
+    let scale = 10f64.powi(places as i32);
+    let digits = format!("{:.*}", places, (value.abs() * scale).round() / scale);
+    let (whole, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+    let whole = if integer.contains(',') {
+        group_thousands(whole)
+    } else {
+        whole.to_string()
+    };
+    let sign = if value < 0.0 && digits.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        "-"
+    } else {
+        ""
+    };
+    let number = if fraction.is_empty() {
+        whole
+    } else {
+        format!("{}.{}", whole, fraction)
+    };
+    Some(format!(
+        "{}{}{}{}",
+        sign,
+        literal(prefix),
+        number,
+        literal(suffix)
+    ))
+}
+
+// Text before the digit placeholders, the placeholders and the text after
+// them. Codes with date or time letters, exponents or no placeholders are
+// not understood.
+fn split(code: &str) -> Option<(&str, &str, &str)> {
+    let start = code.find(['0', '#'])?;
+    let end = code.rfind(['0', '#'])? + 1;
+    let (prefix, suffix) = (&code[..start], &code[end..]);
+    let unquoted = |text: &str| {
+        text.split('"')
+            .step_by(2)
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    if [prefix, suffix]
+        .iter()
+        .any(|text| unquoted(text).contains(['y', 'm', 'd', 'h', 's', 'e', '@', '[']))
+        || !code[start..end]
+            .chars()
+            .all(|c| matches!(c, '0' | '#' | ',' | '.'))
+    {
+        return None;
+    }
+    Some((prefix, &code[start..end], suffix))
+}
+
+// Literal text around the number, without quotes and escapes
+fn literal(text: &str) -> String {
+    text.chars().filter(|c| !matches!(c, '"' | '\\')).collect()
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(12.3456, "0.00"), Some("12.35".to_string()));
+        assert_eq!(format_number(2.5, "0"), Some("3".to_string()));
+        assert_eq!(
+            format_number(1234567.891, "#,##0.00"),
+            Some("1,234,567.89".to_string())
+        );
+        assert_eq!(format_number(-1234.0, "#,##0"), Some("-1,234".to_string()));
+        assert_eq!(format_number(0.256, "0.0%"), Some("25.6%".to_string()));
+        assert_eq!(
+            format_number(9.5, "\"$\"#,##0.00"),
+            Some("$9.50".to_string())
+        );
+        assert_eq!(format_number(-0.001, "0.00"), Some("0.00".to_string()));
+        assert_eq!(
+            format_number(4.0, "0.00;[Red]-0.00"),
+            Some("4.00".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unsupported_codes() {
+        assert_eq!(format_number(45000.0, "yyyy-mm-dd"), None);
+        assert_eq!(format_number(1.0, "General"), None);
+        assert_eq!(format_number(1.0, "0.00E+00"), None);
+        assert_eq!(format_number(1.0, "[h]:mm"), None);
+    }
+}
@@ -0,0 +1,737 @@
+// OpenDocument spreadsheets (.ods), as written by LibreOffice. Formulas are
+// converted between OpenFormula, e.g. "of:=SUM([.A1:.B3];[.C1])", and the
+// form typed into cells, "=SUM(A0:B2, C0)". Functions the engine does not
+// know are kept and evaluate to #NAME.
+
+use crate::formula::{Cell, Range, cell_name};
+use crate::sheet::MAX_ROWS;
+use crate::syntax::TokenKind;
+use crate::workbook::{
+    Workbook, a1_column, drop_spilled, merge_imported, parse_a1, rewrite, shift_references,
+};
+use crate::xml::{self, Element, Node, escaped};
+use crate::{CellValue, Sheet, zip};
+use std::collections::{BTreeMap, HashMap};
+
+const MIMETYPE: &str = "application/vnd.oasis.opendocument.spreadsheet";
+
+// Sheets are at least as large as the GUI grid
+const MIN_ROWS: usize = 100;
+const COLS: usize = 26;
+
+pub fn read(data: &[u8]) -> Result<(Workbook, Vec<String>), String> {
+    let files: HashMap<String, Vec<u8>> = zip::read(data)?.into_iter().collect();
+    if files.get("mimetype").map(|m| m.as_slice()) != Some(MIMETYPE.as_bytes()) {
+        return Err("not an OpenDocument spreadsheet".to_string());
+    }
+    let part = |name: &str| -> Result<Option<Element>, String> {
+        match files.get(name) {
+            Some(bytes) => xml::parse(&String::from_utf8_lossy(bytes))
+                .map(Some)
+                .map_err(|e| format!("{}: {}", name, e)),
+            None => Ok(None),
+        }
+    };
+    let content = part("content.xml")?.ok_or("missing content.xml")?;
+    let styles = part("styles.xml")?.unwrap_or_default();
+    let formats = number_formats(&content, &styles);
+
+    let spreadsheet = content
+        .find("office:spreadsheet")
+        .ok_or("document without a spreadsheet")?;
+    let mut warnings = Vec::new();
+    let mut sheets = Vec::new();
+    for table in spreadsheet.children("table:table") {
+        let name = table.attribute("table:name").unwrap_or("Sheet").to_string();
+        let sheet = read_table(&name, table, &formats, &mut warnings);
+        sheets.push((name, sheet));
+    }
+
+    if let Some(expressions) = spreadsheet.child("table:named-expressions") {
+        for range in expressions.children("table:named-range") {
+            define_name(&mut sheets, range, &mut warnings);
+        }
+    }
+    Ok((Workbook { sheets }, warnings))
+}
+
+// Format code of every cell style with a number format, by style name.
+// Number styles are described element by element, e.g. a number with two
+// decimals followed by a percent sign, and turned into codes like "0.00%".
+fn number_formats(content: &Element, styles: &Element) -> HashMap<String, String> {
+    let containers: Vec<&Element> = [
+        content.child("office:automatic-styles"),
+        styles.child("office:styles"),
+        styles.child("office:automatic-styles"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let mut codes = HashMap::new();
+    for style in containers.iter().flat_map(|c| c.elements()) {
+        if !matches!(
+            style.name.as_str(),
+            "number:number-style" | "number:percentage-style" | "number:currency-style"
+        ) {
+            continue;
+        }
+        let Some(name) = style.attribute("style:name") else {
+            continue;
+        };
+        let mut code = String::new();
+        for part in style.elements() {
+            match part.name.as_str() {
+                "number:number" => {
+                    let decimals: usize = part
+                        .attribute("number:decimal-places")
+                        .and_then(|d| d.parse().ok())
+                        .unwrap_or(0);
+                    code.push_str(if part.attribute("number:grouping") == Some("true") {
+                        "#,##0"
+                    } else {
+                        "0"
+                    });
+                    if decimals > 0 {
+                        code.push('.');
+                        code.push_str(&"0".repeat(decimals));
+                    }
+                }
+                "number:text" | "number:currency-symbol" => {
+                    let text = part.text();
+                    if text == "%" {
+                        code.push('%');
+                    } else if !text.is_empty() {
+                        code.push_str(&format!("\"{}\"", text));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if code.contains('0') {
+            codes.insert(name.to_string(), code);
+        }
+    }
+
+    // Cell styles point to number styles by name
+    containers
+        .iter()
+        .flat_map(|c| c.children("style:style"))
+        .filter_map(|style| {
+            let code = codes.get(style.attribute("style:data-style-name")?)?;
+            Some((style.attribute("style:name")?.to_string(), code.clone()))
+        })
+        .collect()
+}
+
+fn read_table(
+    name: &str,
+    table: &Element,
+    formats: &HashMap<String, String>,
+    warnings: &mut Vec<String>,
+) -> Sheet {
+    let mut inputs: BTreeMap<Cell, String> = BTreeMap::new();
+    let mut number_formats = HashMap::new();
+    let mut merges = Vec::new();
+    let mut arrays = Vec::new();
+    let mut dropped = 0;
+    let mut past_rows = 0usize;
+
+    let mut rows = Vec::new();
+    collect_rows(table, &mut rows);
+    let mut row_index = 0;
+    for row in rows {
+        let repeat = repeated(row, "table:number-rows-repeated");
+        let mut col = 0;
+        for cell in row.elements() {
+            if !matches!(
+                cell.name.as_str(),
+                "table:table-cell" | "table:covered-table-cell"
+            ) {
+                continue;
+            }
+            let cols = repeated(cell, "table:number-columns-repeated");
+            let spanned = (
+                repeated(cell, "table:number-rows-spanned"),
+                repeated(cell, "table:number-columns-spanned"),
+            );
+            if spanned != (1, 1) {
+                merges.push(Range::new(
+                    (row_index, col),
+                    (
+                        row_index.saturating_add(spanned.0 - 1),
+                        col.saturating_add(spanned.1 - 1),
+                    ),
+                ));
+            }
+            let matrix = (
+                repeated(cell, "table:number-matrix-rows-spanned"),
+                repeated(cell, "table:number-matrix-columns-spanned"),
+            );
+            if matrix != (1, 1) {
+                arrays.push(Range::new(
+                    (row_index, col),
+                    (
+                        row_index.saturating_add(matrix.0 - 1),
+                        col.saturating_add(matrix.1 - 1),
+                    ),
+                ));
+            }
+            if let Some(input) = cell_input(cell) {
+                let format = cell
+                    .attribute("table:style-name")
+                    .and_then(|style| formats.get(style));
+                // Repeated rows past the end of the sheet are counted rather
+                // than walked, as filler may repeat a row a million times
+                let end = row_index.saturating_add(repeat);
+                past_rows = past_rows.saturating_add(
+                    end.saturating_sub(row_index.max(MAX_ROWS))
+                        .saturating_mul(cols),
+                );
+                for r in row_index..end.min(MAX_ROWS) {
+                    for c in col..col.saturating_add(cols) {
+                        if c >= COLS {
+                            dropped += 1;
+                            continue;
+                        }
+                        // Formulas are written for the first cell of a
+                        // repeated block
+                        let input = if input.starts_with('=') {
+                            shift_references(&input, (r - row_index) as isize, (c - col) as isize)
+                        } else {
+                            input.clone()
+                        };
+                        inputs.insert((r, c), input);
+                        if let Some(format) = format {
+                            number_formats.insert((r, c), format.clone());
+                        }
+                    }
+                }
+            }
+            col = col.saturating_add(cols);
+        }
+        row_index = row_index.saturating_add(repeat);
+    }
+
+    if dropped > 0 {
+        warnings.push(format!(
+            "{}: {} cells past column Z were left out",
+            name, dropped
+        ));
+    }
+    if past_rows > 0 {
+        warnings.push(format!(
+            "{}: {} cells past row {} were left out",
+            name, past_rows, MAX_ROWS
+        ));
+    }
+    drop_spilled(&mut inputs, &arrays);
+    let rows = inputs
+        .keys()
+        .map(|(row, _)| row + 1)
+        .chain(
+            merges
+                .iter()
+                .map(|merge: &Range| merge.end.0.saturating_add(1)),
+        )
+        .max()
+        .unwrap_or(0)
+        .clamp(MIN_ROWS, MAX_ROWS);
+    let mut sheet = Sheet::new(rows, COLS);
+    for (cell, input) in inputs {
+        sheet.edit(cell, &input);
+    }
+    sheet.recalculate_all();
+    sheet.number_formats = number_formats;
+    merge_imported(name, &mut sheet, &merges, warnings);
+    sheet
+}
+
+// Rows of a table in order, including those inside header rows and groups
+fn collect_rows<'a>(element: &'a Element, rows: &mut Vec<&'a Element>) {
+    for child in element.elements() {
+        match child.name.as_str() {
+            "table:table-row" => rows.push(child),
+            "table:table-header-rows" | "table:table-row-group" | "table:table-rows" => {
+                collect_rows(child, rows)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn repeated(element: &Element, attribute: &str) -> usize {
+    element
+        .attribute(attribute)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1)
+        .max(1)
+}
+
+// What to type into cells for an ODS cell, if it is not empty
+fn cell_input(cell: &Element) -> Option<String> {
+    if let Some(formula) = cell.attribute("table:formula") {
+        return Some(import_formula(formula));
+    }
+    let input = match cell.attribute("office:value-type") {
+        Some("float" | "percentage" | "currency") => cell.attribute("office:value")?.to_string(),
+        Some("boolean") => match cell.attribute("office:boolean-value") {
+            Some("true") => "=TRUE".to_string(),
+            _ => "=FALSE".to_string(),
+        },
+        _ => cell
+            .children("text:p")
+            .map(paragraph_text)
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    (!input.is_empty()).then_some(input)
+}
+
+// Text of a paragraph, where runs of spaces are stored as <text:s/>
+fn paragraph_text(paragraph: &Element) -> String {
+    let mut text = String::new();
+    for node in &paragraph.children {
+        match node {
+            Node::Text(t) => text.push_str(t),
+            Node::Element(e) if e.name == "text:s" => {
+                text.push_str(&" ".repeat(repeated(e, "text:c")));
+            }
+            Node::Element(e) if e.name == "text:tab" => text.push('\t'),
+            Node::Element(e) if e.name == "text:line-break" => text.push('\n'),
+            Node::Element(e) => text.push_str(&paragraph_text(e)),
+        }
+    }
+    text
+}
+
+// An OpenFormula formula in the form typed into cells
+fn import_formula(formula: &str) -> String {
+    // Namespace prefixes such as "of:" come before the '='
+    let formula = match formula.split_once(':') {
+        Some((prefix, rest)) if !prefix.contains(['=', '"', '[']) => rest,
+        _ => formula,
+    };
+
+    let mut out = String::new();
+    let mut chars = formula.chars();
+    let mut in_text = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_text = !in_text;
+                out.push(c);
+            }
+            _ if in_text => out.push(c),
+            ';' => out.push(','),
+            '[' => {
+                // [.A1], [.A1:.B3] or [Sheet2.A1]
+                let reference: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                let parts: Vec<String> = reference
+                    .replace('$', "")
+                    .split(':')
+                    .map(|part| match part.rsplit_once('.') {
+                        Some(("", cell)) => cell.to_string(),
+                        Some((sheet, cell)) => format!("{}!{}", sheet.trim_matches('\''), cell),
+                        None => part.to_string(),
+                    })
+                    .collect();
+                out.push_str(&parts.join(":"));
+            }
+            _ => out.push(c),
+        }
+    }
+    let out = if out.starts_with('=') {
+        out
+    } else {
+        format!("={}", out)
+    };
+    shift_references(&out, -1, 0)
+}
+
+// A formula typed into cells in OpenFormula
+fn export_formula(formula: &str) -> String {
+    let formula = shift_references(formula, 1, 0);
+    let formula = rewrite(&formula, |tokens, i| {
+        let text = |i: usize| &formula[tokens[i].0.clone()];
+        let is = |i: usize, kind: TokenKind| tokens.get(i).is_some_and(|(_, k)| *k == kind);
+        match tokens[i].1 {
+            TokenKind::Reference
+                if is(i + 1, TokenKind::Operator)
+                    && text(i + 1) == ":"
+                    && is(i + 2, TokenKind::Reference) =>
+            {
+                Some((format!("[.{}:.{}]", text(i), text(i + 2)), 3))
+            }
+            TokenKind::Reference => Some((format!("[.{}]", text(i)), 1)),
+            TokenKind::Operator if text(i) == "," => Some((";".to_string(), 1)),
+            _ => None,
+        }
+    });
+    format!("of:{}", formula)
+}
+
+// Named ranges such as "$Sheet1.$B$1:.$B$10" are defined on their sheet
+fn define_name(sheets: &mut [(String, Sheet)], range: &Element, warnings: &mut Vec<String>) {
+    let Some(name) = range.attribute("table:name") else {
+        return;
+    };
+    let address = range
+        .attribute("table:cell-range-address")
+        .unwrap_or_default()
+        .replace('$', "");
+    let target = (|| {
+        let (start, end) = address.split_once(':').unwrap_or((&address, &address));
+        let (sheet, start) = start.rsplit_once('.')?;
+        let (_, end) = end.rsplit_once('.').unwrap_or(("", end));
+        let sheet = sheet.trim_matches('\'');
+        let index = sheets.iter().position(|(n, _)| n == sheet)?;
+        let (start, end) = (parse_a1(start)?, parse_a1(end)?);
+        Some((index, start, end))
+    })();
+    let result = match target {
+        Some((index, start, end)) if start.1 < COLS && end.1 < COLS => sheets[index]
+            .1
+            .define_name(name, &format!("{}:{}", cell_name(start), cell_name(end))),
+        Some(_) => Err("its range is past column Z".to_string()),
+        None => Err(format!("{} is not a range of a sheet", address)),
+    };
+    if let Err(err) = result {
+        warnings.push(format!("name {} was left out: {}", name, err));
+    }
+}
+
+pub fn write(workbook: &Workbook) -> Vec<u8> {
+    let files = [
+        ("mimetype", MIMETYPE.as_bytes().to_vec()),
+        ("META-INF/manifest.xml", MANIFEST.as_bytes().to_vec()),
+        ("content.xml", content_part(workbook).into_bytes()),
+    ];
+    zip::write(&files)
+}
+
+const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest:manifest xmlns:manifest="urn:oasis:names:tc:opendocument:xmlns:manifest:1.0" manifest:version="1.2">
+<manifest:file-entry manifest:full-path="/" manifest:media-type="application/vnd.oasis.opendocument.spreadsheet"/>
+<manifest:file-entry manifest:full-path="content.xml" manifest:media-type="text/xml"/>
+</manifest:manifest>"#;
+
+fn content_part(workbook: &Workbook) -> String {
+    // Every distinct format code the number styles understand gets a
+    // number style N<i> and a cell style ce<i>
+    let mut codes: Vec<&str> = workbook
+        .sheets
+        .iter()
+        .flat_map(|(_, sheet)| sheet.number_formats.values().map(String::as_str))
+        .filter(|code| number_style("", code).is_some())
+        .collect();
+    codes.sort();
+    codes.dedup();
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<office:document-content \
+         xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" \
+         xmlns:style=\"urn:oasis:names:tc:opendocument:xmlns:style:1.0\" \
+         xmlns:text=\"urn:oasis:names:tc:opendocument:xmlns:text:1.0\" \
+         xmlns:table=\"urn:oasis:names:tc:opendocument:xmlns:table:1.0\" \
+         xmlns:number=\"urn:oasis:names:tc:opendocument:xmlns:datastyle:1.0\" \
+         xmlns:of=\"urn:oasis:names:tc:opendocument:xmlns:of:1.2\" office:version=\"1.2\">\
+         <office:automatic-styles>",
+    );
+    for (i, code) in codes.iter().enumerate() {
+        xml.push_str(&number_style(&format!("N{}", i), code).expect("filtered above"));
+        xml.push_str(&format!(
+            "<style:style style:name=\"ce{}\" style:family=\"table-cell\" style:data-style-name=\"N{}\"/>",
+            i, i
+        ));
+    }
+    xml.push_str("</office:automatic-styles><office:body><office:spreadsheet>");
+
+    for (name, sheet) in &workbook.sheets {
+        xml.push_str(&format!("<table:table table:name=\"{}\">", escaped(name)));
+        let used_cols = sheet
+            .formulas
+            .keys()
+            .map(|(_, col)| col + 1)
+            .max()
+            .unwrap_or(1);
+        xml.push_str(&format!(
+            "<table:table-column table:number-columns-repeated=\"{}\"/>",
+            used_cols
+        ));
+
+        let mut rows: BTreeMap<usize, Vec<(usize, String)>> = BTreeMap::new();
+        for (cell, input) in &sheet.formulas {
+            if !input.is_empty() {
+                rows.entry(cell.0)
+                    .or_default()
+                    .push((cell.1, input.clone()));
+            }
+        }
+        // Merges are spanned by their top-left cell, even when it is empty
+        for merge in sheet.merges() {
+            if sheet
+                .formulas
+                .get(&merge.start)
+                .is_none_or(String::is_empty)
+            {
+                rows.entry(merge.start.0)
+                    .or_default()
+                    .push((merge.start.1, String::new()));
+            }
+        }
+        // The values array formulas spill are stored like constants
+        for (cell, value) in &sheet.values {
+            if sheet.spilled_from(*cell).is_some() {
+                rows.entry(cell.0)
+                    .or_default()
+                    .push((cell.1, value.to_string()));
+            }
+        }
+        let mut next_row = 0;
+        for (row, mut cells) in rows {
+            if row > next_row {
+                xml.push_str(&format!(
+                    "<table:table-row table:number-rows-repeated=\"{}\"><table:table-cell/></table:table-row>",
+                    row - next_row
+                ));
+            }
+            next_row = row + 1;
+            cells.sort();
+            xml.push_str("<table:table-row>");
+            let mut next_col = 0;
+            for (col, input) in cells {
+                if col > next_col {
+                    xml.push_str(&format!(
+                        "<table:table-cell table:number-columns-repeated=\"{}\"/>",
+                        col - next_col
+                    ));
+                }
+                next_col = col + 1;
+                let style = sheet
+                    .number_formats
+                    .get(&(row, col))
+                    .and_then(|code| codes.iter().position(|c| c == code))
+                    .map(|i| format!(" table:style-name=\"ce{}\"", i))
+                    .unwrap_or_default();
+                let span = sheet
+                    .merged((row, col))
+                    .filter(|merge| merge.start == (row, col))
+                    .map(|merge| {
+                        format!(
+                            " table:number-columns-spanned=\"{}\" table:number-rows-spanned=\"{}\"",
+                            merge.cols(),
+                            merge.rows()
+                        )
+                    })
+                    .unwrap_or_default();
+                // Array formulas are stored as matrices of the size they
+                // spilled
+                let matrix = sheet
+                    .spill((row, col))
+                    .filter(|spill| !spill.blocked)
+                    .map(|spill| {
+                        format!(
+                            " table:number-matrix-columns-spanned=\"{}\" table:number-matrix-rows-spanned=\"{}\"",
+                            spill.range.cols(),
+                            spill.range.rows()
+                        )
+                    })
+                    .unwrap_or_default();
+                xml.push_str(&cell_part(
+                    &format!("{}{}{}", style, span, matrix),
+                    &input,
+                    sheet.value((row, col)),
+                ));
+            }
+            xml.push_str("</table:table-row>");
+        }
+        xml.push_str("</table:table>");
+    }
+
+    let mut names = String::new();
+    for (sheet_name, sheet) in &workbook.sheets {
+        let sheet_name = format!("'{}'", sheet_name.replace('\'', "''"));
+        for (name, range) in sheet.names() {
+            names.push_str(&format!(
+                "<table:named-range table:name=\"{}\" table:base-cell-address=\"${}.$A$1\" \
+                 table:cell-range-address=\"${}.${}${}:.${}${}\"/>",
+                escaped(name),
+                escaped(&sheet_name),
+                escaped(&sheet_name),
+                a1_column(range.start.1),
+                range.start.0 + 1,
+                a1_column(range.end.1),
+                range.end.0 + 1
+            ));
+        }
+    }
+    if !names.is_empty() {
+        xml.push_str(&format!(
+            "<table:named-expressions>{}</table:named-expressions>",
+            names
+        ));
+    }
+    xml.push_str("</office:spreadsheet></office:body></office:document-content>");
+    xml
+}
+
+// Number style for a simple format code such as "#,##0.00%" or "0.0"
+fn number_style(name: &str, code: &str) -> Option<String> {
+    let percent = code.ends_with('%');
+    let pattern = code.strip_suffix('%').unwrap_or(code);
+    if pattern.is_empty() || !pattern.chars().all(|c| matches!(c, '0' | '#' | ',' | '.')) {
+        return None;
+    }
+    let decimals = pattern.split_once('.').map_or(0, |(_, d)| d.len());
+    let number = format!(
+        "<number:number number:decimal-places=\"{}\" number:min-integer-digits=\"1\"{}/>",
+        decimals,
+        if pattern.contains(',') {
+            " number:grouping=\"true\""
+        } else {
+            ""
+        }
+    );
+    Some(if percent {
+        format!(
+            "<number:percentage-style style:name=\"{}\">{}<number:text>%</number:text></number:percentage-style>",
+            name, number
+        )
+    } else {
+        format!(
+            "<number:number-style style:name=\"{}\">{}</number:number-style>",
+            name, number
+        )
+    })
+}
+
+fn cell_part(attributes: &str, input: &str, value: Option<&CellValue>) -> String {
+    let input = input.trim();
+    let formula = if input.starts_with('=') {
+        format!(" table:formula=\"{}\"", escaped(&export_formula(input)))
+    } else {
+        String::new()
+    };
+    let (value, shown) = match value {
+        Some(number @ (CellValue::Number(_) | CellValue::Decimal(_))) => (
+            format!(" office:value-type=\"float\" office:value=\"{}\"", number),
+            number.to_string(),
+        ),
+        Some(CellValue::Text(text)) => (
+            format!(
+                " office:value-type=\"string\" office:string-value=\"{}\"",
+                escaped(text)
+            ),
+            text.clone(),
+        ),
+        Some(error @ CellValue::Error(_)) => (String::new(), error.to_string()),
+        None => (String::new(), String::new()),
+    };
+    format!(
+        "<table:table-cell{}{}{}><text:p>{}</text:p></table:table-cell>",
+        attributes,
+        formula,
+        value,
+        escaped(&shown)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formulas() {
+        assert_eq!(
+            export_formula("=SUM(A0:B2, C0) + \"a,b\""),
+            "of:=SUM([.A1:.B3]; [.C1]) + \"a,b\""
+        );
+        assert_eq!(
+            import_formula("of:=SUM([.A1:.B3];[.$C$1]) + \"x;[y]\""),
+            "=SUM(A0:B2,C0) + \"x;[y]\""
+        );
+        assert_eq!(import_formula("of:=[Data.A2]*2"), "=Data!A1*2");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut sheet = Sheet::new(100, 26);
+        sheet.set_formula((0, 0), "Fish & chips");
+        sheet.set_formula((0, 2), "0.5");
+        sheet.set_formula((3, 1), "=SUM(C0:C2)*2");
+        sheet.set_formula((4, 1), "=UNKNOWN(C0)");
+        sheet.define_name("rate", "C0").unwrap();
+        sheet.number_formats.insert((0, 2), "0.0%".to_string());
+        sheet.number_formats.insert((3, 1), "#,##0.00".to_string());
+        sheet.merge(Range::new((0, 0), (1, 1))).unwrap();
+        sheet.merge(Range::new((8, 3), (8, 5))).unwrap();
+        sheet.set_formula((5, 0), "=TRANSPOSE(SEQUENCE(3))");
+        let workbook = Workbook {
+            sheets: vec![
+                ("Main".to_string(), sheet.clone()),
+                ("Other".to_string(), Sheet::new(100, 26)),
+            ],
+        };
+
+        let (read, warnings) = read(&write(&workbook)).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(read.sheets.len(), 2);
+        let main = read.sheet("Main").unwrap();
+        assert_eq!(main.formulas, sheet.formulas);
+        assert_eq!(main.values, sheet.values);
+        assert_eq!(main.number_formats, sheet.number_formats);
+        assert_eq!(main.names(), sheet.names());
+        assert_eq!(main.merges(), sheet.merges());
+        assert_eq!(main.spill((5, 0)), sheet.spill((5, 0)));
+        assert_eq!(
+            main.value((4, 1)),
+            Some(&CellValue::Error("NAME".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_read_repeated_cells() {
+        let content = r#"<office:document-content><office:body><office:spreadsheet>
+            <table:table table:name="T">
+              <table:table-header-rows><table:table-row>
+                <table:table-cell office:value-type="string"><text:p>a<text:s text:c="2"/>b</text:p></table:table-cell>
+              </table:table-row></table:table-header-rows>
+              <table:table-row table:number-rows-repeated="2">
+                <table:table-cell table:number-columns-repeated="2"/>
+                <table:table-cell table:formula="of:=[.A1]" table:number-columns-repeated="2"/>
+              </table:table-row>
+              <table:table-row table:number-rows-repeated="1048570"><table:table-cell/></table:table-row>
+            </table:table>
+            <table:table table:name="Long">
+              <table:table-row table:number-rows-repeated="18446744073709551615">
+                <table:table-cell office:value-type="float" office:value="1" table:number-columns-repeated="2"/>
+              </table:table-row>
+              <table:table-row><table:table-cell office:value-type="float" office:value="2"/></table:table-row>
+            </table:table></office:spreadsheet></office:body></office:document-content>"#;
+        let files = [
+            ("mimetype", MIMETYPE.as_bytes().to_vec()),
+            ("content.xml", content.as_bytes().to_vec()),
+        ];
+        let (workbook, warnings) = read(&zip::write(&files)).unwrap();
+        let sheet = workbook.sheet("T").unwrap();
+        assert_eq!(sheet.rows(), 100);
+        assert_eq!(sheet.formulas[&(0, 0)], "a  b");
+        assert_eq!(sheet.formulas[&(1, 2)], "=A0");
+        assert_eq!(sheet.formulas[&(1, 3)], "=B0");
+        assert_eq!(sheet.formulas[&(2, 2)], "=A1");
+        assert_eq!(sheet.formulas.len(), 5);
+
+        // Rows repeated past the end of the sheet are left out
+        let long = workbook.sheet("Long").unwrap();
+        assert_eq!(long.rows(), MAX_ROWS);
+        assert_eq!(long.formulas.len(), 2 * MAX_ROWS);
+        assert_eq!(
+            warnings,
+            [format!(
+                "Long: {} cells past row 10000 were left out",
+                usize::MAX
+            )]
+        );
+    }
+}
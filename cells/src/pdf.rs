@@ -0,0 +1,237 @@
+// Minimal PDF writer for printed sheets: pages of filled rectangles, lines
+// and text in Helvetica, one of the fonts every PDF viewer provides, so
+// nothing needs embedding. Text is limited to the Windows-1252 character
+// set, and other characters print as '?'.
+
+use crate::format::Rgb;
+
+// Page of a document being drawn. Positions are in points from the
+// top-left corner of the page.
+pub struct Page {
+    width: f32,
+    height: f32,
+    content: String,
+}
+
+impl Page {
+    pub fn new(width: f32, height: f32) -> Self {
+        Self {
+            width,
+            height,
+            content: String::new(),
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: f32, y: f32, width: f32, height: f32, color: Rgb) {
+        self.content.push_str(&format!(
+            "{} rg {} {} {} {} re f\n",
+            color_operands(color),
+            number(x),
+            number(self.height - y - height),
+            number(width),
+            number(height)
+        ));
+    }
+
+    pub fn line(&mut self, (x1, y1): (f32, f32), (x2, y2): (f32, f32), color: Rgb, width: f32) {
+        self.content.push_str(&format!(
+            "{} RG {} w {} {} m {} {} l S\n",
+            color_operands(color),
+            number(width),
+            number(x1),
+            number(self.height - y1),
+            number(x2),
+            number(self.height - y2)
+        ));
+    }
+
+    // Text with its baseline at `y`
+    pub fn text(&mut self, x: f32, y: f32, size: f32, color: Rgb, text: &str) {
+        let hex: String = text.chars().map(|c| format!("{:02X}", encode(c))).collect();
+        self.content.push_str(&format!(
+            "BT /F1 {} Tf {} rg {} {} Td <{}> Tj ET\n",
+            number(size),
+            color_operands(color),
+            number(x),
+            number(self.height - y),
+            hex
+        ));
+    }
+
+    // Draws what follows up to `restore` only inside a rectangle
+    pub fn clip(&mut self, x: f32, y: f32, width: f32, height: f32) {
+        self.content.push_str(&format!(
+            "q {} {} {} {} re W n\n",
+            number(x),
+            number(self.height - y - height),
+            number(width),
+            number(height)
+        ));
+    }
+
+    pub fn restore(&mut self) {
+        self.content.push_str("Q\n");
+    }
+}
+
+// The document holding the pages, in order
+pub fn write(pages: &[Page]) -> Vec<u8> {
+    // Objects 1 to 3 are the catalog, the page tree and the font, followed
+    // by every page and its content
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            (0..pages.len())
+                .map(|i| format!("{} 0 R", 4 + 2 * i))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+            .to_string(),
+    ];
+    for (i, page) in pages.iter().enumerate() {
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+             /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            number(page.width),
+            number(page.height),
+            5 + 2 * i
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            page.content.len(),
+            page.content
+        ));
+    }
+
+    let mut pdf = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+    let xref = pdf.len();
+    pdf.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        )
+        .as_bytes(),
+    );
+    pdf
+}
+
+// Width of text in Helvetica at a font size, in points
+pub fn text_width(text: &str, size: f32) -> f32 {
+    let units: u32 = text.chars().map(|c| char_width(encode(c))).sum();
+    units as f32 * size / 1000.0
+}
+
+// Advance widths of the printable ASCII characters, in thousandths of the
+// font size, from Helvetica's font metrics
+const ASCII_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278,
+    278, // ' ' to '/'
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, // digits
+    278, 278, 584, 584, 584, 556, 1015, // ':' to '@'
+    667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, // 'A' to 'M'
+    722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, // 'N' to 'Z'
+    278, 278, 278, 469, 556, 333, // '[' to '`'
+    556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, // 'a' to 'm'
+    556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, // 'n' to 'z'
+    334, 260, 334, 584, // '{' to '~'
+];
+
+fn char_width(byte: u8) -> u32 {
+    match byte {
+        32..=126 => ASCII_WIDTHS[(byte - 32) as usize] as u32,
+        _ => 556,
+    }
+}
+
+// Windows-1252 code of a character
+fn encode(c: char) -> u8 {
+    match c {
+        ' '..='~' | '\u{A0}'..='\u{FF}' => c as u8,
+        '€' => 0x80,
+        '…' => 0x85,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        _ => b'?',
+    }
+}
+
+fn color_operands(Rgb(r, g, b): Rgb) -> String {
+    let channel = |c: u8| number(c as f32 / 255.0);
+    format!("{} {} {}", channel(r), channel(g), channel(b))
+}
+
+// Numbers with at most three decimals and without trailing zeros
+fn number(n: f32) -> String {
+    let text = format!("{:.3}", n);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    if text == "-0" {
+        "0".to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_structure() {
+        let mut page = Page::new(200.0, 100.0);
+        page.fill_rect(10.0, 10.0, 50.0, 20.0, Rgb(255, 0, 0));
+        page.text(12.0, 25.0, 10.0, Rgb(0, 0, 0), "Hé(llo)");
+        let pdf = write(&[page, Page::new(200.0, 100.0)]);
+        // Past the header's binary marker everything is ASCII
+        assert!(pdf.starts_with(b"%PDF-1.4\n%"));
+        let text = std::str::from_utf8(&pdf[15..]).unwrap();
+
+        assert!(text.ends_with("%%EOF\n"));
+        assert!(text.contains("/Kids [4 0 R 6 0 R] /Count 2"));
+        // Positions count from the bottom-left corner in PDF
+        assert!(text.contains("1 0 0 rg 10 70 50 20 re f"));
+        assert!(text.contains("12 75 Td <48E9286C6C6F29> Tj"));
+
+        // The cross-reference table points at every object
+        let xref: usize = text
+            .rsplit("startxref\n")
+            .next()
+            .and_then(|rest| rest.lines().next())
+            .and_then(|offset| offset.parse().ok())
+            .unwrap();
+        assert!(pdf[xref..].starts_with(b"xref\n0 8\n"));
+        let entries = std::str::from_utf8(&pdf[xref..]).unwrap().lines().skip(3);
+        for (i, entry) in entries.take(7).enumerate() {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj", i + 1).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_text_width() {
+        assert_eq!(text_width("", 12.0), 0.0);
+        assert_eq!(text_width("10", 10.0), 11.12);
+        assert!(text_width("WWW", 10.0) > text_width("iii", 10.0));
+        assert_eq!(number(-0.0001), "0");
+        assert_eq!(number(12.5), "12.5");
+    }
+}
@@ -0,0 +1,632 @@
+// Printing a sheet as HTML or PDF reports. The printed range is laid out
+// as in the grid: columns as wide as grid columns, rows growing to fit
+// wrapped text, merged cells spanning their range, and the fills and data
+// bars of conditional formats. Values show in their number formats,
+// numbers right-aligned. Ranges too big for a page are split across pages
+// down, then over, and rows listed as page breaks always start a page.
+//
+// Sizes are in CSS pixels, 96 to the inch; PDF pages use points, 72 to the
+// inch.
+
+use crate::Sheet;
+use crate::format::{self, Appearance, Rgb};
+use crate::formula::col_to_letter;
+use crate::formula::{Cell, Range};
+use crate::number_format;
+use crate::pdf;
+use crate::xml::escaped;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+pub const COLUMN_WIDTH: f32 = 80.0;
+pub const ROW_HEIGHT: f32 = 24.0;
+const LINE_HEIGHT: f32 = 16.0;
+const FONT_SIZE: f32 = 12.0;
+const PADDING: f32 = 4.0;
+
+// A4 paper with half-inch margins
+const PAPER: (f32, f32) = (793.7, 1122.5);
+const MARGIN: f32 = 48.0;
+
+const POINTS_PER_PIXEL: f32 = 0.75;
+const GRID_COLOR: Rgb = Rgb(204, 204, 204);
+const TEXT_COLOR: Rgb = Rgb(0, 0, 0);
+const WHITE: Rgb = Rgb(255, 255, 255);
+
+// How the sheet prints, saved with it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrintSetup {
+    // Range printed when no other is asked for, instead of the used part
+    // of the sheet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub area: Option<Range>,
+    // Rows starting a new page, besides those that do not fit on the last
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub row_breaks: BTreeSet<usize>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub landscape: bool,
+}
+
+impl PrintSetup {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // Width and height of the printable part of a page
+    fn printable(&self) -> (f32, f32) {
+        let (width, height) = if self.landscape {
+            (PAPER.1, PAPER.0)
+        } else {
+            PAPER
+        };
+        (width - 2.0 * MARGIN, height - 2.0 * MARGIN)
+    }
+}
+
+// The range printed by default: the print area, or else the used part of
+// the sheet from A0. None when there is nothing to print.
+pub fn default_range(sheet: &Sheet) -> Option<Range> {
+    if let Some(area) = sheet.print.area {
+        return sheet.clip(area);
+    }
+    let cells = sheet.values.keys().chain(sheet.formulas.keys());
+    let end = cells.fold(None, |end: Option<Cell>, cell| {
+        Some(end.map_or(*cell, |end| (end.0.max(cell.0), end.1.max(cell.1))))
+    })?;
+    Some(Range::new((0, 0), end))
+}
+
+// Writes a range, or by default the print area or used part of the sheet,
+// as an HTML or PDF file told apart by extension. Returns the number of
+// pages.
+pub fn export(
+    sheet: &Sheet,
+    range: Option<Range>,
+    path: impl AsRef<Path>,
+) -> Result<usize, String> {
+    let path = path.as_ref();
+    let range = match range {
+        Some(range) => sheet
+            .clip(range)
+            .ok_or_else(|| format!("{} is outside the sheet", range))?,
+        None => default_range(sheet).ok_or("the sheet is empty")?,
+    };
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let bytes = match extension.as_deref() {
+        Some("html" | "htm") => {
+            let title = path
+                .file_stem()
+                .map_or("Sheet".into(), |stem| stem.to_string_lossy());
+            html(sheet, range, &title).into_bytes()
+        }
+        Some("pdf") => pdf(sheet, range),
+        _ => {
+            return Err(format!(
+                "{}: unsupported format, expected .html or .pdf",
+                path.display()
+            ));
+        }
+    };
+    std::fs::write(path, bytes).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    Ok(pages(sheet, range).len())
+}
+
+// What of a range prints on every page, in order
+pub fn pages(sheet: &Sheet, range: Range) -> Vec<Range> {
+    Layout::new(sheet, range).pages()
+}
+
+// The range as an HTML document, with a table for every page. Browsers
+// print every table on a page of its own.
+pub fn html(sheet: &Sheet, range: Range, title: &str) -> String {
+    let layout = Layout::new(sheet, range);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n\
+         @page {{ size: A4 {}; margin: 0.5in; }}\n\
+         body {{ font-family: Helvetica, Arial, sans-serif; font-size: {}px; }}\n\
+         table {{ border-collapse: collapse; table-layout: fixed; margin-bottom: 2em; }}\n\
+         table + table {{ break-before: page; }}\n\
+         td {{ border: 1px solid #cccccc; padding: 0 {}px; overflow: hidden; \
+         white-space: nowrap; vertical-align: middle; }}\n\
+         td.number {{ text-align: right; }}\n\
+         td.wrap {{ white-space: pre-wrap; overflow-wrap: anywhere; vertical-align: top; }}\n\
+         </style>\n</head>\n<body>\n",
+        escaped(title),
+        if sheet.print.landscape {
+            "landscape"
+        } else {
+            "portrait"
+        },
+        FONT_SIZE,
+        PADDING
+    );
+    for page in layout.pages() {
+        html.push_str(&format!(
+            "<table style=\"width: {}px\">\n<colgroup>",
+            page.cols() as f32 * COLUMN_WIDTH
+        ));
+        for col in page.start.1..=page.end.1 {
+            html.push_str(&format!(
+                "<col class=\"{}\" style=\"width: {}px\">",
+                col_to_letter(col),
+                COLUMN_WIDTH
+            ));
+        }
+        html.push_str("</colgroup>\n");
+        for row in page.start.0..=page.end.0 {
+            html.push_str(&format!("<tr style=\"height: {}px\">", layout.height(row)));
+            for col in page.start.1..=page.end.1 {
+                let Some(span) = layout.span((row, col), page) else {
+                    continue;
+                };
+                html.push_str(&layout.html_cell((row, col), span));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+// The range as a PDF document
+pub fn pdf(sheet: &Sheet, range: Range) -> Vec<u8> {
+    let layout = Layout::new(sheet, range);
+    let (width, height) = if sheet.print.landscape {
+        (PAPER.1, PAPER.0)
+    } else {
+        PAPER
+    };
+    let pages: Vec<pdf::Page> = layout
+        .pages()
+        .into_iter()
+        .map(|page| {
+            let mut canvas = Canvas {
+                page: pdf::Page::new(width * POINTS_PER_PIXEL, height * POINTS_PER_PIXEL),
+            };
+            let mut y = MARGIN;
+            for row in page.start.0..=page.end.0 {
+                let mut x = MARGIN;
+                for col in page.start.1..=page.end.1 {
+                    if let Some(span) = layout.span((row, col), page) {
+                        let width = span.cols() as f32 * COLUMN_WIDTH;
+                        let height = (span.start.0..=span.end.0).map(|r| layout.height(r)).sum();
+                        layout.draw_cell(&mut canvas, (row, col), (x, y, width, height));
+                    }
+                    x += COLUMN_WIDTH;
+                }
+                y += layout.height(row);
+            }
+            canvas.page
+        })
+        .collect();
+    pdf::write(&pages)
+}
+
+// Text and look of every cell of the printed range, and the height of its
+// rows
+struct Layout<'a> {
+    sheet: &'a Sheet,
+    range: Range,
+    appearance: HashMap<Cell, Appearance>,
+    heights: Vec<f32>,
+}
+
+impl<'a> Layout<'a> {
+    fn new(sheet: &'a Sheet, range: Range) -> Self {
+        let mut layout = Self {
+            sheet,
+            range,
+            appearance: format::apply(sheet),
+            heights: vec![ROW_HEIGHT; range.rows()],
+        };
+        // Rows grow to fit wrapped text, except under merges spanning
+        // several rows, as in the grid
+        for cell in range.cells() {
+            let merge = sheet.merged(cell);
+            if !sheet.wrapped.contains(&cell)
+                || merge.is_some_and(|m| m.start != cell || m.rows() > 1)
+            {
+                continue;
+            }
+            let width = merge.map_or(1, |m| m.cols()) as f32 * COLUMN_WIDTH - 2.0 * PADDING;
+            let lines = wrap(&layout.text(cell), width).len();
+            let height = &mut layout.heights[cell.0 - range.start.0];
+            *height = height.max(lines as f32 * LINE_HEIGHT + 2.0 * PADDING);
+        }
+        layout
+    }
+
+    fn height(&self, row: usize) -> f32 {
+        self.heights[row - self.range.start.0]
+    }
+
+    fn text(&self, cell: Cell) -> String {
+        let code = self.sheet.number_formats.get(&cell).map(String::as_str);
+        self.sheet
+            .value(cell)
+            .map(|value| number_format::display(value, code))
+            .unwrap_or_default()
+    }
+
+    fn pages(&self) -> Vec<Range> {
+        let (width, height) = self.sheet.print.printable();
+        let per_page = ((width / COLUMN_WIDTH) as usize).max(1);
+        let (first, last) = (self.range.start.1, self.range.end.1);
+        let column_groups = (first..=last)
+            .step_by(per_page)
+            .map(|start| (start, (start + per_page - 1).min(last)));
+
+        let mut row_groups = Vec::new();
+        let (mut start, mut used) = (self.range.start.0, 0.0);
+        for row in self.range.start.0..=self.range.end.0 {
+            let breaks = self.sheet.print.row_breaks.contains(&row);
+            if row > start && (breaks || used + self.height(row) > height) {
+                row_groups.push((start, row - 1));
+                (start, used) = (row, 0.0);
+            }
+            used += self.height(row);
+        }
+        row_groups.push((start, self.range.end.0));
+
+        column_groups
+            .flat_map(|(first, last)| {
+                row_groups
+                    .iter()
+                    .map(move |&(top, bottom)| Range::new((top, first), (bottom, last)))
+            })
+            .collect()
+    }
+
+    // Cells a cell covers on a page: its merge, cut to the page and the
+    // printed range, or the cell itself. None for cells covered by another.
+    fn span(&self, cell: Cell, page: Range) -> Option<Range> {
+        let Some(merge) = self.sheet.merged(cell) else {
+            return Some(Range::new(cell, cell));
+        };
+        let start = (
+            merge.start.0.max(page.start.0),
+            merge.start.1.max(page.start.1),
+        );
+        let end = (merge.end.0.min(page.end.0), merge.end.1.min(page.end.1));
+        (start == cell).then(|| Range::new(start, end))
+    }
+
+    // Whether a cell shows its text on the page: only the top-left part of
+    // a merge split across pages does
+    fn shows_text(&self, cell: Cell) -> bool {
+        self.sheet.anchor(cell) == cell
+    }
+
+    fn is_number(&self, cell: Cell) -> bool {
+        self.sheet
+            .value(cell)
+            .is_some_and(|value| value.as_number().is_some())
+    }
+
+    fn html_cell(&self, cell: Cell, span: Range) -> String {
+        let mut attributes = String::new();
+        if span.cols() > 1 {
+            attributes.push_str(&format!(" colspan=\"{}\"", span.cols()));
+        }
+        if span.rows() > 1 {
+            attributes.push_str(&format!(" rowspan=\"{}\"", span.rows()));
+        }
+        let mut classes = Vec::new();
+        if self.is_number(cell) {
+            classes.push("number");
+        }
+        if self.sheet.wrapped.contains(&cell) {
+            classes.push("wrap");
+        }
+        if !classes.is_empty() {
+            attributes.push_str(&format!(" class=\"{}\"", classes.join(" ")));
+        }
+
+        let appearance = self.appearance.get(&cell).copied().unwrap_or_default();
+        let fill = appearance.fill.map(tint);
+        let mut style = Vec::new();
+        if let Some(fill) = fill {
+            style.push(format!("background-color: {}", css(fill)));
+        }
+        if let Some((fraction, color)) = appearance.bar {
+            let percent = (fraction * 100.0).clamp(0.0, 100.0);
+            style.push(format!(
+                "background-image: linear-gradient(to right, {} {:.1}%, transparent {:.1}%)",
+                css(tint(color)),
+                percent,
+                percent
+            ));
+        }
+        if !style.is_empty() {
+            attributes.push_str(&format!(" style=\"{}\"", style.join("; ")));
+        }
+
+        let text = if self.shows_text(cell) {
+            escaped(&self.text(cell))
+        } else {
+            String::new()
+        };
+        format!("<td{}>{}</td>", attributes, text)
+    }
+
+    // Draws a cell in its rectangle on the page
+    fn draw_cell(
+        &self,
+        canvas: &mut Canvas,
+        cell: Cell,
+        (x, y, width, height): (f32, f32, f32, f32),
+    ) {
+        let appearance = self.appearance.get(&cell).copied().unwrap_or_default();
+        if let Some(fill) = appearance.fill {
+            canvas.fill_rect((x, y, width, height), tint(fill));
+        }
+        if let Some((fraction, color)) = appearance.bar {
+            let bar = fraction.clamp(0.0, 1.0) as f32 * (width - 2.0 * PADDING);
+            canvas.fill_rect(
+                (x + PADDING, y + PADDING, bar, height - 2.0 * PADDING),
+                tint(color),
+            );
+        }
+        canvas.border((x, y, width, height));
+        if !self.shows_text(cell) {
+            return;
+        }
+
+        let text = self.text(cell);
+        let lines = if self.sheet.wrapped.contains(&cell) {
+            wrap(&text, width - 2.0 * PADDING)
+        } else {
+            vec![text.replace('\n', " ")]
+        };
+        // Single lines are centered vertically, wrapped text starts at the
+        // top
+        let first_baseline = if lines.len() > 1 {
+            y + PADDING + LINE_HEIGHT * 0.75
+        } else {
+            y + (height + FONT_SIZE * 0.7) / 2.0
+        };
+        canvas.page.clip(
+            x * POINTS_PER_PIXEL,
+            y * POINTS_PER_PIXEL,
+            width * POINTS_PER_PIXEL,
+            height * POINTS_PER_PIXEL,
+        );
+        for (i, line) in lines.iter().enumerate() {
+            let line_x = if self.is_number(cell) {
+                x + width - PADDING - pdf::text_width(line, FONT_SIZE)
+            } else {
+                x + PADDING
+            };
+            canvas.page.text(
+                line_x * POINTS_PER_PIXEL,
+                (first_baseline + i as f32 * LINE_HEIGHT) * POINTS_PER_PIXEL,
+                FONT_SIZE * POINTS_PER_PIXEL,
+                TEXT_COLOR,
+                line,
+            );
+        }
+        canvas.page.restore();
+    }
+}
+
+// PDF page drawn in pixels
+struct Canvas {
+    page: pdf::Page,
+}
+
+impl Canvas {
+    fn fill_rect(&mut self, (x, y, width, height): (f32, f32, f32, f32), color: Rgb) {
+        let p = POINTS_PER_PIXEL;
+        self.page
+            .fill_rect(x * p, y * p, width * p, height * p, color);
+    }
+
+    fn border(&mut self, (x, y, width, height): (f32, f32, f32, f32)) {
+        let p = POINTS_PER_PIXEL;
+        let (left, top, right, bottom) = (x * p, y * p, (x + width) * p, (y + height) * p);
+        let lines = [
+            ((left, top), (right, top)),
+            ((right, top), (right, bottom)),
+            ((right, bottom), (left, bottom)),
+            ((left, bottom), (left, top)),
+        ];
+        for (from, to) in lines {
+            self.page.line(from, to, GRID_COLOR, 0.5);
+        }
+    }
+}
+
+// Lines of text wrapped at word boundaries to fit a width, breaking words
+// longer than a line
+fn wrap(text: &str, width: f32) -> Vec<String> {
+    let fits = |line: &str| pdf::text_width(line, FONT_SIZE) <= width;
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", line, word)
+            };
+            if fits(&candidate) {
+                line = candidate;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            for c in word.chars() {
+                line.push(c);
+                if !fits(&line) && line.chars().count() > 1 {
+                    line.pop();
+                    lines.push(std::mem::replace(&mut line, c.to_string()));
+                }
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Colors of conditional formats are shown at 60%, as in the grid
+fn tint(color: Rgb) -> Rgb {
+    WHITE.mix(color, 0.6)
+}
+
+fn css(Rgb(r, g, b): Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Comparison, Condition, Format, Rule};
+    use crate::formula::parse_range;
+
+    fn range(text: &str) -> Range {
+        parse_range(text).unwrap()
+    }
+
+    #[test]
+    fn test_default_range() {
+        let mut sheet = Sheet::new(100, 26);
+        assert_eq!(default_range(&sheet), None);
+        sheet.set_formula((4, 2), "x");
+        sheet.set_formula((1, 3), "=1");
+        assert_eq!(default_range(&sheet), Some(range("A0:D4")));
+        sheet.print.area = Some(range("B1:C2"));
+        assert_eq!(default_range(&sheet), Some(range("B1:C2")));
+        sheet.print.area = Some(range("Y98:Z120"));
+        assert_eq!(default_range(&sheet), Some(range("Y98:Z99")));
+    }
+
+    #[test]
+    fn test_pages() {
+        let mut sheet = Sheet::new(100, 26);
+        // 8 columns of 80px fit across a portrait page, and 42 rows of 24px
+        // down it
+        assert_eq!(pages(&sheet, range("A0:H9")), [range("A0:H9")]);
+        assert_eq!(
+            pages(&sheet, range("A0:J49")),
+            [
+                range("A0:H41"),
+                range("A42:H49"),
+                range("I0:J41"),
+                range("I42:J49")
+            ]
+        );
+
+        sheet.print.row_breaks.extend([5, 20, 60]);
+        assert_eq!(
+            pages(&sheet, range("A3:B30")),
+            [range("A3:B4"), range("A5:B19"), range("A20:B30")]
+        );
+
+        sheet.print.landscape = true;
+        sheet.print.row_breaks.clear();
+        assert_eq!(
+            pages(&sheet, range("A0:Z40")),
+            [
+                range("A0:L28"),
+                range("A29:L40"),
+                range("M0:X28"),
+                range("M29:X40"),
+                range("Y0:Z28"),
+                range("Y29:Z40")
+            ]
+        );
+
+        // Wrapped text makes its row taller
+        let mut sheet = Sheet::new(100, 26);
+        sheet.set_formula((0, 0), &"word ".repeat(40));
+        sheet.wrapped.insert((0, 0));
+        assert!(Layout::new(&sheet, range("A0:A0")).height(0) > 10.0 * LINE_HEIGHT);
+        assert_eq!(pages(&sheet, range("A0:A41")).len(), 2);
+    }
+
+    fn report() -> Sheet {
+        let mut sheet = Sheet::new(100, 26);
+        sheet.set_formula((0, 0), "Quarterly <report>");
+        sheet.set_formula((1, 0), "Sales");
+        sheet.set_formula((1, 1), "1234.5");
+        sheet.set_formula((2, 1), "=B1*2");
+        sheet.number_formats.insert((1, 1), "#,##0.00".to_string());
+        sheet.merge(range("A0:B0")).unwrap();
+        sheet.formats.push(Rule {
+            range: range("B1:B2"),
+            format: Format::Highlight {
+                condition: Condition::Compare {
+                    comparison: Comparison::Greater,
+                    value: 2000.0,
+                },
+                color: Rgb(255, 0, 0),
+            },
+        });
+        sheet
+    }
+
+    #[test]
+    fn test_html() {
+        let html = html(&report(), range("A0:B2"), "Q1 & Q2");
+        assert!(html.contains("<title>Q1 &amp; Q2</title>"));
+        assert!(html.contains("<col class=\"A\" style=\"width: 80px\">"));
+        assert!(html.contains("<td colspan=\"2\">Quarterly &lt;report&gt;</td></tr>"));
+        assert!(html.contains("<td>Sales</td><td class=\"number\">1,234.50</td>"));
+        // Conditional fills at 60%, as in the grid
+        assert!(
+            html.contains("<td class=\"number\" style=\"background-color: #ff6666\">2469.00</td>")
+        );
+        assert_eq!(html.matches("<table").count(), 1);
+
+        let mut sheet = report();
+        sheet.print.row_breaks.insert(2);
+        let html = super::html(&sheet, range("A0:B2"), "Report");
+        assert_eq!(html.matches("<table").count(), 2);
+    }
+
+    #[test]
+    fn test_pdf() {
+        let mut sheet = report();
+        sheet.print.row_breaks.insert(2);
+        let pdf = pdf(&sheet, range("A0:B2"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Count 2"));
+        // Text is hex-encoded: "Sales" and the formatted number
+        assert!(text.contains("<53616C6573> Tj"));
+        assert!(text.contains(&format!("<{}> Tj", hex("1,234.50"))));
+        // The fill of B2, which prints first on the second page
+        assert!(text.contains("1 0.4 0.4 rg 96 787.875 60 18 re f"));
+    }
+
+    fn hex(text: &str) -> String {
+        text.bytes().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let sheet = report();
+
+        assert_eq!(export(&sheet, None, dir.path().join("report.html")), Ok(1));
+        let html = std::fs::read_to_string(dir.path().join("report.html")).unwrap();
+        assert!(html.contains("<title>report</title>"));
+        assert_eq!(
+            export(&sheet, Some(range("A1:A1")), dir.path().join("r.pdf")),
+            Ok(1)
+        );
+        assert!(
+            std::fs::read(dir.path().join("r.pdf"))
+                .unwrap()
+                .starts_with(b"%PDF")
+        );
+
+        assert!(export(&sheet, None, dir.path().join("report.txt")).is_err());
+        assert!(export(&Sheet::new(10, 3), None, dir.path().join("empty.pdf")).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+// Property-based tests of the formula engine: parsing and evaluating any
+// input never panics, arithmetic agrees with a reference interpreter, and
+// recalculating only what an edit touches gives the same values as
+// recalculating the whole sheet.
+
+use crate::formula::{Cell, cell_name, parse_expression};
+use crate::{CellValue, Sheet};
+use proptest::prelude::*;
+
+// Small sheet the generated formulas refer into
+const ROWS: usize = 4;
+const COLS: usize = 3;
+
+#[test]
+fn test_edge_cases() {
+    let mut sheet = Sheet::new(ROWS, COLS);
+    let mut value = |formula: &str| {
+        sheet.set_formula((0, 0), formula);
+        sheet.value((0, 0)).cloned()
+    };
+    let error = |code: &str| Some(CellValue::Error(code.to_string()));
+    assert_eq!(value("=--1"), Some(CellValue::Number(1.0)));
+    assert_eq!(value("=+-+2"), Some(CellValue::Number(-2.0)));
+    assert_eq!(value("=2--1"), Some(CellValue::Number(3.0)));
+    assert_eq!(value("=A"), error("NAME"));
+    assert_eq!(value("=1/"), error("ERR"));
+    assert_eq!(value("=1/0"), error("DIV0"));
+    assert_eq!(value("="), error("ERR"));
+    assert_eq!(value("=()"), error("ERR"));
+    assert_eq!(value("=1..2"), error("ERR"));
+    assert_eq!(value("=A0"), error("CYCLE"));
+    assert_eq!(value("=Z99"), error("REF"));
+    assert_eq!(value("=\"open"), error("ERR"));
+    assert_eq!(value("=SUM("), error("ERR"));
+    assert_eq!(value("=sum(1,,2)"), error("ERR"));
+    assert_eq!(value("  =1+1 "), Some(CellValue::Number(2.0)));
+}
+
+#[test]
+fn test_deeply_nested_formulas() {
+    // Too deep to evaluate, but not deep enough to overflow the stack
+    for depth in [1_000, 100_000] {
+        let formula = format!("={}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_expression(&formula[1..]).is_err());
+        let formula = format!("={}1", "-".repeat(depth));
+        assert!(parse_expression(&formula[1..]).is_err());
+    }
+    let sum = vec!["1"; 100_000].join("+");
+    assert!(parse_expression(&sum).is_err());
+
+    let formula = format!("{}1{}", "(".repeat(64), ")".repeat(64));
+    assert!(parse_expression(&formula).is_ok());
+    let sum = vec!["A0"; 300].join("+");
+    let mut sheet = Sheet::new(ROWS, COLS);
+    sheet.set_formula((0, 0), "2");
+    sheet.set_formula((1, 0), &format!("=SUM({})*-(-{})", sum, formula));
+    assert_eq!(sheet.value((1, 0)), Some(&CellValue::Number(600.0)));
+}
+
+// Arithmetic formula with the structure the reference interpreter follows
+#[derive(Debug, Clone)]
+enum Formula {
+    Number(f64),
+    Cell(Cell),
+    Neg(Box<Formula>),
+    Binary(&'static str, Box<Formula>, Box<Formula>),
+}
+
+impl Formula {
+    // Formula text, with every operation in parentheses
+    fn text(&self) -> String {
+        match self {
+            Formula::Number(n) => n.to_string(),
+            Formula::Cell(cell) => cell_name(*cell),
+            Formula::Neg(inner) => format!("-{}", inner.text()),
+            Formula::Binary(op, left, right) => {
+                format!("({} {} {})", left.text(), op, right.text())
+            }
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &["+", "-", "*", "/", "=", "<>", "<", "<=", ">", ">="];
+
+fn cell() -> impl Strategy<Value = Cell> {
+    (0..ROWS, 0..COLS)
+}
+
+fn range() -> impl Strategy<Value = String> {
+    (cell(), cell()).prop_map(|(a, b)| {
+        format!(
+            "{}:{}",
+            cell_name((a.0.min(b.0), a.1.min(b.1))),
+            cell_name((a.0.max(b.0), a.1.max(b.1)))
+        )
+    })
+}
+
+fn formula() -> impl Strategy<Value = Formula> {
+    let leaf = prop_oneof![
+        (0u32..100).prop_map(|n| Formula::Number(n as f64)),
+        (0u32..1000).prop_map(|n| Formula::Number(n as f64 / 8.0)),
+        cell().prop_map(Formula::Cell),
+    ];
+    leaf.prop_recursive(4, 32, 2, |inner| {
+        prop_oneof![
+            inner.clone().prop_map(|f| Formula::Neg(Box::new(f))),
+            (
+                prop::sample::select(OPERATORS),
+                inner.clone(),
+                inner.clone()
+            )
+                .prop_map(|(op, left, right)| {
+                    Formula::Binary(op, Box::new(left), Box::new(right))
+                }),
+        ]
+    })
+}
+
+// Value of a formula worked out directly, with empty cells reading as zero
+// and the error of the left operand winning over the right one
+fn reference(formula: &Formula, inputs: &[Option<f64>]) -> Result<f64, &'static str> {
+    match formula {
+        Formula::Number(n) => Ok(*n),
+        Formula::Cell((row, col)) => Ok(inputs[row * COLS + col].unwrap_or(0.0)),
+        Formula::Neg(inner) => Ok(-reference(inner, inputs)?),
+        Formula::Binary(op, left, right) => {
+            let left = reference(left, inputs);
+            let right = reference(right, inputs);
+            let (left, right) = (left?, right?);
+            let truth = |holds: bool| if holds { 1.0 } else { 0.0 };
+            Ok(match *op {
+                "+" => left + right,
+                "-" => left - right,
+                "*" => left * right,
+                "/" if right == 0.0 => return Err("DIV0"),
+                "/" => left / right,
+                "=" => truth(left == right),
+                "<>" => truth(left != right),
+                "<" => truth(left < right),
+                "<=" => truth(left <= right),
+                ">" => truth(left > right),
+                _ => truth(left >= right),
+            })
+        }
+    }
+}
+
+// What gets typed into a cell of the sheets whose recalculation is checked:
+// constants, text, nothing, or formulas reading other cells, possibly in a
+// cycle
+fn input() -> impl Strategy<Value = String> {
+    prop_oneof![
+        2 => (-50i32..50).prop_map(|n| n.to_string()),
+        1 => Just("label".to_string()),
+        1 => Just(String::new()),
+        4 => formula().prop_map(|f| format!("={}", f.text())),
+        1 => range().prop_map(|r| format!("=SUM({})", r)),
+        1 => range().prop_map(|r| format!("=COUNT({})*2", r)),
+        1 => (cell(), cell()).prop_map(|(a, b)| {
+            format!("=IF({}>0, {}, \"none\")", cell_name(a), cell_name(b))
+        }),
+    ]
+}
+
+fn edits() -> impl Strategy<Value = Vec<(Cell, String)>> {
+    prop::collection::vec((cell(), input()), 1..24)
+}
+
+proptest! {
+    #[test]
+    fn test_any_input_is_handled(input in "\\PC{0,40}") {
+        let _ = parse_expression(&input);
+        let mut sheet = Sheet::new(ROWS, COLS);
+        sheet.set_formula((1, 1), "4");
+        sheet.set_formula((0, 0), &format!("={}", input));
+        sheet.set_formula((2, 2), &input);
+        let _ = sheet.evaluate_formula(&input);
+        let _ = sheet.check_formula(&input);
+    }
+
+    #[test]
+    fn test_formula_like_input_is_handled(
+        input in "=[-+*/^&%<>=(),:;.\" 0-9A-DZa-c$!]{0,30}"
+    ) {
+        let mut sheet = Sheet::new(ROWS, COLS);
+        sheet.set_formula((3, 2), "label");
+        sheet.set_formula((0, 0), &input);
+        let _ = parse_expression(&input[1..]);
+    }
+
+    #[test]
+    fn test_evaluation_matches_reference(
+        formula in formula(),
+        inputs in prop::collection::vec(prop::option::of(-20i32..20), ROWS * COLS),
+    ) {
+        // Inputs fill every cell but the one holding the formula
+        let mut sheet = Sheet::new(ROWS + 1, COLS);
+        let inputs: Vec<Option<f64>> = inputs.into_iter().map(|n| n.map(f64::from)).collect();
+        for (i, input) in inputs.iter().enumerate() {
+            if let Some(n) = input {
+                sheet.set_formula((i / COLS, i % COLS), &n.to_string());
+            }
+        }
+        let text = formula.text();
+        sheet.set_formula((ROWS, 0), &format!("={}", text));
+        let expected = match reference(&formula, &inputs) {
+            Ok(n) => CellValue::Number(n),
+            Err(code) => CellValue::Error(code.to_string()),
+        };
+        prop_assert_eq!(sheet.value((ROWS, 0)), Some(&expected), "={}", text);
+    }
+
+    #[test]
+    fn test_recalculation_matches_full_recalculation(edits in edits()) {
+        let mut sheet = Sheet::new(ROWS, COLS);
+        for (cell, input) in &edits {
+            sheet.set_formula(*cell, input);
+
+            let mut full = Sheet::new(ROWS, COLS);
+            for (cell, formula) in &sheet.formulas {
+                full.edit(*cell, formula);
+            }
+            full.recalculate_all();
+            prop_assert_eq!(&sheet.values, &full.values, "after {:?}", edits);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+// Recovery of unsaved work. While the GUI's sheet differs from what was
+// last saved or opened, it is written periodically to a recovery file,
+// along with the file it came from. Saving removes the recovery file, so
+// one left behind holds work a crash or an accidental close lost, unless
+// the file it came from was saved after it.
+//
+// Every session writes a file of its own and holds a lock beside it while
+// it runs, so windows open at the same time never offer or remove each
+// other's work: only files whose lock is free, left by sessions that have
+// ended, are offered.
+
+use crate::Sheet;
+use crate::file::SheetFile;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// How often unsaved work is written
+pub const INTERVAL: Duration = Duration::from_secs(30);
+
+// On-disk form of the recovery file
+#[derive(Serialize, Deserialize)]
+struct RecoveryFile {
+    // Sheet file the work was last saved to or opened from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<PathBuf>,
+    sheet: SheetFile,
+}
+
+// Work left behind by an earlier session
+pub struct Recovered {
+    pub sheet: Sheet,
+    pub file: Option<PathBuf>,
+    // When it was last written
+    pub time: SystemTime,
+    // The recovery file holding it, locked until it is restored or
+    // discarded
+    pub source: Recovery,
+}
+
+// Location of a recovery file, and the lock on it when this session owns it
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    path: PathBuf,
+    // Held until the last clone is dropped, which frees the file for other
+    // sessions
+    _lock: Option<Arc<File>>,
+}
+
+impl Recovery {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            _lock: None,
+        }
+    }
+
+    // The user's directory of recovery files: $XDG_STATE_HOME/cells, or
+    // else ~/.local/state/cells, or %LOCALAPPDATA%\cells on Windows. None
+    // when none of them is set.
+    pub fn directory() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+        let state = var("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| Path::new(&home).join(".local").join("state")))
+            .or_else(|| var("LOCALAPPDATA").map(PathBuf::from))?;
+        Some(state.join("cells"))
+    }
+
+    // A recovery file of its own for a new session in the directory
+    pub fn start(dir: &Path) -> Result<Self, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "recovery-{}-{}.json",
+            std::process::id(),
+            since_epoch.as_nanos()
+        );
+        Self::claim(dir.join(name))?.ok_or_else(|| format!("{} is in use", dir.display()))
+    }
+
+    // Recovery files in the directory that no running session owns, locked
+    // for this one
+    pub fn orphans(dir: &Path) -> Vec<Self> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut orphans = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("recovery-") && name.ends_with(".json") {
+                orphans.extend(Self::claim(path).ok().flatten());
+            } else if name.starts_with("recovery-") && name.ends_with(".lock") {
+                // Locks left by sessions that ended with nothing to recover
+                let json = path.with_extension("json");
+                if !json.exists()
+                    && let Ok(Some(_)) = Self::claim(json)
+                {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        orphans
+    }
+
+    // Takes the lock of a recovery file, None when a running session holds
+    // it
+    fn claim(path: PathBuf) -> Result<Option<Self>, String> {
+        let lock_path = path.with_extension("lock");
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| format!("cannot open {}: {}", lock_path.display(), e))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(Some(Self {
+                path,
+                _lock: Some(Arc::new(lock)),
+            })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(e)) => {
+                Err(format!("cannot lock {}: {}", lock_path.display(), e))
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Writes the sheet and the file it belongs to. The previous recovery
+    // file is only replaced once the new one is complete.
+    pub fn write(&self, sheet: &Sheet, file: Option<&Path>) -> Result<(), String> {
+        let recovery = RecoveryFile {
+            file: file.map(Path::to_path_buf),
+            sheet: SheetFile::from_sheet(sheet),
+        };
+        let json = serde_json::to_string(&recovery).expect("sheet files always serialize");
+        let error = |e: std::io::Error| format!("cannot write {}: {}", self.path.display(), e);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(error)?;
+        }
+        let partial = self.path.with_extension("json.partial");
+        std::fs::write(&partial, json).map_err(error)?;
+        std::fs::rename(&partial, &self.path).map_err(error)
+    }
+
+    pub fn discard(&self) -> Result<(), String> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("cannot remove {}: {}", self.path.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Work to offer restoring: the recovery file, unless there is none or
+    // the file it came from was saved since. A stale recovery file is
+    // removed.
+    pub fn pending(&self) -> Result<Option<Recovered>, String> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified());
+        let Ok(time) = modified(&self.path) else {
+            return Ok(None);
+        };
+        let json = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("cannot read {}: {}", self.path.display(), e))?;
+        let recovery: RecoveryFile =
+            serde_json::from_str(&json).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        if let Some(file) = &recovery.file
+            && modified(file).is_ok_and(|saved| saved > time)
+        {
+            self.discard()?;
+            return Ok(None);
+        }
+        let sheet = recovery
+            .sheet
+            .into_sheet()
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        Ok(Some(Recovered {
+            sheet,
+            file: recovery.file,
+            time,
+            source: self.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CellValue, file};
+
+    #[test]
+    fn test_recover_unsaved_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let recovery = Recovery::new(dir.path().join("state").join("recovery.json"));
+        assert!(recovery.pending().unwrap().is_none());
+
+        let mut sheet = Sheet::new(100, 26);
+        sheet.set_formula((0, 0), "2");
+        sheet.set_formula((0, 1), "=A0*21");
+        recovery.write(&sheet, None).unwrap();
+        let recovered = recovery.pending().unwrap().unwrap();
+        assert_eq!(recovered.file, None);
+        assert_eq!(recovered.sheet.formulas, sheet.formulas);
+        assert_eq!(
+            recovered.sheet.value((0, 1)),
+            Some(&CellValue::Number(42.0))
+        );
+
+        recovery.discard().unwrap();
+        recovery.discard().unwrap();
+        assert!(recovery.pending().unwrap().is_none());
+
+        std::fs::write(recovery.path(), "{").unwrap();
+        assert!(recovery.pending().is_err());
+    }
+
+    #[test]
+    fn test_sessions_keep_their_work_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Recovery::start(dir.path()).unwrap();
+        let second = Recovery::start(dir.path()).unwrap();
+        assert_ne!(first.path(), second.path());
+        let mut sheet = Sheet::new(100, 26);
+        sheet.set_formula((0, 0), "first");
+        first.write(&sheet, None).unwrap();
+
+        // Work of a running session is not offered to others
+        assert!(Recovery::orphans(dir.path()).is_empty());
+
+        // Once it ends, its work is offered to one session at a time
+        drop(first);
+        let orphans = Recovery::orphans(dir.path());
+        assert_eq!(orphans.len(), 1);
+        assert!(Recovery::orphans(dir.path()).is_empty());
+        let recovered = orphans[0].pending().unwrap().unwrap();
+        assert_eq!(recovered.sheet.formulas, sheet.formulas);
+        recovered.source.discard().unwrap();
+        drop((orphans, recovered));
+        assert!(Recovery::orphans(dir.path()).is_empty());
+
+        // Locks of sessions that ended with nothing to recover are removed
+        drop(second);
+        Recovery::orphans(dir.path());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_saved_files_make_recovery_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let recovery = Recovery::new(dir.path().join("recovery.json"));
+        let saved = dir.path().join("budget.json");
+        let sheet = Sheet::new(100, 26);
+
+        // Work written after the last save is offered
+        file::save(&sheet, &saved).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&saved)
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+        recovery.write(&sheet, Some(&saved)).unwrap();
+        let recovered = recovery.pending().unwrap().unwrap();
+        assert_eq!(recovered.file.as_deref(), Some(saved.as_path()));
+
+        // A save after it leaves nothing to recover
+        file::save(&sheet, &saved).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&saved)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(recovery.pending().unwrap().is_none());
+        assert!(!recovery.path().exists());
+    }
+}
@@ -0,0 +1,410 @@
+// Functions defined in a Rhai script saved with the sheet. Formulas call
+// them like built-ins, by their name in upper case:
+//
+//     fn commission(sales, rate) {
+//         if sales > 10000.0 { sales * rate * 1.5 } else { sales * rate }
+//     }
+//
+// makes =COMMISSION(B2, 0.05) work. Numbers arrive as floats and text as
+// strings. Ranges and arrays arrive as arrays, flat for a single row or
+// column and otherwise as an array of rows. Functions return a number,
+// text, a bool or an array, which spills like the other array functions;
+// `private` functions are helpers formulas cannot call.
+//
+// Scripts are sandboxed: they cannot import modules or print, and every
+// call is stopped after a time limit and limited in recursion depth and
+// the size of the strings and arrays it builds.
+
+use crate::CellValue;
+use crate::eval::{Array, Evaluator, Value, element, to_element};
+use crate::formula::Expr;
+use crate::functions;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{AST, CallFnOptions, Dynamic, Engine, EvalAltResult, FnAccess};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Longest a single call may run
+pub const TIME_LIMIT: Duration = Duration::from_millis(100);
+
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_STRING_SIZE: usize = 1 << 20;
+const MAX_ARRAY_SIZE: usize = 100_000;
+
+thread_local! {
+    // When the call running on this thread must stop
+    static DEADLINE: std::cell::Cell<Option<Instant>> = const { std::cell::Cell::new(None) };
+}
+
+#[derive(Clone, Default)]
+pub struct Script {
+    source: String,
+    compiled: Option<Arc<Compiled>>,
+}
+
+struct Compiled {
+    engine: Engine,
+    ast: AST,
+    // Overloads of every public function by name in upper case, as the
+    // name in the script and the parameters
+    functions: BTreeMap<String, Vec<(String, Vec<String>)>>,
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script")
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Script {
+    // Compiles a script. Blank scripts define nothing; functions named like
+    // a built-in, or like another function but for case, are refused.
+    pub fn compile(source: &str) -> Result<Self, String> {
+        if source.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let engine = sandbox();
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+
+        let mut functions: BTreeMap<String, Vec<(String, Vec<String>)>> = BTreeMap::new();
+        for function in ast.iter_functions() {
+            if function.access == FnAccess::Private {
+                continue;
+            }
+            let name = function.name.to_ascii_uppercase();
+            if functions::NAMES.contains(&name.as_str()) {
+                return Err(format!("{} is a built-in function", name));
+            }
+            let overloads = functions.entry(name.clone()).or_default();
+            if overloads.iter().any(|(other, _)| other != function.name) {
+                return Err(format!("{} is defined twice", name));
+            }
+            let params = function.params.iter().map(|p| p.to_string()).collect();
+            overloads.push((function.name.to_string(), params));
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            compiled: Some(Arc::new(Compiled {
+                engine,
+                ast,
+                functions,
+            })),
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Names formulas call the functions by, in alphabetical order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.compiled
+            .iter()
+            .flat_map(|compiled| compiled.functions.keys().map(String::as_str))
+    }
+
+    pub fn defines(&self, name: &str) -> bool {
+        self.compiled
+            .as_ref()
+            .is_some_and(|compiled| compiled.functions.contains_key(name))
+    }
+
+    // Every function as it is called from formulas, e.g. "COMMISSION(sales, rate)"
+    pub fn signatures(&self) -> Vec<String> {
+        self.compiled
+            .iter()
+            .flat_map(|compiled| &compiled.functions)
+            .flat_map(|(name, overloads)| {
+                overloads
+                    .iter()
+                    .map(move |(_, params)| format!("{}({})", name, params.join(", ")))
+            })
+            .collect()
+    }
+
+    // Calls a function of the script with the arguments of a formula. Errors
+    // are #NAME for unknown functions, #ERR for a wrong number of
+    // arguments, #TIMEOUT when the call runs out of time and #SCRIPT when it
+    // fails otherwise.
+    pub fn call(&self, eval: &Evaluator, name: &str, args: &[Expr]) -> Result<Value, String> {
+        let Some((compiled, overloads)) = self
+            .compiled
+            .as_ref()
+            .and_then(|compiled| Some((compiled, compiled.functions.get(name)?)))
+        else {
+            return Err("NAME".to_string());
+        };
+        let Some((name, _)) = overloads
+            .iter()
+            .find(|(_, params)| params.len() == args.len())
+        else {
+            return Err("ERR".to_string());
+        };
+
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            let value = match eval.eval(arg)? {
+                Value::Range(range) if range.rows() == 1 && range.cols() == 1 => {
+                    eval.cell(range.start)?
+                }
+                value => value,
+            };
+            values.push(to_dynamic(eval, value)?);
+        }
+
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + TIME_LIMIT)));
+        let result = compiled.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().eval_ast(false),
+            &mut rhai::Scope::new(),
+            &compiled.ast,
+            name,
+            values,
+        );
+        DEADLINE.with(|deadline| deadline.set(None));
+        match result {
+            Ok(value) => from_dynamic(value),
+            Err(err) if matches!(*err, EvalAltResult::ErrorTerminated(..)) => {
+                Err("TIMEOUT".to_string())
+            }
+            Err(_) => Err("SCRIPT".to_string()),
+        }
+    }
+}
+
+// Engine that cannot reach outside the sheet and stops calls past their
+// deadline
+fn sandbox() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .on_print(|_| {})
+        .on_debug(|_, _, _| {})
+        .disable_symbol("eval")
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_ARRAY_SIZE)
+        .on_progress(|_| {
+            let deadline = DEADLINE.with(|deadline| deadline.get());
+            deadline
+                .is_some_and(|deadline| Instant::now() > deadline)
+                .then_some(Dynamic::UNIT)
+        });
+    engine
+}
+
+// Argument of a function: numbers as floats, so that division never
+// truncates, and blocks as arrays
+fn to_dynamic(eval: &Evaluator, value: Value) -> Result<Dynamic, String> {
+    let block = match value {
+        Value::Number(n) => return Ok(Dynamic::from_float(n)),
+        Value::Decimal(d) => return Ok(Dynamic::from_float(d.to_f64())),
+        Value::Text(text) => return Ok(text.into()),
+        block => eval.array(block)?,
+    };
+    let convert = |value: &CellValue| to_dynamic(eval, element(value)?);
+    if block.len() == 1 || block[0].len() == 1 {
+        let values: Result<Vec<Dynamic>, String> = block.iter().flatten().map(convert).collect();
+        return Ok(values?.into());
+    }
+    let rows: Result<Vec<Dynamic>, String> = block
+        .iter()
+        .map(|row| {
+            let row: Result<Vec<Dynamic>, String> = row.iter().map(convert).collect();
+            Ok(row?.into())
+        })
+        .collect();
+    Ok(rows?.into())
+}
+
+// Result of a function. Arrays of arrays are rows; other arrays are a
+// column. Short rows are padded with #N/A.
+fn from_dynamic(value: Dynamic) -> Result<Value, String> {
+    if let Ok(n) = value.as_float() {
+        return if n.is_finite() {
+            Ok(Value::Number(n))
+        } else {
+            Err("NUM".to_string())
+        };
+    }
+    if let Ok(n) = value.as_int() {
+        return Ok(Value::Number(n as f64));
+    }
+    if let Ok(b) = value.as_bool() {
+        return Ok(Value::Number(if b { 1.0 } else { 0.0 }));
+    }
+    if let Ok(c) = value.as_char() {
+        return Ok(Value::Text(c.to_string()));
+    }
+    if value.is_string() {
+        return value
+            .into_string()
+            .map(Value::Text)
+            .map_err(|_| "VALUE".to_string());
+    }
+    let Ok(elements) = value.into_array() else {
+        return Err("VALUE".to_string());
+    };
+    if elements.is_empty() {
+        return Err("VALUE".to_string());
+    }
+    let mut array: Array = if elements.iter().all(Dynamic::is_array) {
+        elements
+            .into_iter()
+            .map(|row| {
+                let row = row.into_array().unwrap_or_default();
+                row.into_iter()
+                    .map(|v| to_element(from_dynamic(v)))
+                    .collect()
+            })
+            .collect()
+    } else {
+        elements
+            .into_iter()
+            .map(|v| vec![to_element(from_dynamic(v))])
+            .collect()
+    };
+    let cols = array.iter().map(Vec::len).max().unwrap_or(0);
+    if cols == 0 {
+        return Err("VALUE".to_string());
+    }
+    for row in &mut array {
+        row.resize(cols, CellValue::Error("N/A".to_string()));
+    }
+    Ok(Value::Array(array))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::parse_expression;
+    use std::collections::HashMap;
+
+    const SCRIPT: &str = r#"
+        fn commission(sales, rate) {
+            if sales > 10000.0 { sales * rate * 1.5 } else { sales * rate }
+        }
+        fn total(xs) { let sum = 0.0; for x in xs { sum += x; } sum }
+        fn greet(name) { "Hello, " + name }
+        fn greet() { "Hello" }
+        fn pairs(n) { let rows = []; for i in 0..n.to_int() { rows.push([i, i * i]); } rows }
+        fn ragged() { [[1, 2], [3]] }
+        fn spin() { loop {} }
+        fn fail() { throw "no"; }
+        private fn helper() { 1 }
+    "#;
+
+    fn call(values: &HashMap<(usize, usize), CellValue>, input: &str) -> Result<Value, String> {
+        let script = Script::compile(SCRIPT).unwrap();
+        let eval = Evaluator::new(values).with_script(&script);
+        eval.eval(&parse_expression(input)?)
+    }
+
+    #[test]
+    fn test_compile() {
+        let script = Script::compile(SCRIPT).unwrap();
+        assert_eq!(
+            script.names().collect::<Vec<_>>(),
+            [
+                "COMMISSION",
+                "FAIL",
+                "GREET",
+                "PAIRS",
+                "RAGGED",
+                "SPIN",
+                "TOTAL"
+            ]
+        );
+        assert!(script.defines("GREET"));
+        assert!(!script.defines("HELPER"));
+        assert!(
+            script
+                .signatures()
+                .contains(&"COMMISSION(sales, rate)".to_string())
+        );
+
+        assert_eq!(Script::compile("  ").unwrap().names().count(), 0);
+        assert!(Script::compile("fn broken( {").is_err());
+        assert_eq!(
+            Script::compile("fn sum(x) { x }").unwrap_err(),
+            "SUM is a built-in function"
+        );
+        assert_eq!(
+            Script::compile("fn f(x) { x } fn F(x, y) { y }").unwrap_err(),
+            "F is defined twice"
+        );
+    }
+
+    #[test]
+    fn test_call() {
+        let mut values = HashMap::new();
+        values.insert((0, 0), CellValue::Number(20000.0));
+        values.insert((1, 0), CellValue::Number(3.0));
+        values.insert((2, 0), CellValue::Text("x".to_string()));
+        let n = CellValue::Number;
+
+        assert_eq!(
+            call(&values, "COMMISSION(A0, 0.1)"),
+            Ok(Value::Number(3000.0))
+        );
+        assert_eq!(call(&values, "COMMISSION(A1, 0.5)"), Ok(Value::Number(1.5)));
+        assert_eq!(call(&values, "TOTAL(A0:A1)"), Ok(Value::Number(20003.0)));
+        assert_eq!(call(&values, "TOTAL(SEQUENCE(4))"), Ok(Value::Number(10.0)));
+        assert_eq!(
+            call(&values, "GREET(A2)"),
+            Ok(Value::Text("Hello, x".to_string()))
+        );
+        assert_eq!(
+            call(&values, "GREET()"),
+            Ok(Value::Text("Hello".to_string()))
+        );
+        assert_eq!(
+            call(&values, "PAIRS(3)"),
+            Ok(Value::Array(vec![
+                vec![n(0.0), n(0.0)],
+                vec![n(1.0), n(1.0)],
+                vec![n(2.0), n(4.0)],
+            ]))
+        );
+        assert_eq!(
+            call(&values, "RAGGED()"),
+            Ok(Value::Array(vec![
+                vec![n(1.0), n(2.0)],
+                vec![n(3.0), CellValue::Error("N/A".to_string())],
+            ]))
+        );
+
+        assert_eq!(call(&values, "GREET(1, 2)"), Err("ERR".to_string()));
+        assert_eq!(call(&values, "HELPER()"), Err("NAME".to_string()));
+        assert_eq!(call(&values, "TOTAL(A2)"), Err("SCRIPT".to_string()));
+        assert_eq!(call(&values, "FAIL()"), Err("SCRIPT".to_string()));
+        assert_eq!(call(&values, "TOTAL(1/0)"), Err("DIV0".to_string()));
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let values = HashMap::new();
+        let started = Instant::now();
+        assert_eq!(call(&values, "SPIN()"), Err("TIMEOUT".to_string()));
+        assert!(started.elapsed() < TIME_LIMIT * 20);
+
+        for source in [
+            r#"fn f() { import "secrets" as s; 1 }"#,
+            r#"fn f() { eval("1") }"#,
+            "fn f() { f() }",
+            r#"fn f() { let s = "x"; loop { s += s; } }"#,
+        ] {
+            let script = Script::compile(source);
+            let result = script.and_then(|script| {
+                Evaluator::new(&values)
+                    .with_script(&script)
+                    .eval(&Expr::Call("F".to_string(), vec![]))
+            });
+            assert!(result.is_err(), "{}", source);
+        }
+    }
+}
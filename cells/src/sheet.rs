@@ -0,0 +1,1871 @@
+use crate::chart::Chart;
+use crate::comment::Comment;
+use crate::decimal::{Arithmetic, Decimal, Number};
+use crate::eval::{Array, Evaluator};
+use crate::format::Rule;
+use crate::formula::{Cell, Expr, Range, parse_cell_reference, parse_expression, parse_range};
+use crate::functions;
+use crate::print::PrintSetup;
+use crate::script::Script;
+use crate::validation::Validation;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Largest sheets that files and workbooks may hold: formulas name columns A
+// to Z with a single letter, and the GUI lays out every row
+pub const MAX_ROWS: usize = 10_000;
+pub const MAX_COLS: usize = 26;
+
+// Levels with fewer cells than this are evaluated on the calling thread even
+// in parallel mode, since handing them to the pool costs more than it saves
+const PARALLEL_THRESHOLD: usize = 64;
+
+// Most follow-up passes a recalculation makes for cells whose spill changed,
+// in case spills keep changing each other
+const MAX_SPILL_PASSES: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellValue {
+    Number(f64),
+    // Number of a sheet in decimal mode, see `decimal`
+    Decimal(Decimal),
+    Text(String),
+    Error(String),
+}
+
+impl CellValue {
+    // The number a value holds, as the nearest f64 for decimals
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            CellValue::Number(n) => Some(*n),
+            CellValue::Decimal(d) => Some(d.to_f64()),
+            _ => None,
+        }
+    }
+
+    // The number a value holds, exact for decimals
+    pub fn as_exact(&self) -> Option<Number> {
+        match self {
+            CellValue::Number(n) => Some(Number::Float(*n)),
+            CellValue::Decimal(d) => Some(Number::Exact(d.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl From<Number> for CellValue {
+    fn from(number: Number) -> Self {
+        match number {
+            Number::Float(n) => CellValue::Number(n),
+            Number::Exact(d) => CellValue::Decimal(d),
+        }
+    }
+}
+
+// Plain text form of a value: numbers in full precision, errors as #CODE
+impl fmt::Display for CellValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CellValue::Number(num) => write!(f, "{}", num),
+            CellValue::Decimal(num) => write!(f, "{}", num),
+            CellValue::Text(text) => write!(f, "{}", text),
+            CellValue::Error(err) => write!(f, "#{}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecalcMode {
+    // Evaluate dirty cells one at a time on the calling thread
+    #[default]
+    Serial,
+    // Evaluate each topological level of dirty cells on the rayon thread pool
+    Parallel,
+}
+
+// Settings of iterative calculation, which evaluates circular references
+// repeatedly instead of failing them with #CYCLE
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Iteration {
+    // Rounds over the cells of the cycles before giving up with #CONVERGE
+    pub max_iterations: usize,
+    // Values have settled once a round changes none by more than this
+    pub max_change: f64,
+}
+
+// Excel's defaults
+impl Default for Iteration {
+    fn default() -> Self {
+        Self {
+            max_iterations: 100,
+            max_change: 0.001,
+        }
+    }
+}
+
+// Range an array formula spills into, starting at its own cell. A blocked
+// spill would cover other cells' input, another spill or the edge of the
+// sheet, so the formula shows #SPILL instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spill {
+    pub range: Range,
+    pub blocked: bool,
+}
+
+// What a recalculation of a copy of the sheet computed, to bring the sheet
+// itself up to date with `Sheet::apply`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Results {
+    // New values of the recalculated cells, None for cells left empty
+    pub values: Vec<(Cell, Option<CellValue>)>,
+    // Spills of the whole sheet afterwards
+    pub spills: HashMap<Cell, Spill>,
+}
+
+// Spreadsheet model independent of any GUI. Formulas are parsed once when
+// they are set, and an edit re-evaluates only the cells downstream of it, in
+// dependency order.
+#[derive(Debug, Clone)]
+pub struct Sheet {
+    rows: usize,
+    cols: usize,
+    // Cell data: formula input by user
+    pub formulas: HashMap<Cell, String>,
+    // Cell data: evaluated value
+    pub values: HashMap<Cell, CellValue>,
+    // Dependency tracking: which cells does each cell depend on
+    pub dependencies: HashMap<Cell, HashSet<Cell>>,
+    // Reverse dependencies: which cells depend on this cell
+    pub dependents: HashMap<Cell, HashSet<Cell>>,
+    // Cells calling volatile functions, re-evaluated after every change
+    pub volatile: HashSet<Cell>,
+    // Files each cell imports with IMPORTCSV, as written in its formula,
+    // re-evaluated when they change
+    imports: HashMap<Cell, Vec<PathBuf>>,
+    // Directory of the file the sheet was loaded from or saved to, against
+    // which relative IMPORTCSV paths are read
+    directory: Option<PathBuf>,
+    // Parsed expression of every cell whose formula starts with '='
+    compiled: HashMap<Cell, Result<Expr, String>>,
+    // Named ranges, replaced by their range when formulas are compiled
+    names: BTreeMap<String, Range>,
+    // Conditional formatting rules, in order of priority
+    pub formats: Vec<Rule>,
+    // Charts drawn from ranges of the sheet
+    pub charts: Vec<Chart>,
+    // Number format code of cells, e.g. "0.00%", see `number_format`
+    pub number_formats: HashMap<Cell, String>,
+    // Ranges shown as a single cell, which never overlap
+    merges: Vec<Range>,
+    // Cells whose text wraps onto several lines
+    pub wrapped: HashSet<Cell>,
+    // Rules for what may be typed into ranges, checked in order
+    pub validations: Vec<Validation>,
+    // Notes attached to cells
+    pub comments: BTreeMap<Cell, Comment>,
+    // Ranges that array formulas spill into, by the cell holding the formula
+    spills: HashMap<Cell, Spill>,
+    // The formula cell every spilled cell gets its value from
+    spilled: HashMap<Cell, Cell>,
+    mode: RecalcMode,
+    // How formulas do arithmetic, chosen when the sheet is created
+    arithmetic: Arithmetic,
+    // Iterative calculation of circular references, off when None
+    iteration: Option<Iteration>,
+    // Functions defined in a script, see `script`
+    script: Script,
+    // Print area and page breaks, see `print`
+    pub print: PrintSetup,
+}
+
+impl Sheet {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Self::with_arithmetic(rows, cols, Arithmetic::default())
+    }
+
+    pub fn with_arithmetic(rows: usize, cols: usize, arithmetic: Arithmetic) -> Self {
+        Self {
+            rows,
+            cols,
+            formulas: HashMap::new(),
+            values: HashMap::new(),
+            dependencies: HashMap::new(),
+            dependents: HashMap::new(),
+            volatile: HashSet::new(),
+            imports: HashMap::new(),
+            directory: None,
+            compiled: HashMap::new(),
+            names: BTreeMap::new(),
+            formats: Vec::new(),
+            charts: Vec::new(),
+            number_formats: HashMap::new(),
+            merges: Vec::new(),
+            wrapped: HashSet::new(),
+            validations: Vec::new(),
+            comments: BTreeMap::new(),
+            spills: HashMap::new(),
+            spilled: HashMap::new(),
+            mode: RecalcMode::default(),
+            arithmetic,
+            iteration: None,
+            script: Script::default(),
+            print: PrintSetup::default(),
+        }
+    }
+
+    pub fn arithmetic(&self) -> Arithmetic {
+        self.arithmetic
+    }
+
+    // Switches how formulas do arithmetic and re-evaluates every formula
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) -> Vec<Cell> {
+        self.arithmetic = arithmetic;
+        // Numbers typed into cells are read again in the new mode
+        for (cell, formula) in &self.formulas {
+            if !self.compiled.contains_key(cell) {
+                self.values.insert(*cell, constant(formula, arithmetic));
+            }
+        }
+        self.recalculate_all()
+    }
+
+    pub fn directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
+
+    // Moves the sheet to the directory of its file, giving the cells that
+    // import files and must be recalculated to read them from there
+    pub fn set_directory(&mut self, directory: Option<PathBuf>) -> Vec<Cell> {
+        if self.directory == directory {
+            return Vec::new();
+        }
+        self.directory = directory;
+        self.imports.keys().copied().collect()
+    }
+
+    pub fn iteration(&self) -> Option<Iteration> {
+        self.iteration
+    }
+
+    // Turns iterative calculation on, with its settings, or off, and
+    // re-evaluates every formula
+    pub fn set_iteration(&mut self, iteration: Option<Iteration>) -> Vec<Cell> {
+        self.iteration = iteration;
+        self.recalculate_all()
+    }
+
+    pub fn script(&self) -> &Script {
+        &self.script
+    }
+
+    // Replaces the script defining functions for formulas and re-evaluates
+    // every formula. A script that does not compile leaves the sheet as it
+    // was.
+    pub fn set_script(&mut self, source: &str) -> Result<Vec<Cell>, String> {
+        self.replace_script(source)?;
+        Ok(self.recalculate_all())
+    }
+
+    // Like `set_script`, leaving the formulas to be re-evaluated by the
+    // caller
+    pub fn replace_script(&mut self, source: &str) -> Result<(), String> {
+        self.script = Script::compile(source)?;
+        Ok(())
+    }
+
+    // Whether any of the cells holds a formula calling a function of the
+    // script, which may take up to the script's time limit per call
+    pub fn calls_script(&self, cells: &HashSet<Cell>) -> bool {
+        cells
+            .iter()
+            .filter_map(|cell| self.compiled.get(cell)?.as_ref().ok())
+            .any(|expr| {
+                let mut calls = false;
+                expr.walk(&mut |e| {
+                    if let Expr::Call(name, _) = e {
+                        calls |= self.script.defines(name);
+                    }
+                });
+                calls
+            })
+    }
+
+    // Cells holding formulas
+    pub fn formula_cells(&self) -> Vec<Cell> {
+        self.compiled.keys().copied().collect()
+    }
+
+    pub fn recalc_mode(&self) -> RecalcMode {
+        self.mode
+    }
+
+    pub fn set_recalc_mode(&mut self, mode: RecalcMode) {
+        self.mode = mode;
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn value(&self, cell: Cell) -> Option<&CellValue> {
+        self.values.get(&cell)
+    }
+
+    // The part of a range inside the sheet, if any
+    pub fn clip(&self, range: Range) -> Option<Range> {
+        if range.start.0 >= self.rows || range.start.1 >= self.cols {
+            return None;
+        }
+        let end = (
+            range.end.0.min(self.rows - 1),
+            range.end.1.min(self.cols - 1),
+        );
+        Some(Range::new(range.start, end))
+    }
+
+    pub fn names(&self) -> &BTreeMap<String, Range> {
+        &self.names
+    }
+
+    // Names a range, e.g. RATES for "B0:B9", so formulas can use RATES in
+    // its place. Names are case-insensitive and cannot look like a cell
+    // reference or a function. Formulas using the name are recalculated.
+    pub fn define_name(&mut self, name: &str, range: &str) -> Result<(), String> {
+        let name = name.trim().to_ascii_uppercase();
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+        if !valid || name == "TRUE" || name == "FALSE" {
+            return Err(format!("invalid name {:?}", name));
+        }
+        if parse_cell_reference(&name).is_some()
+            || functions::NAMES.contains(&name.as_str())
+            || self.script.defines(&name)
+        {
+            return Err(format!("{} is already a cell or function", name));
+        }
+
+        let range = parse_range(range)
+            .filter(|r| self.contains(r.end))
+            .ok_or_else(|| format!("invalid range {:?}", range.trim()))?;
+
+        self.names.insert(name, range);
+        self.recompile();
+        Ok(())
+    }
+
+    pub fn remove_name(&mut self, name: &str) {
+        if self
+            .names
+            .remove(&name.trim().to_ascii_uppercase())
+            .is_some()
+        {
+            self.recompile();
+        }
+    }
+
+    pub fn merges(&self) -> &[Range] {
+        &self.merges
+    }
+
+    // Merges a range into one cell, showing the content of its top-left
+    // cell. Merges inside the range are replaced by it. The other cells keep
+    // their formulas, hidden until the range is unmerged.
+    pub fn merge(&mut self, range: Range) -> Result<(), String> {
+        if !self.contains(range.end) {
+            return Err(format!("{} is outside the sheet", range));
+        }
+        if range.start == range.end {
+            return Err("merge at least two cells".to_string());
+        }
+        if let Some(other) = self
+            .merges
+            .iter()
+            .find(|m| m.intersects(&range) && !(range.contains(m.start) && range.contains(m.end)))
+        {
+            return Err(format!("{} overlaps the merged cells {}", range, other));
+        }
+        self.merges.retain(|m| !range.contains(m.start));
+        self.merges.push(range);
+        Ok(())
+    }
+
+    // Splits the merge covering a cell back into single cells, returning it
+    pub fn unmerge(&mut self, cell: Cell) -> Option<Range> {
+        let index = self.merges.iter().position(|m| m.contains(cell))?;
+        Some(self.merges.remove(index))
+    }
+
+    // The merge covering a cell, if any
+    pub fn merged(&self, cell: Cell) -> Option<Range> {
+        self.merges.iter().find(|m| m.contains(cell)).copied()
+    }
+
+    // The cell shown in place of a cell: the top-left cell of its merge, or
+    // the cell itself
+    pub fn anchor(&self, cell: Cell) -> Cell {
+        self.merged(cell).map_or(cell, |m| m.start)
+    }
+
+    // The cell one step away in a direction, such as (0, 1) for the cell to
+    // the right, with merges counting as one cell. None past the edge of the
+    // sheet.
+    pub fn neighbor(&self, cell: Cell, rows: isize, cols: isize) -> Option<Cell> {
+        let span = self.merged(cell).unwrap_or(Range::new(cell, cell));
+        let step = |at: usize, start: usize, end: usize, by: isize| match by.signum() {
+            1 => Some(end + 1),
+            -1 => start.checked_sub(1),
+            _ => Some(at),
+        };
+        let next = (
+            step(cell.0, span.start.0, span.end.0, rows)?,
+            step(cell.1, span.start.1, span.end.1, cols)?,
+        );
+        self.contains(next).then(|| self.anchor(next))
+    }
+
+    // Compiles every formula again, e.g. after a name changed, and
+    // recalculates the sheet
+    fn recompile(&mut self) {
+        let formulas: Vec<(Cell, String)> = self
+            .formulas
+            .iter()
+            .filter(|(_, formula)| formula.starts_with('='))
+            .map(|(cell, formula)| (*cell, formula.clone()))
+            .collect();
+        for (cell, formula) in formulas {
+            self.edit(cell, &formula);
+        }
+        self.recalculate_all();
+    }
+
+    // Problem with a formula being typed, in words, or None when it would
+    // compile. Unknown functions and names are reported here although they
+    // only evaluate to #NAME.
+    pub fn check_formula(&self, formula: &str) -> Option<String> {
+        let expr = formula.trim().strip_prefix('=')?;
+        let expr = match self.compile(expr) {
+            Ok(expr) => expr,
+            Err(err) if err == "REF" => return Some("reference outside the sheet".to_string()),
+            Err(_) => return Some("syntax error".to_string()),
+        };
+
+        let mut problem = None;
+        expr.walk(&mut |e| match e {
+            Expr::Call(name, _)
+                if !functions::NAMES.contains(&name.as_str()) && !self.script.defines(name) =>
+            {
+                problem.get_or_insert(format!("unknown function {}", name));
+            }
+            Expr::Name(name) => {
+                problem.get_or_insert(format!("unknown name {}", name));
+            }
+            _ => {}
+        });
+        problem
+    }
+
+    // Sets the formula of a cell and recalculates everything that depends on
+    // it. Returns the recalculated cells in evaluation order.
+    pub fn set_formula(&mut self, cell: Cell, formula: &str) -> Vec<Cell> {
+        let roots = self.edit(cell, formula);
+        self.recalculate(roots)
+    }
+
+    // Sets the formula of a cell and relinks it in the dependency graph
+    // without evaluating anything. Constants take effect immediately; the
+    // returned roots must be passed to `recalculate` to bring the formula
+    // values downstream up to date.
+    pub fn edit(&mut self, cell: Cell, formula: &str) -> Vec<Cell> {
+        let formula = formula.trim();
+
+        // Remove old dependencies
+        if let Some(old_deps) = self.dependencies.remove(&cell) {
+            for dep in old_deps {
+                if let Some(rev_deps) = self.dependents.get_mut(&dep) {
+                    rev_deps.remove(&cell);
+                }
+            }
+        }
+        self.compiled.remove(&cell);
+        self.volatile.remove(&cell);
+        self.imports.remove(&cell);
+
+        // Spills over the cell are blocked or freed by the edit
+        let mut roots: Vec<Cell> = self.spills_over(cell).map(|(anchor, _)| anchor).collect();
+        if !formula.starts_with('=') {
+            roots.extend(self.place(cell, None));
+        }
+
+        if formula.is_empty() {
+            self.formulas.remove(&cell);
+            self.values.remove(&cell);
+        } else {
+            self.formulas.insert(cell, formula.to_string());
+
+            match formula.strip_prefix('=') {
+                Some(expr) => {
+                    let compiled = self.compile(expr);
+                    if let Ok(expr) = &compiled {
+                        let mut deps = HashSet::new();
+                        expr.references(&mut deps);
+                        if !deps.is_empty() {
+                            for dep in &deps {
+                                self.dependents.entry(*dep).or_default().insert(cell);
+                            }
+                            self.dependencies.insert(cell, deps);
+                        }
+                        if expr.is_volatile() {
+                            self.volatile.insert(cell);
+                        }
+                        let files = expr.imported_files();
+                        if !files.is_empty() {
+                            self.imports.insert(cell, files);
+                        }
+                    }
+                    self.compiled.insert(cell, compiled);
+                }
+                None => {
+                    self.values.insert(cell, constant(formula, self.arithmetic));
+                }
+            }
+        }
+
+        // Volatile cells such as RAND() get a fresh value on every change
+        roots.push(cell);
+        roots.extend(self.volatile.iter().copied());
+        roots
+    }
+
+    // Files the formulas import, to watch for changes
+    pub fn imported_files(&self) -> BTreeSet<PathBuf> {
+        self.imports
+            .values()
+            .flatten()
+            .map(|file| functions::import_path(self.directory(), file))
+            .collect()
+    }
+
+    // Cells importing the file, with relative paths in their formulas read
+    // from the sheet's directory
+    pub fn importers(&self, path: &Path) -> Vec<Cell> {
+        self.imports
+            .iter()
+            .filter(|(_, files)| {
+                files
+                    .iter()
+                    .any(|file| functions::import_path(self.directory(), file) == path)
+            })
+            .map(|(cell, _)| *cell)
+            .collect()
+    }
+
+    // Reads a file that changed again, recalculating the cells importing it
+    // and everything that depends on them
+    pub fn reimport(&mut self, path: &Path) -> Vec<Cell> {
+        let roots = self.importers(path);
+        self.recalculate(roots)
+    }
+
+    // Re-evaluates every formula, e.g. after loading a sheet
+    pub fn recalculate_all(&mut self) -> Vec<Cell> {
+        let roots = self.formula_cells();
+        self.recalculate(roots)
+    }
+
+    // Evaluates a formula against the current values without storing it
+    pub fn evaluate_formula(&self, formula: &str) -> CellValue {
+        let formula = formula.trim();
+
+        // Empty formula
+        if formula.is_empty() {
+            return CellValue::Text(String::new());
+        }
+
+        // If it starts with '=', it's a formula
+        match formula.strip_prefix('=') {
+            Some(expr) => {
+                let array = self.evaluate(&self.compile(expr));
+                array[0][0].clone()
+            }
+            None => constant(formula, self.arithmetic),
+        }
+    }
+
+    pub(crate) fn compile(&self, expr: &str) -> Result<Expr, String> {
+        let expr = self.resolve_names(parse_expression(expr)?);
+
+        // References outside the sheet
+        let mut in_bounds = true;
+        expr.walk(&mut |e| match e {
+            Expr::Ref(cell) => in_bounds &= self.contains(*cell),
+            Expr::Range(range) => in_bounds &= self.contains(range.end),
+            _ => {}
+        });
+        if !in_bounds {
+            return Err("REF".to_string());
+        }
+
+        Ok(expr)
+    }
+
+    // Replaces defined names by their ranges
+    fn resolve_names(&self, expr: Expr) -> Expr {
+        expr.map(&mut |expr| match expr {
+            Expr::Name(name) => match self.names.get(&name) {
+                Some(range) => Expr::Range(*range),
+                None => Expr::Name(name),
+            },
+            other => other,
+        })
+    }
+
+    // Whether the cell lies within the sheet's rows and columns
+    pub fn contains(&self, cell: Cell) -> bool {
+        cell.0 < self.rows && cell.1 < self.cols
+    }
+
+    // Value of a formula, or the values it spills when it gives an array or
+    // a range
+    fn evaluate(&self, compiled: &Result<Expr, String>) -> Array {
+        let evaluator = Evaluator::with_arithmetic(&self.values, self.arithmetic)
+            .with_script(&self.script)
+            .with_directory(self.directory());
+        let result = compiled
+            .as_ref()
+            .map_err(Clone::clone)
+            .and_then(|expr| evaluator.array(evaluator.eval(expr)?));
+        result.unwrap_or_else(|err| vec![vec![CellValue::Error(err)]])
+    }
+
+    // The spill starting at a cell, if the cell holds an array formula
+    pub fn spill(&self, cell: Cell) -> Option<Spill> {
+        self.spills.get(&cell).copied()
+    }
+
+    // The formula cell a spilled cell gets its value from
+    pub fn spilled_from(&self, cell: Cell) -> Option<Cell> {
+        self.spilled.get(&cell).copied()
+    }
+
+    // Spills of other cells whose range covers the cell, blocked or not
+    fn spills_over(&self, cell: Cell) -> impl Iterator<Item = (Cell, Spill)> + '_ {
+        self.spills
+            .iter()
+            .filter(move |(anchor, spill)| **anchor != cell && spill.range.contains(cell))
+            .map(|(anchor, spill)| (*anchor, *spill))
+    }
+
+    // Cells a formula cell currently spills into, besides itself
+    fn spilled_cells(&self, cell: Cell) -> Vec<Cell> {
+        match self.spills.get(&cell) {
+            Some(spill) if !spill.blocked => spill.range.cells().filter(|c| *c != cell).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Stores the result of a formula: its value, or its array spilled into
+    // the cells below and to the right when they are free. None only frees
+    // the cells the formula spilled into. Returns the cells that were
+    // spilled into before or after, but not both.
+    fn place(&mut self, cell: Cell, result: Option<Array>) -> Vec<Cell> {
+        let before = self.spilled_cells(cell);
+        for covered in &before {
+            self.spilled.remove(covered);
+            // Input typed over a spill replaces it
+            if !self.formulas.contains_key(covered) {
+                self.values.remove(covered);
+            }
+        }
+        self.spills.remove(&cell);
+
+        match result {
+            // Functions never give empty or ragged arrays, but should one
+            // do so it is an error rather than a spill
+            Some(array)
+                if array.first().is_none_or(|first| {
+                    first.is_empty() || array.iter().any(|row| row.len() != first.len())
+                }) =>
+            {
+                self.values
+                    .insert(cell, CellValue::Error("CALC".to_string()));
+            }
+            Some(array) if array.len() > 1 || array[0].len() > 1 => {
+                let end = (cell.0 + array.len() - 1, cell.1 + array[0].len() - 1);
+                let range = Range::new(cell, end);
+                // A formula reading the cells it would spill into
+                if self
+                    .dependencies
+                    .get(&cell)
+                    .is_some_and(|deps| deps.iter().any(|d| *d != cell && range.contains(*d)))
+                {
+                    self.values
+                        .insert(cell, CellValue::Error("CYCLE".to_string()));
+                    return before;
+                }
+                let blocked = !self.contains(end)
+                    || range.cells().any(|c| {
+                        c != cell
+                            && (self.formulas.contains_key(&c) || self.spilled.contains_key(&c))
+                    });
+                self.spills.insert(cell, Spill { range, blocked });
+                if blocked {
+                    self.values
+                        .insert(cell, CellValue::Error("SPILL".to_string()));
+                } else {
+                    for (row, values) in array.into_iter().enumerate() {
+                        for (col, value) in values.into_iter().enumerate() {
+                            let covered = range.cell(row, col);
+                            if covered != cell {
+                                self.spilled.insert(covered, cell);
+                            }
+                            self.values.insert(covered, value);
+                        }
+                    }
+                }
+            }
+            Some(mut array) => {
+                let value = array[0].remove(0);
+                self.values.insert(cell, value);
+            }
+            None => {}
+        }
+
+        let after: HashSet<Cell> = self.spilled_cells(cell).into_iter().collect();
+        let before: HashSet<Cell> = before.into_iter().collect();
+        before.symmetric_difference(&after).copied().collect()
+    }
+
+    // Values and spills of the given cells, e.g. the cells a recalculation
+    // evaluated, for applying to another copy of the sheet
+    pub fn results(&self, cells: &[Cell]) -> Results {
+        Results {
+            values: cells
+                .iter()
+                .map(|cell| (*cell, self.values.get(cell).cloned()))
+                .collect(),
+            spills: self.spills.clone(),
+        }
+    }
+
+    // Takes on the results of a recalculation of a copy of this sheet made
+    // after its last edit
+    pub fn apply(&mut self, results: Results) {
+        for (cell, value) in results.values {
+            match value {
+                Some(value) => self.values.insert(cell, value),
+                None => self.values.remove(&cell),
+            };
+        }
+        self.spills = results.spills;
+        self.spilled.clear();
+        for anchor in self.spills.keys().copied().collect::<Vec<_>>() {
+            for covered in self.spilled_cells(anchor) {
+                self.spilled.insert(covered, anchor);
+            }
+        }
+    }
+
+    // Recalculates the roots and everything downstream of them. Dirty cells
+    // are grouped into topological levels: a level holds the cells whose
+    // inputs are all final, so its cells are independent of each other and
+    // can be evaluated in any order, or in parallel. Each level's results are
+    // applied to `values` together once the whole level is done. Cells on a
+    // cycle become #CYCLE, or are iterated in iterative mode.
+    pub fn recalculate(&mut self, roots: Vec<Cell>) -> Vec<Cell> {
+        self.recalculate_cancellable(roots, &AtomicBool::new(false))
+            .unwrap_or_default()
+    }
+
+    // Like `recalculate`, but checks `cancel` between levels and gives up as
+    // soon as it is set, returning None. The values of a cancelled sheet are
+    // partially updated and should be thrown away.
+    pub fn recalculate_cancellable(
+        &mut self,
+        roots: Vec<Cell>,
+        cancel: &AtomicBool,
+    ) -> Option<Vec<Cell>> {
+        let mut changed = HashSet::new();
+        let mut order = self.recalculate_pass(roots, cancel, &mut changed)?;
+
+        // Cells a spill newly covers or leaves were not known to be dirty
+        // when the pass started. They are recalculated with their dependents
+        // and the blocked spills over them, which may now fit.
+        for _ in 0..MAX_SPILL_PASSES {
+            if changed.is_empty() {
+                break;
+            }
+            let mut roots = Vec::new();
+            for cell in std::mem::take(&mut changed) {
+                roots.push(cell);
+                roots.extend(
+                    self.spills_over(cell)
+                        .filter(|(_, spill)| spill.blocked)
+                        .map(|(anchor, _)| anchor),
+                );
+            }
+            order.extend(self.recalculate_pass(roots, cancel, &mut changed)?);
+        }
+        Some(order)
+    }
+
+    // One recalculation of the roots and everything downstream of them, as
+    // the sheet's spills stood at its start. Cells whose spill changed are
+    // added to `changed`.
+    fn recalculate_pass(
+        &mut self,
+        roots: Vec<Cell>,
+        cancel: &AtomicBool,
+        changed: &mut HashSet<Cell>,
+    ) -> Option<Vec<Cell>> {
+        let dirty = self.dirty_cells(roots);
+
+        // Spilled cells are evaluated with the formula they spill from
+        let spill_links: HashMap<Cell, Vec<Cell>> = dirty
+            .iter()
+            .map(|cell| (*cell, self.spilled_cells(*cell)))
+            .filter(|(_, cells)| !cells.is_empty())
+            .collect();
+
+        let (mut order, left) = self.evaluate_in_order(&dirty, &spill_links, cancel, changed)?;
+        if left.is_empty() {
+            return Some(order);
+        }
+
+        // Cells on a cycle or downstream of one
+        match self.iteration {
+            Some(iteration) => {
+                self.iterate(&left, iteration, changed);
+                order.extend(left);
+            }
+            None => {
+                // Cells on the cycle become #CYCLE, and the cells downstream
+                // of them are evaluated with that value, as they would be
+                // when edited on their own
+                let cyclic = self.on_cycle(&left, &spill_links);
+                for cell in &left {
+                    if cyclic.contains(cell) && self.compiled.contains_key(cell) {
+                        let cycle = vec![vec![CellValue::Error("CYCLE".to_string())]];
+                        changed.extend(self.place(*cell, Some(cycle)));
+                    }
+                }
+                let downstream: HashSet<Cell> = left
+                    .iter()
+                    .filter(|cell| !cyclic.contains(cell))
+                    .copied()
+                    .collect();
+                order.extend(left.iter().filter(|cell| cyclic.contains(cell)));
+                let (evaluated, _) =
+                    self.evaluate_in_order(&downstream, &spill_links, cancel, changed)?;
+                order.extend(evaluated);
+            }
+        }
+
+        Some(order)
+    }
+
+    // Evaluates the cells in dependency order, level by level, as far as
+    // cycles allow. Returns the cells evaluated in order, and the cells left
+    // over on a cycle or downstream of one, in sheet order.
+    fn evaluate_in_order(
+        &mut self,
+        cells: &HashSet<Cell>,
+        spill_links: &HashMap<Cell, Vec<Cell>>,
+        cancel: &AtomicBool,
+        changed: &mut HashSet<Cell>,
+    ) -> Option<(Vec<Cell>, Vec<Cell>)> {
+        // Number of not yet evaluated inputs of each cell
+        let mut pending: HashMap<Cell, usize> = cells
+            .iter()
+            .map(|cell| {
+                let count = self
+                    .dependencies
+                    .get(cell)
+                    .map_or(0, |deps| deps.iter().filter(|d| cells.contains(d)).count())
+                    + usize::from(self.spilled.get(cell).is_some_and(|a| cells.contains(a)));
+                (*cell, count)
+            })
+            .collect();
+
+        let mut level: Vec<Cell> = pending
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(cell, _)| *cell)
+            .collect();
+        let mut order = Vec::with_capacity(cells.len());
+
+        while !level.is_empty() {
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            for (cell, result) in self.evaluate_level(&level) {
+                changed.extend(self.place(cell, Some(result)));
+            }
+
+            let mut next = Vec::new();
+            for cell in &level {
+                pending.remove(cell);
+                let deps = self.dependents.get(cell).into_iter().flatten();
+                for dep in deps.chain(spill_links.get(cell).into_iter().flatten()) {
+                    if let Some(count) = pending.get_mut(dep) {
+                        *count -= 1;
+                        if *count == 0 {
+                            next.push(*dep);
+                        }
+                    }
+                }
+            }
+
+            order.append(&mut level);
+            level = next;
+        }
+
+        let mut left: Vec<Cell> = pending.into_keys().collect();
+        left.sort();
+        Some((order, left))
+    }
+
+    // The cells among `cells` that lie on a cycle: the strongly connected
+    // components of more than one cell, or of a cell reading itself, found
+    // with Tarjan's algorithm. It keeps its own stack of visits, so long
+    // chains of cells cannot overflow the call stack.
+    fn on_cycle(&self, cells: &[Cell], spill_links: &HashMap<Cell, Vec<Cell>>) -> HashSet<Cell> {
+        let set: HashSet<Cell> = cells.iter().copied().collect();
+        let successors = |cell: Cell| -> Vec<Cell> {
+            let deps = self.dependents.get(&cell).into_iter().flatten();
+            deps.chain(spill_links.get(&cell).into_iter().flatten())
+                .filter(|c| set.contains(c))
+                .copied()
+                .collect()
+        };
+
+        // Order of discovery of every visited cell, and the earliest cell
+        // on the stack it reaches
+        let mut index: HashMap<Cell, usize> = HashMap::new();
+        let mut low: HashMap<Cell, usize> = HashMap::new();
+        let mut stack = Vec::new();
+        let mut on_stack = HashSet::new();
+        let mut cyclic = HashSet::new();
+        for &root in cells {
+            if index.contains_key(&root) {
+                continue;
+            }
+            // Cells being visited, with the successors left to visit
+            let mut visits = vec![(root, successors(root))];
+            index.insert(root, index.len());
+            low.insert(root, index[&root]);
+            stack.push(root);
+            on_stack.insert(root);
+
+            while let Some((cell, remaining)) = visits.last_mut() {
+                let cell = *cell;
+                if let Some(next) = remaining.pop() {
+                    if !index.contains_key(&next) {
+                        index.insert(next, index.len());
+                        low.insert(next, index[&next]);
+                        stack.push(next);
+                        on_stack.insert(next);
+                        visits.push((next, successors(next)));
+                    } else if on_stack.contains(&next) {
+                        low.insert(cell, low[&cell].min(index[&next]));
+                    }
+                    continue;
+                }
+
+                visits.pop();
+                if let Some((parent, _)) = visits.last() {
+                    low.insert(*parent, low[parent].min(low[&cell]));
+                }
+                if low[&cell] == index[&cell] {
+                    let mut component = Vec::new();
+                    while let Some(member) = stack.pop() {
+                        on_stack.remove(&member);
+                        component.push(member);
+                        if member == cell {
+                            break;
+                        }
+                    }
+                    if component.len() > 1 || successors(cell).contains(&cell) {
+                        cyclic.extend(component);
+                    }
+                }
+            }
+        }
+        cyclic
+    }
+
+    // Evaluates the formulas among the cells round after round, in sheet
+    // order, each reading the latest values of the others, until a round
+    // changes no value by more than the maximum change. Cells that have not
+    // settled after the most rounds allowed become #CONVERGE.
+    fn iterate(&mut self, cells: &[Cell], iteration: Iteration, changed: &mut HashSet<Cell>) {
+        let formulas: Vec<Cell> = cells
+            .iter()
+            .filter(|cell| self.compiled.contains_key(cell))
+            .copied()
+            .collect();
+        // An error, such as #CYCLE from before iteration was turned on, would
+        // feed back forever, so such cells start out empty
+        for cell in &formulas {
+            if matches!(self.values.get(cell), Some(CellValue::Error(_))) {
+                self.values.remove(cell);
+            }
+        }
+
+        for _ in 0..iteration.max_iterations {
+            let mut settled = true;
+            for cell in &formulas {
+                let before = self.values.get(cell).cloned();
+                let result = self.evaluate(&self.compiled[cell]);
+                changed.extend(self.place(*cell, Some(result)));
+                let after = self.values.get(cell);
+                settled &= match (
+                    before.as_ref().and_then(CellValue::as_number),
+                    after.and_then(CellValue::as_number),
+                ) {
+                    (Some(a), Some(b)) => (a - b).abs() <= iteration.max_change,
+                    _ => before.as_ref() == after,
+                };
+            }
+            if settled {
+                return;
+            }
+        }
+
+        for cell in formulas {
+            let unsettled = vec![vec![CellValue::Error("CONVERGE".to_string())]];
+            changed.extend(self.place(cell, Some(unsettled)));
+        }
+    }
+
+    // New values of the formula cells of one level, computed against the
+    // current values without modifying them
+    fn evaluate_level(&self, level: &[Cell]) -> Vec<(Cell, Array)> {
+        let evaluate = |cell: &Cell| {
+            self.compiled
+                .get(cell)
+                .map(|compiled| (*cell, self.evaluate(compiled)))
+        };
+
+        if self.mode == RecalcMode::Parallel && level.len() >= PARALLEL_THRESHOLD {
+            level.par_iter().filter_map(evaluate).collect()
+        } else {
+            level.iter().filter_map(evaluate).collect()
+        }
+    }
+
+    // Every cell the given cell reads, directly or through other cells, with
+    // its depth: 1 for direct references, 2 for their references, and so on
+    pub fn trace_precedents(&self, cell: Cell) -> Vec<(Cell, usize)> {
+        trace(cell, &self.dependencies)
+    }
+
+    // Every cell that reads the given cell, directly or through other cells,
+    // with its depth
+    pub fn trace_dependents(&self, cell: Cell) -> Vec<(Cell, usize)> {
+        trace(cell, &self.dependents)
+    }
+
+    // The roots plus every cell that transitively depends on them, through
+    // references or spills
+    pub fn dirty_cells(&self, roots: Vec<Cell>) -> HashSet<Cell> {
+        let mut dirty = HashSet::new();
+        let mut stack = roots;
+        while let Some(cell) = stack.pop() {
+            if dirty.insert(cell) {
+                if let Some(deps) = self.dependents.get(&cell) {
+                    stack.extend(deps.iter().copied());
+                }
+                stack.extend(self.spilled_cells(cell));
+            }
+        }
+        dirty
+    }
+}
+
+// Breadth-first walk of the links from a cell. Each cell is reported once, at
+// its shortest depth, ordered by depth and then by position.
+fn trace(cell: Cell, links: &HashMap<Cell, HashSet<Cell>>) -> Vec<(Cell, usize)> {
+    let mut seen = HashSet::from([cell]);
+    let mut found = Vec::new();
+    let mut level = vec![cell];
+    let mut depth = 0;
+    while !level.is_empty() {
+        depth += 1;
+        let mut next: Vec<Cell> = level
+            .iter()
+            .filter_map(|cell| links.get(cell))
+            .flatten()
+            .filter(|linked| seen.insert(**linked))
+            .copied()
+            .collect();
+        next.sort();
+        found.extend(next.iter().map(|cell| (*cell, depth)));
+        level = next;
+    }
+    found
+}
+
+// Value of a cell that does not start with '='. In decimal mode numbers are
+// read as the decimals they are written as, however many digits they have.
+pub(crate) fn constant(formula: &str, arithmetic: Arithmetic) -> CellValue {
+    let Ok(num) = formula.parse::<f64>() else {
+        return CellValue::Text(formula.to_string()); // It's text/label
+    };
+    match arithmetic {
+        Arithmetic::Decimal => Decimal::parse(formula)
+            .or_else(|| Decimal::from_f64(num))
+            .map_or(CellValue::Number(num), CellValue::Decimal),
+        Arithmetic::Float => CellValue::Number(num),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::cell_name;
+
+    fn number(sheet: &Sheet, cell: Cell) -> f64 {
+        match sheet.value(cell).and_then(CellValue::as_number) {
+            Some(n) => n,
+            None => panic!("{:?} is {:?}, not a number", cell, sheet.value(cell)),
+        }
+    }
+
+    #[test]
+    fn test_diamond_is_evaluated_in_dependency_order() {
+        // A0 feeds B0 and C0, and D0 reads both
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "1");
+        sheet.set_formula((0, 1), "=A0*10");
+        sheet.set_formula((0, 2), "=A0+B0");
+        sheet.set_formula((0, 3), "=B0+C0");
+        assert_eq!(number(&sheet, (0, 3)), 21.0);
+
+        let order = sheet.set_formula((0, 0), "2");
+        assert_eq!(number(&sheet, (0, 3)), 42.0);
+
+        // Every cell is evaluated once, after all of its inputs
+        assert_eq!(order.len(), 4);
+        let position = |cell| order.iter().position(|c| *c == cell).unwrap();
+        assert!(position((0, 1)) < position((0, 2)));
+        assert!(position((0, 2)) < position((0, 3)));
+    }
+
+    #[test]
+    fn test_only_dirty_cells_are_recalculated() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "1");
+        sheet.set_formula((1, 0), "=A0+1");
+        sheet.set_formula((0, 1), "5");
+        sheet.set_formula((1, 1), "=B0+1");
+
+        let order = sheet.set_formula((0, 1), "6");
+        assert_eq!(order.len(), 2);
+        assert!(!order.contains(&(1, 0)));
+        assert_eq!(number(&sheet, (1, 1)), 7.0);
+    }
+
+    #[test]
+    fn test_cycle_is_reported() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=B0+1");
+        sheet.set_formula((0, 1), "=A0+1");
+        sheet.set_formula((0, 2), "=B0");
+        assert_eq!(
+            sheet.value((0, 0)),
+            Some(&CellValue::Error("CYCLE".to_string()))
+        );
+        assert_eq!(
+            sheet.value((0, 1)),
+            Some(&CellValue::Error("CYCLE".to_string()))
+        );
+
+        // Breaking the cycle recovers all cells
+        sheet.set_formula((0, 1), "4");
+        assert_eq!(number(&sheet, (0, 0)), 5.0);
+        assert_eq!(number(&sheet, (0, 2)), 4.0);
+    }
+
+    #[test]
+    fn test_cells_downstream_of_a_cycle_are_evaluated() {
+        // Whether the cycle is made before or after the cells reading it,
+        // they see its #CYCLE like any other error
+        for cycle_first in [true, false] {
+            let mut sheet = Sheet::new(10, 4);
+            let cycle = |sheet: &mut Sheet| {
+                sheet.set_formula((0, 0), "=B0+1");
+                sheet.set_formula((0, 1), "=A0+1");
+            };
+            if cycle_first {
+                cycle(&mut sheet);
+            }
+            sheet.set_formula((1, 0), "=COUNT(A0:B0)+1");
+            sheet.set_formula((1, 1), "=A0*2");
+            if !cycle_first {
+                cycle(&mut sheet);
+            }
+            assert_eq!(number(&sheet, (1, 0)), 1.0);
+            assert_eq!(
+                sheet.value((1, 1)),
+                Some(&CellValue::Error("CYCLE".to_string()))
+            );
+            sheet.recalculate_all();
+            assert_eq!(number(&sheet, (1, 0)), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_reimport_changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reimport.csv");
+        std::fs::write(&path, "1\n2\n").unwrap();
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), &format!("=IMPORTCSV(\"{}\")", path.display()));
+        sheet.set_formula((0, 1), "=SUM(A0:A4)");
+        assert_eq!(number(&sheet, (0, 1)), 3.0);
+        assert_eq!(sheet.imported_files(), [path.clone()].into());
+
+        // The spill grows with the file, and its dependents follow
+        std::fs::write(&path, "1\n2\n3\n").unwrap();
+        assert!(sheet.reimport(&path).contains(&(0, 1)));
+        assert_eq!(number(&sheet, (2, 0)), 3.0);
+        assert_eq!(number(&sheet, (0, 1)), 6.0);
+        assert!(sheet.reimport(Path::new("other.csv")).is_empty());
+
+        sheet.set_formula((0, 0), "");
+        assert!(sheet.imported_files().is_empty());
+    }
+
+    #[test]
+    fn test_relative_imports_follow_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relative.csv");
+        std::fs::write(&path, "7\n").unwrap();
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=IMPORTCSV(\"relative.csv\")");
+        assert_eq!(
+            sheet.value((0, 0)),
+            Some(&CellValue::Error("REF".to_string()))
+        );
+
+        // Moving the sheet reads the file from its new directory, which is
+        // where it is watched
+        let roots = sheet.set_directory(Some(dir.path().to_path_buf()));
+        assert_eq!(roots, [(0, 0)]);
+        sheet.recalculate(roots);
+        assert_eq!(number(&sheet, (0, 0)), 7.0);
+        assert_eq!(sheet.imported_files(), [path.clone()].into());
+        assert_eq!(sheet.importers(&path), [(0, 0)]);
+        assert!(sheet.importers(Path::new("relative.csv")).is_empty());
+        assert!(
+            sheet
+                .set_directory(Some(dir.path().to_path_buf()))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_self_reference_is_a_cycle() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=A0+1");
+        assert_eq!(
+            sheet.value((0, 0)),
+            Some(&CellValue::Error("CYCLE".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_references_outside_the_sheet() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=A10");
+        sheet.set_formula((0, 1), "=SUM(A0:E1)");
+        sheet.set_formula((0, 2), "=A9");
+        assert_eq!(
+            sheet.value((0, 0)),
+            Some(&CellValue::Error("REF".to_string()))
+        );
+        assert_eq!(
+            sheet.value((0, 1)),
+            Some(&CellValue::Error("REF".to_string()))
+        );
+        assert_eq!(number(&sheet, (0, 2)), 0.0);
+        assert!(!sheet.dependencies.contains_key(&(0, 0)));
+    }
+
+    #[test]
+    fn test_clearing_a_cell_recalculates_dependents() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "3");
+        sheet.set_formula((1, 0), "=A0*2");
+        sheet.set_formula((0, 0), "");
+        assert_eq!(sheet.value((0, 0)), None);
+        assert_eq!(number(&sheet, (1, 0)), 0.0);
+    }
+
+    #[test]
+    fn test_recalculate_all() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "2");
+        sheet.set_formula((1, 0), "=A0*2");
+        sheet.set_formula((2, 0), "=A1*2");
+        sheet.values.clear();
+
+        let order = sheet.recalculate_all();
+        assert_eq!(order.len(), 2);
+        assert_eq!(number(&sheet, (2, 0)), 0.0);
+
+        sheet.values.insert((0, 0), CellValue::Number(2.0));
+        sheet.recalculate_all();
+        assert_eq!(number(&sheet, (2, 0)), 8.0);
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let rows = 5000;
+        let mut sheet = Sheet::new(rows, 1);
+        sheet.set_formula((0, 0), "0");
+        for row in 1..rows {
+            sheet.set_formula((row, 0), &format!("=A{}+1", row - 1));
+        }
+        assert_eq!(number(&sheet, (rows - 1, 0)), (rows - 1) as f64);
+
+        let order = sheet.set_formula((0, 0), "10");
+        assert_eq!(order.len(), rows);
+        assert_eq!(number(&sheet, (rows - 1, 0)), (rows + 9) as f64);
+    }
+
+    // Every cell of row r reads two cells of row r / 2, so the dependency
+    // levels double in width and soon exceed the parallel threshold
+    fn branching(rows: usize) -> Sheet {
+        let cols = 26;
+        let mut sheet = Sheet::new(rows, cols);
+        for col in 0..cols {
+            sheet.set_formula((0, col), &(col + 1).to_string());
+        }
+        for row in 1..rows {
+            for col in 0..cols {
+                let a = cell_name((row / 2, col));
+                let b = cell_name((row / 2, (col + 1) % cols));
+                let formula = match (row + col) % 4 {
+                    0 => format!("={}+{}", a, b),
+                    1 => format!("=MOD({}*3, 1000)+{}", a, b),
+                    2 => format!("=ROUND(SQRT(ABS({})) + {}/7, 3)", a, b),
+                    _ => format!("=SUM(A{}:{})-{}", row / 2, a, b),
+                };
+                sheet.set_formula((row, col), &formula);
+            }
+        }
+        sheet
+    }
+
+    #[test]
+    fn test_parallel_recalculation_matches_serial() {
+        let mut serial = branching(200);
+        let mut parallel = serial.clone();
+        parallel.set_recalc_mode(RecalcMode::Parallel);
+
+        for (cell, formula) in [((0, 0), "17"), ((0, 5), "=A0*2"), ((3, 2), "-4")] {
+            let serial_order = serial.set_formula(cell, formula);
+            let parallel_order = parallel.set_formula(cell, formula);
+            assert_eq!(serial_order.len(), parallel_order.len());
+            assert_eq!(serial.values, parallel.values);
+        }
+
+        serial.values.clear();
+        parallel.values.clear();
+        serial.recalculate_all();
+        parallel.recalculate_all();
+        assert_eq!(serial.values, parallel.values);
+    }
+
+    #[test]
+    fn test_parallel_recalculation_handles_cycles() {
+        let mut sheet = branching(200);
+        sheet.set_recalc_mode(RecalcMode::Parallel);
+        sheet.set_formula((0, 0), "=Z199");
+        assert_eq!(
+            sheet.value((0, 0)),
+            Some(&CellValue::Error("CYCLE".to_string()))
+        );
+        assert_eq!(
+            sheet.value((199, 25)),
+            Some(&CellValue::Error("CYCLE".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_volatile_cells_are_always_dirty() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=RAND()");
+        sheet.set_formula((1, 0), "=A0+1");
+        let order = sheet.set_formula((5, 3), "1");
+        assert!(order.contains(&(0, 0)));
+        assert!(order.contains(&(1, 0)));
+    }
+
+    #[test]
+    fn test_edit_defers_evaluation() {
+        let mut sheet = Sheet::new(10, 2);
+        sheet.set_formula((0, 0), "1");
+        sheet.set_formula((1, 0), "=A0+1");
+
+        let roots = sheet.edit((0, 0), "5");
+        assert_eq!(number(&sheet, (0, 0)), 5.0);
+        assert_eq!(number(&sheet, (1, 0)), 2.0);
+        assert_eq!(
+            sheet.dirty_cells(roots.clone()),
+            HashSet::from([(0, 0), (1, 0)])
+        );
+
+        sheet.recalculate(roots);
+        assert_eq!(number(&sheet, (1, 0)), 6.0);
+    }
+
+    #[test]
+    fn test_cancelled_recalculation_stops() {
+        let mut sheet = Sheet::new(100, 1);
+        sheet.set_formula((0, 0), "0");
+        for row in 1..100 {
+            sheet.set_formula((row, 0), &format!("=A{}+1", row - 1));
+        }
+
+        let roots = sheet.edit((0, 0), "10");
+        let cancel = AtomicBool::new(true);
+        assert_eq!(sheet.recalculate_cancellable(roots.clone(), &cancel), None);
+        assert_eq!(number(&sheet, (99, 0)), 99.0);
+
+        cancel.store(false, Ordering::Relaxed);
+        let order = sheet.recalculate_cancellable(roots, &cancel).unwrap();
+        assert_eq!(order.len(), 100);
+        assert_eq!(number(&sheet, (99, 0)), 109.0);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "1");
+        sheet.set_formula((1, 0), "2");
+        sheet.set_formula((0, 1), "=A0+A1");
+        sheet.set_formula((0, 2), "=B0*2");
+        sheet.set_formula((1, 2), "=B0+C0+A0");
+
+        assert_eq!(
+            sheet.trace_precedents((1, 2)),
+            vec![((0, 0), 1), ((0, 1), 1), ((0, 2), 1), ((1, 0), 2)]
+        );
+        assert_eq!(
+            sheet.trace_dependents((0, 0)),
+            vec![((0, 1), 1), ((1, 2), 1), ((0, 2), 2)]
+        );
+        assert!(sheet.trace_precedents((0, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_trace_cycle() {
+        let mut sheet = Sheet::new(10, 1);
+        sheet.set_formula((0, 0), "=A1");
+        sheet.set_formula((1, 0), "=A0");
+        // The traced cell is its own precedent but is not listed again
+        assert_eq!(sheet.trace_precedents((0, 0)), vec![((1, 0), 1)]);
+    }
+
+    #[test]
+    fn test_named_ranges() {
+        let mut sheet = Sheet::new(10, 3);
+        for row in 0..3 {
+            sheet.set_formula((row, 0), &(row + 1).to_string());
+        }
+        sheet.set_formula((0, 1), "=SUM(Values)*2");
+        assert_eq!(
+            sheet.value((0, 1)),
+            Some(&CellValue::Error("NAME".to_string()))
+        );
+
+        sheet.define_name("values", "A0:A2").unwrap();
+        assert_eq!(number(&sheet, (0, 1)), 12.0);
+        assert!(sheet.names().contains_key("VALUES"));
+
+        // Formulas using a name depend on every cell of its range
+        sheet.set_formula((2, 0), "10");
+        assert_eq!(number(&sheet, (0, 1)), 26.0);
+
+        sheet.define_name("Values", "A0").unwrap();
+        assert_eq!(number(&sheet, (0, 1)), 2.0);
+
+        sheet.remove_name("VALUES");
+        assert_eq!(
+            sheet.value((0, 1)),
+            Some(&CellValue::Error("NAME".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_merges() {
+        let mut sheet = Sheet::new(10, 5);
+        sheet.merge(Range::new((0, 0), (0, 2))).unwrap();
+        sheet.merge(Range::new((2, 1), (3, 2))).unwrap();
+        assert_eq!(sheet.merged((0, 1)), Some(Range::new((0, 0), (0, 2))));
+        assert_eq!(sheet.anchor((3, 2)), (2, 1));
+        assert_eq!(sheet.anchor((4, 4)), (4, 4));
+
+        assert!(sheet.merge(Range::new((1, 1), (1, 1))).is_err());
+        assert!(sheet.merge(Range::new((0, 4), (10, 4))).is_err());
+        let err = sheet.merge(Range::new((0, 2), (1, 3))).unwrap_err();
+        assert!(err.contains("A0:C0"), "{}", err);
+
+        // A larger merge swallows the ones inside it
+        sheet.merge(Range::new((2, 0), (4, 3))).unwrap();
+        assert_eq!(sheet.merges().len(), 2);
+        assert_eq!(sheet.unmerge((4, 0)), Some(Range::new((2, 0), (4, 3))));
+        assert_eq!(sheet.unmerge((4, 0)), None);
+    }
+
+    #[test]
+    fn test_neighbor_skips_merges() {
+        let mut sheet = Sheet::new(10, 5);
+        sheet.merge(Range::new((0, 0), (0, 2))).unwrap();
+        sheet.merge(Range::new((2, 1), (3, 2))).unwrap();
+
+        assert_eq!(sheet.neighbor((0, 0), 0, 1), Some((0, 3)));
+        assert_eq!(sheet.neighbor((0, 3), 0, -1), Some((0, 0)));
+        assert_eq!(sheet.neighbor((1, 1), 1, 0), Some((2, 1)));
+        assert_eq!(sheet.neighbor((2, 1), 1, 0), Some((4, 1)));
+        assert_eq!(sheet.neighbor((4, 2), -1, 0), Some((2, 1)));
+        assert_eq!(sheet.neighbor((0, 0), -1, 0), None);
+        assert_eq!(sheet.neighbor((0, 4), 0, 1), None);
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let mut sheet = Sheet::new(10, 3);
+        assert!(sheet.define_name("B5", "A0").is_err());
+        assert!(sheet.define_name("sum", "A0").is_err());
+        assert!(sheet.define_name("TRUE", "A0").is_err());
+        assert!(sheet.define_name("1X", "A0").is_err());
+        assert!(sheet.define_name("total", "A0+1").is_err());
+        assert!(sheet.define_name("total", "A0:Z99").is_err());
+        assert!(sheet.names().is_empty());
+    }
+
+    #[test]
+    fn test_check_formula() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.define_name("rates", "B0:B9").unwrap();
+        assert_eq!(sheet.check_formula("=SUM(rates) + A1"), None);
+        assert_eq!(sheet.check_formula("plain text"), None);
+        assert_eq!(
+            sheet.check_formula("=SUM(A0"),
+            Some("syntax error".to_string())
+        );
+        assert_eq!(
+            sheet.check_formula("=A10"),
+            Some("reference outside the sheet".to_string())
+        );
+        assert_eq!(
+            sheet.check_formula("=SUMM(A0:A2)"),
+            Some("unknown function SUMM".to_string())
+        );
+        assert_eq!(
+            sheet.check_formula("=taxes*2"),
+            Some("unknown name TAXES".to_string())
+        );
+    }
+
+    fn error(sheet: &Sheet, cell: Cell) -> Option<String> {
+        match sheet.value(cell) {
+            Some(CellValue::Error(e)) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_arrays_spill() {
+        let mut sheet = Sheet::new(10, 4);
+        for row in 0..3 {
+            sheet.set_formula((row, 0), &(row + 1).to_string());
+        }
+        sheet.set_formula((0, 1), "=A0:A2*2");
+        sheet.set_formula((2, 2), "=B2+1");
+        sheet.set_formula((3, 2), "=SUM(B0:B2)");
+        assert_eq!(number(&sheet, (2, 1)), 6.0);
+        assert_eq!(sheet.spilled_from((2, 1)), Some((0, 1)));
+        assert_eq!(sheet.formulas.get(&(2, 1)), None);
+        assert_eq!(number(&sheet, (2, 2)), 7.0);
+        assert_eq!(number(&sheet, (3, 2)), 12.0);
+
+        // Readers of spilled cells follow the inputs of the array formula
+        sheet.set_formula((2, 0), "10");
+        assert_eq!(number(&sheet, (2, 2)), 21.0);
+        assert_eq!(number(&sheet, (3, 2)), 26.0);
+
+        // A single value does not spill
+        sheet.set_formula((0, 1), "=A0:A0*2");
+        assert_eq!(number(&sheet, (0, 1)), 2.0);
+        assert_eq!(sheet.value((1, 1)), None);
+        assert_eq!(sheet.spill((0, 1)), None);
+        assert_eq!(number(&sheet, (2, 2)), 1.0);
+    }
+
+    #[test]
+    fn test_spill_grows_and_shrinks() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "2");
+        sheet.set_formula((0, 1), "=SEQUENCE(A0)");
+        sheet.set_formula((3, 2), "=B3*10");
+        assert_eq!(number(&sheet, (3, 2)), 0.0);
+
+        sheet.set_formula((0, 0), "4");
+        assert_eq!(number(&sheet, (3, 1)), 4.0);
+        assert_eq!(number(&sheet, (3, 2)), 40.0);
+
+        sheet.set_formula((0, 0), "1");
+        assert_eq!(number(&sheet, (0, 1)), 1.0);
+        assert_eq!(sheet.value((1, 1)), None);
+        assert_eq!(sheet.value((3, 1)), None);
+        assert_eq!(number(&sheet, (3, 2)), 0.0);
+    }
+
+    #[test]
+    fn test_blocked_spill() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=SEQUENCE(3)");
+        sheet.set_formula((1, 1), "=A1");
+        assert_eq!(number(&sheet, (1, 1)), 2.0);
+
+        // Input in the way blocks the spill until it is cleared
+        sheet.set_formula((2, 0), "x");
+        assert_eq!(error(&sheet, (0, 0)), Some("SPILL".to_string()));
+        assert_eq!(sheet.value((1, 0)), None);
+        assert_eq!(sheet.value((2, 0)), Some(&CellValue::Text("x".to_string())));
+        assert_eq!(number(&sheet, (1, 1)), 0.0);
+        assert!(sheet.spill((0, 0)).unwrap().blocked);
+
+        sheet.set_formula((2, 0), "");
+        assert_eq!(number(&sheet, (2, 0)), 3.0);
+        assert_eq!(number(&sheet, (1, 1)), 2.0);
+
+        // Spills block each other, and the edge of the sheet blocks them
+        sheet.set_formula((0, 2), "=TRANSPOSE(SEQUENCE(2))");
+        sheet.set_formula((0, 3), "=SEQUENCE(2)");
+        assert_eq!(error(&sheet, (0, 2)), Some("SPILL".to_string()));
+        sheet.set_formula((0, 3), "");
+        assert_eq!(number(&sheet, (0, 3)), 2.0);
+        sheet.set_formula((5, 0), "=SEQUENCE(6)");
+        assert_eq!(error(&sheet, (5, 0)), Some("SPILL".to_string()));
+
+        // Replacing the formula by a constant frees its cells
+        sheet.set_formula((0, 0), "7");
+        assert_eq!(sheet.value((1, 0)), None);
+        assert_eq!(number(&sheet, (1, 1)), 0.0);
+    }
+
+    #[test]
+    fn test_spill_reading_itself_is_a_cycle() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "=SEQUENCE(2+A1*0)");
+        assert_eq!(error(&sheet, (0, 0)), Some("CYCLE".to_string()));
+        assert_eq!(sheet.value((1, 0)), None);
+    }
+
+    #[test]
+    fn test_nan_sizes_do_not_spill() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((5, 3), "nan");
+        sheet.set_formula((0, 0), "=SEQUENCE(D5)");
+        assert_eq!(error(&sheet, (0, 0)), Some("NUM".to_string()));
+        sheet.set_formula((0, 1), "=SEQUENCE(2, D5)");
+        assert_eq!(error(&sheet, (0, 1)), Some("NUM".to_string()));
+
+        // Arrays without values are refused rather than spilled
+        for array in [
+            vec![],
+            vec![vec![]],
+            vec![vec![CellValue::Number(1.0)], vec![]],
+        ] {
+            sheet.place((2, 0), Some(array));
+            assert_eq!(error(&sheet, (2, 0)), Some("CALC".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_results_carry_spills() {
+        let mut sheet = Sheet::new(10, 4);
+        sheet.set_formula((0, 0), "3");
+        sheet.set_formula((0, 1), "=SEQUENCE(A0)");
+
+        let mut copy = sheet.clone();
+        let order = copy.set_formula((0, 0), "2");
+        sheet.apply(copy.results(&order));
+        assert_eq!(sheet.values, copy.values);
+        assert_eq!(sheet.spilled_from((1, 1)), Some((0, 1)));
+        assert_eq!(sheet.spilled_from((2, 1)), None);
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        let traps = [
+            ("=0.1+0.2", 0.3),
+            ("=1-0.9", 0.1),
+            ("=1.1*1.1", 1.21),
+            ("=0.3/0.1", 3.0),
+            ("=SUM(A0:A9)", 1.0),
+            ("=AVERAGE(0.1, 0.2, 0.3)", 0.2),
+            ("=ROUND(0.285, 2)", 0.29),
+            ("=ROUND(1.005, 2)", 1.01),
+            ("=MOD(0.3, 0.1)", 0.0),
+            ("=FLOOR(0.3, 0.1)", 0.3),
+            ("=CEILING(0.7, 0.1)", 0.7),
+            ("=(0.1+0.2)=0.3", 1.0),
+            ("=SUM(SEQUENCE(3, 1, 0.1, 0.1))", 0.6),
+        ];
+        let mut sheet = Sheet::with_arithmetic(10, 3, Arithmetic::Decimal);
+        let mut float = Sheet::new(10, 3);
+        for row in 0..10 {
+            sheet.set_formula((row, 0), "0.1");
+            float.set_formula((row, 0), "0.1");
+        }
+        for (row, (formula, expected)) in traps.iter().enumerate() {
+            sheet.set_formula((row, 1), formula);
+            float.set_formula((row, 1), formula);
+            assert_eq!(number(&sheet, (row, 1)), *expected, "{}", formula);
+            assert_ne!(number(&float, (row, 1)), *expected, "{}", formula);
+        }
+
+        // Switching modes re-evaluates every formula
+        float.set_arithmetic(Arithmetic::Decimal);
+        assert_eq!(float.values, sheet.values);
+        sheet.set_arithmetic(Arithmetic::Float);
+        assert_eq!(number(&sheet, (0, 1)), 0.1 + 0.2);
+    }
+
+    #[test]
+    fn test_decimals_keep_more_digits_than_floats() {
+        let mut sheet = Sheet::with_arithmetic(10, 3, Arithmetic::Decimal);
+        sheet.set_formula((0, 0), "12345678901234567.5");
+        let cases = [
+            ("=10000000000000001+1", "10000000000000002"),
+            ("=A0+0.25", "12345678901234567.75"),
+            ("=SUM(A0, A0, 0.25)", "24691357802469135.25"),
+            ("=MAX(A0, 12345678901234567.4)", "12345678901234567.5"),
+            ("=A0>12345678901234567.4", "1"),
+            ("=-A0", "-12345678901234567.5"),
+            ("=ROUND(A0, 0)", "12345678901234568"),
+            ("=ROUND(A0, -100000000)", "0"),
+            ("=MOD(A0, 10)", "7.5"),
+            ("=AVERAGE(A0, 12345678901234568.5)", "12345678901234568"),
+            ("=1/3*3", &format!("0.{}", "9".repeat(34))),
+        ];
+        for (row, (formula, expected)) in cases.iter().enumerate() {
+            sheet.set_formula((row, 1), formula);
+            let value = sheet.value((row, 1)).map(ToString::to_string);
+            assert_eq!(value.as_deref(), Some(*expected), "{}", formula);
+        }
+
+        // Floats round every one of them
+        sheet.set_arithmetic(Arithmetic::Float);
+        assert_eq!(number(&sheet, (0, 1)), 1e16);
+        assert_eq!(
+            sheet.value((0, 0)),
+            Some(&CellValue::Number(12345678901234568.0))
+        );
+    }
+
+    #[test]
+    fn test_script_functions() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "4");
+        sheet.set_formula((0, 1), "=SQUARE(A0)+1");
+        assert_eq!(
+            sheet.value((0, 1)),
+            Some(&CellValue::Error("NAME".to_string()))
+        );
+        assert_eq!(
+            sheet.check_formula("=SQUARE(A0)"),
+            Some("unknown function SQUARE".to_string())
+        );
+
+        // Formulas already calling the function pick it up, and follow
+        // their inputs like any formula
+        sheet.set_script("fn square(x) { x * x }").unwrap();
+        assert_eq!(sheet.value((0, 1)), Some(&CellValue::Number(17.0)));
+        assert_eq!(sheet.check_formula("=SQUARE(A0)"), None);
+        sheet.set_formula((0, 0), "5");
+        assert_eq!(sheet.value((0, 1)), Some(&CellValue::Number(26.0)));
+        assert!(sheet.define_name("square", "A0:A1").is_err());
+
+        assert!(sheet.set_script("fn square(x) {").is_err());
+        assert_eq!(sheet.value((0, 1)), Some(&CellValue::Number(26.0)));
+        sheet.set_script("").unwrap();
+        assert_eq!(
+            sheet.value((0, 1)),
+            Some(&CellValue::Error("NAME".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_iterative_calculation() {
+        // Interest on the closing balance, which includes the interest
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "1000");
+        sheet.set_formula((1, 0), "=A2*0.05");
+        sheet.set_formula((2, 0), "=A0+A1");
+        sheet.set_formula((3, 0), "=A2*2");
+        assert_eq!(error(&sheet, (2, 0)), Some("CYCLE".to_string()));
+        assert_eq!(error(&sheet, (3, 0)), Some("CYCLE".to_string()));
+
+        sheet.set_iteration(Some(Iteration::default()));
+        let balance = 1000.0 / 0.95;
+        assert!((number(&sheet, (2, 0)) - balance).abs() < 0.001);
+        assert!((number(&sheet, (1, 0)) - balance * 0.05).abs() < 0.001);
+        assert!((number(&sheet, (3, 0)) - balance * 2.0).abs() < 0.01);
+
+        // Edits iterate from the values reached so far
+        sheet.set_formula((0, 0), "2000");
+        assert!((number(&sheet, (2, 0)) - 2.0 * balance).abs() < 0.001);
+
+        // A tighter threshold gets closer
+        sheet.set_iteration(Some(Iteration {
+            max_iterations: 100,
+            max_change: 1e-12,
+        }));
+        assert!((number(&sheet, (2, 0)) - 2.0 * balance).abs() < 1e-9);
+
+        sheet.set_iteration(None);
+        assert_eq!(error(&sheet, (2, 0)), Some("CYCLE".to_string()));
+    }
+
+    #[test]
+    fn test_iteration_that_does_not_converge() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_iteration(Some(Iteration {
+            max_iterations: 50,
+            max_change: 0.001,
+        }));
+        // Grows forever
+        sheet.set_formula((0, 0), "=A0+1");
+        assert_eq!(error(&sheet, (0, 0)), Some("CONVERGE".to_string()));
+        // Flips between 0 and 1
+        sheet.set_formula((1, 0), "=1-A1");
+        assert_eq!(error(&sheet, (1, 0)), Some("CONVERGE".to_string()));
+        // Halves towards zero
+        sheet.set_formula((2, 0), "=B2/2");
+        sheet.set_formula((2, 1), "=A2+1");
+        assert!((number(&sheet, (2, 0)) - 1.0).abs() < 0.01);
+        assert!((number(&sheet, (2, 1)) - 2.0).abs() < 0.01);
+        // Errors on a cycle are stable and kept
+        sheet.set_formula((3, 0), "=A3+\"x\"");
+        assert_eq!(error(&sheet, (3, 0)), Some("TEXT".to_string()));
+    }
+}
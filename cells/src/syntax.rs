@@ -0,0 +1,286 @@
+use crate::formula::{self, Cell, parse_cell_reference};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+
+// Kind of a formula token, for syntax highlighting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Reference,
+    Number,
+    Text,
+    Function,
+    Name,
+    Operator,
+    // Characters that cannot start a token, and unterminated text
+    Invalid,
+}
+
+// Splits what was typed into a cell into highlighted tokens, as byte ranges.
+// Unlike the parser this never fails, so it can follow every keystroke.
+// Plain values that do not start with '=' have no tokens.
+pub fn tokens(input: &str) -> Vec<(Range<usize>, TokenKind)> {
+    let Some(formula) = input.strip_prefix('=') else {
+        return Vec::new();
+    };
+    let mut tokens = vec![(0..1, TokenKind::Operator)];
+
+    let mut chars = formula.char_indices().map(|(i, c)| (i + 1, c)).peekable();
+    while let Some((start, ch)) = chars.next() {
+        let mut end = start + ch.len_utf8();
+        let kind = match ch {
+            ' ' | '\t' => continue,
+            '0'..='9' | '.' => {
+                while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    end = i + c.len_utf8();
+                }
+                TokenKind::Number
+            }
+            '"' => {
+                // Text runs to the next quote that is not part of a "" escape
+                let mut closed = false;
+                while let Some((i, c)) = chars.next() {
+                    end = i + c.len_utf8();
+                    if c == '"' {
+                        if chars.next_if(|(_, c)| *c == '"').is_some() {
+                            end += 1;
+                        } else {
+                            closed = true;
+                            break;
+                        }
+                    }
+                }
+                if closed {
+                    TokenKind::Text
+                } else {
+                    TokenKind::Invalid
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+                {
+                    end = i + c.len_utf8();
+                }
+                if input[end..].trim_start().starts_with('(') {
+                    TokenKind::Function
+                } else if parse_cell_reference(&input[start..end]).is_some() {
+                    TokenKind::Reference
+                } else {
+                    TokenKind::Name
+                }
+            }
+            '<' | '>' => {
+                if let Some((i, c)) = chars.next_if(|(_, c)| *c == '=' || (ch == '<' && *c == '>'))
+                {
+                    end = i + c.len_utf8();
+                }
+                TokenKind::Operator
+            }
+            '+' | '-' | '*' | '/' | ':' | ',' | '(' | ')' | '=' => TokenKind::Operator,
+            _ => TokenKind::Invalid,
+        };
+        tokens.push((start..end, kind));
+    }
+    tokens
+}
+
+// Cells referenced by a formula being typed, with ranges and defined names
+// expanded. Works on incomplete formulas too.
+pub fn references(input: &str, names: &BTreeMap<String, formula::Range>) -> HashSet<Cell> {
+    let tokens = tokens(input);
+    let text = |i: usize| &input[tokens[i].0.clone()];
+    let is = |i: usize, kind: TokenKind| tokens.get(i).is_some_and(|(_, k)| *k == kind);
+
+    let mut cells = HashSet::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].1 {
+            TokenKind::Reference => {
+                let start = parse_cell_reference(text(i)).expect("reference token");
+                if is(i + 1, TokenKind::Operator)
+                    && text(i + 1) == ":"
+                    && is(i + 2, TokenKind::Reference)
+                {
+                    let end = parse_cell_reference(text(i + 2)).expect("reference token");
+                    cells.extend(formula::Range::new(start, end).cells());
+                    i += 2;
+                } else {
+                    cells.insert(start);
+                }
+            }
+            TokenKind::Name => {
+                if let Some(range) = names.get(&text(i).to_ascii_uppercase()) {
+                    cells.extend(range.cells());
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    cells
+}
+
+// Whether an operand is expected at byte `cursor`: right after the '=' or
+// after an operator, opening parenthesis, comma or colon, so that a
+// reference can be inserted there
+pub fn at_operand(input: &str, cursor: usize) -> bool {
+    tokens(input)
+        .into_iter()
+        .take_while(|(range, _)| range.end <= cursor)
+        .last()
+        .is_some_and(|(range, kind)| kind == TokenKind::Operator && &input[range] != ")")
+}
+
+// The function or name being typed when the cursor is at byte `cursor`: its
+// byte range and its text
+pub fn word_at(input: &str, cursor: usize) -> Option<(Range<usize>, &str)> {
+    tokens(input)
+        .into_iter()
+        .find(|(range, kind)| {
+            range.end == cursor && matches!(kind, TokenKind::Function | TokenKind::Name)
+        })
+        .map(|(range, _)| (range.clone(), &input[range]))
+}
+
+// Functions and names starting with the typed prefix, in alphabetical order.
+// Functions come with their opening parenthesis.
+pub fn completions<'a>(
+    prefix: &str,
+    functions: impl IntoIterator<Item = &'a str>,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Vec<String> {
+    let prefix = prefix.to_ascii_uppercase();
+    let mut completions: Vec<String> = functions
+        .into_iter()
+        .filter(|function| function.starts_with(&prefix))
+        .map(|function| format!("{}(", function))
+        .chain(
+            names
+                .into_iter()
+                .filter(|name| name.starts_with(&prefix))
+                .map(str::to_string),
+        )
+        .collect();
+    completions.sort();
+    completions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenKind::*;
+
+    // Text of every token with its kind
+    fn kinds(input: &str) -> Vec<(&str, TokenKind)> {
+        tokens(input)
+            .into_iter()
+            .map(|(range, kind)| (&input[range], kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            kinds("=SUM(A0:B2, 1.5) * rate - \"a\"\"b\""),
+            vec![
+                ("=", Operator),
+                ("SUM", Function),
+                ("(", Operator),
+                ("A0", Reference),
+                (":", Operator),
+                ("B2", Reference),
+                (",", Operator),
+                ("1.5", Number),
+                (")", Operator),
+                ("*", Operator),
+                ("rate", Name),
+                ("-", Operator),
+                ("\"a\"\"b\"", Text),
+            ]
+        );
+        assert_eq!(kinds("=max (1)")[1], ("max", Function));
+        assert_eq!(
+            kinds("=A0<>1>=2<3="),
+            vec![
+                ("=", Operator),
+                ("A0", Reference),
+                ("<>", Operator),
+                ("1", Number),
+                (">=", Operator),
+                ("2", Number),
+                ("<", Operator),
+                ("3", Number),
+                ("=", Operator),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokens_never_fail() {
+        assert!(tokens("12.5").is_empty());
+        assert!(tokens("").is_empty());
+        assert_eq!(
+            kinds("=A0 & \"x"),
+            vec![
+                ("=", Operator),
+                ("A0", Reference),
+                ("&", Invalid),
+                ("\"x", Invalid),
+            ]
+        );
+        assert_eq!(
+            kinds("=é1"),
+            vec![("=", Operator), ("é", Invalid), ("1", Number)]
+        );
+    }
+
+    #[test]
+    fn test_references() {
+        let names = BTreeMap::from([("RATES".to_string(), formula::Range::new((0, 3), (1, 3)))]);
+        assert_eq!(
+            references("=A0+SUM(B1:C2, rates) + ", &names),
+            HashSet::from([(0, 0), (1, 1), (1, 2), (2, 1), (2, 2), (0, 3), (1, 3)])
+        );
+        assert_eq!(references("=B3:", &names), HashSet::from([(3, 1)]));
+        assert!(references("A0", &names).is_empty());
+    }
+
+    #[test]
+    fn test_at_operand() {
+        assert!(at_operand("=", 1));
+        assert!(at_operand("=A1+", 4));
+        assert!(at_operand("=A1 + ", 6));
+        assert!(at_operand("=SUM(", 5));
+        assert!(at_operand("=SUM(A0,", 8));
+        assert!(at_operand("=SUM(A0:", 8));
+        assert!(at_operand("=A1>=", 5));
+        assert!(!at_operand("=A1", 3));
+        assert!(!at_operand("=SUM(A0)", 8));
+        assert!(!at_operand("=2*", 2));
+        assert!(!at_operand("12+", 3));
+        assert!(!at_operand("", 0));
+        // Only what comes before the cursor matters
+        assert!(at_operand("=A1+B2", 4));
+    }
+
+    #[test]
+    fn test_word_at() {
+        assert_eq!(word_at("=SU", 3), Some((1..3, "SU")));
+        assert_eq!(word_at("=A0+av", 6), Some((4..6, "av")));
+        assert_eq!(word_at("=A0+av", 5), None);
+        assert_eq!(word_at("=A0", 3), None);
+        assert_eq!(word_at("SU", 2), None);
+    }
+
+    #[test]
+    fn test_completions() {
+        let functions = ["SUM", "SIN", "SQRT", "STDEV"];
+        let names = ["SALES", "TAX"];
+        assert_eq!(
+            completions("s", functions, names),
+            vec!["SALES", "SIN(", "SQRT(", "STDEV(", "SUM("]
+        );
+        assert_eq!(completions("TA", functions, names), vec!["TAX"]);
+        assert!(completions("X", functions, names).is_empty());
+    }
+}
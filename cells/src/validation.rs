@@ -0,0 +1,235 @@
+use crate::eval::{Evaluator, number_of};
+use crate::formula::{Cell, Range};
+use crate::{CellValue, Sheet};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Criterion {
+    // Numbers between the bounds, inclusive. A missing bound is open.
+    Number {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<f64>,
+    },
+    // Whole numbers between the bounds, inclusive
+    Integer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<f64>,
+    },
+    // One of the values, ignoring case. Cells with a list get a dropdown.
+    List {
+        values: Vec<String>,
+    },
+    // Text matching the whole regular expression
+    Regex {
+        pattern: String,
+    },
+    // A formula written for the top-left cell of the range, shifted for the
+    // others like conditional format formulas. It sees the new value in the
+    // cell and holds when it evaluates to a nonzero number.
+    Formula {
+        formula: String,
+    },
+}
+
+// What happens to input breaking a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    // The input is refused and the cell stays in edit
+    #[default]
+    Reject,
+    // The input may be kept after a warning
+    Warn,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Validation {
+    pub range: Range,
+    pub criterion: Criterion,
+    #[serde(default)]
+    pub severity: Severity,
+    // Shown instead of the default explanation
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+impl Validation {
+    // Explanation shown when input breaks the rule
+    pub fn explanation(&self) -> String {
+        if !self.message.is_empty() {
+            return self.message.clone();
+        }
+        let between = |what: &str, min: &Option<f64>, max: &Option<f64>| match (min, max) {
+            (Some(min), Some(max)) => format!("enter {} between {} and {}", what, min, max),
+            (Some(min), None) => format!("enter {} of at least {}", what, min),
+            (None, Some(max)) => format!("enter {} of at most {}", what, max),
+            (None, None) => format!("enter {}", what),
+        };
+        match &self.criterion {
+            Criterion::Number { min, max } => between("a number", min, max),
+            Criterion::Integer { min, max } => between("a whole number", min, max),
+            Criterion::List { values } => format!("enter one of {}", values.join(", ")),
+            Criterion::Regex { pattern } => format!("enter text matching {}", pattern),
+            Criterion::Formula { formula } => format!("input must satisfy {}", formula),
+        }
+    }
+
+    // Whether `value`, about to be entered into `cell`, meets the rule
+    fn allows(&self, sheet: &Sheet, cell: Cell, value: &CellValue) -> bool {
+        let within = |n: f64, min: &Option<f64>, max: &Option<f64>| {
+            min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max)
+        };
+        let number = value.as_number();
+        match (&self.criterion, value) {
+            (Criterion::Number { min, max }, _) => number.is_some_and(|n| within(n, min, max)),
+            (Criterion::Integer { min, max }, _) => {
+                number.is_some_and(|n| n.fract() == 0.0 && within(n, min, max))
+            }
+            (Criterion::List { values }, value) => {
+                let value = value.to_string();
+                values.iter().any(|v| v.trim().eq_ignore_ascii_case(&value))
+            }
+            (Criterion::Regex { pattern }, value) => {
+                regex::Regex::new(&format!("^(?:{})$", pattern))
+                    .is_ok_and(|regex| regex.is_match(&value.to_string()))
+            }
+            (Criterion::Formula { formula }, value) => {
+                let Some(Ok(expr)) = formula.trim().strip_prefix('=').map(|f| sheet.compile(f))
+                else {
+                    return false;
+                };
+                let mut values = sheet.values.clone();
+                values.insert(cell, value.clone());
+                let (rows, cols) = (cell.0 - self.range.start.0, cell.1 - self.range.start.1);
+                let expr = expr.shifted(rows, cols);
+                let evaluator = Evaluator::with_arithmetic(&values, sheet.arithmetic())
+                    .with_script(sheet.script())
+                    .with_directory(sheet.directory());
+                evaluator
+                    .scalar(&expr)
+                    .is_ok_and(|value| number_of(&value).is_some_and(|n| n.to_f64() != 0.0))
+            }
+        }
+    }
+}
+
+// The first rule covering `cell` that `input` would break, if any. Clearing
+// a cell is always allowed.
+pub fn check<'a>(sheet: &'a Sheet, cell: Cell, input: &str) -> Option<&'a Validation> {
+    if input.trim().is_empty() {
+        return None;
+    }
+    let value = sheet.evaluate_formula(input);
+    sheet
+        .validations
+        .iter()
+        .filter(|validation| validation.range.contains(cell))
+        .find(|validation| !validation.allows(sheet, cell, &value))
+}
+
+// Values offered in a dropdown for the cell, from the first list rule
+// covering it
+pub fn choices(sheet: &Sheet, cell: Cell) -> Option<&[String]> {
+    sheet
+        .validations
+        .iter()
+        .filter(|validation| validation.range.contains(cell))
+        .find_map(|validation| match &validation.criterion {
+            Criterion::List { values } => Some(values.as_slice()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::parse_range;
+
+    fn sheet(rules: Vec<(&str, Criterion)>) -> Sheet {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 2), "10");
+        sheet.validations = rules
+            .into_iter()
+            .map(|(range, criterion)| Validation {
+                range: parse_range(range).unwrap(),
+                criterion,
+                severity: Severity::Reject,
+                message: String::new(),
+            })
+            .collect();
+        sheet
+    }
+
+    #[test]
+    fn test_numbers() {
+        let sheet = sheet(vec![
+            (
+                "A0:A9",
+                Criterion::Number {
+                    min: Some(0.0),
+                    max: Some(1.0),
+                },
+            ),
+            (
+                "B0:B9",
+                Criterion::Integer {
+                    min: Some(1.0),
+                    max: None,
+                },
+            ),
+        ]);
+        assert!(check(&sheet, (0, 0), "0.5").is_none());
+        assert!(check(&sheet, (0, 0), "=C0/20").is_none());
+        assert!(check(&sheet, (0, 0), "1.5").is_some());
+        assert!(check(&sheet, (0, 0), "abc").is_some());
+        assert!(check(&sheet, (0, 0), "").is_none());
+        assert!(check(&sheet, (0, 1), "3").is_none());
+        assert!(check(&sheet, (0, 1), "3.5").is_some());
+        assert!(check(&sheet, (0, 1), "0").is_some());
+        assert!(check(&sheet, (0, 2), "anything").is_none());
+        assert_eq!(
+            check(&sheet, (0, 1), "0").unwrap().explanation(),
+            "enter a whole number of at least 1"
+        );
+    }
+
+    #[test]
+    fn test_list_and_regex() {
+        let values = vec!["Open".to_string(), "Closed".to_string()];
+        let sheet = sheet(vec![
+            ("A0:A9", Criterion::List { values }),
+            (
+                "B0:B9",
+                Criterion::Regex {
+                    pattern: "[A-Z]{3}-\\d+".to_string(),
+                },
+            ),
+        ]);
+        assert!(check(&sheet, (1, 0), "closed").is_none());
+        assert!(check(&sheet, (1, 0), "Pending").is_some());
+        assert!(check(&sheet, (1, 1), "ABC-12").is_none());
+        assert!(check(&sheet, (1, 1), "xABC-12").is_some());
+        assert_eq!(choices(&sheet, (1, 0)).unwrap().len(), 2);
+        assert_eq!(choices(&sheet, (1, 1)), None);
+    }
+
+    #[test]
+    fn test_formula_sees_new_value() {
+        // Each value must be below the one to its right
+        let sheet = sheet(vec![(
+            "B0:B9",
+            Criterion::Formula {
+                formula: "=B0<C0".to_string(),
+            },
+        )]);
+        assert!(check(&sheet, (0, 1), "9").is_none());
+        assert!(check(&sheet, (0, 1), "11").is_some());
+        // C1 is empty
+        assert!(check(&sheet, (1, 1), "1").is_some());
+    }
+}
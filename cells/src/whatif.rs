@@ -0,0 +1,383 @@
+// What-if analysis: goal seek and data tables. Both work on a copy of the
+// sheet, setting input cells and letting the dependency graph recalculate
+// what lies downstream of them, so the sheet itself is never changed.
+
+use crate::eval::Array;
+use crate::formula::{Cell, cell_name};
+use crate::{CellValue, Sheet};
+
+// Most guesses each stage of goal seek tries before giving up
+const MAX_ITERATIONS: usize = 100;
+
+// Goal seek stops once the target is this close to the goal, relative to
+// the goal when it is above 1
+const TOLERANCE: f64 = 1e-9;
+
+// Input found by goal seek and the target's value with it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Solution {
+    pub input: f64,
+    pub result: f64,
+}
+
+// Finds a number for the input cell that brings the target cell to the
+// goal, starting from the input's current value. The input must hold a
+// number or nothing, and the target a formula depending on it.
+pub fn goal_seek(sheet: &Sheet, target: Cell, goal: f64, input: Cell) -> Result<Solution, String> {
+    check_cell(sheet, target)?;
+    check_cell(sheet, input)?;
+    let start = match sheet.value(input) {
+        _ if sheet
+            .formulas
+            .get(&input)
+            .is_some_and(|formula| formula.starts_with('=')) =>
+        {
+            return Err(format!(
+                "{} must hold a number, not a formula",
+                cell_name(input)
+            ));
+        }
+        Some(CellValue::Number(n)) => *n,
+        Some(CellValue::Decimal(d)) => d.to_f64(),
+        None => 0.0,
+        Some(_) => return Err(format!("{} must hold a number", cell_name(input))),
+    };
+    if target == input || !sheet.dirty_cells(vec![input]).contains(&target) {
+        return Err(format!(
+            "{} does not depend on {}",
+            cell_name(target),
+            cell_name(input)
+        ));
+    }
+
+    let mut copy = sheet.clone();
+    // Distance of the target from the goal with a given input, None when
+    // the target is not a number
+    let mut miss = |x: f64| -> Option<f64> {
+        copy.set_formula(input, &x.to_string());
+        copy.value(target)
+            .and_then(CellValue::as_number)
+            .map(|n| n - goal)
+    };
+    let close = |miss: f64| miss.abs() <= TOLERANCE * goal.abs().max(1.0);
+
+    let Some(start_miss) = miss(start) else {
+        return Err(format!("{} is not a number", cell_name(target)));
+    };
+    let Some((x, x_miss)) = solve(&mut miss, &close, start, start_miss) else {
+        return Err(format!(
+            "no value of {} brings {} to {}",
+            cell_name(input),
+            cell_name(target),
+            goal
+        ));
+    };
+
+    // The shortest number doing as well, e.g. 7 rather than 7.000000000000027
+    for digits in 1..16 {
+        let short = format!("{:.*e}", digits - 1, x).parse().unwrap_or(x);
+        if let Some(short_miss) = miss(short)
+            && close(short_miss)
+        {
+            return Ok(Solution {
+                input: short,
+                result: goal + short_miss,
+            });
+        }
+    }
+    Ok(Solution {
+        input: x,
+        result: goal + x_miss,
+    })
+}
+
+// Input and miss where `close` holds, by secant steps from the start and
+// then, should they stall, by widening the search on both sides of the
+// start until the miss changes sign and bisecting there
+fn solve(
+    miss: &mut impl FnMut(f64) -> Option<f64>,
+    close: &impl Fn(f64) -> bool,
+    start: f64,
+    start_miss: f64,
+) -> Option<(f64, f64)> {
+    if close(start_miss) {
+        return Some((start, start_miss));
+    }
+    let step = if start == 0.0 {
+        1.0
+    } else {
+        start.abs() / 10.0
+    };
+
+    // Secant method
+    let (mut x0, mut m0) = (start, start_miss);
+    let mut x1 = start + step;
+    for _ in 0..MAX_ITERATIONS {
+        let Some(m1) = miss(x1) else { break };
+        if close(m1) {
+            return Some((x1, m1));
+        }
+        if m1 == m0 {
+            break;
+        }
+        let x2 = x1 - m1 * (x1 - x0) / (m1 - m0);
+        if !x2.is_finite() {
+            break;
+        }
+        (x0, m0, x1) = (x1, m1, x2);
+    }
+
+    // Bracketing
+    let mut bracket = None;
+    let (mut low, mut low_miss) = (start, start_miss);
+    let (mut high, mut high_miss) = (start, start_miss);
+    let mut width = step;
+    for _ in 0..MAX_ITERATIONS {
+        if let Some(m) = miss(high + width) {
+            if m.signum() != high_miss.signum() {
+                bracket = Some((high, high_miss, high + width));
+                break;
+            }
+            (high, high_miss) = (high + width, m);
+        }
+        if let Some(m) = miss(low - width) {
+            if m.signum() != low_miss.signum() {
+                bracket = Some((low - width, m, low));
+                break;
+            }
+            (low, low_miss) = (low - width, m);
+        }
+        width *= 2.0;
+        if !width.is_finite() {
+            break;
+        }
+    }
+    let (mut low, mut low_miss, mut high) = bracket?;
+
+    // Bisection
+    for _ in 0..MAX_ITERATIONS {
+        let middle = low + (high - low) / 2.0;
+        let m = miss(middle)?;
+        if close(m) {
+            return Some((middle, m));
+        }
+        if m.signum() == low_miss.signum() {
+            (low, low_miss) = (middle, m);
+        } else {
+            high = middle;
+        }
+    }
+    None
+}
+
+// One-variable data table: the values of the output cells, one row per
+// input value and one column per output, as if the input cell held that
+// value. Values are entered like cell input, e.g. "5" or "=A0*2".
+pub fn data_table(
+    sheet: &Sheet,
+    input: Cell,
+    values: &[impl AsRef<str>],
+    outputs: &[Cell],
+) -> Result<Array, String> {
+    check_cell(sheet, input)?;
+    for output in outputs {
+        check_cell(sheet, *output)?;
+    }
+    if values.is_empty() || outputs.is_empty() {
+        return Err("a data table needs input values and output cells".to_string());
+    }
+    let mut copy = sheet.clone();
+    Ok(values
+        .iter()
+        .map(|value| {
+            copy.set_formula(input, value.as_ref());
+            outputs.iter().map(|output| read(&copy, *output)).collect()
+        })
+        .collect())
+}
+
+// Two-variable data table: the value of the output cell for every pair of
+// input values, one row per value of the `down` input and one column per
+// value of the `across` input
+pub fn data_table_2d(
+    sheet: &Sheet,
+    output: Cell,
+    (down_input, down_values): (Cell, &[impl AsRef<str>]),
+    (across_input, across_values): (Cell, &[impl AsRef<str>]),
+) -> Result<Array, String> {
+    check_cell(sheet, output)?;
+    check_cell(sheet, down_input)?;
+    check_cell(sheet, across_input)?;
+    if down_input == across_input {
+        return Err("the two input cells must differ".to_string());
+    }
+    if down_values.is_empty() || across_values.is_empty() {
+        return Err("a data table needs input values".to_string());
+    }
+    let mut copy = sheet.clone();
+    Ok(down_values
+        .iter()
+        .map(|down| {
+            copy.set_formula(down_input, down.as_ref());
+            across_values
+                .iter()
+                .map(|across| {
+                    copy.set_formula(across_input, across.as_ref());
+                    read(&copy, output)
+                })
+                .collect()
+        })
+        .collect())
+}
+
+fn check_cell(sheet: &Sheet, cell: Cell) -> Result<(), String> {
+    if cell.0 < sheet.rows() && cell.1 < sheet.cols() {
+        Ok(())
+    } else {
+        Err(format!("{} is outside the sheet", cell_name(cell)))
+    }
+}
+
+// Value of a cell as formulas read it, empty cells as zero
+fn read(sheet: &Sheet, cell: Cell) -> CellValue {
+    sheet.value(cell).cloned().unwrap_or(CellValue::Number(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A loan: principal in A0, yearly rate in A1, years in A2 and the
+    // monthly payment in A3
+    fn loan() -> Sheet {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "10000");
+        sheet.set_formula((1, 0), "0.06");
+        sheet.set_formula((2, 0), "5");
+        sheet.set_formula((3, 0), "=A0*(A1/12)/(1-POWER(1+A1/12, -A2*12))");
+        sheet
+    }
+
+    fn number(value: &CellValue) -> f64 {
+        value
+            .as_number()
+            .unwrap_or_else(|| panic!("{:?} is not a number", value))
+    }
+
+    #[test]
+    fn test_goal_seek_linear() {
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 1), "=A0*3+4");
+        let solution = goal_seek(&sheet, (0, 1), 25.0, (0, 0)).unwrap();
+        assert!((solution.input - 7.0).abs() < 1e-9);
+        assert!((solution.result - 25.0).abs() < 1e-9);
+        // The sheet itself is untouched
+        assert_eq!(sheet.value((0, 0)), None);
+        assert_eq!(sheet.value((0, 1)), Some(&CellValue::Number(4.0)));
+    }
+
+    #[test]
+    fn test_goal_seek_through_dependency_chain() {
+        // The principal affordable with a payment of 150 a month
+        let sheet = loan();
+        let solution = goal_seek(&sheet, (3, 0), 150.0, (0, 0)).unwrap();
+        assert!((solution.result - 150.0).abs() < 1e-6);
+        assert!(
+            (solution.input - 7758.83).abs() < 0.01,
+            "{}",
+            solution.input
+        );
+
+        // The rate at which 10000 costs 200 a month
+        let solution = goal_seek(&sheet, (3, 0), 200.0, (1, 0)).unwrap();
+        assert!((solution.result - 200.0).abs() < 1e-6);
+        assert!(
+            (solution.input - 0.0742).abs() < 0.0001,
+            "{}",
+            solution.input
+        );
+    }
+
+    #[test]
+    fn test_goal_seek_needs_bisection() {
+        // Flat near the start, so secant steps stall
+        let mut sheet = Sheet::new(10, 3);
+        sheet.set_formula((0, 0), "0");
+        sheet.set_formula((0, 1), "=MAX(A0-5, 0)");
+        let solution = goal_seek(&sheet, (0, 1), 10.0, (0, 0)).unwrap();
+        assert!((solution.input - 15.0).abs() < 1e-6, "{}", solution.input);
+    }
+
+    #[test]
+    fn test_goal_seek_errors() {
+        let mut sheet = loan();
+        sheet.set_formula((5, 0), "=A1*2");
+        sheet.set_formula((6, 0), "=SQRT(A8)");
+        sheet.set_formula((7, 0), "text");
+        sheet.set_formula((8, 0), "4");
+        let error = |target, goal, input| goal_seek(&sheet, target, goal, input).unwrap_err();
+
+        assert_eq!(
+            error((3, 0), 1.0, (5, 0)),
+            "A5 must hold a number, not a formula"
+        );
+        assert_eq!(error((3, 0), 1.0, (7, 0)), "A7 must hold a number");
+        assert_eq!(error((5, 0), 1.0, (0, 0)), "A5 does not depend on A0");
+        assert_eq!(
+            error((3, 0), 1.0, (3, 0)),
+            "A3 must hold a number, not a formula"
+        );
+        assert_eq!(error((20, 0), 1.0, (0, 0)), "A20 is outside the sheet");
+        assert_eq!(error((6, 1), 1.0, (0, 0)), "B6 does not depend on A0");
+        // A square root never gets below zero
+        assert_eq!(
+            error((6, 0), -1.0, (8, 0)),
+            "no value of A8 brings A6 to -1"
+        );
+    }
+
+    #[test]
+    fn test_one_variable_data_table() {
+        let sheet = loan();
+        let table =
+            data_table(&sheet, (1, 0), &["0.04", "0.06", "0.08"], &[(3, 0), (1, 0)]).unwrap();
+        assert_eq!(table.len(), 3);
+        assert!((number(&table[0][0]) - 184.17).abs() < 0.01);
+        assert!((number(&table[1][0]) - 193.33).abs() < 0.01);
+        assert!((number(&table[2][0]) - 202.76).abs() < 0.01);
+        assert_eq!(table[2][1], CellValue::Number(0.08));
+
+        // The sheet keeps its own rate
+        assert_eq!(sheet.value((1, 0)), Some(&CellValue::Number(0.06)));
+        assert!((number(sheet.value((3, 0)).unwrap()) - 193.33).abs() < 0.01);
+
+        let table = data_table(&sheet, (1, 0), &["x"], &[(3, 0)]).unwrap();
+        assert_eq!(table, [[CellValue::Error("TEXT".to_string())]]);
+        assert!(data_table(&sheet, (1, 0), &[] as &[&str], &[(3, 0)]).is_err());
+    }
+
+    #[test]
+    fn test_two_variable_data_table() {
+        let sheet = loan();
+        let table = data_table_2d(
+            &sheet,
+            (3, 0),
+            ((0, 0), &["10000", "20000"]),
+            ((2, 0), &["5", "10", "=A0/1000"]),
+        )
+        .unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[0].len(), 3);
+        assert!((number(&table[0][0]) - 193.33).abs() < 0.01);
+        assert!((number(&table[0][1]) - 111.02).abs() < 0.01);
+        assert!((number(&table[1][0]) - 386.66).abs() < 0.01);
+        // 20000 over 20 years
+        assert!((number(&table[1][2]) - 143.29).abs() < 0.01);
+        assert_eq!(sheet.value((0, 0)), Some(&CellValue::Number(10000.0)));
+
+        assert_eq!(
+            data_table_2d(&sheet, (3, 0), ((0, 0), &["1"]), ((0, 0), &["2"])),
+            Err("the two input cells must differ".to_string())
+        );
+    }
+}
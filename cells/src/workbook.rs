@@ -0,0 +1,242 @@
+use crate::Sheet;
+use crate::formula::{Cell, Range, col_to_letter, parse_cell_reference};
+use crate::syntax::{self, TokenKind};
+use crate::{file, ods, xlsx};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// Named sheets exchanged with other spreadsheet applications. Sheet files
+// of our own hold a single sheet.
+#[derive(Debug, Clone, Default)]
+pub struct Workbook {
+    pub sheets: Vec<(String, Sheet)>,
+}
+
+// Formats told apart by file extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Json,
+    Xlsx,
+    Ods,
+}
+
+fn kind(path: &Path) -> Result<Kind, String> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("json") => Ok(Kind::Json),
+        Some("xlsx") => Ok(Kind::Xlsx),
+        Some("ods") => Ok(Kind::Ods),
+        _ => Err(format!(
+            "{}: unknown file type, expected .json, .xlsx or .ods",
+            path.display()
+        )),
+    }
+}
+
+impl Workbook {
+    // Reads a workbook, along with warnings about what could not be
+    // imported
+    pub fn load(path: impl AsRef<Path>) -> Result<(Workbook, Vec<String>), String> {
+        let path = path.as_ref();
+        let kind = kind(path)?;
+        if kind == Kind::Json {
+            let sheet = file::load(path)?;
+            return Ok((
+                Workbook {
+                    sheets: vec![("Sheet1".to_string(), sheet)],
+                },
+                Vec::new(),
+            ));
+        }
+
+        let data =
+            std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let result = match kind {
+            Kind::Xlsx => xlsx::read(&data),
+            _ => ods::read(&data),
+        };
+        result.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let data = match kind(path)? {
+            Kind::Json => match self.sheets.as_slice() {
+                [(_, sheet)] => return file::save(sheet, path),
+                _ => {
+                    return Err(format!(
+                        "{}: sheet files hold a single sheet, pick one",
+                        path.display()
+                    ));
+                }
+            },
+            Kind::Xlsx => xlsx::write(self),
+            Kind::Ods => ods::write(self),
+        };
+        std::fs::write(path, data).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    pub fn sheet(&self, name: &str) -> Option<&Sheet> {
+        self.sheets
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, sheet)| sheet)
+    }
+}
+
+// Merges cells of an imported sheet, warning about merges that cannot be
+// kept, such as those reaching past column Z
+pub(crate) fn merge_imported(
+    name: &str,
+    sheet: &mut Sheet,
+    merges: &[Range],
+    warnings: &mut Vec<String>,
+) {
+    for merge in merges {
+        let merged = if merge.end.1 >= sheet.cols() {
+            Err("it reaches past column Z".to_string())
+        } else if merge.end.0 >= sheet.rows() {
+            Err(format!("it reaches past row {}", sheet.rows()))
+        } else {
+            sheet.merge(*merge)
+        };
+        if let Err(err) = merged {
+            warnings.push(format!(
+                "{}: merged cells {}:{} were left out: {}",
+                name,
+                a1_name(merge.start),
+                a1_name(merge.end),
+                err
+            ));
+        }
+    }
+}
+
+// Leaves out the values other applications store for the cells an array
+// formula spills into, which would otherwise block the spill. `arrays` are
+// the ranges of array formulas, starting at the formula cell.
+pub(crate) fn drop_spilled(inputs: &mut BTreeMap<Cell, String>, arrays: &[Range]) {
+    inputs.retain(|cell, _| {
+        !arrays
+            .iter()
+            .any(|array| array.start != *cell && array.contains(*cell))
+    });
+}
+
+// Replaces tokens of a formula starting with '=', keeping everything between
+// them. `replace` gets the tokens and the index of the current one, and
+// returns the replacement text and how many tokens it covers, or None to
+// keep the token as it is.
+pub(crate) fn rewrite(
+    formula: &str,
+    mut replace: impl FnMut(&[(std::ops::Range<usize>, TokenKind)], usize) -> Option<(String, usize)>,
+) -> String {
+    let tokens = syntax::tokens(formula);
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        match replace(&tokens, i) {
+            Some((text, count)) => {
+                out.push_str(&formula[copied..tokens[i].0.start]);
+                out.push_str(&text);
+                copied = tokens[i + count - 1].0.end;
+                i += count;
+            }
+            None => i += 1,
+        }
+    }
+    out.push_str(&formula[copied..]);
+    out
+}
+
+// Moves the references of a formula by whole rows and columns. References
+// that would leave the grid are kept as they are.
+pub(crate) fn shift_references(formula: &str, rows: isize, cols: isize) -> String {
+    rewrite(formula, |tokens, i| {
+        let (range, kind) = &tokens[i];
+        if *kind != TokenKind::Reference {
+            return None;
+        }
+        let (row, col) = parse_cell_reference(&formula[range.clone()])?;
+        let row = row.checked_add_signed(rows)?;
+        let col = col.checked_add_signed(cols).filter(|col| *col < 26)?;
+        Some((format!("{}{}", col_to_letter(col), row), 1))
+    })
+}
+
+// Name of a cell in other applications, with rows counted from 1: "B3" for
+// (2, 1)
+pub(crate) fn a1_name(cell: Cell) -> String {
+    format!("{}{}", a1_column(cell.1), cell.0 + 1)
+}
+
+pub(crate) fn a1_column(col: usize) -> String {
+    let mut name = String::new();
+    let mut n = col + 1;
+    while n > 0 {
+        name.insert(0, (b'A' + ((n - 1) % 26) as u8) as char);
+        n = (n - 1) / 26;
+    }
+    name
+}
+
+// Parses a cell name with rows counted from 1, such as "AB12" or "$B$3".
+// Columns past Z are allowed, for the caller to report.
+pub(crate) fn parse_a1(name: &str) -> Option<Cell> {
+    let name = name.replace('$', "");
+    let digits = name.find(|c: char| c.is_ascii_digit())?;
+    let (letters, row) = name.split_at(digits);
+    if !(1..=3).contains(&letters.len()) || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let col = letters.chars().fold(0, |col, c| {
+        col * 26 + (c.to_ascii_uppercase() as u8 - b'A') as usize + 1
+    });
+    let row = row.parse::<usize>().ok()?.checked_sub(1)?;
+    Some((row, col - 1))
+}
+
+// Removes the '$' of absolute references, which the cells grid does not
+// need, outside of text literals
+pub(crate) fn strip_absolute(formula: &str) -> String {
+    let mut in_text = false;
+    formula
+        .chars()
+        .filter(|c| {
+            if *c == '"' {
+                in_text = !in_text;
+            }
+            in_text || *c != '$'
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_references() {
+        assert_eq!(
+            shift_references("=SUM(A1:B3) + C2*2 + \"A1\"", -1, 0),
+            "=SUM(A0:B2) + C1*2 + \"A1\""
+        );
+        assert_eq!(shift_references("=A0+Z0", 1, 1), "=B1+Z0");
+    }
+
+    #[test]
+    fn test_a1_names() {
+        assert_eq!(a1_name((2, 1)), "B3");
+        assert_eq!(a1_column(27), "AB");
+        assert_eq!(parse_a1("$B$3"), Some((2, 1)));
+        assert_eq!(parse_a1("AB12"), Some((11, 27)));
+        assert_eq!(parse_a1("B0"), None);
+        assert_eq!(parse_a1("12"), None);
+        assert_eq!(parse_a1("Sheet1"), None);
+        assert_eq!(strip_absolute("=$A$1&\"$\""), "=A1&\"$\"");
+    }
+}
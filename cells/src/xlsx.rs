@@ -0,0 +1,866 @@
+// Office Open XML workbooks (.xlsx), as written by Excel. Each worksheet
+// becomes a sheet: formulas are kept as typed, with rows renumbered from 0,
+// and cells without a formula keep their value. Functions the engine does
+// not know are kept too, and evaluate to #NAME.
+
+use crate::formula::{Cell, Range, cell_name};
+use crate::sheet::MAX_ROWS;
+use crate::syntax::TokenKind;
+use crate::workbook::{
+    Workbook, a1_column, a1_name, drop_spilled, merge_imported, parse_a1, rewrite,
+    shift_references, strip_absolute,
+};
+use crate::xml::{self, Element, escaped};
+use crate::{CellValue, Sheet, zip};
+use std::collections::{BTreeMap, HashMap};
+
+// Functions Excel stores with the "_xlfn." prefix because they are newer
+// than the file format
+const NEWER_FUNCTIONS: &[&str] = &[
+    "XLOOKUP", "STDEV.S", "STDEV.P", "VAR.S", "VAR.P", "SEQUENCE", "UNIQUE",
+];
+
+// Functions Excel stores with the "_xlfn._xlws." prefix instead
+const WORKSHEET_FUNCTIONS: &[&str] = &["FILTER", "SORT"];
+
+// Format codes Excel refers to by number instead of storing them
+const BUILTIN_FORMATS: &[(u32, &str)] = &[
+    (1, "0"),
+    (2, "0.00"),
+    (3, "#,##0"),
+    (4, "#,##0.00"),
+    (9, "0%"),
+    (10, "0.00%"),
+    (11, "0.00E+00"),
+    (14, "mm-dd-yy"),
+    (15, "d-mmm-yy"),
+    (16, "d-mmm"),
+    (17, "mmm-yy"),
+    (18, "h:mm AM/PM"),
+    (19, "h:mm:ss AM/PM"),
+    (20, "h:mm"),
+    (21, "h:mm:ss"),
+    (22, "m/d/yy h:mm"),
+    (49, "@"),
+];
+
+// Sheets are at least as large as the GUI grid
+const MIN_ROWS: usize = 100;
+const COLS: usize = 26;
+
+pub fn read(data: &[u8]) -> Result<(Workbook, Vec<String>), String> {
+    let files: HashMap<String, Vec<u8>> = zip::read(data)?.into_iter().collect();
+    let part = |name: &str| -> Result<Option<Element>, String> {
+        match files.get(name) {
+            Some(bytes) => xml::parse(&String::from_utf8_lossy(bytes))
+                .map(Some)
+                .map_err(|e| format!("{}: {}", name, e)),
+            None => Ok(None),
+        }
+    };
+
+    let workbook = part("xl/workbook.xml")?.ok_or("not an xlsx workbook")?;
+    let relationships = part("xl/_rels/workbook.xml.rels")?.unwrap_or_default();
+    let shared_strings: Vec<String> = part("xl/sharedStrings.xml")?
+        .map(|sst| sst.children("si").map(shared_string).collect())
+        .unwrap_or_default();
+    let formats = part("xl/styles.xml")?
+        .map(|styles| cell_formats(&styles))
+        .unwrap_or_default();
+
+    let mut warnings = Vec::new();
+    let mut sheets = Vec::new();
+    let sheet_list = workbook.child("sheets").ok_or("workbook without sheets")?;
+    for entry in sheet_list.children("sheet") {
+        let name = entry.attribute("name").unwrap_or("Sheet").to_string();
+        let target = entry.attribute("r:id").and_then(|id| {
+            relationships
+                .children("Relationship")
+                .find(|r| r.attribute("Id") == Some(id))
+                .and_then(|r| r.attribute("Target"))
+        });
+        let path = match target {
+            Some(target) if target.starts_with('/') => target[1..].to_string(),
+            Some(target) => format!("xl/{}", target),
+            None => return Err(format!("missing worksheet for sheet {}", name)),
+        };
+        let worksheet = part(&path)?.ok_or_else(|| format!("missing {}", path))?;
+        let sheet = read_sheet(&name, &worksheet, &shared_strings, &formats, &mut warnings);
+        sheets.push((name, sheet));
+    }
+
+    if let Some(names) = workbook.child("definedNames") {
+        for defined in names.children("definedName") {
+            define_name(&mut sheets, defined, &mut warnings);
+        }
+    }
+    Ok((Workbook { sheets }, warnings))
+}
+
+// Text of a shared string, leaving out phonetic hints
+fn shared_string(si: &Element) -> String {
+    si.elements()
+        .filter_map(|e| match e.name.as_str() {
+            "t" => Some(e.text()),
+            "r" => e.child("t").map(Element::text),
+            _ => None,
+        })
+        .collect()
+}
+
+// Format code of every cell style that has one, by style index
+fn cell_formats(styles: &Element) -> Vec<Option<String>> {
+    let custom: HashMap<u32, String> = styles
+        .child("numFmts")
+        .into_iter()
+        .flat_map(|formats| formats.children("numFmt"))
+        .filter_map(|format| {
+            Some((
+                format.attribute("numFmtId")?.parse().ok()?,
+                format.attribute("formatCode")?.to_string(),
+            ))
+        })
+        .collect();
+    styles
+        .child("cellXfs")
+        .into_iter()
+        .flat_map(|xfs| xfs.children("xf"))
+        .map(|xf| {
+            let id: u32 = xf.attribute("numFmtId")?.parse().ok()?;
+            custom.get(&id).cloned().or_else(|| {
+                BUILTIN_FORMATS
+                    .iter()
+                    .find(|(builtin, _)| *builtin == id)
+                    .map(|(_, code)| code.to_string())
+            })
+        })
+        .collect()
+}
+
+fn read_sheet(
+    name: &str,
+    worksheet: &Element,
+    shared_strings: &[String],
+    formats: &[Option<String>],
+    warnings: &mut Vec<String>,
+) -> Sheet {
+    let mut inputs: BTreeMap<Cell, String> = BTreeMap::new();
+    let mut number_formats = HashMap::new();
+    // Formulas shared by a block of cells are stored once, in the first one,
+    // as Excel wrote them
+    let mut shared_formulas: HashMap<String, (Cell, String)> = HashMap::new();
+    // Ranges of array formulas, whose other cells hold the spilled values
+    let mut arrays = Vec::new();
+    let mut dropped = 0;
+    let mut past_rows = 0;
+
+    let rows = worksheet
+        .child("sheetData")
+        .into_iter()
+        .flat_map(|data| data.children("row"));
+    let mut next_row = 0;
+    for row in rows {
+        let row_index = row
+            .attribute("r")
+            .and_then(|r| r.parse::<usize>().ok())
+            .map_or(next_row, |r| r.saturating_sub(1));
+        next_row = row_index + 1;
+
+        let mut next_col = 0;
+        for c in row.children("c") {
+            let cell = c
+                .attribute("r")
+                .and_then(parse_a1)
+                .unwrap_or((row_index, next_col));
+            next_col = cell.1 + 1;
+
+            let formula = c.child("f").and_then(|f| {
+                let text = f.text();
+                if f.attribute("t") == Some("array")
+                    && let Some(range) = f.attribute("ref").and_then(parse_a1_range)
+                {
+                    arrays.push(range);
+                }
+                match (f.attribute("t"), f.attribute("si")) {
+                    (Some("shared"), Some(si)) if text.is_empty() => {
+                        let (origin, formula) = shared_formulas.get(si)?;
+                        Some(import_formula(&shift_shared(
+                            formula,
+                            cell.0 as isize - origin.0 as isize,
+                            cell.1 as isize - origin.1 as isize,
+                        )))
+                    }
+                    (Some("shared"), Some(si)) => {
+                        shared_formulas.insert(si.to_string(), (cell, text.clone()));
+                        Some(import_formula(&text))
+                    }
+                    _ if text.is_empty() => None,
+                    _ => Some(import_formula(&text)),
+                }
+            });
+            let value = c.child("v").map(Element::text);
+            let input = match (formula, c.attribute("t")) {
+                (Some(formula), _) => Some(formula),
+                (None, Some("s")) => value
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .and_then(|i| shared_strings.get(i).cloned()),
+                (None, Some("inlineStr")) => c.child("is").map(shared_string),
+                (None, Some("b")) => {
+                    value.map(|v| if v == "1" { "=TRUE" } else { "=FALSE" }.to_string())
+                }
+                (None, _) => value,
+            };
+            let Some(input) = input.filter(|input| !input.is_empty()) else {
+                continue;
+            };
+
+            if cell.1 >= COLS {
+                dropped += 1;
+                continue;
+            }
+            if cell.0 >= MAX_ROWS {
+                past_rows += 1;
+                continue;
+            }
+            if let Some(Some(code)) = c
+                .attribute("s")
+                .and_then(|s| s.parse::<usize>().ok())
+                .and_then(|s| formats.get(s))
+            {
+                number_formats.insert(cell, code.clone());
+            }
+            inputs.insert(cell, input);
+        }
+    }
+
+    if dropped > 0 {
+        warnings.push(format!(
+            "{}: {} cells past column Z were left out",
+            name, dropped
+        ));
+    }
+    if past_rows > 0 {
+        warnings.push(format!(
+            "{}: {} cells past row {} were left out",
+            name, past_rows, MAX_ROWS
+        ));
+    }
+    drop_spilled(&mut inputs, &arrays);
+    let merges: Vec<Range> = worksheet
+        .child("mergeCells")
+        .into_iter()
+        .flat_map(|merges| merges.children("mergeCell"))
+        .filter_map(|merge| parse_a1_range(merge.attribute("ref")?))
+        .collect();
+    let rows = inputs
+        .keys()
+        .map(|(row, _)| row + 1)
+        .chain(merges.iter().map(|merge| merge.end.0.saturating_add(1)))
+        .max()
+        .unwrap_or(0)
+        .clamp(MIN_ROWS, MAX_ROWS);
+    let mut sheet = Sheet::new(rows, COLS);
+    for (cell, input) in inputs {
+        sheet.edit(cell, &input);
+    }
+    sheet.recalculate_all();
+    sheet.number_formats = number_formats;
+    // A break with id n comes after row n, counting from 1, so it starts
+    // the page at row n counting from 0
+    sheet.print.row_breaks = worksheet
+        .child("rowBreaks")
+        .into_iter()
+        .flat_map(|breaks| breaks.children("brk"))
+        .filter_map(|brk| brk.attribute("id")?.parse().ok())
+        .filter(|row| *row > 0)
+        .collect();
+    sheet.print.landscape = worksheet
+        .child("pageSetup")
+        .and_then(|setup| setup.attribute("orientation"))
+        == Some("landscape");
+    merge_imported(name, &mut sheet, &merges, warnings);
+    sheet
+}
+
+// Range in Excel's notation, e.g. "B2:C10", or a single cell
+fn parse_a1_range(reference: &str) -> Option<Range> {
+    match reference.split_once(':') {
+        Some((start, end)) => Some(Range::new(parse_a1(start)?, parse_a1(end)?)),
+        None => parse_a1(reference).map(|cell| Range::new(cell, cell)),
+    }
+}
+
+// A formula as stored by Excel, without its '=', in the form typed into
+// cells
+fn import_formula(formula: &str) -> String {
+    let formula = strip_absolute(formula)
+        .replace("_xlfn._xlws.", "")
+        .replace("_xlfn.", "");
+    shift_references(&format!("={}", formula), -1, 0)
+}
+
+// Moves the relative parts of the references of a formula as Excel stores
+// it, for a cell sharing the formula of another one. "$" marks the parts
+// that stay.
+fn shift_shared(formula: &str, rows: isize, cols: isize) -> String {
+    let chars: Vec<char> = formula.chars().collect();
+    let is_word = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$');
+    let mut out = String::new();
+    let mut in_text = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' {
+            in_text = !in_text;
+        }
+        if in_text || !is_word(c) {
+            out.push(c);
+            i += 1;
+            continue;
+        }
+
+        let end = (i..chars.len())
+            .find(|&j| !is_word(chars[j]))
+            .unwrap_or(chars.len());
+        let word: String = chars[i..end].iter().collect();
+        let is_function = chars.get(end) == Some(&'(');
+        match parse_a1(&word).filter(|_| !is_function) {
+            Some((row, col)) => {
+                // "$B3" keeps its column, "B$3" its row
+                let letters = word
+                    .split(|c: char| c.is_ascii_digit())
+                    .next()
+                    .unwrap_or("");
+                let col_fixed = word.starts_with('$');
+                let row_fixed = letters.len() > 1 && letters.ends_with('$');
+                let row = if row_fixed {
+                    Some(row)
+                } else {
+                    row.checked_add_signed(rows)
+                };
+                let col = if col_fixed {
+                    Some(col)
+                } else {
+                    col.checked_add_signed(cols)
+                };
+                match (row, col) {
+                    (Some(row), Some(col)) => out.push_str(&format!(
+                        "{}{}{}{}",
+                        if col_fixed { "$" } else { "" },
+                        a1_column(col),
+                        if row_fixed { "$" } else { "" },
+                        row + 1
+                    )),
+                    _ => out.push_str(&word),
+                }
+            }
+            None => out.push_str(&word),
+        }
+        i = end;
+    }
+    out
+}
+
+// A formula typed into cells in the form Excel stores, without its '='
+fn export_formula(formula: &str) -> String {
+    let formula = shift_references(formula, 1, 0);
+    let formula = rewrite(&formula, |tokens, i| {
+        let (range, kind) = &tokens[i];
+        let name = formula[range.clone()].to_ascii_uppercase();
+        if *kind != TokenKind::Function {
+            None
+        } else if NEWER_FUNCTIONS.contains(&name.as_str()) {
+            Some((format!("_xlfn.{}", name), 1))
+        } else if WORKSHEET_FUNCTIONS.contains(&name.as_str()) {
+            Some((format!("_xlfn._xlws.{}", name), 1))
+        } else {
+            None
+        }
+    });
+    formula[1..].to_string()
+}
+
+// Names referring to a block of one sheet, e.g. "Sheet1!$B$1:$B$10", are
+// defined on that sheet
+fn define_name(sheets: &mut [(String, Sheet)], defined: &Element, warnings: &mut Vec<String>) {
+    let Some(name) = defined.attribute("name") else {
+        return;
+    };
+    let refers_to = strip_absolute(&defined.text());
+    let target = refers_to.rsplit_once('!').and_then(|(sheet, range)| {
+        let sheet = sheet.trim_matches('\'');
+        let (start, end) = range.split_once(':').unwrap_or((range, range));
+        let (start, end) = (parse_a1(start)?, parse_a1(end)?);
+        let index = sheets.iter().position(|(n, _)| n == sheet)?;
+        Some((index, start, end))
+    });
+    if name == "_xlnm.Print_Area" {
+        // Only print areas of a single block are kept
+        match target {
+            Some((index, start, end)) if end.1 < COLS => {
+                sheets[index].1.print.area = Some(Range::new(start, end));
+            }
+            _ => warnings.push(format!("print area {} was left out", refers_to)),
+        }
+        return;
+    }
+    if name.starts_with("_xlnm.") {
+        // Print titles and other names of Excel's own
+        return;
+    }
+    // Ranges are only written out as text once they are known to fit
+    let result = match target {
+        Some((index, start, end)) if end.1 < COLS => sheets[index]
+            .1
+            .define_name(name, &format!("{}:{}", cell_name(start), cell_name(end))),
+        Some(_) => Err("its range is past column Z".to_string()),
+        None => Err(format!("{} is not a range of a sheet", refers_to)),
+    };
+    if let Err(err) = result {
+        warnings.push(format!("name {} was left out: {}", name, err));
+    }
+}
+
+pub fn write(workbook: &Workbook) -> Vec<u8> {
+    // Every distinct format code gets a style, after the default one
+    let mut codes: Vec<&str> = workbook
+        .sheets
+        .iter()
+        .flat_map(|(_, sheet)| sheet.number_formats.values().map(String::as_str))
+        .collect();
+    codes.sort();
+    codes.dedup();
+    let style = |code: &str| codes.iter().position(|c| *c == code).map(|i| i + 1);
+
+    let mut files = vec![
+        ("[Content_Types].xml", content_types(workbook.sheets.len())),
+        ("_rels/.rels", ROOT_RELATIONSHIPS.to_string()),
+        ("xl/workbook.xml", workbook_part(workbook)),
+        (
+            "xl/_rels/workbook.xml.rels",
+            workbook_relationships(workbook.sheets.len()),
+        ),
+        ("xl/styles.xml", styles_part(&codes)),
+    ];
+    let names: Vec<String> = (1..=workbook.sheets.len())
+        .map(|i| format!("xl/worksheets/sheet{}.xml", i))
+        .collect();
+    for ((_, sheet), name) in workbook.sheets.iter().zip(&names) {
+        files.push((name, worksheet_part(sheet, &style)));
+    }
+
+    let files: Vec<(&str, Vec<u8>)> = files
+        .into_iter()
+        .map(|(name, xml)| (name, xml.into_bytes()))
+        .collect();
+    zip::write(&files)
+}
+
+const XML_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#;
+
+const ROOT_RELATIONSHIPS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#;
+
+fn content_types(sheets: usize) -> String {
+    let mut xml = format!(
+        "{}\n<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">\
+         <Default Extension=\"rels\" ContentType=\"application/vnd.openxmlformats-package.relationships+xml\"/>\
+         <Default Extension=\"xml\" ContentType=\"application/xml\"/>\
+         <Override PartName=\"/xl/workbook.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml\"/>\
+         <Override PartName=\"/xl/styles.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.styles+xml\"/>",
+        XML_HEADER
+    );
+    for i in 1..=sheets {
+        xml.push_str(&format!(
+            "<Override PartName=\"/xl/worksheets/sheet{}.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml\"/>",
+            i
+        ));
+    }
+    xml.push_str("</Types>");
+    xml
+}
+
+fn workbook_part(workbook: &Workbook) -> String {
+    let mut xml = format!(
+        "{}\n<workbook xmlns=\"http://schemas.openxmlformats.org/spreadsheetml/2006/main\" \
+         xmlns:r=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships\"><sheets>",
+        XML_HEADER
+    );
+    for (i, (name, _)) in workbook.sheets.iter().enumerate() {
+        xml.push_str(&format!(
+            "<sheet name=\"{}\" sheetId=\"{}\" r:id=\"rId{}\"/>",
+            escaped(name),
+            i + 1,
+            i + 1
+        ));
+    }
+    xml.push_str("</sheets>");
+
+    let mut names = String::new();
+    for (i, (sheet_name, sheet)) in workbook.sheets.iter().enumerate() {
+        let reference = |range: &Range| {
+            format!(
+                "'{}'!${}${}:${}${}",
+                escaped(&sheet_name.replace('\'', "''")),
+                a1_column(range.start.1),
+                range.start.0 + 1,
+                a1_column(range.end.1),
+                range.end.0 + 1
+            )
+        };
+        for (name, range) in sheet.names() {
+            names.push_str(&format!(
+                "<definedName name=\"{}\">{}</definedName>",
+                escaped(name),
+                reference(range)
+            ));
+        }
+        if let Some(area) = &sheet.print.area {
+            names.push_str(&format!(
+                "<definedName name=\"_xlnm.Print_Area\" localSheetId=\"{}\">{}</definedName>",
+                i,
+                reference(area)
+            ));
+        }
+    }
+    if !names.is_empty() {
+        xml.push_str(&format!("<definedNames>{}</definedNames>", names));
+    }
+    // Values are cached for viewers, Excel recalculates them on load
+    xml.push_str("<calcPr fullCalcOnLoad=\"1\"/></workbook>");
+    xml
+}
+
+fn workbook_relationships(sheets: usize) -> String {
+    let mut xml = format!(
+        "{}\n<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">",
+        XML_HEADER
+    );
+    for i in 1..=sheets {
+        xml.push_str(&format!(
+            "<Relationship Id=\"rId{}\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet\" Target=\"worksheets/sheet{}.xml\"/>",
+            i, i
+        ));
+    }
+    xml.push_str(&format!(
+        "<Relationship Id=\"rId{}\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles\" Target=\"styles.xml\"/></Relationships>",
+        sheets + 1
+    ));
+    xml
+}
+
+// Custom format codes start at 164, after the builtin ones
+fn styles_part(codes: &[&str]) -> String {
+    let mut formats = String::new();
+    let mut xfs =
+        String::from("<xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\"/>");
+    for (i, code) in codes.iter().enumerate() {
+        formats.push_str(&format!(
+            "<numFmt numFmtId=\"{}\" formatCode=\"{}\"/>",
+            164 + i,
+            escaped(code)
+        ));
+        xfs.push_str(&format!(
+            "<xf numFmtId=\"{}\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyNumberFormat=\"1\"/>",
+            164 + i
+        ));
+    }
+    format!(
+        "{}\n<styleSheet xmlns=\"http://schemas.openxmlformats.org/spreadsheetml/2006/main\">\
+         <numFmts count=\"{}\">{}</numFmts>\
+         <fonts count=\"1\"><font><sz val=\"11\"/><name val=\"Calibri\"/></font></fonts>\
+         <fills count=\"2\"><fill><patternFill patternType=\"none\"/></fill><fill><patternFill patternType=\"gray125\"/></fill></fills>\
+         <borders count=\"1\"><border><left/><right/><top/><bottom/><diagonal/></border></borders>\
+         <cellStyleXfs count=\"1\"><xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\"/></cellStyleXfs>\
+         <cellXfs count=\"{}\">{}</cellXfs>\
+         <cellStyles count=\"1\"><cellStyle name=\"Normal\" xfId=\"0\" builtinId=\"0\"/></cellStyles>\
+         </styleSheet>",
+        XML_HEADER,
+        codes.len(),
+        formats,
+        codes.len() + 1,
+        xfs
+    )
+}
+
+fn worksheet_part(sheet: &Sheet, style: &dyn Fn(&str) -> Option<usize>) -> String {
+    let mut rows: BTreeMap<usize, Vec<(usize, String)>> = BTreeMap::new();
+    for (cell, input) in &sheet.formulas {
+        if !input.is_empty() {
+            rows.entry(cell.0)
+                .or_default()
+                .push((cell.1, input.clone()));
+        }
+    }
+    // Excel stores the values array formulas spill, like constants
+    for (cell, value) in &sheet.values {
+        if sheet.spilled_from(*cell).is_some() {
+            rows.entry(cell.0)
+                .or_default()
+                .push((cell.1, value.to_string()));
+        }
+    }
+
+    let mut xml = format!(
+        "{}\n<worksheet xmlns=\"http://schemas.openxmlformats.org/spreadsheetml/2006/main\"><sheetData>",
+        XML_HEADER
+    );
+    for (row, mut cells) in rows {
+        cells.sort();
+        xml.push_str(&format!("<row r=\"{}\">", row + 1));
+        for (col, input) in cells {
+            let cell = (row, col);
+            let style = sheet
+                .number_formats
+                .get(&cell)
+                .and_then(|code| style(code))
+                .map(|s| format!(" s=\"{}\"", s))
+                .unwrap_or_default();
+            let array = sheet
+                .spill(cell)
+                .filter(|spill| !spill.blocked)
+                .map(|spill| spill.range);
+            xml.push_str(&cell_part(
+                &a1_name(cell),
+                &style,
+                &input,
+                sheet.value(cell),
+                array,
+            ));
+        }
+        xml.push_str("</row>");
+    }
+    xml.push_str("</sheetData>");
+    if !sheet.merges().is_empty() {
+        xml.push_str(&format!("<mergeCells count=\"{}\">", sheet.merges().len()));
+        for merge in sheet.merges() {
+            xml.push_str(&format!(
+                "<mergeCell ref=\"{}:{}\"/>",
+                a1_name(merge.start),
+                a1_name(merge.end)
+            ));
+        }
+        xml.push_str("</mergeCells>");
+    }
+    if sheet.print.landscape {
+        xml.push_str("<pageSetup orientation=\"landscape\"/>");
+    }
+    let breaks = &sheet.print.row_breaks;
+    if !breaks.is_empty() {
+        xml.push_str(&format!(
+            "<rowBreaks count=\"{}\" manualBreakCount=\"{}\">",
+            breaks.len(),
+            breaks.len()
+        ));
+        for row in breaks {
+            xml.push_str(&format!("<brk id=\"{}\" max=\"16383\" man=\"1\"/>", row));
+        }
+        xml.push_str("</rowBreaks>");
+    }
+    xml.push_str("</worksheet>");
+    xml
+}
+
+// A cell with its input and value. Formulas spilling into `array` are
+// stored as array formulas over it.
+fn cell_part(
+    reference: &str,
+    style: &str,
+    input: &str,
+    value: Option<&CellValue>,
+    array: Option<Range>,
+) -> String {
+    let array = array
+        .map(|range| {
+            format!(
+                " t=\"array\" ref=\"{}:{}\"",
+                a1_name(range.start),
+                a1_name(range.end)
+            )
+        })
+        .unwrap_or_default();
+    let formula = input
+        .trim()
+        .starts_with('=')
+        .then(|| format!("<f{}>{}</f>", array, escaped(&export_formula(input.trim()))));
+    match (formula, value) {
+        (Some(formula), Some(number @ (CellValue::Number(_) | CellValue::Decimal(_)))) => {
+            format!(
+                "<c r=\"{}\"{}>{}<v>{}</v></c>",
+                reference, style, formula, number
+            )
+        }
+        (Some(formula), Some(CellValue::Text(text))) => format!(
+            "<c r=\"{}\"{} t=\"str\">{}<v>{}</v></c>",
+            reference,
+            style,
+            formula,
+            escaped(text)
+        ),
+        (Some(formula), Some(CellValue::Error(code))) => format!(
+            "<c r=\"{}\"{} t=\"e\">{}<v>{}</v></c>",
+            reference,
+            style,
+            formula,
+            excel_error(code)
+        ),
+        (Some(formula), None) => format!("<c r=\"{}\"{}>{}</c>", reference, style, formula),
+        (None, Some(number @ (CellValue::Number(_) | CellValue::Decimal(_)))) => {
+            format!("<c r=\"{}\"{}><v>{}</v></c>", reference, style, number)
+        }
+        (None, _) => format!(
+            "<c r=\"{}\"{} t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+            reference,
+            style,
+            escaped(input)
+        ),
+    }
+}
+
+// Excel's spelling of an error code
+fn excel_error(code: &str) -> &'static str {
+    match code {
+        "DIV0" => "#DIV/0!",
+        "NAME" => "#NAME?",
+        "REF" => "#REF!",
+        "NUM" => "#NUM!",
+        "N/A" => "#N/A",
+        _ => "#VALUE!",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workbook() -> Workbook {
+        let mut sales = Sheet::new(100, 26);
+        sales.set_formula((0, 0), "Fruit & veg");
+        sales.set_formula((1, 0), "12.5");
+        sales.set_formula((2, 0), "=A1*2");
+        sales.set_formula((3, 0), "=XLOOKUP(1, B0:B9, C0:C9, 0) + STDEV.S(A1:A2)");
+        sales.set_formula((4, 0), "=FORECAST(A1)");
+        sales.define_name("prices", "A1:A2").unwrap();
+        sales.number_formats.insert((1, 0), "0.00%".to_string());
+        sales.merge(Range::new((6, 0), (7, 2))).unwrap();
+        sales.set_formula((0, 3), "=SORT(UNIQUE(A1:A3))");
+        sales.print.area = Some(Range::new((0, 0), (7, 3)));
+        sales.print.row_breaks.insert(5);
+        sales.print.landscape = true;
+        let mut notes = Sheet::new(100, 26);
+        notes.set_formula((0, 1), "hello");
+        Workbook {
+            sheets: vec![("Sales".to_string(), sales), ("Notes".to_string(), notes)],
+        }
+    }
+
+    #[test]
+    fn test_formulas() {
+        assert_eq!(export_formula("=SUM(A0:B2)+C1"), "SUM(A1:B3)+C2");
+        assert_eq!(
+            export_formula("=xlookup(A0, B0:B1, C0:C1)"),
+            "_xlfn.XLOOKUP(A1, B1:B2, C1:C2)"
+        );
+        assert_eq!(import_formula("SUM($A$1:B3)+C2"), "=SUM(A0:B2)+C1");
+        assert_eq!(import_formula("_xlfn.STDEV.S(A1:A3)"), "=STDEV.S(A0:A2)");
+        assert_eq!(
+            export_formula("=FILTER(A0:A2, SEQUENCE(3)>1)"),
+            "_xlfn._xlws.FILTER(A1:A3, _xlfn.SEQUENCE(3)>1)"
+        );
+        assert_eq!(
+            import_formula("_xlfn._xlws.SORT(_xlfn.UNIQUE(A1:A3))"),
+            "=SORT(UNIQUE(A0:A2))"
+        );
+        assert_eq!(
+            shift_shared("SUM($A1:A$1)+B$2*$C$3+\"A1\"+LOG10(A1)", 2, 1),
+            "SUM($A3:B$1)+C$2*$C$3+\"A1\"+LOG10(B3)"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (read, warnings) = read(&write(&workbook())).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(read.sheets.len(), 2);
+        assert_eq!(read.sheets[1].0, "Notes");
+
+        let original = &workbook().sheets[0].1;
+        let sales = read.sheet("sales").unwrap();
+        assert_eq!(sales.formulas, original.formulas);
+        assert_eq!(sales.values, original.values);
+        assert_eq!(sales.number_formats, original.number_formats);
+        assert_eq!(sales.names(), original.names());
+        assert_eq!(sales.merges(), original.merges());
+        assert_eq!(sales.print, original.print);
+        assert!(read.sheets[1].1.print.is_default());
+        assert_eq!(sales.spill((0, 3)), original.spill((0, 3)));
+        assert!(sales.spill((0, 3)).is_some());
+        // Unknown functions are kept and show #NAME
+        assert_eq!(
+            sales.value((4, 0)),
+            Some(&CellValue::Error("NAME".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_read_excel_parts() {
+        // Shared strings, shared formulas, booleans, styles with builtin
+        // formats, and cells, merges and names past column Z or the last row
+        let sheet = r#"<worksheet><sheetData>
+            <row r="1"><c r="A1" t="s"><v>1</v></c><c r="B1" s="1"><v>0.25</v></c></row>
+            <row r="2"><c r="A2"><f t="shared" si="0" ref="A2:A3">B1*$B$1</f><v>1</v></c>
+              <c r="B2" t="b"><v>1</v></c><c r="AA2"><v>3</v></c></row>
+            <row r="3"><c r="A3"><f t="shared" si="0"/></c></row>
+            <row r="1048576"><c r="A1048576"><v>1</v></c></row>
+            </sheetData><mergeCells><mergeCell ref="A5:B6"/><mergeCell ref="Y1:AB1"/>
+            <mergeCell ref="C9999:C1048576"/></mergeCells>
+            </worksheet>"#;
+        let files = [
+            (
+                "xl/workbook.xml",
+                r#"<workbook xmlns:r="r"><sheets><sheet name="Data" r:id="rId7"/></sheets>
+                <definedNames><definedName name="wide">Data!$AA$1:$AB$3</definedName>
+                <definedName name="far">Data!$A$1:$GZ$1</definedName></definedNames></workbook>"#,
+            ),
+            (
+                "xl/_rels/workbook.xml.rels",
+                r#"<Relationships><Relationship Id="rId7" Target="/xl/worksheets/data.xml"/></Relationships>"#,
+            ),
+            (
+                "xl/sharedStrings.xml",
+                r#"<sst><si><t>first</t></si><si><r><t>rich </t></r><r><t>text</t></r><rPh><t>x</t></rPh></si></sst>"#,
+            ),
+            (
+                "xl/styles.xml",
+                r#"<styleSheet><cellXfs><xf numFmtId="0"/><xf numFmtId="9"/></cellXfs></styleSheet>"#,
+            ),
+            ("xl/worksheets/data.xml", sheet),
+        ];
+        let files: Vec<(&str, Vec<u8>)> = files
+            .iter()
+            .map(|(name, xml)| (*name, xml.as_bytes().to_vec()))
+            .collect();
+
+        let (workbook, warnings) = read(&zip::write(&files)).unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                "Data: 1 cells past column Z were left out",
+                "Data: 1 cells past row 10000 were left out",
+                "Data: merged cells Y1:AB1 were left out: it reaches past column Z",
+                "Data: merged cells C9999:C1048576 were left out: it reaches past row 10000",
+                "name wide was left out: its range is past column Z",
+                "name far was left out: its range is past column Z"
+            ]
+        );
+        let data = workbook.sheet("Data").unwrap();
+        assert_eq!(data.rows(), MAX_ROWS);
+        assert_eq!(data.formulas[&(0, 0)], "rich text");
+        assert_eq!(data.formulas[&(1, 0)], "=B0*B0");
+        assert_eq!(data.formulas[&(2, 0)], "=B1*B0");
+        assert_eq!(data.value((1, 1)), Some(&CellValue::Number(1.0)));
+        assert_eq!(data.number_formats[&(0, 1)], "0%");
+        assert_eq!(data.merges(), [Range::new((4, 0), (5, 1))]);
+        assert!(read(b"not a zip").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+// Small XML tree for the parts of spreadsheet packages, which are read
+// whole. Names keep their namespace prefix, e.g. "table:table-cell".
+
+use quick_xml::escape::{escape, unescape};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    // Child elements with the given name
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.elements().filter(move |element| element.name == name)
+    }
+
+    pub fn child<'a>(&'a self, name: &'a str) -> Option<&'a Element> {
+        self.children(name).next()
+    }
+
+    // First descendant with the given name, depth first
+    pub fn find(&self, name: &str) -> Option<&Element> {
+        self.elements().find_map(|element| {
+            (element.name == name)
+                .then_some(element)
+                .or(element.find(name))
+        })
+    }
+
+    // Text of the element and all its descendants
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            match node {
+                Node::Text(t) => text.push_str(t),
+                Node::Element(element) => text.push_str(&element.text()),
+            }
+        }
+        text
+    }
+}
+
+// The root element of a document
+pub fn parse(xml: &str) -> Result<Element, String> {
+    let error = |e: &dyn std::fmt::Display| format!("invalid XML: {}", e);
+    let mut reader = Reader::from_str(xml);
+    // Parents of the element being read, innermost last
+    let mut stack = vec![Element::default()];
+
+    loop {
+        let event = reader.read_event().map_err(|e| error(&e))?;
+        let top = stack
+            .last_mut()
+            .expect("the document is always on the stack");
+        match event {
+            Event::Start(start) => stack.push(element(&start)?),
+            Event::Empty(start) => top.children.push(Node::Element(element(&start)?)),
+            Event::End(_) => {
+                let done = stack.pop().expect("end tags follow start tags");
+                let parent = stack.last_mut().ok_or("unbalanced XML")?;
+                parent.children.push(Node::Element(done));
+            }
+            Event::Text(text) => push_text(top, &text.decode().map_err(|e| error(&e))?),
+            Event::CData(data) => push_text(top, &data.decode().map_err(|e| error(&e))?),
+            Event::GeneralRef(reference) => {
+                let name = reference.decode().map_err(|e| error(&e))?;
+                let entity = format!("&{};", name);
+                let text = unescape(&entity).map_err(|e| error(&e))?;
+                push_text(top, &text);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let document = stack
+        .pop()
+        .filter(|_| stack.is_empty())
+        .ok_or("unbalanced XML")?;
+    document
+        .elements()
+        .next()
+        .cloned()
+        .ok_or_else(|| "empty XML document".to_string())
+}
+
+fn element(start: &BytesStart) -> Result<Element, String> {
+    let mut attributes = Vec::new();
+    for attribute in start.attributes() {
+        let attribute = attribute.map_err(|e| format!("invalid XML: {}", e))?;
+        let value = attribute
+            .normalized_value(XmlVersion::Implicit1_0)
+            .map_err(|e| format!("invalid XML: {}", e))?;
+        attributes.push((
+            String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+            value.into_owned(),
+        ));
+    }
+    Ok(Element {
+        name: String::from_utf8_lossy(start.name().as_ref()).into_owned(),
+        attributes,
+        children: Vec::new(),
+    })
+}
+
+// Text split by entity references is joined back together
+fn push_text(element: &mut Element, text: &str) {
+    if let Some(Node::Text(last)) = element.children.last_mut() {
+        last.push_str(text);
+    } else {
+        element.children.push(Node::Text(text.to_string()));
+    }
+}
+
+// Escapes text for element content and attribute values
+pub fn escaped(text: &str) -> String {
+    escape(text).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let root = parse(
+            r#"<?xml version="1.0"?>
+            <a:root x="1 &amp; 2"><b>fish &amp; <i>chips</i></b><b/><c><b>deep</b></c></a:root>"#,
+        )
+        .unwrap();
+        assert_eq!(root.name, "a:root");
+        assert_eq!(root.attribute("x"), Some("1 & 2"));
+        assert_eq!(root.children("b").count(), 2);
+        assert_eq!(root.child("b").unwrap().text(), "fish & chips");
+        assert_eq!(root.child("c").unwrap().find("b").unwrap().text(), "deep");
+        assert!(parse("<a><b></a>").is_err());
+        assert_eq!(escaped("<\"&\">"), "&lt;&quot;&amp;&quot;&gt;");
+    }
+}
@@ -0,0 +1,417 @@
+// Just enough of the ZIP format for XLSX and ODS packages: reading stored
+// and deflated entries, and writing stored ones. ZIP64, encryption and
+// multi-disk archives are not supported.
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+
+// Names and contents of every file in the archive, in directory order
+pub fn read(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let truncated = || "truncated zip archive".to_string();
+    let u16_at = |at: usize| -> Result<usize, String> {
+        let bytes = data.get(at..at + 2).ok_or_else(truncated)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    };
+    let u32_at = |at: usize| -> Result<u32, String> {
+        let bytes = data.get(at..at + 4).ok_or_else(truncated)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    // The end of central directory record is last, followed only by a
+    // comment of up to 64 KiB
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .take(65536 + 22)
+        .find(|&at| u32_at(at) == Ok(END_OF_DIRECTORY))
+        .ok_or("not a zip archive")?;
+    let count = u16_at(end + 10)?;
+    let mut at = u32_at(end + 16)? as usize;
+
+    let mut files = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(at)? != CENTRAL_HEADER {
+            return Err("corrupt zip directory".to_string());
+        }
+        let method = u16_at(at + 10)?;
+        let compressed = u32_at(at + 20)? as usize;
+        let size = u32_at(at + 24)? as usize;
+        let name_len = u16_at(at + 28)?;
+        let extra_len = u16_at(at + 30)?;
+        let comment_len = u16_at(at + 32)?;
+        let offset = u32_at(at + 42)? as usize;
+        let name = data
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(truncated)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        at += 46 + name_len + extra_len + comment_len;
+
+        if u32_at(offset)? != LOCAL_HEADER {
+            return Err(format!("corrupt zip entry {}", name));
+        }
+        let start = offset + 30 + u16_at(offset + 26)? + u16_at(offset + 28)?;
+        let raw = data.get(start..start + compressed).ok_or_else(truncated)?;
+        let contents = match method {
+            0 => raw.to_vec(),
+            8 => inflate(raw, size).map_err(|e| format!("{}: {}", name, e))?,
+            _ => {
+                return Err(format!(
+                    "{}: unsupported compression method {}",
+                    name, method
+                ));
+            }
+        };
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+// Archive holding the files uncompressed, in the given order
+pub fn write(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for (name, contents) in files {
+        let offset = out.len() as u32;
+        let crc = crc32(contents);
+        let size = contents.len() as u32;
+
+        // Fields shared by the local and central headers: version needed,
+        // flags (names are UTF-8), stored, 1980-01-01 00:00, checksum and
+        // sizes
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&0x0800u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0x0021u16.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(contents);
+
+        directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        directory.extend_from_slice(&common);
+        // Comment length, disk, attributes and the local header offset
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = out.len() as u32;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Bits of a deflate stream, least significant first
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32, String> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or("unexpected end of data")?;
+            self.buffer |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << n) - 1) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Stored blocks start at the next byte boundary
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+// Canonical Huffman code: how many codes have each length, and the symbols
+// ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.take(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order in which the lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// Most memory reserved up front for a file, whatever size the archive
+// declares
+const MAX_CAPACITY: usize = 1 << 20;
+
+// Decompresses a raw deflate stream (RFC 1951) of the size the archive
+// declares, failing as soon as it gets any bigger
+fn inflate(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(size.min(MAX_CAPACITY));
+    let mut bits = Bits {
+        data,
+        pos: 0,
+        buffer: 0,
+        count: 0,
+    };
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let header = data
+                    .get(bits.pos..bits.pos + 4)
+                    .ok_or("unexpected end of data")?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err("corrupt stored block".to_string());
+                }
+                let start = bits.pos + 4;
+                let block = data
+                    .get(start..start + len as usize)
+                    .ok_or("unexpected end of data")?;
+                out.extend_from_slice(block);
+                bits.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut bits, &mut out, size, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, size, &literals, &distances)?;
+            }
+            _ => return Err("invalid block type".to_string()),
+        }
+        if out.len() > size {
+            return Err(larger_than_declared());
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_length_count = bits.take(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or("repeat without a length")?;
+                (previous, 3 + bits.take(2)?)
+            }
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("too many code lengths".to_string());
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn larger_than_declared() -> String {
+    "data larger than its declared size".to_string()
+}
+
+fn inflate_block(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    size: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        if out.len() > size {
+            return Err(larger_than_declared());
+        }
+        let symbol = literals.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err("invalid length".to_string());
+                }
+                let length =
+                    LENGTH_BASE[index] as usize + bits.take(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(bits)? as usize;
+                if index >= DISTANCE_BASE.len() {
+                    return Err("invalid distance".to_string());
+                }
+                let distance = DISTANCE_BASE[index] as usize
+                    + bits.take(DISTANCE_EXTRA[index] as u32)? as usize;
+                if distance > out.len() {
+                    return Err("distance too far back".to_string());
+                }
+                // Copies may overlap what they produce
+                let start = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let files = [
+            ("mimetype", b"text/plain".to_vec()),
+            ("dir/é.xml", b"<a/>".to_vec()),
+        ];
+        let read = read(&write(&files)).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0], ("mimetype".to_string(), b"text/plain".to_vec()));
+        assert_eq!(read[1], ("dir/é.xml".to_string(), b"<a/>".to_vec()));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_inflate() {
+        // "hello hello hello" compressed with the fixed Huffman codes
+        let fixed = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00];
+        assert_eq!(inflate(&fixed, 17).unwrap(), b"hello hello hello");
+
+        // A pangram repeated four times and then another, compressed by
+        // zlib with dynamic Huffman codes and back-references
+        let dynamic = [
+            0xcd, 0xcb, 0xc9, 0x11, 0x80, 0x20, 0x10, 0x44, 0xd1, 0x54, 0x3a, 0x0f, 0xa3, 0x01,
+            0x65, 0x53, 0x60, 0xd8, 0x11, 0xa3, 0x77, 0xca, 0x28, 0x3c, 0x76, 0xfd, 0xd7, 0xcd,
+            0x2a, 0xe4, 0xee, 0xf6, 0x0b, 0xb2, 0xd0, 0x8c, 0xd0, 0x74, 0xe3, 0xec, 0x21, 0x55,
+            0xd0, 0x50, 0x05, 0x8d, 0xb3, 0x17, 0xcf, 0xc2, 0x41, 0x66, 0xfb, 0xd6, 0x0f, 0x70,
+            0x12, 0xec, 0xc2, 0x82, 0x64, 0x34, 0x5d, 0xb3, 0xd0, 0x6e, 0x28, 0x4e, 0x8f, 0x8a,
+            0xf0, 0x2e, 0x77, 0x2a, 0xfc, 0x35, 0xf5, 0x05,
+        ];
+        let expected = "the quick brown fox jumps over the lazy dog; ".repeat(4)
+            + "pack my box with five dozen liquor jugs";
+        assert_eq!(inflate(&dynamic, 219).unwrap(), expected.as_bytes());
+
+        // A stored block
+        let stored = [0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(inflate(&stored, 3).unwrap(), b"abc");
+
+        assert!(inflate(&[0x07], 0).is_err());
+
+        // Output past the declared size, as from a zip bomb, is an error
+        assert!(inflate(&fixed, 16).is_err());
+        assert!(inflate(&dynamic, 100).is_err());
+        assert!(inflate(&stored, 2).is_err());
+    }
+}